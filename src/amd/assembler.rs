@@ -68,12 +68,18 @@ impl Assembler {
             Rule::new(r"subsd xmm([0-7]), xmm([0-7])", Self::subsd_xmm_xmm),
             Rule::new(r"mulsd xmm([0-7]), xmm([0-7])", Self::mulsd_xmm_xmm),
             Rule::new(r"divsd xmm([0-7]), xmm([0-7])", Self::divsd_xmm_xmm),
+            Rule::new(r"minsd xmm([0-7]), xmm([0-7])", Self::minsd_xmm_xmm),
+            Rule::new(r"maxsd xmm([0-7]), xmm([0-7])", Self::maxsd_xmm_xmm),
             Rule::new(r"sqrtsd xmm([0-7]), xmm([0-7])", Self::sqrtsd_xmm_xmm),
             Rule::new(r"rsqrtsd xmm([0-7]), xmm([0-7])", Self::rsqrtsd_xmm_xmm),
             Rule::new(r"andpd xmm([0-7]), xmm([0-7])", Self::andpd_xmm_xmm),
             Rule::new(r"andnpd xmm([0-7]), xmm([0-7])", Self::andnpd_xmm_xmm),
             Rule::new(r"^orpd xmm([0-7]), xmm([0-7])", Self::orpd_xmm_xmm),
             Rule::new(r"xorpd xmm([0-7]), xmm([0-7])", Self::xorpd_xmm_xmm),
+            Rule::new(
+                r"vfmadd(213|231)sd xmm([0-7]), xmm([0-7]), xmm([0-7])",
+                Self::vfmadd_xmm_xmm_xmm,
+            ),
             Rule::new(r"call r([a-z0-9]+)", Self::call_reg),
             Rule::new(r"push r([a-z0-9]+)", Self::push_reg),
             Rule::new(r"pop r([a-z0-9]+)", Self::pop_reg),
@@ -259,6 +265,18 @@ impl Assembler {
         vec![0xf2, 0x0f, 0x5e, Self::modrm_reg(src, dst)]
     }
 
+    fn minsd_xmm_xmm(caps: &Captures) -> Vec<u8> {
+        let dst = Self::xmm(&caps[1]);
+        let src = Self::xmm(&caps[2]);
+        vec![0xf2, 0x0f, 0x5d, Self::modrm_reg(src, dst)]
+    }
+
+    fn maxsd_xmm_xmm(caps: &Captures) -> Vec<u8> {
+        let dst = Self::xmm(&caps[1]);
+        let src = Self::xmm(&caps[2]);
+        vec![0xf2, 0x0f, 0x5f, Self::modrm_reg(src, dst)]
+    }
+
     fn sqrtsd_xmm_xmm(caps: &Captures) -> Vec<u8> {
         let dst = Self::xmm(&caps[1]);
         let src = Self::xmm(&caps[2]);
@@ -314,6 +332,26 @@ impl Assembler {
         vec![0xf2, 0x0f, 0xc2, Self::modrm_reg(src, dst), code]
     }
 
+    fn vfmadd_xmm_xmm_xmm(caps: &Captures) -> Vec<u8> {
+        let code = match &caps[1] {
+            "213" => 0xa9,
+            "231" => 0xb9,
+            _ => {
+                panic!("unrecognized fma form");
+            }
+        };
+        let dst = Self::xmm(&caps[2]);
+        let src2 = Self::xmm(&caps[3]);
+        let src3 = Self::xmm(&caps[4]);
+        vec![
+            0xc4,
+            0xe2,
+            0x81 | ((!src2 & 0x0f) << 3),
+            code,
+            Self::modrm_reg(src3, dst),
+        ]
+    }
+
     fn call_reg(caps: &Captures) -> Vec<u8> {
         let src = Self::reg(&caps[1]);
         vec![0xff, 0xd0 + src]
@@ -359,6 +397,16 @@ fn test_amd() {
     );
     assert_eq!(vec![0xf2, 0x0f, 0x59, 0xc1], a.translate("mulsd xmm0,xmm1"));
     assert_eq!(vec![0xf2, 0x0f, 0x5e, 0xc1], a.translate("divsd xmm0,xmm1"));
+    assert_eq!(vec![0xf2, 0x0f, 0x5d, 0xc5], a.translate("minsd xmm0,xmm5"));
+    assert_eq!(vec![0xf2, 0x0f, 0x5f, 0xc1], a.translate("maxsd xmm0,xmm1"));
+    assert_eq!(
+        vec![0xc4, 0xe2, 0xf1, 0xa9, 0xc2],
+        a.translate("vfmadd213sd xmm0,xmm1,xmm2")
+    );
+    assert_eq!(
+        vec![0xc4, 0xe2, 0xf1, 0xb9, 0xc2],
+        a.translate("vfmadd231sd xmm0,xmm1,xmm2")
+    );
     assert_eq!(
         vec![0x48, 0x8b, 0x43, 0x10],
         a.translate("mov rax,QWORD PTR [rbx+0x10]")
@@ -373,6 +421,21 @@ fn test_amd() {
         vec![0xf2, 0x0f, 0xc2, 0xc1, 0x05],
         a.translate("cmpnltsd xmm0,xmm1")
     );
+    assert_eq!(
+        vec![0xf2, 0x0f, 0xc2, 0xc1, 0x04],
+        a.translate("cmpneqsd xmm0,xmm1")
+    );
+    assert_eq!(
+        vec![0xf2, 0x0f, 0xc2, 0xc1, 0x06],
+        a.translate("cmpnlesd xmm0,xmm1")
+    );
+    for (i, cmp) in ["eq", "lt", "le", "unord", "neq", "nlt", "nle", "ord"]
+        .iter()
+        .enumerate()
+    {
+        let s = format!("cmp{}sd xmm2,xmm3", cmp);
+        assert_eq!(vec![0xf2, 0x0f, 0xc2, 0xd3, i as u8], a.translate(&s));
+    }
     assert_eq!(
         vec![0x66, 0x0f, 0x55, 0xd9],
         a.translate("andnpd xmm3,xmm1")
@@ -109,6 +109,20 @@ macro_rules! make_modrm {
     };
 }
 
+macro_rules! vex256 {
+    ($src1:expr) => {{
+        let src1: u8 = $src1;
+        0x85 | ((!src1 & 0x0f) << 3)
+    }};
+}
+
+// emits an instruction and records its text for the listing
+macro_rules! emit {
+    ($self:ident, $($t:tt)*) => {
+        $self.emit(amd! {$($t)*}, asm_text! {$($t)*})
+    };
+}
+
 macro_rules! amd {
     (movsd xmm($dst:expr), xmm($src:expr)) => {
         make_modrm![0xf2, 0x0f, 0x10; $src, $dst]
@@ -167,6 +181,12 @@ macro_rules! amd {
     (divsd xmm($dst:expr), xmm($src:expr)) => {
         make_modrm![0xf2, 0x0f, 0x5e; $src, $dst]
     };
+    (minsd xmm($dst:expr), xmm($src:expr)) => {
+        make_modrm![0xf2, 0x0f, 0x5d; $src, $dst]
+    };
+    (maxsd xmm($dst:expr), xmm($src:expr)) => {
+        make_modrm![0xf2, 0x0f, 0x5f; $src, $dst]
+    };
     (sqrtsd xmm($dst:expr), xmm($src:expr)) => {
         make_modrm![0xf2, 0x0f, 0x51; $src, $dst]
     };
@@ -209,6 +229,75 @@ macro_rules! amd {
     (cmpordsd xmm($dst:expr), xmm($src:expr)) => {
         make_modrm![0xf2, 0x0f, 0xc2; $src, $dst; 7]
     };
+    // VEX.66.0F38.W1; vvvv holds the inverted second operand
+    (vfmadd213sd xmm($dst:expr), xmm($src2:expr), xmm($src3:expr)) => {
+        {
+            let src2: u8 = $src2;
+            make_modrm![0xc4, 0xe2, 0x81 | ((!src2 & 0x0f) << 3), 0xa9; $src3, $dst]
+        }
+    };
+    (vfmadd231sd xmm($dst:expr), xmm($src2:expr), xmm($src3:expr)) => {
+        {
+            let src2: u8 = $src2;
+            make_modrm![0xc4, 0xe2, 0x81 | ((!src2 & 0x0f) << 3), 0xb9; $src3, $dst]
+        }
+    };
+    // VEX.256.66.0F (two-byte prefix); vvvv holds the inverted first source
+    (vmovupd ymm($dst:expr), ymmword ptr [$base:ident + $offset:expr]) => {
+        make_modrm![0xc5, 0xfd, 0x10; $dst, $base, $offset]
+    };
+    (vmovupd ymmword ptr [$base:ident + $offset:expr], ymm($src:expr)) => {
+        make_modrm![0xc5, 0xfd, 0x11; $src, $base, $offset]
+    };
+    (vmovapd ymm($dst:expr), ymm($src:expr)) => {
+        make_modrm![0xc5, 0xfd, 0x28; $src, $dst]
+    };
+    (vaddpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0x58; $src2, $dst]
+    };
+    (vsubpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0x5c; $src2, $dst]
+    };
+    (vmulpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0x59; $src2, $dst]
+    };
+    (vdivpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0x5e; $src2, $dst]
+    };
+    (vminpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0x5d; $src2, $dst]
+    };
+    (vmaxpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0x5f; $src2, $dst]
+    };
+    (vandpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0x54; $src2, $dst]
+    };
+    (vandnpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0x55; $src2, $dst]
+    };
+    (vorpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0x56; $src2, $dst]
+    };
+    (vxorpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0x57; $src2, $dst]
+    };
+    (vcmpeqpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0xc2; $src2, $dst; 0]
+    };
+    (vcmpltpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0xc2; $src2, $dst; 1]
+    };
+    (vcmplepd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0xc2; $src2, $dst; 2]
+    };
+    (vcmpunordpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0xc2; $src2, $dst; 3]
+    };
+    (vcmpneqpd ymm($dst:expr), ymm($src1:expr), ymm($src2:expr)) => {
+        make_modrm![0xc5, vex256!($src1), 0xc2; $src2, $dst; 4]
+    };
+    (vzeroupper) => { vec![0xc5, 0xf8, 0x77] };
     (call $src:ident) => {
         {
             let src = reg!($src);
@@ -275,6 +364,8 @@ fn test_amd() {
     );
     assert_eq!(vec![0xf2, 0x0f, 0x59, 0xc1], amd! {mulsd xmm(0),xmm(1)});
     assert_eq!(vec![0xf2, 0x0f, 0x5e, 0xc1], amd! {divsd xmm(0),xmm(1)});
+    assert_eq!(vec![0xf2, 0x0f, 0x5d, 0xc5], amd! {minsd xmm(0),xmm(5)});
+    assert_eq!(vec![0xf2, 0x0f, 0x5f, 0xc1], amd! {maxsd xmm(0),xmm(1)});
     assert_eq!(
         vec![0x48, 0x8b, 0x43, 0x10],
         amd! {mov rax,qword ptr [rbx+0x10]}
@@ -289,6 +380,29 @@ fn test_amd() {
         vec![0xf2, 0x0f, 0xc2, 0xc1, 0x05],
         amd! {cmpnltsd xmm(0),xmm(1)}
     );
+    assert_eq!(
+        vec![0xf2, 0x0f, 0xc2, 0xc1, 0x04],
+        amd! {cmpneqsd xmm(0),xmm(1)}
+    );
+    assert_eq!(
+        vec![0xf2, 0x0f, 0xc2, 0xc1, 0x06],
+        amd! {cmpnlesd xmm(0),xmm(1)}
+    );
+    assert_eq!(
+        vec![
+            amd! {cmpeqsd xmm(2),xmm(3)},
+            amd! {cmpltsd xmm(2),xmm(3)},
+            amd! {cmplesd xmm(2),xmm(3)},
+            amd! {cmpunordsd xmm(2),xmm(3)},
+            amd! {cmpneqsd xmm(2),xmm(3)},
+            amd! {cmpnltsd xmm(2),xmm(3)},
+            amd! {cmpnlesd xmm(2),xmm(3)},
+            amd! {cmpordsd xmm(2),xmm(3)},
+        ],
+        (0..8)
+            .map(|i| vec![0xf2, 0x0f, 0xc2, 0xd3, i])
+            .collect::<Vec<_>>()
+    );
     assert_eq!(vec![0x66, 0x0f, 0x55, 0xd9], amd! {andnpd xmm(3),xmm(1)});
     assert_eq!(vec![0x66, 0x0f, 0x54, 0xe2], amd! {andpd xmm(4),xmm(2)});
     assert_eq!(
@@ -302,6 +416,80 @@ fn test_amd() {
     assert_eq!(vec![0xf2, 0x0f, 0x10, 0xcd], amd! {movsd xmm(1),xmm(5)});
     assert_eq!(vec![0x66, 0x48, 0x0f, 0x7e, 0xde], amd! {movq rsi,xmm(3)});
     assert_eq!(vec![0x66, 0x48, 0x0f, 0x6e, 0xe9], amd! {movq xmm(5),rcx});
+    assert_eq!(
+        vec![0xc4, 0xe2, 0xf1, 0xa9, 0xc2],
+        amd! {vfmadd213sd xmm(0),xmm(1),xmm(2)}
+    );
+    assert_eq!(
+        vec![0xc4, 0xe2, 0xd1, 0xa9, 0xc6],
+        amd! {vfmadd213sd xmm(0),xmm(5),xmm(6)}
+    );
+    assert_eq!(
+        vec![0xc4, 0xe2, 0xf1, 0xb9, 0xc2],
+        amd! {vfmadd231sd xmm(0),xmm(1),xmm(2)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xfd, 0x10, 0x45, 0x20],
+        amd! {vmovupd ymm(0),ymmword ptr [rbp+0x20]}
+    );
+    assert_eq!(
+        vec![0xc5, 0xfd, 0x11, 0x9c, 0x24, 0x00, 0x10, 0x00, 0x00],
+        amd! {vmovupd ymmword ptr [rsp+0x1000],ymm(3)}
+    );
+    assert_eq!(vec![0xc5, 0xfd, 0x28, 0xc8], amd! {vmovapd ymm(1),ymm(0)});
+    assert_eq!(
+        vec![0xc5, 0xfd, 0x58, 0xc1],
+        amd! {vaddpd ymm(0),ymm(0),ymm(1)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xd5, 0x5c, 0xc2],
+        amd! {vsubpd ymm(0),ymm(5),ymm(2)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xf5, 0x59, 0xda],
+        amd! {vmulpd ymm(3),ymm(1),ymm(2)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xfd, 0x5e, 0xc7],
+        amd! {vdivpd ymm(0),ymm(0),ymm(7)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xfd, 0x5d, 0xc1],
+        amd! {vminpd ymm(0),ymm(0),ymm(1)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xfd, 0x5f, 0xc1],
+        amd! {vmaxpd ymm(0),ymm(0),ymm(1)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xfd, 0x54, 0xc2],
+        amd! {vandpd ymm(0),ymm(0),ymm(2)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xed, 0x55, 0xd9],
+        amd! {vandnpd ymm(3),ymm(2),ymm(1)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xfd, 0x56, 0xc3],
+        amd! {vorpd ymm(0),ymm(0),ymm(3)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xf5, 0x57, 0xc9],
+        amd! {vxorpd ymm(1),ymm(1),ymm(1)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xf5, 0xc2, 0xc0, 0x01],
+        amd! {vcmpltpd ymm(0),ymm(1),ymm(0)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xfd, 0xc2, 0xd0, 0x03],
+        amd! {vcmpunordpd ymm(2),ymm(0),ymm(0)}
+    );
+    assert_eq!(
+        vec![0xc5, 0xfd, 0xc2, 0xc1, 0x04],
+        amd! {vcmpneqpd ymm(0),ymm(0),ymm(1)}
+    );
+    assert_eq!(vec![0xc5, 0xf8, 0x77], amd! {vzeroupper});
     assert_eq!(vec![0x5d], amd! {pop rbp});
     assert_eq!(vec![0xc3], amd! {ret});
     assert_eq!(
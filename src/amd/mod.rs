@@ -1,98 +1,162 @@
 #[macro_use]
 mod macros;
+mod packed;
 
 use std::collections::{HashMap, HashSet};
 
 use super::analyzer::{Analyzer, Stack};
 use super::code::*;
+use super::listing::listing_line;
 use super::machine::MachineCode;
 use super::model::Program;
 use super::register::{Frame, Word};
+use super::runnable::CompileError;
 use super::utils::*;
 
+pub use packed::{PackedCompiler, LANES};
+
 #[derive(Debug)]
 pub struct AmdCompiler {
     machine_code: Vec<u8>,
+    listing: Vec<String>,
     stack: Stack,
     allocs: HashMap<Word, u8>,
+    optimize: bool,
+    fma: bool,
 }
 
 impl AmdCompiler {
-    pub fn new() -> AmdCompiler {
+    // if optimize is false, every value goes through memory,
+    // which is useful to A/B test the register allocation
+    pub fn new(optimize: bool) -> AmdCompiler {
         Self {
             machine_code: Vec::new(),
+            listing: Vec::new(),
             stack: Stack::new(),
             allocs: HashMap::new(),
+            optimize,
+            fma: false,
         }
     }
 
-    pub fn emit(&mut self, v: Vec<u8>) {
+    pub fn emit(&mut self, v: Vec<u8>, text: String) {
+        let offset = self.machine_code.len();
+        self.listing.push(listing_line(offset, &v, &text));
         self.machine_code.extend_from_slice(&v[..]);
     }
 
     fn op_code(&mut self, op: &str, p: Proc, ry: u8) {
         match op {
             "mov" => {}
-            "plus" => self.emit(amd! {addsd xmm(0), xmm(ry)}),
-            "minus" => self.emit(amd! {subsd xmm(0), xmm(ry)}),
-            "times" => self.emit(amd! {mulsd xmm(0), xmm(ry)}),
-            "divide" => self.emit(amd! {divsd xmm(0), xmm(ry)}),
-            "gt" => self.emit(amd! {cmpnlesd xmm(0), xmm(ry)}),
-            "geq" => self.emit(amd! {cmpnltsd xmm(0), xmm(ry)}),
-            "lt" => self.emit(amd! {cmpltsd xmm(0), xmm(ry)}),
-            "leq" => self.emit(amd! {cmplesd xmm(0), xmm(ry)}),
-            "eq" => self.emit(amd! {cmpeqsd xmm(0), xmm(ry)}),
-            "neq" => self.emit(amd! {cmpneqsd xmm(0), xmm(ry)}),
-            "and" => self.emit(amd! {andpd xmm(0), xmm(ry)}),
-            "or" => self.emit(amd! {orpd xmm(0), xmm(ry)}),
-            "xor" => self.emit(amd! {xorpd xmm(0), xmm(ry)}),
+            "plus" => emit!(self, addsd xmm(0), xmm(ry)),
+            "minus" => emit!(self, subsd xmm(0), xmm(ry)),
+            "times" => emit!(self, mulsd xmm(0), xmm(ry)),
+            "divide" => emit!(self, divsd xmm(0), xmm(ry)),
+            // x > y is computed as y < x, since cmpnlesd (not x <= y) is
+            // also true if either operand is NaN
+            "gt" | "geq" => {
+                emit!(self, movapd xmm(2), xmm(ry));
+                if op == "gt" {
+                    emit!(self, cmpltsd xmm(2), xmm(0));
+                } else {
+                    emit!(self, cmplesd xmm(2), xmm(0));
+                }
+                emit!(self, movapd xmm(0), xmm(2));
+            }
+            "lt" => emit!(self, cmpltsd xmm(0), xmm(ry)),
+            "leq" => emit!(self, cmplesd xmm(0), xmm(ry)),
+            "eq" => emit!(self, cmpeqsd xmm(0), xmm(ry)),
+            "neq" => emit!(self, cmpneqsd xmm(0), xmm(ry)),
+            "and" => emit!(self, andpd xmm(0), xmm(ry)),
+            "or" => emit!(self, orpd xmm(0), xmm(ry)),
+            "xor" => emit!(self, xorpd xmm(0), xmm(ry)),
             "neg" => {
-                self.emit(amd! {movsd xmm(1), qword ptr [rbp+8*Frame::MINUS_ZERO.0]});
-                self.emit(amd! {xorpd xmm(0), xmm(1)});
+                emit!(self, movsd xmm(1), qword ptr [rbp+8*Frame::MINUS_ZERO.0]);
+                emit!(self, xorpd xmm(0), xmm(1));
+            }
+            "abs" => {
+                emit!(self, movsd xmm(1), qword ptr [rbp+8*Frame::ABS_MASK.0]);
+                emit!(self, andpd xmm(0), xmm(1));
+            }
+            // minsd/maxsd return the second operand if either is NaN,
+            // so a NaN in xmm(0) is propagated by oring in its unordered mask
+            "min" | "max" => {
+                emit!(self, movapd xmm(2), xmm(0));
+                emit!(self, cmpunordsd xmm(2), xmm(2));
+                if op == "min" {
+                    emit!(self, minsd xmm(0), xmm(ry));
+                } else {
+                    emit!(self, maxsd xmm(0), xmm(ry));
+                }
+                emit!(self, orpd xmm(0), xmm(2));
             }
-            "power" | "rem" => {
+            "power" | "rem" | "atan2" | "logb" | "hypot" | "interp1" | "interp1_slope" => {
                 if ry != 1 {
-                    self.emit(amd! {movsd xmm(1), xmm(ry)});
+                    emit!(self, movsd xmm(1), xmm(ry));
                 }
-                self.emit(amd! {mov rax, qword ptr [rbx+8*p.0]});
-                self.emit(amd! {call rax});
+                emit!(self, mov rax, qword ptr [rbx+8*p.0]);
+                emit!(self, call rax);
             }
             _ => {                
-                self.emit(amd! {mov rax, qword ptr [rbx+8*p.0]});
-                self.emit(amd! {call rax});
+                emit!(self, mov rax, qword ptr [rbx+8*p.0]);
+                emit!(self, call rax);
             }
         }
     }
 
+    // if the product t is only consumed by the next instruction, t + c,
+    // returns c and the destination of the sum
+    fn fusable(
+        &self,
+        t: Word,
+        next: Option<&Instruction>,
+        saveable: &HashSet<Word>,
+    ) -> Option<(Word, Word)> {
+        if !self.fma || !t.is_temp() || saveable.contains(&t) {
+            return None;
+        }
+
+        match next {
+            Some(Instruction::Binary { x, y, dst, op, .. }) if op == "plus" => {
+                if *x == t && *y != t {
+                    Some((*y, *dst))
+                } else if *y == t && *x != t {
+                    Some((*x, *dst))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
     // xmm(2) == true ? xmm(0) : xmm(1)
     fn ifelse(&mut self) {
-        self.emit(amd! {movapd xmm(3), xmm(2)});
-        self.emit(amd! {andpd xmm(0), xmm(2)});
-        self.emit(amd! {andnpd xmm(3), xmm(1)});
-        self.emit(amd! {orpd xmm(0), xmm(3)});
+        emit!(self, movapd xmm(3), xmm(2));
+        emit!(self, andpd xmm(0), xmm(2));
+        emit!(self, andnpd xmm(3), xmm(1));
+        emit!(self, orpd xmm(0), xmm(3));
     }
 
     fn load(&mut self, x: u8, r: Word, rename: bool) -> u8 {
         if let Some(s) = self.allocs.get(&r) {
             let s = *s;
 
-            if s < 4 {
-                if rename {
-                    return s + 4;
-                } else {
-                    self.emit(amd! {movapd xmm(x), xmm(s+4)});
-                    return x;
-                }
+            if rename {
+                return s;
+            } else {
+                emit!(self, movapd xmm(x), xmm(s));
+                return x;
             }
         }
 
         if r == Frame::ZERO {
-            self.emit(amd! {xorpd xmm(x), xmm(x)});
+            emit!(self, xorpd xmm(x), xmm(x));
         } else if r.is_temp() {
             let k = self.stack.pop(&r);
-            self.emit(amd! {movsd xmm(x), qword ptr [rsp+8*k]});
+            emit!(self, movsd xmm(x), qword ptr [rsp+8*k]);
         } else {
-            self.emit(amd! {movsd xmm(x), qword ptr [rbp+8*r.0]});
+            emit!(self, movsd xmm(x), qword ptr [rbp+8*r.0]);
         };
 
         x
@@ -101,122 +165,423 @@ impl AmdCompiler {
     fn save(&mut self, x: u8, r: Word) {
         if let Some(s) = self.allocs.get(&r) {
             let s = *s;
-
-            if s < 4 {
-                self.emit(amd! {movapd xmm(s+4), xmm(x)});
-                return;
-            }
+            emit!(self, movapd xmm(s), xmm(x));
+            return;
         }
 
         if r.is_temp() {
             let k = self.stack.push(&r);
-            self.emit(amd! {movsd qword ptr [rsp+8*k], xmm(x)});
+            emit!(self, movsd qword ptr [rsp+8*k], xmm(x));
         } else {
-            self.emit(amd! {movsd qword ptr [rbp+8*r.0], xmm(x)});
+            emit!(self, movsd qword ptr [rbp+8*r.0], xmm(x));
         }
     }
 
     fn prologue(&mut self, n: usize) {
-        self.emit(amd! {push rbp});
-        self.emit(amd! {push rbx});
-        self.emit(amd! {mov rbp, rdi});
-        self.emit(amd! {mov rbx, rdx});
-        self.emit(amd! {sub rsp, n});
+        emit!(self, push rbp);
+        emit!(self, push rbx);
+        emit!(self, mov rbp, rdi);
+        emit!(self, mov rbx, rdx);
+        emit!(self, sub rsp, n);
     }
 
     fn epilogue(&mut self, n: usize) {
-        self.emit(amd! {add rsp, n});
-        self.emit(amd! {pop rbx});
-        self.emit(amd! {pop rbp});
-        self.emit(amd! {ret});
+        emit!(self, add rsp, n);
+        emit!(self, pop rbx);
+        emit!(self, pop rbp);
+        emit!(self, ret);
     }
 
     fn codegen(&mut self, prog: &Program, saveable: &HashSet<Word>) {
-        let mut r = Frame::ZERO;
+        // the register whose value is in xmm(0), if any
+        let mut r: Option<Word> = None;
+        let mut fused = false;
+
+        for (i, c) in prog.code.iter().enumerate() {
+            // the plus has already been emitted as part of a fused multiply-add
+            if fused {
+                fused = false;
+                continue;
+            }
 
-        for c in prog.code.iter() {
             match c {
                 Instruction::Unary { p, x, dst, op } => {
-                    if r != *x {
+                    if r != Some(*x) {
                         self.load(0, *x, false);
                     };
                     self.op_code(&op, *p, 0);
-                    r = *dst;
+                    r = Some(*dst);
                 }
                 Instruction::Binary { p, x, y, dst, op } => {
                     // commutative operators
-                    let (x, y) = if (op == "plus" || op == "times") && *y == r {
+                    let (x, y) = if (op == "plus" || op == "times") && Some(*y) == r {
                         (y, x)
                     } else {
                         (x, y)
                     };
 
-                    let ry = if *y == r {
-                        self.emit(amd! {movapd xmm(1), xmm(0)});
+                    let ry = if Some(*y) == r {
+                        emit!(self, movapd xmm(1), xmm(0));
                         1
                     } else {
                         self.load(1, *y, true)
                     };
 
-                    if *x != r {
+                    if Some(*x) != r {
                         self.load(0, *x, false);
                     }
 
-                    self.op_code(&op, *p, ry);
-                    r = *dst;
+                    let addend = if op == "times" {
+                        self.fusable(*dst, prog.code.get(i + 1), saveable)
+                    } else {
+                        None
+                    };
+
+                    if let Some((c, sum)) = addend {
+                        let rc = self.load(2, c, true);
+                        emit!(self, vfmadd213sd xmm(0), xmm(ry), xmm(rc));
+                        r = Some(sum);
+                        fused = true;
+                    } else {
+                        self.op_code(&op, *p, ry);
+                        r = Some(*dst);
+                    }
                 }
                 Instruction::IfElse { x1, x2, cond, dst } => {
-                    if *cond == r {
-                        self.emit(amd! {movapd xmm(2), xmm(0)});
+                    if Some(*cond) == r {
+                        emit!(self, movapd xmm(2), xmm(0));
                     } else {
                         self.load(2, *cond, false);
                     }
 
-                    if *x2 == r {
-                        self.emit(amd! {movapd xmm(1), xmm(0)});
+                    if Some(*x2) == r {
+                        emit!(self, movapd xmm(1), xmm(0));
                     } else {
                         self.load(1, *x2, false);
                     }
 
-                    if *x1 != r {
+                    if Some(*x1) != r {
                         self.load(0, *x1, false);
                     }
 
                     self.ifelse();
-                    r = *dst;
+                    r = Some(*dst);
                 }
                 _ => {
                     continue;
                 }
             }
 
-            if prog.frame.is_diff(&r) || saveable.contains(&r) {
-                self.save(0, r);
-                r = Frame::ZERO;
+            let w = r.unwrap();
+
+            // obs are read back from mem after the run, even if no
+            // instruction consumes them, so they are saved like the diffs
+            if prog.frame.is_diff(&w)
+                || prog.frame.is_obs(&w)
+                || prog.frame.is_local(&w)
+                || saveable.contains(&w)
+            {
+                self.save(0, w);
+                r = None;
             }
         }
     }
 }
 
+/*
+    System V ABI requires rsp to be 16-byte aligned before a call
+    on entry, rsp is 8 (mod 16) because of the return address and the
+    two pushes in the prologue (rbp and rbx) keep it at 8 (mod 16),
+    therefore, the stack frame should be an odd multiple of 8
+*/
+fn frame_size(cap: usize) -> usize {
+    8 * (cap | 1)
+}
+
+/*
+    the registers other than xmm(0) and xmm(1), which hold the operands of
+    every op, that op overwrites as scratch; calls clobber all of them
+*/
+fn clobbers(op: &str, fma: bool) -> Vec<u8> {
+    match op {
+        "gt" | "geq" | "min" | "max" => vec![2],
+        "select" => vec![2, 3],
+        "times" if fma => vec![2],
+        "mov" | "plus" | "minus" | "times" | "divide" | "lt" | "leq" | "eq" | "neq" | "and"
+        | "or" | "xor" | "neg" | "abs" => vec![],
+        _ => (0..8).collect(),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn has_fma() -> bool {
+    is_x86_feature_detected!("fma")
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn has_fma() -> bool {
+    false
+}
+
 impl Compiler<MachineCode> for AmdCompiler {
     fn compile(&mut self, prog: &Program) -> MachineCode {
+        self.try_compile(prog).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_compile(&mut self, prog: &Program) -> Result<MachineCode, CompileError> {
         let analyzer = Analyzer::new(prog);
         let saveable = analyzer.find_saveable();
 
-        self.allocs = analyzer.alloc_regs();
+        self.fma = prog.options.fma && has_fma();
+
+        if self.optimize {
+            // xmm(4)-xmm(7) are never scratch, so they are preferred
+            let fma = self.fma;
+            self.allocs = analyzer.color_regs(&[4, 5, 6, 7, 2, 3], &|op| clobbers(op, fma));
+        }
 
         self.codegen(prog, &saveable);
         self.machine_code.clear();
-        let n = 8 * self.stack.capacity();
+        self.listing.clear();
+        let n = frame_size(self.stack.capacity());
         self.prologue(n);
         self.codegen(prog, &saveable);
         self.epilogue(n);
 
-        MachineCode::new(
-            "x86_64",
-            &self.machine_code.clone(),
-            prog.virtual_table(),
-            prog.frame.mem(),
+        let code = MachineCode::try_new("x86_64", &self.machine_code, prog, prog.frame.mem())?;
+        let features: &[&str] = if self.fma { &["fma"] } else { &[] };
+        Ok(code.with_listing(&self.listing).with_features(features))
+    }
+}
+
+#[test]
+fn test_frame_size() {
+    for cap in 0..20 {
+        let n = frame_size(cap);
+        assert!(n >= 8 * cap);
+        // return address + rbp + rbx + frame
+        assert_eq!((8 + 8 + 8 + n) % 16, 0);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_amd_calls() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    // beeler uses exp, which is called through the virtual table
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+
+    let mut r1 = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let u = r1.initial_states();
+    let p = r1.params();
+    let mut du1 = vec![0.0; u.len()];
+    r1.call(&mut du1, &u, &p, 0.0);
+
+    for optimize in [false, true] {
+        let mut r2 = Runnable::new(Program::new(&ml), CompilerType::Amd { optimize });
+        let mut du2 = vec![0.0; u.len()];
+        r2.call(&mut du2, &u, &p, 0.0);
+        assert_eq!(du1, du2);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_amd_spills() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    // sin(x1) + (sin(x2) + (... + exp(x8))): every call clobbers the
+    // scratch registers, so the pending partial sums are spilled
+    let n = 8;
+    let var = |i: usize| format!(r#"{{"type": "Var", "name": "x{}"}}"#, i);
+    let call = |op: &str, i: usize| {
+        format!(
+            r#"{{"type": "Tree", "op": "{}", "args": [{}]}}"#,
+            op,
+            var(i)
         )
+    };
+    let rhs = (1..n).rev().fold(call("exp", n), |acc, i| {
+        format!(
+            r#"{{"type": "Tree", "op": "plus", "args": [{}, {}]}}"#,
+            call("sin", i),
+            acc
+        )
+    });
+    let states: Vec<String> = (1..=n)
+        .map(|i| format!(r#"{{"name": "x{}", "val": {}}}"#, i, 0.1 * i as f64))
+        .collect();
+    let odes: Vec<String> = (1..=n)
+        .map(|i| {
+            format!(
+                r#"{{"lhs": {{"type": "Tree", "op": "Differential", "args": [{}]}}, "rhs": {}}}"#,
+                var(i),
+                rhs
+            )
+        })
+        .collect();
+    let text = format!(
+        r#"{{"iv": {{"name": "t", "val": 0.0}}, "params": [], "states": [{}], "algs": [], "odes": [{}], "obs": []}}"#,
+        states.join(", "),
+        odes.join(", ")
+    );
+
+    let ml = CellModel::load(&text).unwrap();
+    let prog = Program::new(&ml);
+
+    let mut compiler = AmdCompiler::new(true);
+    let _ = compiler.compile(&prog);
+    assert!(compiler.stack.capacity() > 0);
+
+    let mut r1 = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let mut r2 = Runnable::new(prog, CompilerType::Amd { optimize: true });
+
+    let u = r1.initial_states();
+    let p = r1.params();
+    let mut du1 = vec![0.0; u.len()];
+    let mut du2 = vec![0.0; u.len()];
+
+    r1.call(&mut du1, &u, &p, 0.0);
+    r2.call(&mut du2, &u, &p, 0.0);
+    assert_eq!(du1, du2);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_fma() {
+    use crate::model::{CellModel, Options};
+    use crate::runnable::{CompilerType, Runnable};
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let opts = Options {
+        fma: true,
+        ..Default::default()
+    };
+
+    let mut plain = AmdCompiler::new(true);
+    let _ = plain.compile(&Program::new(&ml));
+    let mut fused = AmdCompiler::new(true);
+    let _ = fused.compile(&Program::with_options(&ml, opts.clone()));
+
+    if is_x86_feature_detected!("fma") {
+        assert!(fused.machine_code.len() < plain.machine_code.len());
+    } else {
+        assert_eq!(fused.machine_code, plain.machine_code);
+    }
+
+    let mut r1 = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let mut r2 = Runnable::new(Program::with_options(&ml, opts), CompilerType::Native);
+
+    let u = r1.initial_states();
+    let p = r1.params();
+    let mut du1 = vec![0.0; u.len()];
+    let mut du2 = vec![0.0; u.len()];
+
+    r1.call(&mut du1, &u, &p, 0.0);
+    r2.call(&mut du2, &u, &p, 0.0);
+
+    for (a, b) in du1.iter().zip(du2.iter()) {
+        assert!((a - b).abs() <= 1e-12 * a.abs().max(1.0));
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_amd_listing() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    let text = std::fs::read_to_string("julia/lorenz.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let prog = Program::new(&ml);
+
+    let mut compiler = AmdCompiler::new(true);
+    let _ = compiler.compile(&prog);
+
+    let r = Runnable::new(prog, CompilerType::Native);
+    let listing = r.disassemble();
+    assert_eq!(listing, compiler.listing.join("\n"));
+
+    // the bytes column adds up to the machine code
+    let bytes: Vec<u8> = listing
+        .lines()
+        .flat_map(|l| {
+            l[9..39]
+                .split_whitespace()
+                .map(|b| u8::from_str_radix(b, 16).unwrap())
+        })
+        .collect();
+    assert_eq!(bytes, compiler.machine_code);
+
+    assert!(listing.starts_with("     0:  55                            push rbp"));
+    assert!(listing.contains("mulsd xmm0, xmm"));
+    assert!(listing.ends_with("ret"));
+
+    let r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    assert_eq!(r.disassemble(), "; no listing for the bytecode backend");
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_amd_regs() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    // x1 * x2 + (x3 * x4 + (... + x11 * x12)): six pending products without
+    // calls, which fit in xmm(2)-xmm(7) without spilling
+    let n = 6;
+    let var = |i: usize| format!(r#"{{"type": "Var", "name": "x{}"}}"#, i);
+    let times = |i: usize| {
+        format!(
+            r#"{{"type": "Tree", "op": "times", "args": [{}, {}]}}"#,
+            var(2 * i - 1),
+            var(2 * i)
+        )
+    };
+    let rhs = (1..n).rev().fold(times(n), |acc, i| {
+        format!(
+            r#"{{"type": "Tree", "op": "plus", "args": [{}, {}]}}"#,
+            times(i),
+            acc
+        )
+    });
+    let states: Vec<String> = (1..=2 * n)
+        .map(|i| format!(r#"{{"name": "x{}", "val": {}}}"#, i, 0.1 * i as f64))
+        .collect();
+    let text = format!(
+        r#"{{"iv": {{"name": "t", "val": 0.0}}, "params": [], "states": [{}], "algs": [], "odes": [{{"lhs": {{"type": "Tree", "op": "Differential", "args": [{}]}}, "rhs": {}}}], "obs": []}}"#,
+        states.join(", "),
+        var(1),
+        rhs
+    );
+
+    let ml = CellModel::load(&text).unwrap();
+
+    let mut compiler = AmdCompiler::new(true);
+    let _ = compiler.compile(&Program::new(&ml));
+    assert_eq!(compiler.stack.capacity(), 0);
+    assert!(compiler.allocs.values().any(|&s| s == 2 || s == 3));
+
+    // a large model, checked against the interpreter
+    let text = std::fs::read_to_string("julia/ohara.json").unwrap();
+    let ohara = CellModel::load(&text).unwrap();
+
+    for ml in [ml, ohara] {
+        let mut r1 = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+        let mut r2 = Runnable::new(Program::new(&ml), CompilerType::Amd { optimize: true });
+
+        let u = r1.initial_states();
+        let p = r1.params();
+        let mut du1 = vec![0.0; u.len()];
+        let mut du2 = vec![0.0; u.len()];
+
+        r1.call(&mut du1, &u, &p, 0.0);
+        r2.call(&mut du2, &u, &p, 0.0);
+        assert_eq!(du1, du2);
     }
 }
@@ -0,0 +1,331 @@
+use std::collections::HashSet;
+
+use crate::analyzer::{Analyzer, Stack};
+use crate::code::*;
+use crate::listing::listing_line;
+use crate::machine::MachineCode;
+use crate::model::Program;
+use crate::register::{Frame, Word};
+use crate::runnable::CompileError;
+use crate::utils::*;
+
+pub const LANES: usize = 4;
+
+/*
+    PackedCompiler evaluates four independent copies of a model at once
+    using AVX ymm registers. The memory is laid out as structure-of-arrays,
+    i.e., lane j of register r is at mem[4*r + j]. Arithmetic, comparisons,
+    and logical operators are packed; transcendental functions have no AVX
+    counterpart and are called through the virtual table once per lane.
+*/
+#[derive(Debug)]
+pub struct PackedCompiler {
+    machine_code: Vec<u8>,
+    listing: Vec<String>,
+    stack: Stack,
+}
+
+impl PackedCompiler {
+    pub fn new() -> PackedCompiler {
+        Self {
+            machine_code: Vec::new(),
+            listing: Vec::new(),
+            stack: Stack::new(),
+        }
+    }
+
+    pub fn emit(&mut self, v: Vec<u8>, text: String) {
+        let offset = self.machine_code.len();
+        self.listing.push(listing_line(offset, &v, &text));
+        self.machine_code.extend_from_slice(&v[..]);
+    }
+
+    fn op_code(&mut self, op: &str, p: Proc, ry: u8) {
+        match op {
+            "mov" => {}
+            "plus" => emit!(self, vaddpd ymm(0), ymm(0), ymm(ry)),
+            "minus" => emit!(self, vsubpd ymm(0), ymm(0), ymm(ry)),
+            "times" => emit!(self, vmulpd ymm(0), ymm(0), ymm(ry)),
+            "divide" => emit!(self, vdivpd ymm(0), ymm(0), ymm(ry)),
+            // x > y is computed as y < x to be NaN-correct, as in AmdCompiler
+            "gt" => emit!(self, vcmpltpd ymm(0), ymm(ry), ymm(0)),
+            "geq" => emit!(self, vcmplepd ymm(0), ymm(ry), ymm(0)),
+            "lt" => emit!(self, vcmpltpd ymm(0), ymm(0), ymm(ry)),
+            "leq" => emit!(self, vcmplepd ymm(0), ymm(0), ymm(ry)),
+            "eq" => emit!(self, vcmpeqpd ymm(0), ymm(0), ymm(ry)),
+            "neq" => emit!(self, vcmpneqpd ymm(0), ymm(0), ymm(ry)),
+            "and" => emit!(self, vandpd ymm(0), ymm(0), ymm(ry)),
+            "or" => emit!(self, vorpd ymm(0), ymm(0), ymm(ry)),
+            "xor" => emit!(self, vxorpd ymm(0), ymm(0), ymm(ry)),
+            "neg" => {
+                emit!(self, vmovupd ymm(1), ymmword ptr [rbp+32*Frame::MINUS_ZERO.0]);
+                emit!(self, vxorpd ymm(0), ymm(0), ymm(1));
+            }
+            "abs" => {
+                emit!(self, vmovupd ymm(1), ymmword ptr [rbp+32*Frame::ABS_MASK.0]);
+                emit!(self, vandpd ymm(0), ymm(0), ymm(1));
+            }
+            "min" | "max" => {
+                emit!(self, vcmpunordpd ymm(2), ymm(0), ymm(0));
+                if op == "min" {
+                    emit!(self, vminpd ymm(0), ymm(0), ymm(ry));
+                } else {
+                    emit!(self, vmaxpd ymm(0), ymm(0), ymm(ry));
+                }
+                emit!(self, vorpd ymm(0), ymm(0), ymm(2));
+            }
+            "power" | "rem" | "atan2" | "logb" | "hypot" | "interp1" | "interp1_slope" => {
+                self.call_lanes(p, Some(ry))
+            }
+            _ => self.call_lanes(p, None),
+        }
+    }
+
+    // spills ymm(0) (and ymm(ry) for binary functions) to the scratch area
+    // past the temporaries and calls the scalar function on each lane
+    fn call_lanes(&mut self, p: Proc, ry: Option<u8>) {
+        let s = 32 * self.stack.capacity();
+
+        emit!(self, vmovupd ymmword ptr [rsp+s], ymm(0));
+        if let Some(ry) = ry {
+            emit!(self, vmovupd ymmword ptr [rsp+s+32], ymm(ry));
+        }
+        emit!(self, vzeroupper);
+
+        for j in 0..LANES {
+            emit!(self, movsd xmm(0), qword ptr [rsp+s+8*j]);
+            if ry.is_some() {
+                emit!(self, movsd xmm(1), qword ptr [rsp+s+32+8*j]);
+            }
+            emit!(self, mov rax, qword ptr [rbx+8*p.0]);
+            emit!(self, call rax);
+            emit!(self, movsd qword ptr [rsp+s+8*j], xmm(0));
+        }
+
+        emit!(self, vmovupd ymm(0), ymmword ptr [rsp+s]);
+    }
+
+    // ymm(2) == true ? ymm(0) : ymm(1)
+    fn ifelse(&mut self) {
+        emit!(self, vandnpd ymm(3), ymm(2), ymm(1));
+        emit!(self, vandpd ymm(0), ymm(0), ymm(2));
+        emit!(self, vorpd ymm(0), ymm(0), ymm(3));
+    }
+
+    fn load(&mut self, x: u8, r: Word) {
+        if r == Frame::ZERO {
+            emit!(self, vxorpd ymm(x), ymm(x), ymm(x));
+        } else if r.is_temp() {
+            let k = self.stack.pop(&r);
+            emit!(self, vmovupd ymm(x), ymmword ptr [rsp+32*k]);
+        } else {
+            emit!(self, vmovupd ymm(x), ymmword ptr [rbp+32*r.0]);
+        }
+    }
+
+    fn save(&mut self, x: u8, r: Word) {
+        if r.is_temp() {
+            let k = self.stack.push(&r);
+            emit!(self, vmovupd ymmword ptr [rsp+32*k], ymm(x));
+        } else {
+            emit!(self, vmovupd ymmword ptr [rbp+32*r.0], ymm(x));
+        }
+    }
+
+    fn prologue(&mut self, n: usize) {
+        emit!(self, push rbp);
+        emit!(self, push rbx);
+        emit!(self, mov rbp, rdi);
+        emit!(self, mov rbx, rdx);
+        emit!(self, sub rsp, n);
+    }
+
+    fn epilogue(&mut self, n: usize) {
+        emit!(self, vzeroupper);
+        emit!(self, add rsp, n);
+        emit!(self, pop rbx);
+        emit!(self, pop rbp);
+        emit!(self, ret);
+    }
+
+    fn codegen(&mut self, prog: &Program, saveable: &HashSet<Word>) {
+        // the register whose value is in ymm(0), if any
+        let mut r: Option<Word> = None;
+
+        for c in prog.code.iter() {
+            match c {
+                Instruction::Unary { p, x, dst, op } => {
+                    if r != Some(*x) {
+                        self.load(0, *x);
+                    };
+                    self.op_code(op, *p, 0);
+                    r = Some(*dst);
+                }
+                Instruction::Binary { p, x, y, dst, op } => {
+                    // commutative operators
+                    let (x, y) = if (op == "plus" || op == "times") && Some(*y) == r {
+                        (y, x)
+                    } else {
+                        (x, y)
+                    };
+
+                    if Some(*y) == r {
+                        emit!(self, vmovapd ymm(1), ymm(0));
+                    } else {
+                        self.load(1, *y);
+                    }
+
+                    if Some(*x) != r {
+                        self.load(0, *x);
+                    }
+
+                    self.op_code(op, *p, 1);
+                    r = Some(*dst);
+                }
+                Instruction::IfElse { x1, x2, cond, dst } => {
+                    if Some(*cond) == r {
+                        emit!(self, vmovapd ymm(2), ymm(0));
+                    } else {
+                        self.load(2, *cond);
+                    }
+
+                    if Some(*x2) == r {
+                        emit!(self, vmovapd ymm(1), ymm(0));
+                    } else {
+                        self.load(1, *x2);
+                    }
+
+                    if Some(*x1) != r {
+                        self.load(0, *x1);
+                    }
+
+                    self.ifelse();
+                    r = Some(*dst);
+                }
+                _ => {
+                    continue;
+                }
+            }
+
+            let w = r.unwrap();
+
+            if prog.frame.is_diff(&w)
+                || prog.frame.is_obs(&w)
+                || prog.frame.is_local(&w)
+                || saveable.contains(&w)
+            {
+                self.save(0, w);
+                r = None;
+            }
+        }
+    }
+}
+
+/*
+    the temporaries are followed by two ymm-sized scratch slots for
+    call_lanes; as in AmdCompiler, the frame should be 8 (mod 16)
+*/
+fn frame_size(cap: usize) -> usize {
+    32 * (cap + 2) + 8
+}
+
+// broadcasts each word of mem to the four lanes
+pub fn broadcast(mem: &[f64]) -> Vec<f64> {
+    mem.iter()
+        .flat_map(|x| std::iter::repeat_n(*x, LANES))
+        .collect()
+}
+
+impl Compiler<MachineCode> for PackedCompiler {
+    fn compile(&mut self, prog: &Program) -> MachineCode {
+        self.try_compile(prog).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_compile(&mut self, prog: &Program) -> Result<MachineCode, CompileError> {
+        let analyzer = Analyzer::new(prog);
+        let saveable = analyzer.find_saveable();
+
+        self.codegen(prog, &saveable);
+        self.machine_code.clear();
+        self.listing.clear();
+        let n = frame_size(self.stack.capacity());
+        self.prologue(n);
+        self.codegen(prog, &saveable);
+        self.epilogue(n);
+
+        let mem = broadcast(&prog.frame.mem());
+        let code = MachineCode::try_new("x86_64", &self.machine_code, prog, mem)?;
+        Ok(code.with_listing(&self.listing).with_features(&["avx"]))
+    }
+}
+
+#[test]
+fn test_packed_frame_size() {
+    for cap in 0..20 {
+        let n = frame_size(cap);
+        assert!(n >= 32 * cap + 64);
+        assert_eq!((8 + 8 + 8 + n) % 16, 0);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_packed() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    if !is_x86_feature_detected!("avx") {
+        return;
+    }
+
+    for name in ["beeler", "ohara"] {
+        let text = std::fs::read_to_string(format!("julia/{}.json", name)).unwrap();
+        let ml = CellModel::load(&text).unwrap();
+
+        let mut r1 = Runnable::new(Program::new(&ml), CompilerType::Amd { optimize: false });
+        let mut r2 = Runnable::new(Program::new(&ml), CompilerType::Native).with_packed();
+
+        // lane j is scaled by 1 + j / 10, so the branches may differ across lanes
+        let lanes: Vec<(Vec<f64>, Vec<f64>)> = (0..LANES)
+            .map(|j| {
+                let a = 1.0 + 0.1 * j as f64;
+                let u = r1.initial_states().iter().map(|x| a * x).collect();
+                let p = r1.params().iter().map(|x| a * x).collect();
+                (u, p)
+            })
+            .collect();
+
+        let soa =
+            |k: usize, f: &dyn Fn(usize) -> f64| -> Vec<f64> { (0..k * LANES).map(f).collect() };
+        let n = r1.count_states;
+        let u4 = soa(n, &|i| lanes[i % LANES].0[i / LANES]);
+        let p4 = soa(r1.count_params, &|i| lanes[i % LANES].1[i / LANES]);
+        let mut du4 = vec![0.0; n * LANES];
+        r2.call_packed(&mut du4, &u4, &p4, 1.5);
+
+        for (j, (u, p)) in lanes.iter().enumerate() {
+            let mut du = vec![0.0; n];
+            r1.call(&mut du, u, p, 1.5);
+            for i in 0..n {
+                assert_eq!(du[i].to_bits(), du4[LANES * i + j].to_bits());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_packed_unsupported() {
+    use crate::machine::has_feature;
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    // refused rather than mapping x86 code on another architecture
+    let ml = CellModel::from_path("julia/lorenz.json").unwrap();
+    let r = Runnable::new(Program::new(&ml), CompilerType::ByteCode).try_with_packed();
+
+    if cfg!(target_arch = "x86_64") && has_feature("avx") {
+        assert!(r.is_ok_and(|r| r.packed.is_some()));
+    } else {
+        assert!(matches!(r.err(), Some(CompileError::Arch(_))));
+    }
+}
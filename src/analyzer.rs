@@ -4,6 +4,14 @@ use super::code::Instruction;
 use super::model::Program;
 use super::register::Word;
 
+// the ops that are calls on the native backends and clobber the registers
+pub const CALLERS: [&str; 35] = [
+    "rem", "power", "atan2", "logb", "hypot", "interp1", "sin", "cos", "tan", "csc", "sec", "cot",
+    "arcsin", "arccos", "arctan", "exp", "ln", "log", "log2", "root", "cbrt", "erf", "erfc",
+    "gamma", "lgamma", "sech2", "sinh", "cosh", "tanh", "expm1", "log1p", "signum", "safe_arcsin",
+    "safe_arccos", "interp1_slope",
+];
+
 pub enum Event {
     Producer(Word),
     Consumer(Word),
@@ -76,12 +84,83 @@ impl Analyzer {
         saveable
     }
 
-    pub fn alloc_regs(&self) -> HashMap<Word, u8> {
-        let caller = [
-            "rem", "power", "sin", "cos", "tan", "csc", "sec", "cot", "arcsin", "arccos", "arctan",
-            "exp", "ln", "log", "root",
-        ];
+    /*
+        Linear-scan allocation of the saveable temps to the physical registers
+        in regs (in order of preference). A temp lives from its producer
+        (exclusive) to its consumer (inclusive) and cannot take a register
+        that is in use by an overlapping temp or is clobbered by any of the
+        ops in its range, as given by clobbers (e.g., every register for a call).
+        Since temps are consumed in stack order, the live ranges are nested and
+        the allocation is optimal in the absence of clobbers. The temps left out
+        go through the stack.
+    */
+    pub fn color_regs(&self, regs: &[u8], clobbers: &dyn Fn(&str) -> Vec<u8>) -> HashMap<Word, u8> {
+        let saveable = self.find_saveable();
+
+        let mut ops: Vec<Vec<u8>> = Vec::new();
+        let mut starts: Vec<(Word, usize)> = Vec::new();
+        let mut ends: HashMap<Word, usize> = HashMap::new();
+
+        for l in self.events.iter() {
+            match l {
+                Event::Producer(p) => {
+                    if saveable.contains(p) && p.is_temp() {
+                        starts.push((*p, ops.len() - 1));
+                    }
+                }
+                Event::Consumer(c) => {
+                    ends.insert(*c, ops.len());
+                }
+                Event::Caller(op) => ops.push(clobbers(op)),
+            }
+        }
+
+        let mut allocs: HashMap<Word, u8> = HashMap::new();
+        let mut active: Vec<(usize, u8)> = Vec::new();
+
+        for (w, start) in starts {
+            let Some(&end) = ends.get(&w) else {
+                continue;
+            };
+
+            active.retain(|(e, _)| *e > start);
+
+            let free = regs.iter().find(|r| {
+                !active.iter().any(|(_, s)| s == *r)
+                    && !ops[start + 1..=end].iter().any(|c| c.contains(r))
+            });
 
+            if let Some(&r) = free {
+                allocs.insert(w, r);
+                active.push((end, r));
+            }
+        }
+
+        allocs
+    }
+
+    // the maximum number of temps live at once
+    pub fn max_live_temps(&self) -> usize {
+        let mut lives: usize = 0;
+        let mut depth: usize = 0;
+
+        for l in self.events.iter() {
+            match l {
+                Event::Producer(p) if p.is_temp() => {
+                    lives += 1;
+                    depth = depth.max(lives);
+                }
+                Event::Consumer(c) if c.is_temp() => {
+                    lives = lives.saturating_sub(1);
+                }
+                _ => {}
+            }
+        }
+
+        depth
+    }
+
+    pub fn alloc_regs(&self) -> HashMap<Word, u8> {
         let mut allocs: HashMap<Word, u8> = HashMap::new();
         let mut lives: Vec<Word> = Vec::new();
         let mut depth: usize = 0;
@@ -107,7 +186,7 @@ impl Analyzer {
                     }
                 }
                 Event::Caller(op) => {
-                    if caller.contains(&op.as_str()) {
+                    if CALLERS.contains(&op.as_str()) {
                         lives.clear();
                         depth = 0;
                     }
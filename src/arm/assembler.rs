@@ -150,8 +150,17 @@ impl Assembler {
                 0x1e601800,
                 r"fdiv d(?<rd>[0-9]+), d(?<rn>[0-9]+), d(?<rm>[0-9]+)",
             ),
+            Rule::new(
+                0x1e605800,
+                r"fmin d(?<rd>[0-9]+), d(?<rn>[0-9]+), d(?<rm>[0-9]+)",
+            ),
+            Rule::new(
+                0x1e604800,
+                r"fmax d(?<rd>[0-9]+), d(?<rn>[0-9]+), d(?<rm>[0-9]+)",
+            ),
             Rule::new(0x1e61c000, r"fsqrt d(?<rd>[0-7]+), d(?<rn>[0-7]+)"),
             Rule::new(0x1e614000, r"fneg d(?<rd>[0-7]+), d(?<rn>[0-7]+)"),
+            Rule::new(0x1e60c000, r"fabs d(?<rd>[0-7]+), d(?<rn>[0-7]+)"),
             Rule::new(
                 0x0e201c00,
                 r"and v(?<rd>[0-9]+).8b, v(?<rn>[0-9]+).8b, v(?<rm>[0-9]+).8b",
@@ -286,6 +295,9 @@ fn test_arm() {
     assert_eq!(a.translate("fadd d0, d0, d1"), vec![0x00, 0x28, 0x61, 0x1E]);
     assert_eq!(a.translate("fmul d0, d0, d1"), vec![0x00, 0x08, 0x61, 0x1E]);
     assert_eq!(a.translate("fsub d0, d0, d1"), vec![0x00, 0x38, 0x61, 0x1E]);
+    assert_eq!(a.translate("fabs d0, d1"), vec![0x20, 0xC0, 0x60, 0x1E]);
+    assert_eq!(a.translate("fmin d0, d0, d1"), vec![0x00, 0x58, 0x61, 0x1E]);
+    assert_eq!(a.translate("fmax d0, d2, d1"), vec![0x40, 0x48, 0x61, 0x1E]);
 
     assert_eq!(
         a.translate("fcmeq d10, d21, d9"),
@@ -55,6 +55,13 @@ macro_rules! of7 {
 }
 
 #[macro_export]
+// emits an instruction and records its text for the listing
+macro_rules! emit {
+    ($self:ident, $($t:tt)*) => {
+        $self.emit(arm! {$($t)*}, asm_text! {$($t)*})
+    };
+}
+
 macro_rules! arm {
     // lr/sp substitution rules
     ($op:ident lr, [sp, #$imm:expr]) => {
@@ -127,6 +134,12 @@ macro_rules! arm {
     (fdiv d($rd:expr), d($rn:expr), d($rm:expr)) => {
         0x1e601800 | rd!($rd) | rn!($rn) | rm!($rm)
     };
+    (fmin d($rd:expr), d($rn:expr), d($rm:expr)) => {
+        0x1e605800 | rd!($rd) | rn!($rn) | rm!($rm)
+    };
+    (fmax d($rd:expr), d($rn:expr), d($rm:expr)) => {
+        0x1e604800 | rd!($rd) | rn!($rn) | rm!($rm)
+    };
 
     (fsqrt d($rd:expr), d($rn:expr)) => {
         0x1e61c000 | rd!($rd) | rn!($rn)
@@ -134,6 +147,9 @@ macro_rules! arm {
     (fneg d($rd:expr), d($rn:expr)) => {
         0x1e614000 | rd!($rd) | rn!($rn)
     };
+    (fabs d($rd:expr), d($rn:expr)) => {
+        0x1e60c000 | rd!($rd) | rn!($rn)
+    };
 
     // logical ops
     (and v($rd:expr).8b, v($rn:expr).8b, v($rm:expr).8b) => {
@@ -242,6 +258,10 @@ fn test_arm() {
         arm! {fsub d(0), d(0), d(1)},
         u32::from_le_bytes([0x00, 0x38, 0x61, 0x1E])
     );
+    assert_eq!(
+        arm! {fabs d(0), d(1)},
+        u32::from_le_bytes([0x20, 0xC0, 0x60, 0x1E])
+    );
 
     assert_eq!(
         arm! {fcmeq d(10), d(21), d(9)},
@@ -268,6 +288,14 @@ fn test_arm() {
         arm! {fdiv d(0), d(0), d(1)},
         u32::from_le_bytes([0x00, 0x18, 0x61, 0x1E])
     );
+    assert_eq!(
+        arm! {fmin d(0), d(0), d(1)},
+        u32::from_le_bytes([0x00, 0x58, 0x61, 0x1E])
+    );
+    assert_eq!(
+        arm! {fmax d(0), d(2), d(1)},
+        u32::from_le_bytes([0x40, 0x48, 0x61, 0x1E])
+    );
     assert_eq!(
         arm! {str d(0), [x(30), #200]},
         u32::from_le_bytes([0xC0, 0x67, 0x00, 0xFD])
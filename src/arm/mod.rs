@@ -5,14 +5,17 @@ use std::collections::{HashMap, HashSet};
 
 use super::analyzer::{Analyzer, Stack};
 use super::code::*;
+use super::listing::listing_line;
 use super::machine::MachineCode;
 use super::model::Program;
 use super::register::{Frame, Word};
+use super::runnable::CompileError;
 use super::utils::*;
 
 #[derive(Debug)]
 pub struct ArmCompiler {
     machine_code: Vec<u8>,
+    listing: Vec<String>,
     stack: Stack,
     allocs: HashMap<Word, u8>,
 }
@@ -21,12 +24,16 @@ impl ArmCompiler {
     pub fn new() -> ArmCompiler {
         Self {
             machine_code: Vec::new(),
+            listing: Vec::new(),
             stack: Stack::new(),
             allocs: HashMap::new(),
         }
     }
 
-    pub fn emit(&mut self, w: u32) {
+    pub fn emit(&mut self, w: u32, text: String) {
+        let offset = self.machine_code.len();
+        self.listing
+            .push(listing_line(offset, &w.to_le_bytes(), &text));
         self.machine_code.push(w as u8);
         self.machine_code.push((w >> 8) as u8);
         self.machine_code.push((w >> 16) as u8);
@@ -36,49 +43,52 @@ impl ArmCompiler {
     fn op_code(&mut self, op: &str, p: Proc, rx: u8, ry: u8) {
         match op {
             "mov" => {}
-            "plus" => self.emit(arm! {fadd d(0), d(rx), d(ry)}),
-            "minus" => self.emit(arm! {fsub d(0), d(rx), d(ry)}),
-            "times" => self.emit(arm! {fmul d(0), d(rx), d(ry)}),
-            "divide" => self.emit(arm! {fdiv d(0), d(rx), d(ry)}),
-            "gt" => self.emit(arm! {fcmgt d(0), d(rx), d(ry)}),
-            "geq" => self.emit(arm! {fcmge d(0), d(rx), d(ry)}),
-            "lt" => self.emit(arm! {fcmlt d(0), d(rx), d(ry)}),
-            "leq" => self.emit(arm! {fcmle d(0), d(rx), d(ry)}),
-            "eq" => self.emit(arm! {fcmeq d(0), d(rx), d(ry)}),
-            "and" => self.emit(arm! {and v(0).8b, v(rx).8b, v(ry).8b}),
-            "or" => self.emit(arm! {orr v(0).8b, v(rx).8b, v(ry).8b}),
-            "xor" => self.emit(arm! {eor v(0).8b, v(rx).8b, v(ry).8b}),
-            "neg" => self.emit(arm! {fneg d(0), d(rx)}),
-            "root" => self.emit(arm! {fsqrt d(0), d(rx)}),
+            "plus" => emit!(self, fadd d(0), d(rx), d(ry)),
+            "minus" => emit!(self, fsub d(0), d(rx), d(ry)),
+            "times" => emit!(self, fmul d(0), d(rx), d(ry)),
+            "divide" => emit!(self, fdiv d(0), d(rx), d(ry)),
+            "min" => emit!(self, fmin d(0), d(rx), d(ry)),
+            "max" => emit!(self, fmax d(0), d(rx), d(ry)),
+            "gt" => emit!(self, fcmgt d(0), d(rx), d(ry)),
+            "geq" => emit!(self, fcmge d(0), d(rx), d(ry)),
+            "lt" => emit!(self, fcmlt d(0), d(rx), d(ry)),
+            "leq" => emit!(self, fcmle d(0), d(rx), d(ry)),
+            "eq" => emit!(self, fcmeq d(0), d(rx), d(ry)),
+            "and" => emit!(self, and v(0).8b, v(rx).8b, v(ry).8b),
+            "or" => emit!(self, orr v(0).8b, v(rx).8b, v(ry).8b),
+            "xor" => emit!(self, eor v(0).8b, v(rx).8b, v(ry).8b),
+            "neg" => emit!(self, fneg d(0), d(rx)),
+            "abs" => emit!(self, fabs d(0), d(rx)),
+            "root" => emit!(self, fsqrt d(0), d(rx)),
             "neq" => {
-                self.emit(arm! {fcmeq d(0), d(rx), d(ry)});
-                self.emit(arm! {not v(0).8b, v(0).8b});
+                emit!(self, fcmeq d(0), d(rx), d(ry));
+                emit!(self, not v(0).8b, v(0).8b);
             }
-            "power" | "rem" => {
+            "power" | "rem" | "atan2" | "logb" | "hypot" | "interp1" | "interp1_slope" => {
                 if rx != 0 {
-                    self.emit(arm! {fmov d(0), d(rx)});
+                    emit!(self, fmov d(0), d(rx));
                 }
                 if ry != 1 {
-                    self.emit(arm! {fmov d(1), d(ry)});
+                    emit!(self, fmov d(1), d(ry));
                 }
-                self.emit(arm! {ldr x(0), [x(20), #8*p.0]});
-                self.emit(arm! {blr x(0)});
+                emit!(self, ldr x(0), [x(20), #8*p.0]);
+                emit!(self, blr x(0));
             }
             _ => {
                 if rx != 0 {
-                    self.emit(arm! {fmov d(0), d(rx)});
+                    emit!(self, fmov d(0), d(rx));
                 }
-                self.emit(arm! {ldr x(0), [x(20), #8*p.0]});
-                self.emit(arm! {blr x(0)});
+                emit!(self, ldr x(0), [x(20), #8*p.0]);
+                emit!(self, blr x(0));
             }
         }
     }
 
     // d2 == true ? d0 : d1
     fn ifelse(&mut self, rc: u8, r1: u8, r2: u8) {
-        self.emit(arm! {bsl v(rc).8b, v(r1).8b, v(r2).8b});
+        emit!(self, bsl v(rc).8b, v(r1).8b, v(r2).8b);
         if rc != 0 {
-            self.emit(arm! {fmov d(0), d(rc)});
+            emit!(self, fmov d(0), d(rc));
         }
     }
 
@@ -90,30 +100,30 @@ impl ArmCompiler {
                 if rename {
                     return s + 4;
                 } else {
-                    self.emit(arm! {fmov d(x), d(s+4)});
+                    emit!(self, fmov d(x), d(s+4));
                     return x;
                 }
             }
         }
 
         if r == Frame::ZERO {
-            self.emit(arm! {fmov d(x), #0.0});
+            emit!(self, fmov d(x), #0.0);
         } else if r == Frame::ONE {
-            self.emit(arm! {fmov d(x), #1.0});
+            emit!(self, fmov d(x), #1.0);
         } else if r == Frame::MINUS_ONE {
-            self.emit(arm! {fmov d(x), #-1.0});
+            emit!(self, fmov d(x), #-1.0);
         } else if r.is_temp() {
             let k = self.stack.pop(&r);
-            self.emit(arm! {ldr d(x), [sp, #8*k]});
+            emit!(self, ldr d(x), [sp, #8*k]);
         } else {
-            self.emit(arm! {ldr d(x), [x(19), #8*r.0]});
+            emit!(self, ldr d(x), [x(19), #8*r.0]);
         };
 
         x
     }
     
-    fn fuse_load(&mut self, r0: Word, x: u8, r: Word, rename: bool) -> u8 {
-        if r == r0 {
+    fn fuse_load(&mut self, r0: Option<Word>, x: u8, r: Word, rename: bool) -> u8 {
+        if Some(r) == r0 {
             0
         } else {
             self.load(x, r, rename)
@@ -125,74 +135,97 @@ impl ArmCompiler {
             let s = *s;
 
             if s < 4 {
-                self.emit(arm! {fmov d(s+4), d(x)});
+                emit!(self, fmov d(s+4), d(x));
                 return;
             }
         }
 
         if r.is_temp() {
             let k = self.stack.push(&r);
-            self.emit(arm! {str d(x), [sp, #8*k]});
+            emit!(self, str d(x), [sp, #8*k]);
         } else {
-            self.emit(arm! {str d(x), [x(19), #8*r.0]});
+            emit!(self, str d(x), [x(19), #8*r.0]);
         }
     }
 
     fn prologue(&mut self, n: usize) {
-        self.emit(arm! {sub sp, sp, #n+32});
-        self.emit(arm! {str lr, [sp, #n]});
-        self.emit(arm! {stp x(19), x(20), [sp, #n+16]});
-        self.emit(arm! {mov x(19), x(0)});
-        self.emit(arm! {mov x(20), x(2)});
+        emit!(self, sub sp, sp, #n+32);
+        emit!(self, str lr, [sp, #n]);
+        emit!(self, stp x(19), x(20), [sp, #n+16]);
+        emit!(self, mov x(19), x(0));
+        emit!(self, mov x(20), x(2));
     }
 
     fn epilogue(&mut self, n: usize) {
-        self.emit(arm! {ldp x(19), x(20), [sp, #n+16]});
-        self.emit(arm! {ldr lr, [sp, #n]});
-        self.emit(arm! {add sp, sp, #n+32});
-        self.emit(arm! {ret});
+        emit!(self, ldp x(19), x(20), [sp, #n+16]);
+        emit!(self, ldr lr, [sp, #n]);
+        emit!(self, add sp, sp, #n+32);
+        emit!(self, ret);
     }
 
     fn codegen(&mut self, prog: &Program, saveable: &HashSet<Word>) {
-        let mut r = Frame::ZERO;
+        // the register whose value is in d(0), if any
+        let mut r: Option<Word> = None;
 
         for c in prog.code.iter() {
             match c {
                 Instruction::Unary { p, x, dst, op } => {
-                    if *x != r {
+                    if Some(*x) != r {
                         self.load(0, *x, false);
                     };
                     self.op_code(&op, *p, 0, 0);
-                    r = *dst;
+                    r = Some(*dst);
                 }
                 Instruction::Binary { p, x, y, dst, op } => {
                     let rx = self.fuse_load(r, 1, *x, true);
                     let ry = self.fuse_load(r, 2, *y, true);
                     self.op_code(&op, *p, rx, ry);
-                    r = *dst;
+                    r = Some(*dst);
                 }
                 Instruction::IfElse { x1, x2, cond, dst } => {
                     let r1 = self.fuse_load(r, 1, *x1, true);
                     let r2 = self.fuse_load(r, 2, *x2, true);
-                    let rc = self.fuse_load(r, 3, *cond, true);
+                    // bsl overwrites the mask, so cond is not renamed
+                    let rc = self.fuse_load(r, 3, *cond, false);
                     self.ifelse(rc, r1, r2);
-                    r = *dst;
+                    r = Some(*dst);
                 }
                 _ => {
                     continue;
                 }
             }
 
-            if prog.frame.is_diff(&r) || saveable.contains(&r) {
-                self.save(0, r);
-                r = Frame::ZERO;
+            let w = r.unwrap();
+
+            // obs are read back from mem after the run, even if no
+            // instruction consumes them, so they are saved like the diffs
+            if prog.frame.is_diff(&w)
+                || prog.frame.is_obs(&w)
+                || prog.frame.is_local(&w)
+                || saveable.contains(&w)
+            {
+                self.save(0, w);
+                r = None;
             }
         }
     }
 }
 
+/*
+    AAPCS64 requires sp to be 16-byte aligned at all times, so the
+    spill area is rounded up to an even number of 8-byte slots (the
+    32 bytes for lr, x19 and x20 are already a multiple of 16)
+*/
+fn frame_size(cap: usize) -> usize {
+    8 * ((cap + 1) & !1)
+}
+
 impl Compiler<MachineCode> for ArmCompiler {
     fn compile(&mut self, prog: &Program) -> MachineCode {
+        self.try_compile(prog).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_compile(&mut self, prog: &Program) -> Result<MachineCode, CompileError> {
         let analyzer = Analyzer::new(prog);
         let saveable = analyzer.find_saveable();
 
@@ -200,16 +233,84 @@ impl Compiler<MachineCode> for ArmCompiler {
 
         self.codegen(prog, &saveable);
         self.machine_code.clear();
-        let n = 8 * ((self.stack.capacity() + 1) & 0xfffe);
+        self.listing.clear();
+        let n = frame_size(self.stack.capacity());
         self.prologue(n);
         self.codegen(prog, &saveable);
         self.epilogue(n);
 
-        MachineCode::new(
-            "aarch64",
-            &self.machine_code.clone(),
-            prog.virtual_table(),
-            prog.frame.mem(),
-        )
+        let code = MachineCode::try_new("aarch64", &self.machine_code, prog, prog.frame.mem())?;
+        Ok(code.with_listing(&self.listing))
     }
 }
+
+#[test]
+fn test_frame_size() {
+    for cap in 0..20 {
+        let n = frame_size(cap);
+        assert!(n >= 8 * cap);
+        assert_eq!((n + 32) % 16, 0);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[test]
+fn test_arm_spills() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    // sin(x1) + (sin(x2) + (... + exp(x8))): every call clobbers the
+    // scratch registers, so the pending partial sums are spilled
+    let n = 8;
+    let var = |i: usize| format!(r#"{{"type": "Var", "name": "x{}"}}"#, i);
+    let call = |op: &str, i: usize| {
+        format!(
+            r#"{{"type": "Tree", "op": "{}", "args": [{}]}}"#,
+            op,
+            var(i)
+        )
+    };
+    let rhs = (1..n).rev().fold(call("exp", n), |acc, i| {
+        format!(
+            r#"{{"type": "Tree", "op": "plus", "args": [{}, {}]}}"#,
+            call("sin", i),
+            acc
+        )
+    });
+    let states: Vec<String> = (1..=n)
+        .map(|i| format!(r#"{{"name": "x{}", "val": {}}}"#, i, 0.1 * i as f64))
+        .collect();
+    let odes: Vec<String> = (1..=n)
+        .map(|i| {
+            format!(
+                r#"{{"lhs": {{"type": "Tree", "op": "Differential", "args": [{}]}}, "rhs": {}}}"#,
+                var(i),
+                rhs
+            )
+        })
+        .collect();
+    let text = format!(
+        r#"{{"iv": {{"name": "t", "val": 0.0}}, "params": [], "states": [{}], "algs": [], "odes": [{}], "obs": []}}"#,
+        states.join(", "),
+        odes.join(", ")
+    );
+
+    let ml = CellModel::load(&text).unwrap();
+    let prog = Program::new(&ml);
+
+    let mut compiler = ArmCompiler::new();
+    let _ = compiler.compile(&prog);
+    assert!(compiler.stack.capacity() > 0);
+
+    let mut r1 = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let mut r2 = Runnable::new(prog, CompilerType::Arm);
+
+    let u = r1.initial_states();
+    let p = r1.params();
+    let mut du1 = vec![0.0; u.len()];
+    let mut du2 = vec![0.0; u.len()];
+
+    r1.call(&mut du1, &u, &p, 0.0);
+    r2.call(&mut du2, &u, &p, 0.0);
+    assert_eq!(du1, du2);
+}
@@ -0,0 +1,419 @@
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use libloading::Library;
+
+use crate::code::*;
+use crate::model::Program;
+use crate::register::WordType;
+use crate::utils::*;
+
+type CFunc = unsafe extern "C" fn(*mut f64);
+
+// helpers mirroring Code, so that the C code is bit-identical to the interpreter
+const PRELUDE: &str = r#"#include <math.h>
+#include <stdint.h>
+#include <string.h>
+
+static double from_bits(uint64_t u) { double x; memcpy(&x, &u, 8); return x; }
+static uint64_t to_bits(double x) { uint64_t u; memcpy(&u, &x, 8); return u; }
+static double boolean(int b) { return from_bits(b ? ~(uint64_t)0 : 0); }
+static double select(double c, double x1, double x2) {
+    return from_bits((to_bits(x1) & to_bits(c)) | (to_bits(x2) & ~to_bits(c)));
+}
+static double clamp1(double x) { return x < -1.0 ? -1.0 : (x > 1.0 ? 1.0 : x); }
+static double min_(double x, double y) { return isnan(x) || isnan(y) ? NAN : fmin(x, y); }
+static double max_(double x, double y) { return isnan(x) || isnan(y) ? NAN : fmax(x, y); }
+static double signum(double x) { return x > 0.0 ? 1.0 : (x < 0.0 ? -1.0 : (x == 0.0 ? 0.0 : x)); }
+static double sech2(double x) { double e = exp(-2.0 * fabs(x)); return 4.0 * e / ((1.0 + e) * (1.0 + e)); }
+"#;
+
+// as Lookup::eval
+const LERP: &str = r#"
+static double lerp(const double *xs, const double *ys, int n, double x) {
+    if (isnan(x)) return x;
+    if (x <= xs[0]) return ys[0];
+    if (x >= xs[n - 1]) return ys[n - 1];
+    int i = 1;
+    while (xs[i] <= x) i++;
+    return ys[i - 1] + (ys[i] - ys[i - 1]) * (x - xs[i - 1]) / (xs[i] - xs[i - 1]);
+}
+
+// as Lookup::slope
+static double slope(const double *xs, const double *ys, int n, double x) {
+    if (isnan(x)) return x;
+    if (x < xs[0] || x >= xs[n - 1]) return 0.0;
+    int i = 1;
+    while (xs[i] <= x) i++;
+    return (ys[i] - ys[i - 1]) / (xs[i] - xs[i - 1]);
+}
+"#;
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub struct CCompiler {
+    stack: Vec<String>,
+    path: PathBuf,
+    build: bool,
+}
+
+impl CCompiler {
+    // writes the source to a temporary file and builds it with cc into a
+    // shared library, which is loaded; both files are removed afterward
+    // unless CELL_KEEP_ARTIFACTS is set
+    pub fn new() -> CCompiler {
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let name = format!("cell_{}_{}.c", std::process::id(), n);
+
+        Self {
+            stack: Vec::new(),
+            path: std::env::temp_dir().join(name),
+            build: true,
+        }
+    }
+
+    // only writes the source to path, e.g., to embed it in a C/C++ simulator
+    pub fn source(path: &Path) -> CCompiler {
+        Self {
+            stack: Vec::new(),
+            path: path.to_path_buf(),
+            build: false,
+        }
+    }
+
+    fn unary(op: &str, x: String) -> String {
+        match op {
+            "neg" => format!("-({})", x),
+            "abs" => format!("fabs({})", x),
+            "sin" => format!("sin({})", x),
+            "cos" => format!("cos({})", x),
+            "tan" => format!("tan({})", x),
+            "csc" => format!("(1.0 / sin({}))", x),
+            "sec" => format!("(1.0 / cos({}))", x),
+            "cot" => format!("(1.0 / tan({}))", x),
+            "arcsin" => format!("asin({})", x),
+            "arccos" => format!("acos({})", x),
+            "arctan" => format!("atan({})", x),
+            "safe_arcsin" => format!("asin(clamp1({}))", x),
+            "safe_arccos" => format!("acos(clamp1({}))", x),
+            "exp" => format!("exp({})", x),
+            "ln" => format!("log({})", x),
+            // as f64::log(x, 10.0), which is not always equal to log10
+            "log" => format!("(log({}) / log(10.0))", x),
+            "log2" => format!("log2({})", x),
+            "root" => format!("sqrt({})", x),
+            "cbrt" => format!("cbrt({})", x),
+            "erf" => format!("erf({})", x),
+            "erfc" => format!("erfc({})", x),
+            "gamma" => format!("tgamma({})", x),
+            "lgamma" => format!("lgamma({})", x),
+            "sinh" => format!("sinh({})", x),
+            "cosh" => format!("cosh({})", x),
+            "tanh" => format!("tanh({})", x),
+            "expm1" => format!("expm1({})", x),
+            "log1p" => format!("log1p({})", x),
+            "sech2" => format!("sech2({})", x),
+            "signum" => format!("signum({})", x),
+            _ => {
+                let msg = format!("unary op_code {} not found", op);
+                panic!("{}", msg);
+            }
+        }
+    }
+
+    fn binary(op: &str, x: String, y: String) -> String {
+        match op {
+            "plus" => format!("({}) + ({})", x, y),
+            "minus" => format!("({}) - ({})", x, y),
+            "times" => format!("({}) * ({})", x, y),
+            "divide" => format!("({}) / ({})", x, y),
+            "rem" => format!("fmod({}, {})", x, y),
+            // booleans are bitmasks, as in Code::boolean
+            "gt" => format!("boolean(({}) > ({}))", x, y),
+            "geq" => format!("boolean(({}) >= ({}))", x, y),
+            "lt" => format!("boolean(({}) < ({}))", x, y),
+            "leq" => format!("boolean(({}) <= ({}))", x, y),
+            "eq" => format!("boolean(({}) == ({}))", x, y),
+            "neq" => format!("boolean(({}) != ({}))", x, y),
+            "and" => format!("from_bits(to_bits({}) & to_bits({}))", x, y),
+            "or" => format!("from_bits(to_bits({}) | to_bits({}))", x, y),
+            "xor" => format!("from_bits(to_bits({}) ^ to_bits({}))", x, y),
+            "power" => format!("pow({}, {})", x, y),
+            "atan2" => format!("atan2({}, {})", x, y),
+            // as f64::log(x, b)
+            "logb" => format!("(log({}) / log({}))", x, y),
+            "hypot" => format!("hypot({}, {})", x, y),
+            "interp1" => format!("interp1({}, {})", x, y),
+            "interp1_slope" => format!("interp1_slope({}, {})", x, y),
+            "min" | "max" => format!("{}_({}, {})", op, x, y),
+            _ => {
+                let msg = format!("binary op_code {} not found", op);
+                panic!("{}", msg);
+            }
+        }
+    }
+
+    fn number(val: f64) -> String {
+        if val.is_nan() {
+            "NAN".to_string()
+        } else if val.is_infinite() {
+            format!("({}INFINITY)", if val < 0.0 { "-" } else { "" })
+        } else {
+            // {:?} is the shortest representation that round-trips
+            format!("({:?})", val)
+        }
+    }
+
+    // an op coalesced with its mov (see Program::coalesce_movs) assigns
+    // the lhs pushed by Eq
+    fn assign(&mut self, c: &Instruction, rhs: String) -> String {
+        if c.assigned().is_some() {
+            let lhs = self.stack.pop().unwrap();
+            format!("{} = {}", lhs, rhs)
+        } else {
+            rhs
+        }
+    }
+
+    // unlike the Rusty backend, obs are kept in mem, as run_obs reads them from there
+    fn compose(&mut self, prog: &Program) {
+        for c in prog.code.iter() {
+            match c {
+                Instruction::Unary { op, .. } => {
+                    let s = if op == "mov" {
+                        let rhs = self.stack.pop().unwrap();
+                        let lhs = self.stack.pop().unwrap();
+                        format!("{} = {}", lhs, rhs)
+                    } else {
+                        let x = self.stack.pop().unwrap();
+                        self.assign(c, Self::unary(op, x))
+                    };
+                    self.stack.push(s);
+                }
+                Instruction::Binary { op, .. } => {
+                    let y = self.stack.pop().unwrap();
+                    let x = self.stack.pop().unwrap();
+                    let s = self.assign(c, Self::binary(op, x, y));
+                    self.stack.push(s);
+                }
+                Instruction::IfElse { .. } => {
+                    let cond = self.stack.pop().unwrap();
+                    let x2 = self.stack.pop().unwrap();
+                    let x1 = self.stack.pop().unwrap();
+                    let s = self.assign(c, format!("select({}, {}, {})", cond, x1, x2));
+                    self.stack.push(s);
+                }
+                Instruction::Eq { dst } => {
+                    self.stack.push(format!("mem[{}]", dst.0));
+                }
+                Instruction::Num { val, .. } => {
+                    self.stack.push(Self::number(*val));
+                }
+                Instruction::Var { reg, .. } => {
+                    self.stack.push(format!("mem[{}]", reg.0));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // the interp1 tables are embedded and dispatched on the handle held in mem,
+    // both by interp1 and by interp1_slope
+    fn tables(prog: &Program) -> String {
+        let mut cases = String::new();
+
+        for w in prog.frame.words.iter() {
+            if let WordType::Table(_, xs, ys) = w {
+                let t = Lookup::sorted(xs, ys);
+                let (xs, ys) = t.points();
+                let list = |v: &[f64]| -> String {
+                    let v: Vec<String> = v.iter().map(|x| Self::number(*x)).collect();
+                    v.join(", ")
+                };
+                cases.push_str(&format!(
+                    "\tcase {}: {{\n\t\tstatic const double xs[] = {{{}}};\n\t\tstatic const double ys[] = {{{}}};\n\t\treturn F(xs, ys, {}, x);\n\t}}\n",
+                    t.register(),
+                    list(xs),
+                    list(ys),
+                    xs.len()
+                ));
+            }
+        }
+
+        if cases.is_empty() {
+            return String::new();
+        }
+
+        let dispatch = |name: &str, f: &str| {
+            format!(
+                "\nstatic double {}(double h, double x) {{\n\tswitch ((int)h) {{\n{}\t}}\n\treturn NAN;\n}}\n",
+                name,
+                cases.replace("F(xs", &format!("{}(xs", f))
+            )
+        };
+
+        format!(
+            "{}{}{}",
+            LERP,
+            dispatch("interp1", "lerp"),
+            dispatch("interp1_slope", "slope")
+        )
+    }
+
+    fn text(&self, prog: &Program) -> String {
+        let mut src = String::from(PRELUDE);
+        src.push_str(&Self::tables(prog));
+        src.push_str("\nvoid func(double *mem) {\n");
+
+        for sm in self.stack.iter() {
+            src.push_str(&format!("\t{};\n", sm));
+        }
+
+        src.push_str("}\n");
+        src
+    }
+
+    fn write(&self, src: &str) -> std::io::Result<()> {
+        let fd = fs::File::create(&self.path)?;
+        let mut buf = BufWriter::new(fd);
+        buf.write_all(src.as_bytes())?;
+        buf.flush()
+    }
+
+    // fp-contract is off, since fused multiply-adds change the results
+    fn build(&self) -> (Arc<Library>, CFunc) {
+        let so = self.path.with_extension("so");
+
+        let status = Command::new("cc")
+            .args(["-O2", "-ffp-contract=off", "-shared", "-fPIC", "-o"])
+            .arg(&so)
+            .arg(&self.path)
+            .arg("-lm")
+            .status()
+            .expect("cannot run cc");
+        assert!(status.success(), "cc failed to compile {:?}", &self.path);
+
+        let (lib, func) = unsafe {
+            let l = Library::new(&so).expect("cannot load the compiled model");
+            let func = *l.get::<CFunc>(b"func").expect("func is not defined");
+            (l, func)
+        };
+
+        if keep_artifacts() {
+            eprintln!("cell: kept {:?} and {:?}", &self.path, &so);
+        } else {
+            let _ = fs::remove_file(&so);
+            let _ = fs::remove_file(&self.path);
+        }
+
+        (Arc::new(lib), func)
+    }
+}
+
+impl Compiler<CCode> for CCompiler {
+    fn compile(&mut self, prog: &Program) -> CCode {
+        self.compose(prog);
+        let src = self.text(prog);
+        self.write(&src).expect("cannot write the C source");
+
+        let (lib, func) = if self.build {
+            let (lib, func) = self.build();
+            (Some(lib), Some(func))
+        } else {
+            (None, None)
+        };
+
+        CCode {
+            _mem: prog.frame.mem(),
+            _lib: lib,
+            func,
+            src: Arc::from(src),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CCode {
+    _mem: Vec<f64>,
+    _lib: Option<Arc<Library>>, // keeps func loaded, shared by the forks
+    func: Option<CFunc>,
+    src: Arc<str>,
+}
+
+impl Compiled for CCode {
+    fn run(&mut self) {
+        let f = self.func.expect("the C source was not built");
+        unsafe { f(self._mem.as_mut_ptr()) };
+    }
+
+    #[inline]
+    fn mem(&self) -> &[f64] {
+        &self._mem[..]
+    }
+
+    #[inline]
+    fn mem_mut(&mut self) -> &mut [f64] {
+        &mut self._mem[..]
+    }
+
+    fn source(&self) -> Option<&str> {
+        Some(&self.src)
+    }
+
+    fn fork(&self) -> Box<dyn Compiled + Send> {
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_c() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    for name in ["beeler", "ohara"] {
+        let text = std::fs::read_to_string(format!("julia/{}.json", name)).unwrap();
+        let ml = CellModel::load(&text).unwrap();
+
+        let mut r1 = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+        let mut r2 = Runnable::new(Program::new(&ml), CompilerType::C);
+
+        let u = r1.initial_states();
+        let p = r1.params();
+        let mut du1 = vec![0.0; u.len()];
+        let mut du2 = vec![0.0; u.len()];
+
+        r1.call(&mut du1, &u, &p, 0.0);
+        r2.call(&mut du2, &u, &p, 0.0);
+
+        // with the libm feature, the bytecode no longer shares the system libm
+        if cfg!(feature = "libm") {
+            for (x, y) in du1.iter().zip(du2.iter()) {
+                assert!((x - y).abs() <= 1e-9 * x.abs(), "{} {}", x, y);
+            }
+        } else {
+            assert_eq!(du1, du2);
+        }
+        assert!(r2.source().unwrap().contains("void func(double *mem) {"));
+    }
+}
+
+#[test]
+fn test_c_source() {
+    use crate::model::CellModel;
+
+    let text = std::fs::read_to_string("julia/lorenz.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let path = std::env::temp_dir().join(format!("cell_{}_lorenz.c", std::process::id()));
+
+    let _ = CCompiler::source(&path).compile(&Program::new(&ml));
+    let src = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(src.contains("void func(double *mem) {"));
+    assert!(src.contains("mem["));
+}
@@ -1,14 +1,94 @@
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+
 use crate::register::Word;
 
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+/*
+    the transcendental functions behind Code; with the libm feature, they
+    come from the libm crate, which gives the same bits on every platform,
+    instead of the platform libm (through the f64 methods). The C and rusty
+    backends call their own math libraries and are not covered.
+    erf, erfc, gamma, and lgamma have no f64 method and always use libm.
+*/
+#[cfg(feature = "libm")]
+mod math {
+    pub use libm::{acos, asin, atan, atan2, cbrt, cos, cosh, exp, expm1, hypot, log as ln};
+    pub use libm::{log1p, log2, pow, sin, sinh, tan, tanh};
+}
+
+#[cfg(not(feature = "libm"))]
+mod math {
+    macro_rules! unary {
+        ($($f:ident => $m:ident),*) => {
+            $(pub fn $f(x: f64) -> f64 { x.$m() })*
+        };
+    }
+
+    unary!(acos => acos, asin => asin, atan => atan, cbrt => cbrt, cos => cos, cosh => cosh);
+    unary!(exp => exp);
+    unary!(expm1 => exp_m1, ln => ln, log1p => ln_1p, log2 => log2, sin => sin, sinh => sinh);
+    unary!(tan => tan, tanh => tanh);
+
+    pub fn atan2(y: f64, x: f64) -> f64 {
+        y.atan2(x)
+    }
+
+    pub fn pow(x: f64, y: f64) -> f64 {
+        x.powf(y)
+    }
+
+    pub fn hypot(x: f64, y: f64) -> f64 {
+        x.hypot(y)
+    }
+}
+
+// the single-precision counterparts of math, for the f32 bytecode (see Code::from_str32)
+#[cfg(feature = "libm")]
+mod math32 {
+    pub use libm::{acosf as acos, asinf as asin, atan2f as atan2, atanf as atan, cbrtf as cbrt};
+    pub use libm::{cosf as cos, coshf as cosh, expf as exp, expm1f as expm1, hypotf as hypot};
+    pub use libm::{log1pf as log1p, log2f as log2, logf as ln, powf as pow, sinf as sin};
+    pub use libm::{sinhf as sinh, tanf as tan, tanhf as tanh};
+}
+
+#[cfg(not(feature = "libm"))]
+mod math32 {
+    macro_rules! unary {
+        ($($f:ident => $m:ident),*) => {
+            $(pub fn $f(x: f32) -> f32 { x.$m() })*
+        };
+    }
+
+    unary!(acos => acos, asin => asin, atan => atan, cbrt => cbrt, cos => cos, cosh => cosh);
+    unary!(exp => exp);
+    unary!(expm1 => exp_m1, ln => ln, log1p => ln_1p, log2 => log2, sin => sin, sinh => sinh);
+    unary!(tan => tan, tanh => tanh);
+
+    pub fn atan2(y: f32, x: f32) -> f32 {
+        y.atan2(x)
+    }
+
+    pub fn pow(x: f32, y: f32) -> f32 {
+        x.powf(y)
+    }
+
+    pub fn hypot(x: f32, y: f32) -> f32 {
+        x.hypot(y)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Hash, Eq, PartialEq)]
 pub struct Proc(pub usize);
 
-#[derive(Clone)]
+// p indexes the function table of the Program, so it is not serialized
+// but rebuilt from op (see Program::from_ir_json)
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Instruction {
     Unary {
         op: String,
         x: Word,
         dst: Word,
+        #[serde(skip)]
         p: Proc,
     },
     Binary {
@@ -16,6 +96,7 @@ pub enum Instruction {
         x: Word,
         y: Word,
         dst: Word,
+        #[serde(skip)]
         p: Proc,
     },
     IfElse {
@@ -58,6 +139,51 @@ impl std::fmt::Display for Instruction {
     }
 }
 
+impl Instruction {
+    // the register assigned by the op that completes an equation (Eq..op),
+    // i.e., a mov or an op coalesced with its mov, both with a non-temp dst
+    pub fn assigned(&self) -> Option<Word> {
+        match self {
+            Instruction::Unary { dst, .. }
+            | Instruction::Binary { dst, .. }
+            | Instruction::IfElse { dst, .. }
+                if !dst.is_temp() =>
+            {
+                Some(*dst)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_mov(&self) -> bool {
+        matches!(self, Instruction::Unary { op, .. } if op == "mov")
+    }
+
+    // applies f to every register referenced by the instruction
+    pub fn map_words(&mut self, f: &impl Fn(Word) -> Word) {
+        match self {
+            Instruction::Unary { x, dst, .. } => {
+                *x = f(*x);
+                *dst = f(*dst);
+            }
+            Instruction::Binary { x, y, dst, .. } => {
+                *x = f(*x);
+                *y = f(*y);
+                *dst = f(*dst);
+            }
+            Instruction::IfElse { x1, x2, cond, dst } => {
+                *x1 = f(*x1);
+                *x2 = f(*x2);
+                *cond = f(*cond);
+                *dst = f(*dst);
+            }
+            Instruction::Num { dst, .. } | Instruction::Eq { dst } => *dst = f(*dst),
+            Instruction::Var { reg, .. } => *reg = f(*reg),
+            Instruction::Nop => {}
+        }
+    }
+}
+
 impl std::fmt::Debug for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         std::fmt::Display::fmt(self, f)
@@ -65,21 +191,35 @@ impl std::fmt::Debug for Instruction {
 }
 
 pub type BinaryFunc = fn(f64, f64) -> f64;
+pub type BinaryFunc32 = fn(f32, f32) -> f32;
 
 pub struct Code {}
 
 impl Code {
     pub fn from_str(op: &str) -> BinaryFunc {
-        match op {
+        Code::try_from_str(op).unwrap_or_else(|| panic!("op_code {} not found", op))
+    }
+
+    pub fn try_from_str(op: &str) -> Option<BinaryFunc> {
+        let f: BinaryFunc = match op {
             "nop" => Code::nop,
             "mov" => Code::mov,
             "plus" => Code::plus,
             "minus" => Code::minus,
             "neg" => Code::neg,
+            "abs" => Code::abs,
+            "signum" => Code::signum,
             "times" => Code::times,
             "divide" => Code::divide,
             "rem" => Code::rem,
             "power" => Code::power,
+            "atan2" => Code::atan2,
+            "logb" => Code::logb,
+            "hypot" => Code::hypot,
+            "interp1" => Code::interp1,
+            "interp1_slope" => Code::interp1_slope,
+            "min" => Code::min,
+            "max" => Code::max,
             "gt" => Code::gt,
             "geq" => Code::geq,
             "lt" => Code::lt,
@@ -100,16 +240,117 @@ impl Code {
             "arcsin" => Code::asin,
             "arccos" => Code::acos,
             "arctan" => Code::atan,
+            "safe_arcsin" => Code::safe_asin,
+            "safe_arccos" => Code::safe_acos,
             "exp" => Code::exp,
             "ln" => Code::ln,
             "log" => Code::log,
+            "log2" => Code::log2,
             "root" => Code::root,
+            "cbrt" => Code::cbrt,
+            "erf" => Code::erf,
+            "erfc" => Code::erfc,
+            "gamma" => Code::gamma,
+            "lgamma" => Code::lgamma,
+            "sech2" => Code::sech2,
+            "sinh" => Code::sinh,
+            "cosh" => Code::cosh,
+            "tanh" => Code::tanh,
+            "expm1" => Code::expm1,
+            "log1p" => Code::log1p,
             "ifelse" => Code::nop,
-            _ => {
-                let msg = format!("op_code {} not found", op);
-                panic!("{}", msg)
-            }
-        }
+            "lookup" => Code::nop, // evaluated by the interpreter using its lookup tables
+            "lookup_slope" => Code::nop,
+            _ => return None,
+        };
+        Some(f)
+    }
+
+    pub fn from_str32(op: &str) -> BinaryFunc32 {
+        Code::try_from_str32(op).unwrap_or_else(|| panic!("op_code {} not found", op))
+    }
+
+    /*
+        the single-precision ops of the f32 bytecode, with the semantics of
+        the f64 ones above; interp1 evaluates the shared f64 tables and
+        rounds the result (lookup is done by the interpreter itself)
+    */
+    pub fn try_from_str32(op: &str) -> Option<BinaryFunc32> {
+        let f: BinaryFunc32 = match op {
+            "nop" | "ifelse" | "lookup" | "lookup_slope" => |_, _| 0.0,
+            "mov" => |x, _| x,
+            "plus" => |x, y| x + y,
+            "minus" => |x, y| x - y,
+            "neg" => |x, _| -x,
+            "abs" => |x, _| x.abs(),
+            "signum" => |x, _| Code::signum(x as f64, 0.0) as f32,
+            "times" => |x, y| x * y,
+            "divide" => |x, y| x / y,
+            "rem" => |x, y| x % y,
+            "power" => math32::pow,
+            "atan2" => math32::atan2,
+            "logb" => |x, b| math32::ln(x) / math32::ln(b),
+            "hypot" => math32::hypot,
+            "interp1" => |x, y| Code::interp1(x as f64, y as f64) as f32,
+            "interp1_slope" => |x, y| Code::interp1_slope(x as f64, y as f64) as f32,
+            "min" => |x, y| {
+                if x.is_nan() || y.is_nan() {
+                    f32::NAN
+                } else {
+                    x.min(y)
+                }
+            },
+            "max" => |x, y| {
+                if x.is_nan() || y.is_nan() {
+                    f32::NAN
+                } else {
+                    x.max(y)
+                }
+            },
+            "gt" => |x, y| Code::boolean32(x > y),
+            "geq" => |x, y| Code::boolean32(x >= y),
+            "lt" => |x, y| Code::boolean32(x < y),
+            "leq" => |x, y| Code::boolean32(x <= y),
+            "eq" => |x, y| Code::boolean32(x == y),
+            "neq" => |x, y| Code::boolean32(x != y),
+            "and" => |x, y| f32::from_bits(x.to_bits() & y.to_bits()),
+            "or" => |x, y| f32::from_bits(x.to_bits() | y.to_bits()),
+            "xor" => |x, y| f32::from_bits(x.to_bits() ^ y.to_bits()),
+            "if_pos" => |x, y| if x > 0.0 { y } else { 0.0 },
+            "if_neg" => |x, y| if x < 0.0 { y } else { 0.0 },
+            "sin" => |x, _| math32::sin(x),
+            "cos" => |x, _| math32::cos(x),
+            "tan" => |x, _| math32::tan(x),
+            "csc" => |x, _| 1.0 / math32::sin(x),
+            "sec" => |x, _| 1.0 / math32::cos(x),
+            "cot" => |x, _| 1.0 / math32::tan(x),
+            "arcsin" => |x, _| math32::asin(x),
+            "arccos" => |x, _| math32::acos(x),
+            "arctan" => |x, _| math32::atan(x),
+            "safe_arcsin" => |x, _| math32::asin(x.clamp(-1.0, 1.0)),
+            "safe_arccos" => |x, _| math32::acos(x.clamp(-1.0, 1.0)),
+            "exp" => |x, _| math32::exp(x),
+            "ln" => |x, _| math32::ln(x),
+            "log" => |x, _| math32::ln(x) / math32::ln(10.0),
+            "log2" => |x, _| math32::log2(x),
+            "root" => |x, _| x.sqrt(),
+            "cbrt" => |x, _| math32::cbrt(x),
+            "erf" => |x, _| libm::erff(x),
+            "erfc" => |x, _| libm::erfcf(x),
+            "gamma" => |x, _| libm::tgammaf(x),
+            "lgamma" => |x, _| libm::lgammaf(x),
+            "sech2" => |x, _| {
+                let e = math32::exp(-2.0 * x.abs());
+                4.0 * e / ((1.0 + e) * (1.0 + e))
+            },
+            "sinh" => |x, _| math32::sinh(x),
+            "cosh" => |x, _| math32::cosh(x),
+            "tanh" => |x, _| math32::tanh(x),
+            "expm1" => |x, _| math32::expm1(x),
+            "log1p" => |x, _| math32::log1p(x),
+            _ => return None,
+        };
+        Some(f)
     }
 
     pub fn nop(_x: f64, _y: f64) -> f64 {
@@ -132,6 +373,27 @@ impl Code {
         -x
     }
 
+    pub fn abs(x: f64, _y: f64) -> f64 {
+        x.abs()
+    }
+
+    /*
+        signum is a number, -1, 0, or 1, not a boolean bitmask (see
+        Code::boolean); unlike f64::signum, both 0.0 and -0.0 give 0.0,
+        and NaN is passed through
+    */
+    pub fn signum(x: f64, _y: f64) -> f64 {
+        if x > 0.0 {
+            1.0
+        } else if x < 0.0 {
+            -1.0
+        } else if x == 0.0 {
+            0.0
+        } else {
+            x
+        }
+    }
+
     pub fn times(x: f64, y: f64) -> f64 {
         x * y
     }
@@ -145,79 +407,91 @@ impl Code {
     }
 
     pub fn power(x: f64, y: f64) -> f64 {
-        x.powf(y)
+        math::pow(x, y)
     }
 
-    pub fn gt(x: f64, y: f64) -> f64 {
-        if x > y {
-            1.0
+    // atan2(y, x), the angle of the point (x, y); note the argument order
+    pub fn atan2(y: f64, x: f64) -> f64 {
+        math::atan2(y, x)
+    }
+
+    // min and max propagate NaN (as f64.min/f64.max in wasm and fmin/fmax on arm),
+    // unlike f64::min/f64::max which return the other argument;
+    // the sign of a zero result, e.g. min(0.0, -0.0), is unspecified
+    pub fn min(x: f64, y: f64) -> f64 {
+        if x.is_nan() || y.is_nan() {
+            f64::NAN
         } else {
-            -1.0
+            x.min(y)
         }
     }
 
-    pub fn geq(x: f64, y: f64) -> f64 {
-        if x >= y {
-            1.0
+    pub fn max(x: f64, y: f64) -> f64 {
+        if x.is_nan() || y.is_nan() {
+            f64::NAN
         } else {
-            -1.0
+            x.max(y)
         }
     }
 
+    /*
+        booleans are bitmasks, as produced by the cmp*sd (amd) and fcm* (arm)
+        instructions: true is all ones (a NaN) and false is all zeros (0.0);
+        and/or/xor are bitwise and a select blends its operands bitwise,
+        so every backend gives bit-identical results
+    */
+    pub fn boolean(b: bool) -> f64 {
+        f64::from_bits(if b { u64::MAX } else { 0 })
+    }
+
+    pub fn select(cond: f64, x1: f64, x2: f64) -> f64 {
+        let c = cond.to_bits();
+        f64::from_bits((x1.to_bits() & c) | (x2.to_bits() & !c))
+    }
+
+    pub fn boolean32(b: bool) -> f32 {
+        f32::from_bits(if b { u32::MAX } else { 0 })
+    }
+
+    pub fn select32(cond: f32, x1: f32, x2: f32) -> f32 {
+        let c = cond.to_bits();
+        f32::from_bits((x1.to_bits() & c) | (x2.to_bits() & !c))
+    }
+
+    pub fn gt(x: f64, y: f64) -> f64 {
+        Code::boolean(x > y)
+    }
+
+    pub fn geq(x: f64, y: f64) -> f64 {
+        Code::boolean(x >= y)
+    }
+
     pub fn lt(x: f64, y: f64) -> f64 {
-        if x < y {
-            1.0
-        } else {
-            -1.0
-        }
+        Code::boolean(x < y)
     }
 
     pub fn leq(x: f64, y: f64) -> f64 {
-        if x <= y {
-            1.0
-        } else {
-            -1.0
-        }
+        Code::boolean(x <= y)
     }
 
     pub fn eq(x: f64, y: f64) -> f64 {
-        if x == y {
-            1.0
-        } else {
-            -1.0
-        }
+        Code::boolean(x == y)
     }
 
     pub fn neq(x: f64, y: f64) -> f64 {
-        if x != y {
-            1.0
-        } else {
-            -1.0
-        }
+        Code::boolean(x != y)
     }
 
     pub fn and(x: f64, y: f64) -> f64 {
-        if x > 0.0 && y > 0.0 {
-            1.0
-        } else {
-            -1.0
-        }
+        f64::from_bits(x.to_bits() & y.to_bits())
     }
 
     pub fn or(x: f64, y: f64) -> f64 {
-        if x > 0.0 || y > 0.0 {
-            1.0
-        } else {
-            -1.0
-        }
+        f64::from_bits(x.to_bits() | y.to_bits())
     }
 
     pub fn xor(x: f64, y: f64) -> f64 {
-        if x * y < 0.0 {
-            1.0
-        } else {
-            -1.0
-        }
+        f64::from_bits(x.to_bits() ^ y.to_bits())
     }
 
     pub fn if_pos(x: f64, y: f64) -> f64 {
@@ -237,54 +511,322 @@ impl Code {
     }
 
     pub fn sin(x: f64, _y: f64) -> f64 {
-        x.sin()
+        math::sin(x)
     }
 
     pub fn cos(x: f64, _y: f64) -> f64 {
-        x.cos()
+        math::cos(x)
     }
 
     pub fn tan(x: f64, _y: f64) -> f64 {
-        x.tan()
+        math::tan(x)
     }
 
     pub fn csc(x: f64, _y: f64) -> f64 {
-        1.0 / x.sin()
+        1.0 / math::sin(x)
     }
 
     pub fn sec(x: f64, _y: f64) -> f64 {
-        1.0 / x.cos()
+        1.0 / math::cos(x)
     }
 
     pub fn cot(x: f64, _y: f64) -> f64 {
-        1.0 / x.tan()
+        1.0 / math::tan(x)
     }
 
     pub fn asin(x: f64, _y: f64) -> f64 {
-        x.asin()
+        math::asin(x)
     }
 
     pub fn acos(x: f64, _y: f64) -> f64 {
-        x.acos()
+        math::acos(x)
     }
 
     pub fn atan(x: f64, _y: f64) -> f64 {
-        x.atan()
+        math::atan(x)
+    }
+
+    // safe-math variants clamp the argument to [-1, 1]
+    pub fn safe_asin(x: f64, _y: f64) -> f64 {
+        math::asin(x.clamp(-1.0, 1.0))
+    }
+
+    pub fn safe_acos(x: f64, _y: f64) -> f64 {
+        math::acos(x.clamp(-1.0, 1.0))
     }
 
     pub fn exp(x: f64, _y: f64) -> f64 {
-        x.exp()
+        math::exp(x)
     }
 
     pub fn ln(x: f64, _y: f64) -> f64 {
-        x.ln()
+        math::ln(x)
     }
 
+    // log is base 10 for compatibility with the existing models; ln is the natural log
     pub fn log(x: f64, _y: f64) -> f64 {
-        x.log(10.0)
+        math::ln(x) / math::ln(10.0)
+    }
+
+    pub fn log2(x: f64, _y: f64) -> f64 {
+        math::log2(x)
+    }
+
+    // logb(x, b), the logarithm of x in base b, i.e., ln(x) / ln(b)
+    pub fn logb(x: f64, b: f64) -> f64 {
+        math::ln(x) / math::ln(b)
     }
 
     pub fn root(x: f64, _y: f64) -> f64 {
         x.sqrt()
     }
+
+    pub fn cbrt(x: f64, _y: f64) -> f64 {
+        math::cbrt(x)
+    }
+
+    pub fn erf(x: f64, _y: f64) -> f64 {
+        libm::erf(x)
+    }
+
+    // 1 - erf(x) without the cancellation for large x
+    pub fn erfc(x: f64, _y: f64) -> f64 {
+        libm::erfc(x)
+    }
+
+    pub fn gamma(x: f64, _y: f64) -> f64 {
+        libm::tgamma(x)
+    }
+
+    // ln|gamma(x)|
+    pub fn lgamma(x: f64, _y: f64) -> f64 {
+        libm::lgamma(x)
+    }
+
+    // sqrt(x^2 + y^2) without the overflow (or underflow) of the squares
+    pub fn hypot(x: f64, y: f64) -> f64 {
+        math::hypot(x, y)
+    }
+
+    pub fn sinh(x: f64, _y: f64) -> f64 {
+        math::sinh(x)
+    }
+
+    pub fn cosh(x: f64, _y: f64) -> f64 {
+        math::cosh(x)
+    }
+
+    pub fn tanh(x: f64, _y: f64) -> f64 {
+        math::tanh(x)
+    }
+
+    // exp(x) - 1 and ln(1 + x) without the cancellation near x = 0
+    pub fn expm1(x: f64, _y: f64) -> f64 {
+        math::expm1(x)
+    }
+
+    pub fn log1p(x: f64, _y: f64) -> f64 {
+        math::log1p(x)
+    }
+
+    // sech(x)^2 = 1 - tanh(x)^2, calculated as 4e / (1 + e)^2 with e = exp(-2|x|)
+    // to avoid the cancellation in 1 - tanh(x)^2 for large |x|
+    pub fn sech2(x: f64, _y: f64) -> f64 {
+        let e = math::exp(-2.0 * x.abs());
+        4.0 * e / ((1.0 + e) * (1.0 + e))
+    }
+
+    // x is the handle returned by Lookup::register, as stored in a Table word
+    pub fn interp1(x: f64, y: f64) -> f64 {
+        TABLES.read().unwrap()[x as usize].eval(y)
+    }
+
+    // the derivative of interp1 with respect to y, see Lookup::slope
+    pub fn interp1_slope(x: f64, y: f64) -> f64 {
+        TABLES.read().unwrap()[x as usize].slope(y)
+    }
+}
+
+// the tables of the interp1 op, shared by all the backends through the vt
+static TABLES: RwLock<Vec<Lookup>> = RwLock::new(Vec::new());
+
+// a tabulated function (sorted breakpoints + values)
+// evaluated by binary search and linear interpolation, clamped to the endpoints
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lookup {
+    xs: Vec<f64>,
+    ys: Vec<f64>,
+}
+
+impl Lookup {
+    pub fn new(xs: Vec<f64>, ys: Vec<f64>) -> Lookup {
+        assert!(!xs.is_empty(), "empty lookup table");
+        assert_eq!(xs.len(), ys.len(), "mismatched lookup table");
+        assert!(
+            xs.windows(2).all(|w| w[0] < w[1]),
+            "lookup breakpoints should be sorted"
+        );
+        Lookup { xs, ys }
+    }
+
+    // as new, but the breakpoints can be given in any order
+    pub fn sorted(xs: &[f64], ys: &[f64]) -> Lookup {
+        assert_eq!(xs.len(), ys.len(), "mismatched lookup table");
+        let mut pairs: Vec<(f64, f64)> = xs.iter().copied().zip(ys.iter().copied()).collect();
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let (xs, ys) = pairs.into_iter().unzip();
+        Lookup::new(xs, ys)
+    }
+
+    // adds the table to the interp1 tables (once) and returns its handle
+    pub fn register(&self) -> usize {
+        let mut tables = TABLES.write().unwrap();
+
+        if let Some(h) = tables.iter().position(|t| t == self) {
+            h
+        } else {
+            tables.push(self.clone());
+            tables.len() - 1
+        }
+    }
+
+    // the breakpoints and values, e.g., to emit the table in a generated source
+    pub fn points(&self) -> (&[f64], &[f64]) {
+        (&self.xs, &self.ys)
+    }
+
+    pub fn eval(&self, x: f64) -> f64 {
+        let n = self.xs.len();
+
+        if x.is_nan() {
+            return x;
+        }
+
+        if x <= self.xs[0] {
+            return self.ys[0];
+        }
+
+        if x >= self.xs[n - 1] {
+            return self.ys[n - 1];
+        }
+
+        // the first breakpoint larger than x, 0 < i < n
+        let i = self.xs.partition_point(|v| *v <= x);
+        let (x0, x1) = (self.xs[i - 1], self.xs[i]);
+        let (y0, y1) = (self.ys[i - 1], self.ys[i]);
+
+        y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+    }
+
+    // the slope of the segment containing x, i.e., the derivative of eval,
+    // which is 0 outside the breakpoints and taken from the right at them
+    pub fn slope(&self, x: f64) -> f64 {
+        let n = self.xs.len();
+
+        if x.is_nan() {
+            return x;
+        }
+
+        if x < self.xs[0] || x >= self.xs[n - 1] {
+            return 0.0;
+        }
+
+        let i = self.xs.partition_point(|v| *v <= x);
+        (self.ys[i] - self.ys[i - 1]) / (self.xs[i] - self.xs[i - 1])
+    }
+}
+
+#[test]
+fn test_sech2() {
+    for x in [0.0f64, 0.1, -0.5, 1.0, 3.0] {
+        let y = 1.0 - x.tanh().powi(2);
+        assert!((Code::sech2(x, 0.0) - y).abs() < 1e-15);
+    }
+
+    // 1 - tanh(x)^2 underflows to 0 while sech2 keeps full relative accuracy
+    for x in [20.0f64, -30.0, 100.0] {
+        let y = 1.0 / x.cosh().powi(2);
+        assert_eq!(1.0 - x.tanh().powi(2), 0.0);
+        assert!(((Code::sech2(x, 0.0) - y) / y).abs() < 1e-14);
+    }
+}
+
+#[test]
+fn test_expm1_log1p() {
+    // the truncated series are exact to double precision at x = 1e-12
+    let x = 1e-12f64;
+    let y = x * (1.0 + 0.5 * x); // exp(x) - 1 = x + x^2/2 + ...
+    let z = x * (1.0 - 0.5 * x); // ln(1 + x) = x - x^2/2 + ...
+
+    assert!(((Code::expm1(x, 0.0) - y) / y).abs() < 1e-15);
+    assert!(((x.exp() - 1.0 - y) / y).abs() > 1e-5);
+
+    assert!(((Code::log1p(x, 0.0) - z) / z).abs() < 1e-15);
+    assert!((((1.0 + x).ln() - z) / z).abs() > 1e-5);
+}
+
+#[test]
+fn test_interp1() {
+    // the grid is given out of order and the values are not monotonic
+    let t = Lookup::sorted(&[2.0, 0.0, 1.0], &[40.0, 0.0, 10.0]);
+    assert_eq!(t, Lookup::new(vec![0.0, 1.0, 2.0], vec![0.0, 10.0, 40.0]));
+
+    let h = t.register() as f64;
+    assert_eq!(t.register() as f64, h);
+
+    assert_eq!(Code::interp1(h, 0.5), 5.0);
+    assert_eq!(Code::interp1(h, 1.5), 25.0);
+    assert_eq!(Code::interp1(h, 1.0), 10.0);
+    // out-of-range lookups are clamped to the endpoints
+    assert_eq!(Code::interp1(h, -3.0), 0.0);
+    assert_eq!(Code::interp1(h, 7.0), 40.0);
+    assert!(Code::interp1(h, f64::NAN).is_nan());
+
+    assert_eq!(Code::interp1_slope(h, 0.5), 10.0);
+    assert_eq!(Code::interp1_slope(h, 1.0), 30.0);
+    assert_eq!(Code::interp1_slope(h, 0.0), 10.0);
+    assert_eq!(Code::interp1_slope(h, -3.0), 0.0);
+    assert_eq!(Code::interp1_slope(h, 2.0), 0.0);
+    assert!(Code::interp1_slope(h, f64::NAN).is_nan());
+
+    let u = Lookup::sorted(&[0.0, 1.0, 2.0], &[1.0, -1.0, 1.0]);
+    assert_ne!(u.register() as f64, h);
+    assert_eq!(Code::interp1(u.register() as f64, 0.25), 0.5);
+}
+
+#[test]
+fn test_ops32() {
+    // the single-precision ops agree with the f64 ones up to f32 rounding
+    let ops = [
+        "plus", "divide", "power", "logb", "exp", "log", "tanh", "sech2", "erf", "lgamma",
+    ];
+
+    for op in ops {
+        let (x, y) = (0.7, 1.3);
+        let a = Code::from_str(op)(x, y);
+        let b = Code::from_str32(op)(x as f32, y as f32) as f64;
+        assert!((a - b).abs() <= 1e-6 * a.abs(), "{}: {} {}", op, a, b);
+    }
+
+    // booleans are all-ones masks in f32 too
+    let gt = Code::from_str32("gt");
+    assert_eq!(gt(2.0, 1.0).to_bits(), u32::MAX);
+    assert_eq!(Code::select32(gt(2.0, 1.0), 3.0, 4.0), 3.0);
+    assert_eq!(Code::select32(gt(1.0, 2.0), 3.0, 4.0), 4.0);
+    assert!(Code::from_str32("min")(f32::NAN, 1.0).is_nan());
+    assert!(Code::try_from_str32("nothing").is_none());
+}
+
+#[cfg(feature = "libm")]
+#[test]
+fn test_libm() {
+    for x in [-3.7, -0.5, 0.0, 1e-9, 0.3, 1.0, 2.5, 40.0] {
+        let same = |a: f64, b: f64| a.to_bits() == b.to_bits() || a.is_nan() && b.is_nan();
+        assert!(same(Code::sin(x, 0.0), libm::sin(x)));
+        assert!(same(Code::exp(x, 0.0), libm::exp(x)));
+        assert!(same(Code::ln(x, 0.0), libm::log(x)));
+        assert!(same(Code::tanh(x, 0.0), libm::tanh(x)));
+        assert!(same(Code::power(x, 1.5), libm::pow(x, 1.5)));
+        assert!(same(Code::log(x, 0.0), libm::log(x) / libm::log(10.0)));
+    }
 }
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use cranelift_codegen::control::ControlPlane;
+use cranelift_codegen::ir::condcodes::FloatCC;
+use cranelift_codegen::ir::{types, AbiParam, Function, InstBuilder, MemFlags, Signature, Value};
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+
+use crate::code::*;
+use crate::machine::MachineCode;
+use crate::model::Program;
+use crate::register::Word;
+use crate::runnable::CompileError;
+use crate::utils::*;
+
+/*
+    CraneliftCompiler lowers prog.code to Cranelift IR and copies the
+    resulting position-independent code into a MachineCode, so it follows
+    the same fn(&[f64], &[BinaryFunc]) convention as the hand-written
+    backends, i.e., the arguments are (mem, mem.len(), vt, vt.len()).
+
+    The code is a single basic block. Each register is loaded at most once
+    and temporaries never touch memory; diffs, obs, and locals are stored
+    back to mem as they are computed.
+*/
+pub struct CraneliftCompiler {
+    isa: OwnedTargetIsa,
+}
+
+impl CraneliftCompiler {
+    pub fn new() -> CraneliftCompiler {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").unwrap();
+        let isa = cranelift_native::builder()
+            .expect("the host is not supported by cranelift")
+            .finish(settings::Flags::new(flags))
+            .unwrap();
+        Self { isa }
+    }
+
+    fn signature(&self) -> Signature {
+        let ptr = self.isa.pointer_type();
+        let mut sig = Signature::new(self.isa.default_call_conv());
+        for _ in 0..4 {
+            sig.params.push(AbiParam::new(ptr));
+        }
+        sig
+    }
+
+    fn func_signature(&self) -> Signature {
+        let mut sig = Signature::new(self.isa.default_call_conv());
+        sig.params.push(AbiParam::new(types::F64));
+        sig.params.push(AbiParam::new(types::F64));
+        sig.returns.push(AbiParam::new(types::F64));
+        sig
+    }
+
+    fn codegen(&self, prog: &Program) -> Function {
+        let mut func = Function::new();
+        func.signature = self.signature();
+        let mut fctx = FunctionBuilderContext::new();
+        let mut b = FunctionBuilder::new(&mut func, &mut fctx);
+
+        let sig = b.import_signature(self.func_signature());
+        let block = b.create_block();
+        b.append_block_params_for_function_params(block);
+        b.switch_to_block(block);
+        b.seal_block(block);
+
+        let mem = b.block_params(block)[0];
+        let vt = b.block_params(block)[2];
+        let flags = MemFlags::trusted();
+        let ptr = self.isa.pointer_type();
+
+        let mut vals: HashMap<Word, Value> = HashMap::new();
+
+        // returns the value of w, which is loaded from mem on first use
+        let get = |b: &mut FunctionBuilder, vals: &mut HashMap<Word, Value>, w: &Word| -> Value {
+            *vals
+                .entry(*w)
+                .or_insert_with(|| b.ins().load(types::F64, flags, mem, 8 * w.0 as i32))
+        };
+
+        for c in prog.code.iter() {
+            let (dst, v) = match c {
+                Instruction::Unary { p, x, dst, op } => {
+                    let x = get(&mut b, &mut vals, x);
+                    let v = match op.as_str() {
+                        "mov" => x,
+                        "neg" => b.ins().fneg(x),
+                        "abs" => b.ins().fabs(x),
+                        "root" => b.ins().sqrt(x),
+                        _ => {
+                            let f = b.ins().load(ptr, flags, vt, 8 * p.0 as i32);
+                            let call = b.ins().call_indirect(sig, f, &[x, x]);
+                            b.inst_results(call)[0]
+                        }
+                    };
+                    (dst, v)
+                }
+                Instruction::Binary { p, x, y, dst, op } => {
+                    let x = get(&mut b, &mut vals, x);
+                    let y = get(&mut b, &mut vals, y);
+                    let v = match op.as_str() {
+                        "plus" => b.ins().fadd(x, y),
+                        "minus" => b.ins().fsub(x, y),
+                        "times" => b.ins().fmul(x, y),
+                        "divide" => b.ins().fdiv(x, y),
+                        "min" => b.ins().fmin(x, y),
+                        "max" => b.ins().fmax(x, y),
+                        "gt" => Self::compare(&mut b, FloatCC::GreaterThan, x, y),
+                        "geq" => Self::compare(&mut b, FloatCC::GreaterThanOrEqual, x, y),
+                        "lt" => Self::compare(&mut b, FloatCC::LessThan, x, y),
+                        "leq" => Self::compare(&mut b, FloatCC::LessThanOrEqual, x, y),
+                        "eq" => Self::compare(&mut b, FloatCC::Equal, x, y),
+                        "neq" => Self::compare(&mut b, FloatCC::NotEqual, x, y),
+                        "and" | "or" | "xor" => {
+                            let x = b.ins().bitcast(types::I64, MemFlags::new(), x);
+                            let y = b.ins().bitcast(types::I64, MemFlags::new(), y);
+                            let v = match op.as_str() {
+                                "and" => b.ins().band(x, y),
+                                "or" => b.ins().bor(x, y),
+                                _ => b.ins().bxor(x, y),
+                            };
+                            b.ins().bitcast(types::F64, MemFlags::new(), v)
+                        }
+                        _ => {
+                            let f = b.ins().load(ptr, flags, vt, 8 * p.0 as i32);
+                            let call = b.ins().call_indirect(sig, f, &[x, y]);
+                            b.inst_results(call)[0]
+                        }
+                    };
+                    (dst, v)
+                }
+                // a bitwise blend, as booleans are bitmasks
+                Instruction::IfElse { x1, x2, cond, dst } => {
+                    let ints = [cond, x1, x2].map(|w| {
+                        let v = get(&mut b, &mut vals, w);
+                        b.ins().bitcast(types::I64, MemFlags::new(), v)
+                    });
+                    let v = b.ins().bitselect(ints[0], ints[1], ints[2]);
+                    (dst, b.ins().bitcast(types::F64, MemFlags::new(), v))
+                }
+                _ => continue,
+            };
+
+            vals.insert(*dst, v);
+
+            if prog.frame.is_diff(dst) || prog.frame.is_obs(dst) || prog.frame.is_local(dst) {
+                b.ins().store(flags, v, mem, 8 * dst.0 as i32);
+            }
+        }
+
+        b.ins().return_(&[]);
+        b.finalize();
+        func
+    }
+
+    // true is all ones and false is 0.0
+    fn compare(b: &mut FunctionBuilder, cc: FloatCC, x: Value, y: Value) -> Value {
+        let c = b.ins().fcmp(cc, x, y);
+        let m = b.ins().bmask(types::I64, c);
+        b.ins().bitcast(types::F64, MemFlags::new(), m)
+    }
+}
+
+impl Compiler<MachineCode> for CraneliftCompiler {
+    fn compile(&mut self, prog: &Program) -> MachineCode {
+        self.try_compile(prog).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_compile(&mut self, prog: &Program) -> Result<MachineCode, CompileError> {
+        let mut ctx = Context::for_function(self.codegen(prog));
+        let code = ctx
+            .compile(&*self.isa, &mut ControlPlane::default())
+            .map_err(|e| CompileError::Codegen(format!("cranelift: {:?}", e.inner)))?;
+        assert!(
+            code.buffer.relocs().is_empty(),
+            "the generated code is not position-independent"
+        );
+
+        MachineCode::try_new(
+            std::env::consts::ARCH,
+            code.code_buffer(),
+            prog,
+            prog.frame.mem(),
+        )
+    }
+}
+
+#[test]
+fn test_cranelift() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    for name in ["beeler", "ohara"] {
+        let text = std::fs::read_to_string(format!("julia/{}.json", name)).unwrap();
+        let ml = CellModel::load(&text).unwrap();
+
+        let mut r1 = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+        let mut r2 = Runnable::new(Program::new(&ml), CompilerType::Cranelift);
+
+        let u = r1.initial_states();
+        let p = r1.params();
+        let mut du1 = vec![0.0; u.len()];
+        let mut du2 = vec![0.0; u.len()];
+
+        r1.call(&mut du1, &u, &p, 0.0);
+        r2.call(&mut du2, &u, &p, 0.0);
+        assert_eq!(du1, du2);
+    }
+}
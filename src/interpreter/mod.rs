@@ -1,19 +1,22 @@
+use std::collections::HashMap;
+
 use super::code::*;
 use super::model::Program;
+use super::register::{Word, WordType};
 use super::utils::*;
-use super::register::Word;
 
-pub enum Fast {
+#[derive(Clone)]
+pub enum Fast<T: Float> {
     Unary {
         x: u32,
         dst: u32,
-        f: BinaryFunc,
+        f: fn(T, T) -> T,
     },
     Binary {
         x: u32,
         y: u32,
         dst: u32,
-        f: BinaryFunc,
+        f: fn(T, T) -> T,
     },
     IfElse {
         x1: u32,
@@ -21,6 +24,12 @@ pub enum Fast {
         cond: u32,
         dst: u32,
     },
+    Lookup {
+        x: u32,
+        table: usize,
+        slope: bool, // lookup_slope, the derivative of the lookup
+        dst: u32,
+    },
 }
 
 #[derive(Debug)]
@@ -34,88 +43,282 @@ impl Interpreter {
 
 impl Compiler<ByteCode> for Interpreter {
     fn compile(&mut self, prog: &Program) -> ByteCode {
-        let vt = prog.virtual_table();
-        let mut code: Vec<Fast> = Vec::new();
-        let mut mem = prog.frame.mem();
+        self.compile_as(prog)
+    }
+}
+
+impl Interpreter {
+    // compiles prog to a bytecode running in T, e.g., compile_as::<f32>
+    pub fn compile_as<T: Float>(&mut self, prog: &Program) -> ByteCode<T> {
+        let vt: Vec<fn(T, T) -> T> = prog.ft.iter().map(|s| T::op(s)).collect();
+        let mut code: Vec<Fast<T>> = Vec::new();
+        let mut hoisted: Vec<Fast<T>> = Vec::new();
+        let mut mem: Vec<T> = prog.frame.mem().into_iter().map(T::from_f64).collect();
         let m = mem.len();
-        let h = |x: &Word|->u32 { (if x.is_temp() { m + x.0 } else { x.0 }) as u32 };
+        let n = prog.frame.stack_size();
+
+        /*
+            invariant instructions depend only on params and constants
+            their results are cached in dedicated slots after the temps,
+            so they survive across runs until the cache is invalidated;
+            they are hoisted to the front of the code, so that a run with
+            a valid cache starts past them
+        */
+        let mut cached: HashMap<Word, u32> = HashMap::new();
+        let mut count_cached = 0;
+
+        let h = |x: &Word, cached: &HashMap<Word, u32>| -> u32 {
+            if let Some(k) = cached.get(x) {
+                *k
+            } else {
+                (if x.is_temp() { m + x.0 } else { x.0 }) as u32
+            }
+        };
+
+        let is_invariant = |x: &Word, cached: &HashMap<Word, u32>| -> bool {
+            if x.is_temp() {
+                cached.contains_key(x)
+            } else {
+                matches!(
+                    prog.frame.words[x.0],
+                    WordType::Const(_) | WordType::Param(_, _) | WordType::Table(_, _, _)
+                )
+            }
+        };
 
         for c in prog.code.iter() {
+            let (args, dst) = match c {
+                Instruction::Unary { x, dst, .. } => (vec![*x], *dst),
+                Instruction::Binary { x, y, dst, .. } => (vec![*x, *y], *dst),
+                Instruction::IfElse { x1, x2, cond, dst } => (vec![*x1, *x2, *cond], *dst),
+                _ => continue,
+            };
+
+            let inv = dst.is_temp() && args.iter().all(|x| is_invariant(x, &cached));
+            let a: Vec<u32> = args.iter().map(|x| h(x, &cached)).collect();
+
+            cached.remove(&dst);
+
+            let dst = if inv {
+                let k = (m + n + count_cached) as u32;
+                count_cached += 1;
+                cached.insert(dst, k);
+                k
+            } else {
+                h(&dst, &cached)
+            };
+
+            let code = if inv { &mut hoisted } else { &mut code };
+
             match c {
-                Instruction::Unary { p, x, dst, .. } => {
+                Instruction::Unary { p, .. } => {
                     code.push(Fast::Unary {
                         f: vt[p.0],
-                        x: h(x),
-                        dst: h(dst),
+                        x: a[0],
+                        dst,
                     });
                 }
-                Instruction::Binary { p, x, y, dst, .. } => {
+                Instruction::Binary { op, y, .. } if op == "lookup" || op == "lookup_slope" => {
+                    let table = match prog.frame.words.get(y.0) {
+                        Some(WordType::Const(id)) if !y.is_temp() => *id as usize,
+                        _ => panic!("lookup table id should be a constant"),
+                    };
+
+                    code.push(Fast::Lookup {
+                        x: a[0],
+                        table,
+                        slope: op == "lookup_slope",
+                        dst,
+                    });
+                }
+                Instruction::Binary { p, .. } => {
                     code.push(Fast::Binary {
                         f: vt[p.0],
-                        x: h(x), 
-                        y: h(y),
-                        dst: h(dst), 
+                        x: a[0],
+                        y: a[1],
+                        dst,
                     });
                 }
-                Instruction::IfElse { x1, x2, cond, dst } => {
+                Instruction::IfElse { .. } => {
                     code.push(Fast::IfElse {
-                        x1: h(x1),
-                        x2: h(x2),
-                        cond: h(cond),
-                        dst: h(dst),
+                        x1: a[0],
+                        x2: a[1],
+                        cond: a[2],
+                        dst,
                     });
                 }
                 _ => {}
             }
         }
-        
-        for _ in 0..prog.frame.stack_size() {
-            mem.push(0.0);
+
+        for _ in 0..n + count_cached {
+            mem.push(T::default());
         }
 
-        ByteCode::new(code, mem)
+        let count_hoisted = hoisted.len();
+        hoisted.extend(code);
+
+        ByteCode::new(hoisted, mem, count_hoisted)
     }
 }
 
-pub struct ByteCode {
-    code: Vec<Fast>,
-    _mem: Vec<f64>,
+#[derive(Clone)]
+pub struct ByteCode<T: Float = f64> {
+    code: Vec<Fast<T>>,
+    _mem: Vec<T>,
+    tables: Vec<Option<Lookup>>,
+    hoisted: usize,  // the leading instructions depending only on params and constants
+    valid: bool,     // true if the results of the hoisted instructions are cached
+    executed: usize, // number of instructions executed by the last run
 }
 
-impl ByteCode {
-    fn new(code: Vec<Fast>, _mem: Vec<f64>) -> ByteCode {
-        ByteCode { code, _mem }
+impl<T: Float> ByteCode<T> {
+    fn new(code: Vec<Fast<T>>, _mem: Vec<T>, hoisted: usize) -> ByteCode<T> {
+        ByteCode {
+            code,
+            _mem,
+            tables: Vec::new(),
+            hoisted,
+            valid: false,
+            executed: 0,
+        }
+    }
+
+    pub fn executed(&self) -> usize {
+        self.executed
     }
 }
 
-impl Compiled for ByteCode {
+impl<T: Float> Compiled<T> for ByteCode<T> {
     fn run(&mut self) {
-        for c in self.code.iter() {
+        let start = if self.valid { self.hoisted } else { 0 };
+        self.executed = self.code.len() - start;
+
+        for c in self.code[start..].iter() {
             match c {
                 Fast::Unary { f, x, dst, .. } => {
-                    self._mem[*dst as usize] = f(self._mem[*x as usize], 0.0);
+                    self._mem[*dst as usize] = f(self._mem[*x as usize], T::default());
                 }
                 Fast::Binary { f, x, y, dst, .. } => {
                     self._mem[*dst as usize] = f(self._mem[*x as usize], self._mem[*y as usize]);
                 }
                 Fast::IfElse { x1, x2, cond, dst } => {
-                    self._mem[*dst as usize] = if self._mem[*cond as usize] > 0.0 {
-                        self._mem[*x1 as usize]
-                    } else {
-                        self._mem[*x2 as usize]
-                    }
+                    self._mem[*dst as usize] = T::select(
+                        self._mem[*cond as usize],
+                        self._mem[*x1 as usize],
+                        self._mem[*x2 as usize],
+                    )
+                }
+                Fast::Lookup {
+                    x,
+                    table,
+                    slope,
+                    dst,
+                } => {
+                    // the tables are f64 in every width
+                    let x = self._mem[*x as usize].to_f64();
+                    self._mem[*dst as usize] = T::from_f64(match self.tables.get(*table) {
+                        Some(Some(t)) if *slope => t.slope(x),
+                        Some(Some(t)) => t.eval(x),
+                        _ => f64::NAN,
+                    })
                 }
             }
         }
+
+        self.valid = true;
     }
 
     #[inline]
-    fn mem(&self) -> &[f64] {
+    fn mem(&self) -> &[T] {
         &self._mem[..]
     }
 
     #[inline]
-    fn mem_mut(&mut self) -> &mut [f64] {
+    fn mem_mut(&mut self) -> &mut [T] {
         &mut self._mem[..]
     }
+
+    fn swap_mem(&mut self, buf: &mut Vec<T>) {
+        assert_eq!(self._mem.len(), buf.len(), "mem size mismatch");
+        std::mem::swap(&mut self._mem, buf);
+        self.valid = false;
+    }
+
+    fn invalidate(&mut self) {
+        self.valid = false;
+    }
+
+    fn set_lookup(&mut self, id: usize, table: Lookup) {
+        if self.tables.len() <= id {
+            self.tables.resize(id + 1, None);
+        }
+        self.tables[id] = Some(table);
+        self.valid = false;
+    }
+
+    fn fork(&self) -> Box<dyn Compiled<T> + Send> {
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_invariant_cache() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    // δx = a * b - exp(a) * x, where a * b and exp(a) depend only on params
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "a", "val": 0.5}, {"name": "b", "val": 2.0}],
+        "states": [{"name": "x", "val": 1.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "minus", "args": [
+                {"type": "Tree", "op": "times", "args": [
+                    {"type": "Var", "name": "a"}, {"type": "Var", "name": "b"}
+                ]},
+                {"type": "Tree", "op": "times", "args": [
+                    {"type": "Tree", "op": "exp", "args": [{"type": "Var", "name": "a"}]},
+                    {"type": "Var", "name": "x"}
+                ]}
+            ]}
+        }],
+        "obs": [{"lhs": {"type": "Var", "name": "y"}, "rhs": {"type": "Var", "name": "x"}}]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let prog = Program::new(&ml);
+    let mut bc = Interpreter::new().compile(&prog);
+    assert_eq!(bc.hoisted, 2);
+
+    bc.run();
+    let first = bc.executed();
+    let dx = bc.mem()[prog.frame.first_diff().unwrap()];
+
+    bc.run();
+    assert_eq!(bc.executed(), first - 2);
+    assert_eq!(bc.mem()[prog.frame.first_diff().unwrap()], dx);
+
+    bc.invalidate();
+    bc.run();
+    assert_eq!(bc.executed(), first);
+
+    // changing params through Runnable invalidates the cache
+    let f = |a: f64, b: f64, x: f64| a * b - a.exp() * x;
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let mut du = vec![0.0];
+
+    r.call(&mut du, &[1.0], &[0.5, 2.0], 0.0);
+    assert_eq!(du[0], f(0.5, 2.0, 1.0));
+    r.call(&mut du, &[3.0], &[0.5, 2.0], 0.0);
+    assert_eq!(du[0], f(0.5, 2.0, 3.0));
+    r.call(&mut du, &[3.0], &[1.5, 2.0], 0.0);
+    assert_eq!(du[0], f(1.5, 2.0, 3.0));
+
+    r.set_param(1, 4.0);
+    let p = r.params();
+    r.call(&mut du, &[3.0], &p, 0.0);
+    assert_eq!(du[0], f(0.5, 4.0, 3.0));
 }
@@ -1,23 +1,37 @@
 use std::ffi::{c_char, CStr, CString};
+use std::io::Read;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use flate2::read::GzDecoder;
 
 mod analyzer;
 mod code;
 mod machine;
 mod model;
+mod pool;
 mod register;
 mod runnable;
+mod solvers;
 mod utils;
 
+#[macro_use]
+mod listing;
 mod amd;
 mod arm;
+#[cfg(feature = "c")]
+mod c;
+#[cfg(feature = "cranelift")]
+mod cranelift;
 mod interpreter;
+mod llvm;
 #[cfg(feature = "rusty")]
 mod rusty;
 #[cfg(feature = "wasm")]
 mod wasm;
 
-use model::{CellModel, Program};
-use runnable::{CompilerType, Runnable};
+use model::{CellModel, Program, Report};
+use runnable::{panic_message, CompilerType, Runnable};
+use solvers::{Euler, Solver};
 use utils::*;
 
 #[derive(Debug, Clone, Copy)]
@@ -27,21 +41,39 @@ pub enum CompilerStatus {
     InvalidUtf8,
     ParseError,
     InvalidCompiler,
+    FileError,
+    DecompressError,
+    CompileError,
+    Panicked,
 }
 
 pub struct CompilerResult {
     func: Option<Runnable>,
     regs: CString,
     status: CompilerStatus,
+    msg: CString,
+    state_names: Vec<CString>,
+    param_names: Vec<CString>,
+    obs_names: Vec<CString>,
+}
+
+impl CompilerResult {
+    fn new(status: CompilerStatus) -> CompilerResult {
+        CompilerResult {
+            func: None,
+            regs: CString::new("").unwrap(),
+            status,
+            msg: CString::default(),
+            state_names: Vec::new(),
+            param_names: Vec::new(),
+            obs_names: Vec::new(),
+        }
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn compile(p: *const c_char, ty: *const c_char) -> *const CompilerResult {
-    let mut res = CompilerResult {
-        func: None,
-        regs: CString::new("").unwrap(),
-        status: CompilerStatus::Incomplete,
-    };
+    let mut res = CompilerResult::new(CompilerStatus::Incomplete);
 
     let p = unsafe {
         match CStr::from_ptr(p).to_str() {
@@ -63,34 +95,166 @@ pub extern "C" fn compile(p: *const c_char, ty: *const c_char) -> *const Compile
         }
     };
 
-    let ml = match CellModel::load(&p) {
+    compile_text(p, ty)
+}
+
+fn failed(status: CompilerStatus) -> *const CompilerResult {
+    let res = CompilerResult::new(status);
+    Box::into_raw(Box::new(res)) as *const _
+}
+
+// compiles a gzip-compressed model
+#[no_mangle]
+pub extern "C" fn compile_gz(p: *const u8, len: usize, ty: *const c_char) -> *const CompilerResult {
+    let bytes: &[u8] = unsafe { std::slice::from_raw_parts(p, len) };
+
+    let ty = match unsafe { CStr::from_ptr(ty).to_str() } {
+        Ok(ty) => ty,
+        Err(_) => return failed(CompilerStatus::InvalidUtf8),
+    };
+
+    match decompress(bytes) {
+        Ok(text) => compile_text(&text, ty),
+        Err(status) => failed(status),
+    }
+}
+
+// compiles a model file, files ending in .gz are decompressed first
+#[no_mangle]
+pub extern "C" fn compile_file(path: *const c_char, ty: *const c_char) -> *const CompilerResult {
+    let (path, ty) = match unsafe { (CStr::from_ptr(path).to_str(), CStr::from_ptr(ty).to_str()) } {
+        (Ok(path), Ok(ty)) => (path, ty),
+        _ => return failed(CompilerStatus::InvalidUtf8),
+    };
+
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(_) => return failed(CompilerStatus::FileError),
+    };
+
+    let text = if path.ends_with(".gz") {
+        decompress(&bytes)
+    } else {
+        String::from_utf8(bytes).map_err(|_| CompilerStatus::InvalidUtf8)
+    };
+
+    match text {
+        Ok(text) => compile_text(&text, ty),
+        Err(status) => failed(status),
+    }
+}
+
+fn decompress(bytes: &[u8]) -> Result<String, CompilerStatus> {
+    let mut buf: Vec<u8> = Vec::new();
+
+    if GzDecoder::new(bytes).read_to_end(&mut buf).is_err() {
+        return Err(CompilerStatus::DecompressError);
+    }
+
+    String::from_utf8(buf).map_err(|_| CompilerStatus::InvalidUtf8)
+}
+
+// the backends panic on malformed models, which should not unwind into the caller
+fn compile_text(p: &str, ty: &str) -> *const CompilerResult {
+    match catch_unwind(|| compile_unguarded(p, ty)) {
+        Ok(res) => res,
+        Err(e) => {
+            let mut res = CompilerResult::new(CompilerStatus::Panicked);
+            res.msg = panicked(e);
+            Box::into_raw(Box::new(res)) as *const _
+        }
+    }
+}
+
+fn panicked(e: Box<dyn std::any::Any + Send>) -> CString {
+    let msg = format!("Panicked: {}", panic_message(e));
+    CString::new(msg.replace('\0', "")).unwrap()
+}
+
+// calls f on the compiled function and returns its result; a panic drops
+// the function, so that the following calls fail, and is reported by check_status
+fn guarded(q: &mut CompilerResult, f: impl FnOnce(&mut Runnable) -> bool) -> bool {
+    let Some(func) = &mut q.func else {
+        return false;
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| f(func))) {
+        Ok(ok) => ok,
+        Err(e) => {
+            q.func = None;
+            q.status = CompilerStatus::Panicked;
+            q.msg = panicked(e);
+            false
+        }
+    }
+}
+
+fn compile_unguarded(p: &str, ty: &str) -> *const CompilerResult {
+    let mut res = CompilerResult::new(CompilerStatus::Incomplete);
+
+    let ml = match CellModel::load(p) {
         Ok(ml) => ml,
-        Err(_) => {
+        Err(e) => {
             res.status = CompilerStatus::ParseError;
+            res.msg = CString::new(e.to_string().replace('\0', "")).unwrap();
             return Box::into_raw(Box::new(res)) as *const _;
         }
     };
 
-    let prog = Program::new(&ml);
+    let prog = match Program::try_new(&ml) {
+        Ok(prog) => prog,
+        Err(e) => {
+            res.status = CompilerStatus::CompileError;
+            res.msg = CString::new(e.to_string()).unwrap_or_default();
+            return Box::into_raw(Box::new(res)) as *const _;
+        }
+    };
 
     // println!("{:#?}", &prog);
 
-    res.func = match ty {
-        "bytecode" => Some(Runnable::new(prog, CompilerType::ByteCode)),
-        "arm" => Some(Runnable::new(prog, CompilerType::Arm)),
-        "amd" => Some(Runnable::new(prog, CompilerType::Amd)),
-        "native" => Some(Runnable::new(prog, CompilerType::Native)),
+    if let Ok(regs) = prog.frame.as_json() {
+        res.regs = CString::new(regs).unwrap_or_default();
+    }
+
+    let cstrings = |names: Vec<String>| -> Vec<CString> {
+        names
+            .into_iter()
+            .map(|s| CString::new(s).unwrap_or_default())
+            .collect()
+    };
+    res.state_names = cstrings(prog.frame.state_names());
+    res.param_names = cstrings(prog.frame.param_names());
+    res.obs_names = cstrings(prog.frame.obs_names());
+
+    let ty = match ty {
+        "bytecode" => CompilerType::ByteCode,
+        "arm" => CompilerType::Arm,
+        "amd" => CompilerType::Amd { optimize: false },
+        "amd-opt" => CompilerType::Amd { optimize: true },
+        "native" => CompilerType::Native,
         #[cfg(feature = "wasm")]
-        "wasm" => Some(Runnable::new(prog, CompilerType::Wasm)),
+        "wasm" => CompilerType::Wasm,
         #[cfg(feature = "rusty")]
-        "rusty" => Some(Runnable::new(prog, CompilerType::Rusty)),
-        _ => None,
+        "rusty" => CompilerType::Rusty,
+        #[cfg(feature = "cranelift")]
+        "cranelift" => CompilerType::Cranelift,
+        #[cfg(feature = "c")]
+        "c" => CompilerType::C,
+        _ => {
+            res.status = CompilerStatus::InvalidCompiler;
+            return Box::into_raw(Box::new(res)) as *const _;
+        }
     };
 
-    res.status = if res.func.is_none() {
-        CompilerStatus::InvalidCompiler
-    } else {
-        CompilerStatus::Ok
+    res.status = match Runnable::try_new(prog, ty) {
+        Ok(func) => {
+            res.func = Some(func);
+            CompilerStatus::Ok
+        }
+        Err(e) => {
+            res.msg = CString::new(e.to_string()).unwrap_or_default();
+            CompilerStatus::CompileError
+        }
     };
     return Box::into_raw(Box::new(res)) as *const _;
 }
@@ -104,10 +268,55 @@ pub extern "C" fn check_status(q: *const CompilerResult) -> *const c_char {
         CompilerStatus::InvalidUtf8 => c"The input string is not valid UTF8",
         CompilerStatus::ParseError => c"Parse error",
         CompilerStatus::InvalidCompiler => c"Compiler type not found",
+        CompilerStatus::FileError => c"Cannot read the model file",
+        CompilerStatus::DecompressError => c"Cannot decompress the model",
+        CompilerStatus::CompileError => c"Compilation error",
+        CompilerStatus::Panicked => q.msg.as_c_str(),
     };
     msg.as_ptr() as *const _
 }
 
+// the details of a failure, e.g., the line and column of a parse error,
+// or an empty string if there are none
+#[no_mangle]
+pub extern "C" fn error_message(q: *const CompilerResult) -> *const c_char {
+    let q: &CompilerResult = unsafe { &*q };
+    q.msg.as_ptr()
+}
+
+// the register layout as JSON (see Frame::as_json), e.g., to map the
+// columns of a solution to variable names; it is owned by q and valid until finalize
+#[no_mangle]
+pub extern "C" fn get_regs(q: *const CompilerResult) -> *const c_char {
+    let q: &CompilerResult = unsafe { &*q };
+    q.regs.as_ptr()
+}
+
+fn name_at(names: &[CString], i: usize) -> *const c_char {
+    names.get(i).map_or(std::ptr::null(), |s| s.as_ptr())
+}
+
+// the name of the i-th state, parameter, or observable (from 0), in the
+// order of u, p, and the obs columns, or null if i is out of range
+// the names are owned by q and the pointers are valid until finalize
+#[no_mangle]
+pub extern "C" fn state_name(q: *const CompilerResult, i: usize) -> *const c_char {
+    let q: &CompilerResult = unsafe { &*q };
+    name_at(&q.state_names, i)
+}
+
+#[no_mangle]
+pub extern "C" fn param_name(q: *const CompilerResult, i: usize) -> *const c_char {
+    let q: &CompilerResult = unsafe { &*q };
+    name_at(&q.param_names, i)
+}
+
+#[no_mangle]
+pub extern "C" fn obs_name(q: *const CompilerResult, i: usize) -> *const c_char {
+    let q: &CompilerResult = unsafe { &*q };
+    name_at(&q.obs_names, i)
+}
+
 #[no_mangle]
 pub extern "C" fn count_states(q: *const CompilerResult) -> usize {
     let q: &CompilerResult = unsafe { &*q };
@@ -172,7 +381,7 @@ pub extern "C" fn run(
 ) -> bool {
     let q: &mut CompilerResult = unsafe { &mut *q };
 
-    if let Some(func) = &mut q.func {
+    guarded(q, |func| {
         if func.count_states != ns || func.count_params != np {
             return false;
         }
@@ -182,9 +391,7 @@ pub extern "C" fn run(
         let p: &[f64] = unsafe { std::slice::from_raw_parts(p, np) };
         func.call(du, u, p, t);
         true
-    } else {
-        false
-    }
+    })
 }
 
 #[no_mangle]
@@ -198,7 +405,7 @@ pub extern "C" fn run_py(
 ) -> bool {
     let q: &mut CompilerResult = unsafe { &mut *q };
 
-    if let Some(func) = &mut q.func {
+    guarded(q, |func| {
         if func.count_states + func.count_params != ns || func.count_obs != nd {
             return false;
         }
@@ -207,9 +414,94 @@ pub extern "C" fn run_py(
         let u: &[f64] = unsafe { std::slice::from_raw_parts(u, ns) };
         func.call_py(du, u, t);
         true
-    } else {
-        false
-    }
+    })
+}
+
+// sets the i-th input (see CellModel::inputs), which keeps its value
+// across the following calls to run until it is set again
+#[no_mangle]
+pub extern "C" fn set_input(q: *mut CompilerResult, i: usize, val: f64) -> bool {
+    let q: &mut CompilerResult = unsafe { &mut *q };
+
+    guarded(q, |func| {
+        if i >= func.count_inputs {
+            return false;
+        }
+
+        func.set_input(i, val);
+        true
+    })
+}
+
+// computes only the observables at (u, p, t), e.g., to recover the currents
+// from a saved trajectory; obs_out receives count_obs values
+#[no_mangle]
+pub extern "C" fn eval_obs(
+    q: *mut CompilerResult,
+    obs_out: *mut f64,
+    no: usize,
+    u: *const f64,
+    ns: usize,
+    p: *const f64,
+    np: usize,
+    t: f64,
+) -> bool {
+    let q: &mut CompilerResult = unsafe { &mut *q };
+
+    guarded(q, |func| {
+        if func.count_states != ns || func.count_params != np || func.count_obs != no {
+            return false;
+        }
+
+        let obs: &mut [f64] = unsafe { std::slice::from_raw_parts_mut(obs_out, no) };
+        let u: &[f64] = unsafe { std::slice::from_raw_parts(u, ns) };
+        let p: &[f64] = unsafe { std::slice::from_raw_parts(p, np) };
+        func.run_obs(obs, u, p, t);
+        true
+    })
+}
+
+// integrates the model from t0 to t1 by the Euler method with step dt
+// out receives one row per step, each row is t followed by the states,
+// and should hold at least floor((t1 - t0) / dt) * (ns + 1) elements
+#[no_mangle]
+pub extern "C" fn solve_euler(
+    q: *mut CompilerResult,
+    u0: *const f64,
+    p: *const f64,
+    t0: f64,
+    t1: f64,
+    dt: f64,
+    out: *mut f64,
+    out_len: usize,
+) -> bool {
+    let q: &mut CompilerResult = unsafe { &mut *q };
+
+    guarded(q, |func| {
+        let ns = func.count_states;
+        let np = func.count_params;
+        let n = ((t1 - t0) / dt).floor() as usize;
+
+        if dt.is_nan() || dt <= 0.0 || out_len < n * (ns + 1) {
+            return false;
+        }
+
+        let u0: &[f64] = unsafe { std::slice::from_raw_parts(u0, ns) };
+        let p: &[f64] = unsafe { std::slice::from_raw_parts(p, np) };
+        let out: &mut [f64] = unsafe { std::slice::from_raw_parts_mut(out, out_len) };
+        let mut rows = out.chunks_mut(ns + 1);
+
+        let alg = Euler::new(dt, 1);
+        let res = alg.solve_with(func, u0.to_vec(), p.to_vec(), t0..t1, |row| {
+            if let Some(r) = rows.next() {
+                r[0] = row.t;
+                r[1..].copy_from_slice(&row.x);
+            }
+            std::ops::ControlFlow::Continue(())
+        });
+
+        res.is_ok()
+    })
 }
 
 #[no_mangle]
@@ -219,6 +511,35 @@ pub extern "C" fn finalize(p: *mut CompilerResult) {
     }
 }
 
+// parses and validates a model without invoking any backend
+// returns a JSON report of errors and warnings, which should be released by finalize_report
+#[no_mangle]
+pub extern "C" fn validate_model(p: *const c_char) -> *const c_char {
+    let report = match unsafe { CStr::from_ptr(p).to_str() } {
+        Ok(p) => Report::new(p),
+        Err(_) => Report {
+            errors: vec!["The input string is not valid UTF8".to_string()],
+            warnings: Vec::new(),
+        },
+    };
+
+    CString::new(report.as_json()).unwrap().into_raw() as *const _
+}
+
+#[no_mangle]
+pub extern "C" fn finalize_report(p: *mut c_char) {
+    if !p.is_null() {
+        let _ = unsafe { CString::from_raw(p) };
+    }
+}
+
+// drops the compiled wasm modules cached by previous calls to compile
+#[cfg(feature = "wasm")]
+#[no_mangle]
+pub extern "C" fn clear_wasm_cache() {
+    wasm::clear_module_cache();
+}
+
 #[no_mangle]
 pub extern "C" fn info() -> *const c_char {
     let msg = c"lib 0.1";
@@ -235,4 +556,225 @@ pub extern "C" fn elem_at(
     v[index]     
 }
 
+#[test]
+fn test_validate_model() {
+    let check = |text: &str| -> String {
+        let text = CString::new(text).unwrap();
+        let p = validate_model(text.as_ptr());
+        let report = unsafe { CStr::from_ptr(p) }.to_str().unwrap().to_string();
+        finalize_report(p as *mut _);
+        report
+    };
+
+    assert_eq!(
+        check(include_str!("../julia/lorenz.json")),
+        r#"{"errors":[],"warnings":[]}"#
+    );
+
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "a", "val": 2.0}],
+        "states": [{"name": "x", "val": 1.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "times", "args": [
+                {"type": "Var", "name": "b"}, {"type": "Var", "name": "x"}
+            ]}
+        }],
+        "obs": []
+    }"#;
+
+    assert_eq!(
+        check(text),
+        r#"{"errors":["undeclared variable b in the equation for δx"],"warnings":["unused parameter a"]}"#
+    );
+}
+
+#[test]
+fn test_compile_gz() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+    enc.write_all(text.as_bytes()).unwrap();
+    let gz = enc.finish().unwrap();
+
+    let ty = c"bytecode";
+    let eval = |q: *const CompilerResult| -> Vec<f64> {
+        let q = unsafe { &mut *(q as *mut CompilerResult) };
+        assert!(matches!(q.status, CompilerStatus::Ok));
+        let func = q.func.as_mut().unwrap();
+        let mut du = vec![0.0; func.count_states];
+        let (u, p) = (func.initial_states(), func.params());
+        func.call(&mut du, &u, &p, 0.0);
+        finalize(q);
+        du
+    };
+
+    let model = CString::new(text).unwrap();
+    let du = eval(compile(model.as_ptr(), ty.as_ptr()));
+    assert_eq!(eval(compile_gz(gz.as_ptr(), gz.len(), ty.as_ptr())), du);
+
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("beeler_{}.json.gz", std::process::id()));
+    std::fs::write(&path, &gz).unwrap();
+    let path_gz = CString::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(eval(compile_file(path_gz.as_ptr(), ty.as_ptr())), du);
+    let _ = std::fs::remove_file(&path);
+
+    let path = CString::new("julia/beeler.json").unwrap();
+    assert_eq!(eval(compile_file(path.as_ptr(), ty.as_ptr())), du);
+
+    // not gzip
+    let q = compile_gz(model.as_ptr() as *const u8, 16, ty.as_ptr());
+    assert!(matches!(
+        unsafe { &*q }.status,
+        CompilerStatus::DecompressError
+    ));
+    finalize(q as *mut _);
+}
+
+#[test]
+fn test_solve_euler() {
+    let model = CString::new(include_str!("../julia/lorenz.json")).unwrap();
+    let q = compile(model.as_ptr(), c"bytecode".as_ptr()) as *mut CompilerResult;
+    let func = unsafe { &mut *q }.func.as_mut().unwrap();
+    let (u0, p) = (func.initial_states(), func.params());
+    let ns = u0.len();
+
+    let sol = Euler::new(0.01, 1)
+        .solve(func, u0.clone(), p.clone(), 1.0..2.0)
+        .unwrap();
+
+    let mut out = vec![0.0; 100 * (ns + 1)];
+    let (u, p, o) = (u0.as_ptr(), p.as_ptr(), out.as_mut_ptr());
+    assert!(solve_euler(q, u, p, 1.0, 2.0, 0.01, o, out.len()));
+
+    assert_eq!(sol.len(), 100);
+    for (row, r) in sol.iter().zip(out.chunks(ns + 1)) {
+        assert_eq!(row.t, r[0]);
+        assert_eq!(row.x.as_ref(), &r[1..]);
+    }
+
+    // the buffer is too small
+    assert!(!solve_euler(q, u, p, 1.0, 2.0, 0.01, o, 10));
+    finalize(q);
+}
+
+#[test]
+fn test_compile_panic() {
+    let text = include_str!("../julia/lorenz.json").replacen("\"times\"", "\"frobnicate\"", 1);
+    let model = CString::new(text).unwrap();
+    let q = compile(model.as_ptr(), c"bytecode".as_ptr());
+
+    // the unknown op is reported by Program::try_new
+    let res = unsafe { &*q };
+    assert!(matches!(res.status, CompilerStatus::CompileError));
+    let msg = res.msg.to_str().unwrap();
+    assert!(msg.starts_with("unknown op frobnicate in the equation for "));
+    finalize(q as *mut _);
+
+    let model = CString::new(include_str!("../julia/lorenz.json")).unwrap();
+    let q = compile(model.as_ptr(), c"bytecode".as_ptr()) as *mut CompilerResult;
+    let res = unsafe { &mut *q };
+    assert!(!guarded(res, |_| panic!("boom")));
+    assert!(res.func.is_none() && matches!(res.status, CompilerStatus::Panicked));
+    let msg = unsafe { CStr::from_ptr(check_status(q)) }.to_str().unwrap();
+    assert_eq!(msg, "Panicked: boom");
+    finalize(q);
+}
+
+#[test]
+fn test_error_message() {
+    let message = |text: &str| -> (String, String) {
+        let model = CString::new(text).unwrap();
+        let q = compile(model.as_ptr(), c"bytecode".as_ptr());
+        let status = unsafe { CStr::from_ptr(check_status(q)) };
+        let msg = unsafe { CStr::from_ptr(error_message(q)) };
+        let res = (
+            status.to_string_lossy().into(),
+            msg.to_string_lossy().into(),
+        );
+        finalize(q as *mut _);
+        res
+    };
+
+    let (status, msg) = message("{\n  \"iv\": }");
+    assert_eq!(status, "Parse error");
+    assert!(msg.starts_with("invalid JSON: ") && msg.ends_with("line 2 column 9"));
+
+    let (_, msg) = message(r#"{"iv": {"name": "t", "val": 0.0}}"#);
+    assert!(msg.starts_with("invalid model: missing field `params`"));
+
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0}, "params": [], "states": [],
+        "algs": [], "odes": [], "obs": []
+    }"#;
+    let (status, msg) = message(text);
+    assert_eq!(status, "Compilation error");
+    assert_eq!(msg, "the model has no states");
+
+    let (status, msg) = message(include_str!("../julia/lorenz.json"));
+    assert_eq!((status.as_str(), msg.as_str()), ("Success", ""));
+}
+
+#[test]
+fn test_get_regs() {
+    use register::Frame;
 
+    let model = CString::new(include_str!("../julia/lorenz.json")).unwrap();
+    let q = compile(model.as_ptr(), c"bytecode".as_ptr()) as *mut CompilerResult;
+    let regs = unsafe { CStr::from_ptr(get_regs(q)) }.to_str().unwrap();
+    let frame = Frame::from_json(regs).unwrap();
+
+    let func = unsafe { &*q }.func.as_ref().unwrap();
+    assert_eq!(frame.state_names().len(), func.count_states);
+    assert!(frame.find("main₊x").is_some());
+    finalize(q);
+}
+
+#[test]
+fn test_names() {
+    let model = CString::new(include_str!("../julia/lorenz.json")).unwrap();
+    let q = compile(model.as_ptr(), c"bytecode".as_ptr());
+    let names = |f: extern "C" fn(*const CompilerResult, usize) -> *const c_char| {
+        (0..)
+            .map_while(|i| {
+                let p = f(q, i);
+                (!p.is_null()).then(|| unsafe { CStr::from_ptr(p) }.to_str().unwrap().to_string())
+            })
+            .collect::<Vec<String>>()
+    };
+
+    assert_eq!(names(state_name), ["main₊x", "main₊y", "main₊z"]);
+    assert_eq!(names(param_name).len(), count_params(q));
+    let func = unsafe { &*q }.func.as_ref().unwrap();
+    assert_eq!(names(obs_name).len(), func.count_obs);
+    finalize(q as *mut _);
+}
+
+#[test]
+fn test_eval_obs() {
+    let model = CString::new(include_str!("../julia/beeler.json")).unwrap();
+    let q = compile(model.as_ptr(), c"bytecode".as_ptr()) as *mut CompilerResult;
+    let func = unsafe { &*q }.func.as_ref().unwrap();
+    let (u, p, no) = (func.initial_states(), func.params(), func.count_obs);
+    let (ns, np) = (u.len(), p.len());
+
+    let mut obs = vec![0.0; no];
+    let o = obs.as_mut_ptr();
+    assert!(eval_obs(q, o, no, u.as_ptr(), ns, p.as_ptr(), np, 1.0));
+
+    // run_py takes the states and params concatenated
+    let up = [u.clone(), p.clone()].concat();
+    let mut expected = vec![0.0; no];
+    let e = expected.as_mut_ptr();
+    assert!(run_py(q, e, no, up.as_ptr(), ns + np, 1.0));
+    assert_eq!(obs, expected);
+
+    assert!(!eval_obs(q, o, no - 1, u.as_ptr(), ns, p.as_ptr(), np, 1.0));
+    finalize(q);
+}
@@ -0,0 +1,94 @@
+/*
+    asm_text! turns the operands of an amd! or arm! invocation into
+    assembly text, evaluating the register numbers, displacements, and
+    immediates, e.g., movsd xmm(x), qword ptr [rbp+8*r.0] becomes
+    movsd xmm0, qword ptr [rbp+0x58]
+*/
+macro_rules! asm_text {
+    (@ $s:ident) => {};
+    // registers, e.g., xmm(0), d(rx), or x(19)
+    (@ $s:ident $r:ident($e:expr) $($rest:tt)*) => {
+        $s.push_str(&format!("{}{} ", stringify!($r), $e));
+        asm_text!(@ $s $($rest)*)
+    };
+    // memory operands
+    (@ $s:ident [$($t:tt)*] $($rest:tt)*) => {
+        $s.push('[');
+        asm_text!(@ $s $($t)*);
+        $s.truncate($s.trim_end().len());
+        $s.push_str("] ");
+        asm_text!(@ $s $($rest)*)
+    };
+    (@ $s:ident + $e:expr) => {
+        $s.truncate($s.trim_end().len());
+        $s.push_str(&format!("+{:#x}", $e));
+    };
+    (@ $s:ident # $e:expr, $($rest:tt)*) => {
+        $s.push_str(&format!("#{:?}, ", $e));
+        asm_text!(@ $s $($rest)*)
+    };
+    (@ $s:ident # $e:expr) => {
+        $s.push_str(&format!("#{:?}", $e));
+    };
+    (@ $s:ident , $($rest:tt)*) => {
+        $s.truncate($s.trim_end().len());
+        $s.push_str(", ");
+        asm_text!(@ $s $($rest)*)
+    };
+    // vector arrangements, e.g., v(0).8b
+    (@ $s:ident . $t:tt $($rest:tt)*) => {
+        $s.truncate($s.trim_end().len());
+        $s.push_str(&format!(".{} ", stringify!($t)));
+        asm_text!(@ $s $($rest)*)
+    };
+    (@ $s:ident $t:tt $($rest:tt)*) => {
+        $s.push_str(&format!("{} ", stringify!($t)));
+        asm_text!(@ $s $($rest)*)
+    };
+    // the stack adjustments take a plain expression
+    ($op:ident rsp, $e:expr) => {
+        format!("{} rsp, {:#x}", stringify!($op), $e)
+    };
+    ($($t:tt)*) => {{
+        let mut s = String::new();
+        asm_text!(@ s $($t)*);
+        s.truncate(s.trim_end().len());
+        s
+    }};
+}
+
+// a line of a listing, i.e., the offset, the encoded bytes, and the text
+pub fn listing_line(offset: usize, code: &[u8], text: &str) -> String {
+    let hex: Vec<String> = code.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{:6x}:  {:<30}{}", offset, hex.join(" "), text)
+}
+
+#[test]
+fn test_asm_text() {
+    let (r, k, n) = (11, 3, 40);
+    let x: u8 = 2;
+
+    assert_eq!(
+        asm_text! {movsd xmm(0), qword ptr [rbp+8*r]},
+        "movsd xmm0, qword ptr [rbp+0x58]"
+    );
+    assert_eq!(
+        asm_text! {vmovupd ymmword ptr [rsp+32*k], ymm(x+1)},
+        "vmovupd ymmword ptr [rsp+0x60], ymm3"
+    );
+    assert_eq!(asm_text! {call rax}, "call rax");
+    assert_eq!(asm_text! {sub rsp, n}, "sub rsp, 0x28");
+    assert_eq!(asm_text! {ldr d(x), [x(19), #8*r]}, "ldr d2, [x19, #88]");
+    assert_eq!(asm_text! {add sp, sp, #n+32}, "add sp, sp, #72");
+    assert_eq!(asm_text! {fmov d(1), #-1.0}, "fmov d1, #-1.0");
+    assert_eq!(
+        asm_text! {and v(0).8b, v(x).8b, v(3).8b},
+        "and v0.8b, v2.8b, v3.8b"
+    );
+    assert_eq!(asm_text! {ret}, "ret");
+
+    assert_eq!(
+        listing_line(0x10, &[0x48, 0x89, 0xfd], "mov rbp, rdi"),
+        "    10:  48 89 fd                      mov rbp, rdi"
+    );
+}
@@ -0,0 +1,318 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::code::*;
+use crate::model::Program;
+use crate::register::{Word, WordType};
+
+/*
+    LlvmIrCompiler emits a textual LLVM IR module defining
+        define void @run(double* %mem)
+    with the same memory layout as the other backends, to be fed to
+    opt/llc/clang. Constants are inlined as hexadecimal literals, so they
+    are exact and visible to the optimizer; the other registers are
+    loaded from mem on first use, and diffs, obs, and locals are stored
+    back as they are computed.
+*/
+#[derive(Debug)]
+pub struct LlvmIrCompiler {
+    body: String,
+    vals: HashMap<Word, String>,
+    decls: BTreeSet<String>,
+    n: usize,
+}
+
+impl LlvmIrCompiler {
+    pub fn new() -> LlvmIrCompiler {
+        Self {
+            body: String::new(),
+            vals: HashMap::new(),
+            decls: BTreeSet::new(),
+            n: 0,
+        }
+    }
+
+    // a double literal with the exact bits of x
+    fn literal(x: f64) -> String {
+        format!("0x{:016X}", x.to_bits())
+    }
+
+    // emits rhs as a new SSA value and returns its name
+    fn emit(&mut self, rhs: String) -> String {
+        self.n += 1;
+        let v = format!("%v{}", self.n);
+        let _ = writeln!(self.body, "  {} = {}", v, rhs);
+        v
+    }
+
+    fn call(&mut self, f: &str, args: &[&str]) -> String {
+        let params = vec!["double"; args.len()].join(", ");
+        let decl = format!("declare double @{}({})", f, params);
+        self.decls.insert(decl);
+        let args: Vec<String> = args.iter().map(|a| format!("double {}", a)).collect();
+        self.emit(format!("call double @{}({})", f, args.join(", ")))
+    }
+
+    fn load(&mut self, prog: &Program, w: &Word) -> String {
+        if let Some(v) = self.vals.get(w) {
+            return v.clone();
+        }
+
+        if !w.is_temp() {
+            if let WordType::Const(val) = prog.frame.words[w.0] {
+                return Self::literal(val);
+            }
+        }
+
+        let p = self.emit(format!(
+            "getelementptr inbounds double, double* %mem, i64 {}",
+            w.0
+        ));
+        let v = self.emit(format!("load double, double* {}", p));
+        self.vals.insert(*w, v.clone());
+        v
+    }
+
+    fn store(&mut self, w: &Word, v: &str) {
+        let p = self.emit(format!(
+            "getelementptr inbounds double, double* %mem, i64 {}",
+            w.0
+        ));
+        let _ = writeln!(self.body, "  store double {}, double* {}", v, p);
+    }
+
+    fn int(&mut self, x: &str) -> String {
+        self.emit(format!("bitcast double {} to i64", x))
+    }
+
+    fn double(&mut self, x: &str) -> String {
+        self.emit(format!("bitcast i64 {} to double", x))
+    }
+
+    fn reciprocal(&mut self, x: &str) -> String {
+        self.emit(format!("fdiv double {}, {}", Self::literal(1.0), x))
+    }
+
+    // clamps x to [-1, 1] and passes NaN through, as f64::clamp
+    fn clamp(&mut self, x: &str) -> String {
+        let (lo, hi) = (Self::literal(-1.0), Self::literal(1.0));
+        let c = self.emit(format!("fcmp olt double {}, {}", x, lo));
+        let x = self.emit(format!("select i1 {}, double {}, double {}", c, lo, x));
+        let c = self.emit(format!("fcmp ogt double {}, {}", x, hi));
+        self.emit(format!("select i1 {}, double {}, double {}", c, hi, x))
+    }
+
+    fn unary(&mut self, op: &str, x: &str) -> String {
+        match op {
+            "mov" => x.to_string(),
+            "neg" => self.emit(format!("fneg double {}", x)),
+            "abs" => self.call("llvm.fabs.f64", &[x]),
+            "root" => self.call("llvm.sqrt.f64", &[x]),
+            "cbrt" | "erf" | "erfc" | "lgamma" => self.call(op, &[x]),
+            "gamma" => self.call("tgamma", &[x]),
+            "sin" => self.call("llvm.sin.f64", &[x]),
+            "cos" => self.call("llvm.cos.f64", &[x]),
+            "exp" => self.call("llvm.exp.f64", &[x]),
+            "ln" => self.call("llvm.log.f64", &[x]),
+            // as f64::log(x, 10.0), i.e., ln(x) / ln(10)
+            "log" => {
+                let l = self.call("llvm.log.f64", &[x]);
+                self.emit(format!(
+                    "fdiv double {}, {}",
+                    l,
+                    Self::literal(f64::ln(10.0))
+                ))
+            }
+            "log2" => self.call("llvm.log2.f64", &[x]),
+            "tan" | "sinh" | "cosh" | "tanh" | "expm1" | "log1p" => self.call(op, &[x]),
+            "arcsin" => self.call("asin", &[x]),
+            "arccos" => self.call("acos", &[x]),
+            "arctan" => self.call("atan", &[x]),
+            "csc" | "sec" | "cot" => {
+                let f = match op {
+                    "csc" => "llvm.sin.f64",
+                    "sec" => "llvm.cos.f64",
+                    _ => "tan",
+                };
+                let y = self.call(f, &[x]);
+                self.reciprocal(&y)
+            }
+            "safe_arcsin" | "safe_arccos" => {
+                let y = self.clamp(x);
+                let f = if op == "safe_arcsin" { "asin" } else { "acos" };
+                self.call(f, &[&y])
+            }
+            "sech2" => {
+                let a = self.call("llvm.fabs.f64", &[x]);
+                let a = self.emit(format!("fmul double {}, {}", Self::literal(-2.0), a));
+                let e = self.call("llvm.exp.f64", &[&a]);
+                let num = self.emit(format!("fmul double {}, {}", Self::literal(4.0), e));
+                let d = self.emit(format!("fadd double {}, {}", Self::literal(1.0), e));
+                let den = self.emit(format!("fmul double {}, {}", d, d));
+                self.emit(format!("fdiv double {}, {}", num, den))
+            }
+            // as Code::signum, ±0 gives 0 and NaN is passed through
+            "signum" => {
+                let zero = Self::literal(0.0);
+                let c = self.emit(format!("fcmp oeq double {}, {}", x, zero));
+                let s = self.emit(format!("select i1 {}, double {}, double {}", c, zero, x));
+                let c = self.emit(format!("fcmp olt double {}, {}", x, zero));
+                let s = self.emit(format!(
+                    "select i1 {}, double {}, double {}",
+                    c,
+                    Self::literal(-1.0),
+                    s
+                ));
+                let c = self.emit(format!("fcmp ogt double {}, {}", x, zero));
+                self.emit(format!(
+                    "select i1 {}, double {}, double {}",
+                    c,
+                    Self::literal(1.0),
+                    s
+                ))
+            }
+            _ => {
+                let msg = format!("unary op_code {} not found", op);
+                panic!("{}", msg);
+            }
+        }
+    }
+
+    fn binary(&mut self, op: &str, x: &str, y: &str) -> String {
+        let arith = |s: &str| format!("{} double {}, {}", s, x, y);
+
+        match op {
+            "plus" => self.emit(arith("fadd")),
+            "minus" => self.emit(arith("fsub")),
+            "times" => self.emit(arith("fmul")),
+            "divide" => self.emit(arith("fdiv")),
+            "rem" => self.emit(arith("frem")),
+            "power" => self.call("llvm.pow.f64", &[x, y]),
+            "atan2" => self.call("atan2", &[x, y]),
+            "hypot" => self.call("hypot", &[x, y]),
+            // as f64::log(x, b)
+            "logb" => {
+                let a = self.call("llvm.log.f64", &[x]);
+                let b = self.call("llvm.log.f64", &[y]);
+                self.emit(format!("fdiv double {}, {}", a, b))
+            }
+            "min" => self.call("llvm.minimum.f64", &[x, y]),
+            "max" => self.call("llvm.maximum.f64", &[x, y]),
+            // booleans are bitmasks, as in Code::boolean
+            "gt" | "geq" | "lt" | "leq" | "eq" | "neq" => {
+                let cc = match op {
+                    "gt" => "ogt",
+                    "geq" => "oge",
+                    "lt" => "olt",
+                    "leq" => "ole",
+                    "eq" => "oeq",
+                    _ => "une",
+                };
+                let c = self.emit(format!("fcmp {} double {}, {}", cc, x, y));
+                let m = self.emit(format!("sext i1 {} to i64", c));
+                self.double(&m)
+            }
+            "and" | "or" | "xor" => {
+                let a = self.int(x);
+                let b = self.int(y);
+                let m = self.emit(format!("{} i64 {}, {}", op, a, b));
+                self.double(&m)
+            }
+            _ => {
+                let msg = format!("binary op_code {} not found", op);
+                panic!("{}", msg);
+            }
+        }
+    }
+
+    // (x1 & cond) | (x2 & !cond)
+    fn ifelse(&mut self, cond: &str, x1: &str, x2: &str) -> String {
+        let c = self.int(cond);
+        let a = self.int(x1);
+        let b = self.int(x2);
+        let a = self.emit(format!("and i64 {}, {}", a, c));
+        let nc = self.emit(format!("xor i64 {}, -1", c));
+        let b = self.emit(format!("and i64 {}, {}", b, nc));
+        let m = self.emit(format!("or i64 {}, {}", a, b));
+        self.double(&m)
+    }
+
+    pub fn compile(&mut self, prog: &Program) -> String {
+        for c in prog.code.iter() {
+            let (dst, v) = match c {
+                Instruction::Unary { x, dst, op, .. } => {
+                    let x = self.load(prog, x);
+                    (dst, self.unary(op, &x))
+                }
+                Instruction::Binary { x, y, dst, op, .. } => {
+                    let x = self.load(prog, x);
+                    let y = self.load(prog, y);
+                    (dst, self.binary(op, &x, &y))
+                }
+                Instruction::IfElse { x1, x2, cond, dst } => {
+                    let cond = self.load(prog, cond);
+                    let x1 = self.load(prog, x1);
+                    let x2 = self.load(prog, x2);
+                    (dst, self.ifelse(&cond, &x1, &x2))
+                }
+                _ => continue,
+            };
+
+            self.vals.insert(*dst, v.clone());
+
+            if prog.frame.is_diff(dst) || prog.frame.is_obs(dst) || prog.frame.is_local(dst) {
+                self.store(dst, &v);
+            }
+        }
+
+        let mut ir = String::new();
+        let _ = writeln!(ir, "; generated by cell, do not edit\n");
+        let _ = writeln!(ir, "define void @run(double* %mem) {{");
+        let _ = writeln!(ir, "entry:");
+        ir.push_str(&self.body);
+        let _ = writeln!(ir, "  ret void");
+        let _ = writeln!(ir, "}}\n");
+
+        for d in self.decls.iter() {
+            let _ = writeln!(ir, "{}", d);
+        }
+
+        ir
+    }
+}
+
+#[test]
+fn test_llvm_ir() {
+    use crate::model::CellModel;
+    use std::process::{Command, Stdio};
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let ir = LlvmIrCompiler::new().compile(&Program::new(&ml));
+
+    assert!(ir.contains("define void @run(double* %mem) {"));
+    assert!(ir.contains("declare double @llvm.exp.f64(double)"));
+
+    // llvm-as validates the module if it is installed
+    let child = Command::new("llvm-as")
+        .args(["-o", "/dev/null", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+
+    if let Ok(mut child) = child {
+        use std::io::Write;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(ir.as_bytes())
+            .unwrap();
+        let out = child.wait_with_output().unwrap();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+}
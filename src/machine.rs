@@ -1,56 +1,223 @@
-use memmap2::{Mmap, MmapOptions};
-use rand::distributions::{Alphanumeric, DistString};
+use memmap2::{Mmap, MmapMut};
 use std::fs;
-use std::io::Write;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use super::code::BinaryFunc;
+use super::model::Program;
+use super::runnable::CompileError;
 use super::utils::*;
 
+const MAGIC: &[u8; 8] = b"CELLAOT2";
+
 pub struct MachineCode {
     p: *const u8,
-    mmap: Mmap, // we need to store mmap and fs here, so that they are not dropped
-    name: String,
-    fs: fs::File,
+    image: Arc<Mmap>, // the executable mapping, shared by the forks
     vt: Vec<BinaryFunc>,
     _mem: Vec<f64>,
+    arch: String,
+    // the CPU features the code uses beyond the baseline of arch
+    features: Vec<String>,
+    ft: Vec<String>,   // the op names of vt, which are saved instead of the pointers
+    checksum: u64,     // Program::checksum of the compiled model
+    listing: Arc<str>, // the assembly text, if recorded by the compiler
 }
 
+/*
+    Safety: p points into the executable mapping owned by image, which is
+    kept alive by the Arc and is never written after construction, so
+    sending it to another thread is fine. Each MachineCode (including the
+    forks) owns its _mem, which is the only memory the code writes to,
+    and vt only holds plain function pointers.
+
+    For the same reasons, sharing a &MachineCode between threads is fine:
+    running the code requires &mut self, so a shared reference can only
+    read _mem.
+*/
+unsafe impl Send for MachineCode {}
+unsafe impl Sync for MachineCode {}
+
 impl MachineCode {
-    pub fn new(
+    pub fn new(arch: &str, machine_code: &Vec<u8>, prog: &Program, _mem: Vec<f64>) -> MachineCode {
+        Self::try_new(arch, machine_code, prog, _mem).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(
         arch: &str,
-        machine_code: &Vec<u8>,
-        vt: Vec<BinaryFunc>,
+        machine_code: &[u8],
+        prog: &Program,
         _mem: Vec<f64>,
-    ) -> MachineCode {
-        let name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16) + ".bin";
-        MachineCode::write_buf(machine_code, &name);
-        let fs = fs::File::open(&name).unwrap();
-        let mmap = unsafe { MmapOptions::new().map_exec(&fs).unwrap() };
-        let p = mmap.as_ptr() as *const u8;
-
-        #[cfg(target_arch = "x86_64")]
-        if arch != "x86_64" {
-            panic!("cannot run {:?} code", arch);
+    ) -> Result<MachineCode, CompileError> {
+        if arch != std::env::consts::ARCH {
+            return Err(CompileError::Arch(arch.to_string()));
         }
 
-        #[cfg(target_arch = "aarch64")]
-        if arch != "aarch64" {
-            panic!("cannot run {:?} code", arch);
-        }
+        // the code is copied into an anonymous read-write mapping, which is
+        // then remapped read-execute, so that it is never writable and executable
+        let mmap = |e: Error| CompileError::Mmap(e.to_string());
+        let mut buf = MmapMut::map_anon(machine_code.len()).map_err(mmap)?;
+        buf.copy_from_slice(machine_code);
+        let image = buf.make_exec().map_err(mmap)?;
+        let p = image.as_ptr();
 
-        MachineCode {
+        Ok(MachineCode {
             p,
-            mmap,
-            name,
-            fs,
-            vt,
+            image: Arc::new(image),
+            vt: prog.virtual_table(),
             _mem,
+            arch: arch.to_string(),
+            features: Vec::new(),
+            ft: prog.ft.clone(),
+            checksum: prog.checksum(),
+            listing: Arc::from(""),
+        })
+    }
+
+    pub fn with_listing(mut self, lines: &[String]) -> MachineCode {
+        self.listing = Arc::from(lines.join("\n"));
+        self
+    }
+
+    // records the CPU features (e.g., fma or avx) the compiler generated code for
+    pub fn with_features(mut self, features: &[&str]) -> MachineCode {
+        self.features = features.iter().map(|f| f.to_string()).collect();
+        self
+    }
+
+    /*
+        the cache file is (all integers are little-endian u64)
+            magic, checksum, len(arch), arch, len(features),
+            [len(feature), feature]..., len(mem),
+            len(ft), [len(op), op]..., len(code), code
+        mem is not saved, as it is rebuilt from the program on load
+    */
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut buf: Vec<u8> = Vec::new();
+        let put = |buf: &mut Vec<u8>, x: usize| buf.extend_from_slice(&(x as u64).to_le_bytes());
+
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&self.checksum.to_le_bytes());
+        put(&mut buf, self.arch.len());
+        buf.extend_from_slice(self.arch.as_bytes());
+        put(&mut buf, self.features.len());
+
+        for f in self.features.iter() {
+            put(&mut buf, f.len());
+            buf.extend_from_slice(f.as_bytes());
+        }
+
+        put(&mut buf, self._mem.len());
+        put(&mut buf, self.ft.len());
+
+        for op in self.ft.iter() {
+            put(&mut buf, op.len());
+            buf.extend_from_slice(op.as_bytes());
+        }
+
+        put(&mut buf, self.image.len());
+        buf.extend_from_slice(&self.image[..]);
+
+        fs::File::create(path)?.write_all(&buf)
+    }
+
+    // loads the code saved by save; the cache is rejected if it was
+    // compiled from another model, for another architecture, or
+    // uses CPU features the running CPU lacks
+    pub fn load(path: &Path, prog: &Program) -> std::io::Result<MachineCode> {
+        let mut buf: Vec<u8> = Vec::new();
+        fs::File::open(path)?.read_to_end(&mut buf)?;
+        let mut r = Reader { rest: &buf[..] };
+
+        if r.bytes(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a cell code cache"));
+        }
+
+        if r.word()? != prog.checksum() {
+            return Err(invalid("the cache is stale"));
+        }
+
+        let arch = r.string()?;
+
+        if arch != std::env::consts::ARCH {
+            return Err(invalid("the cache is for another architecture"));
+        }
+
+        let n = r.word()?;
+        let features = (0..n)
+            .map(|_| r.string())
+            .collect::<std::io::Result<Vec<String>>>()?;
+
+        if let Some(f) = features.iter().find(|f| !has_feature(f)) {
+            return Err(invalid(&format!("the cpu lacks {}", f)));
+        }
+
+        let mem = prog.frame.mem();
+
+        if r.word()? as usize != mem.len() {
+            return Err(invalid("memory size mismatch"));
+        }
+
+        let n = r.word()?;
+        let ft = (0..n)
+            .map(|_| r.string())
+            .collect::<std::io::Result<Vec<String>>>()?;
+
+        if ft != prog.ft {
+            return Err(invalid("function table mismatch"));
+        }
+
+        let n = r.word()? as usize;
+        let code = r.bytes(n)?.to_vec();
+
+        let features: Vec<&str> = features.iter().map(|f| f.as_str()).collect();
+        MachineCode::try_new(&arch, &code, prog, mem)
+            .map(|m| m.with_features(&features))
+            .map_err(|e| Error::other(e.to_string()))
+    }
+}
+
+// true if the running CPU supports feature, unknown features are not supported
+#[cfg(target_arch = "x86_64")]
+pub fn has_feature(feature: &str) -> bool {
+    match feature {
+        "fma" => is_x86_feature_detected!("fma"),
+        "avx" => is_x86_feature_detected!("avx"),
+        _ => false,
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn has_feature(_feature: &str) -> bool {
+    false
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+// reads the fields of a cache file in order
+struct Reader<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> std::io::Result<&'a [u8]> {
+        if self.rest.len() < n {
+            return Err(invalid("the cache is truncated"));
         }
+        let (head, tail) = self.rest.split_at(n);
+        self.rest = tail;
+        Ok(head)
+    }
+
+    fn word(&mut self) -> std::io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
     }
 
-    fn write_buf(machine_code: &Vec<u8>, name: &str) {
-        let mut fs = fs::File::create(name).unwrap();
-        fs.write(machine_code).unwrap();
+    fn string(&mut self) -> std::io::Result<String> {
+        let n = self.word()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(n)?).to_string())
     }
 }
 
@@ -69,10 +236,206 @@ impl Compiled for MachineCode {
     fn mem_mut(&mut self) -> &mut [f64] {
         &mut self._mem[..]
     }
+
+    fn swap_mem(&mut self, buf: &mut Vec<f64>) {
+        assert_eq!(self._mem.len(), buf.len(), "mem size mismatch");
+        std::mem::swap(&mut self._mem, buf);
+    }
+
+    // the code goes to cell_<checksum>_<arch>.bin and the listing, if any,
+    // to cell_<checksum>_<arch>.s in the temp directory
+    fn keep(&self) -> std::io::Result<Option<PathBuf>> {
+        let name = format!("cell_{:016x}_{}.bin", self.checksum, self.arch);
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, &self.image[..])?;
+
+        if !self.listing.is_empty() {
+            fs::write(path.with_extension("s"), self.listing.as_bytes())?;
+        }
+
+        Ok(Some(path))
+    }
+
+    fn listing(&self) -> Option<&str> {
+        if self.listing.is_empty() {
+            None
+        } else {
+            Some(&self.listing)
+        }
+    }
+
+    fn fork(&self) -> Box<dyn Compiled + Send> {
+        Box::new(MachineCode {
+            p: self.p,
+            image: self.image.clone(),
+            vt: self.vt.clone(),
+            _mem: self._mem.clone(),
+            arch: self.arch.clone(),
+            features: self.features.clone(),
+            ft: self.ft.clone(),
+            checksum: self.checksum,
+            listing: self.listing.clone(),
+        })
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[test]
+fn test_anonymous_mapping() {
+    use crate::model::{CellModel, Program};
+    use crate::runnable::{CompilerType, Runnable};
+
+    let bins = || {
+        std::fs::read_dir(".")
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some("bin".as_ref()))
+            .count()
+    };
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let before = bins();
+
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::Native);
+    let mut f = r.fork();
+    assert_eq!(bins(), before);
+
+    let u = r.initial_states();
+    let p = r.params();
+    let mut du1 = vec![0.0; u.len()];
+    let mut du2 = vec![0.0; u.len()];
+
+    r.call(&mut du1, &u, &p, 0.0);
+    drop(r);
+    f.call(&mut du2, &u, &p, 0.0);
+    assert_eq!(du1, du2);
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[test]
+fn test_keep() {
+    use crate::model::CellModel;
+
+    let text = std::fs::read_to_string("julia/lorenz.json").unwrap();
+    let prog = Program::new(&CellModel::load(&text).unwrap());
+
+    #[cfg(target_arch = "x86_64")]
+    let m = crate::amd::AmdCompiler::new(true).compile(&prog);
+    #[cfg(target_arch = "aarch64")]
+    let m = crate::arm::ArmCompiler::new().compile(&prog);
+
+    let path = m.keep().unwrap().unwrap();
+    assert_eq!(fs::read(&path).unwrap(), &m.image[..]);
+    let listing = fs::read_to_string(path.with_extension("s")).unwrap();
+    assert_eq!(Some(listing.as_str()), m.listing());
+
+    fs::remove_file(path.with_extension("s")).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[test]
+fn test_threads() {
+    use crate::model::{CellModel, Program};
+    use crate::runnable::{CompilerType, Runnable};
+
+    fn is_send_sync<T: Send + Sync>() {}
+    is_send_sync::<MachineCode>();
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::Native);
+
+    let u = r.initial_states();
+    let p = r.params();
+    let mut du = vec![0.0; u.len()];
+    r.call(&mut du, &u, &p, 0.0);
+
+    let handles: Vec<_> = (0..2)
+        .map(|_| {
+            let mut f = r.fork();
+            let (u, p) = (u.clone(), p.clone());
+            std::thread::spawn(move || {
+                let mut du = vec![0.0; u.len()];
+                for _ in 0..1000 {
+                    f.call(&mut du, &u, &p, 0.0);
+                }
+                du
+            })
+        })
+        .collect();
+
+    for h in handles {
+        assert_eq!(h.join().unwrap(), du);
+    }
 }
 
-impl Drop for MachineCode {
-    fn drop(&mut self) {
-        let _ = fs::remove_file(&self.name);
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[test]
+fn test_aot_cache() {
+    use crate::model::CellModel;
+
+    let load = |name: &str| {
+        let text = std::fs::read_to_string(format!("julia/{}.json", name)).unwrap();
+        Program::new(&CellModel::load(&text).unwrap())
+    };
+
+    let prog = load("beeler");
+    let path = std::env::temp_dir().join(format!("cell_{}_beeler.aot", std::process::id()));
+    assert!(MachineCode::load(&path, &prog).is_err());
+
+    #[cfg(target_arch = "x86_64")]
+    let mut m1 = crate::amd::AmdCompiler::new(true).compile(&prog);
+    #[cfg(target_arch = "aarch64")]
+    let mut m1 = crate::arm::ArmCompiler::new().compile(&prog);
+
+    m1.save(&path).unwrap();
+    let mut m2 = MachineCode::load(&path, &prog).unwrap();
+
+    m1.run();
+    m2.run();
+    let bits = |m: &MachineCode| m.mem().iter().map(|x| x.to_bits()).collect::<Vec<u64>>();
+    assert_eq!(bits(&m1), bits(&m2));
+
+    // a cache compiled from another model is rejected
+    let err = MachineCode::load(&path, &load("lorenz")).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    // the features are kept and a cache that needs a missing one is rejected
+    #[cfg(target_arch = "x86_64")]
+    if has_feature("fma") {
+        let opts = crate::model::Options {
+            fma: true,
+            ..Default::default()
+        };
+        let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+        let prog = Program::with_options(&CellModel::load(&text).unwrap(), opts);
+        let m = crate::amd::AmdCompiler::new(true).compile(&prog);
+        assert_eq!(m.features, ["fma"]);
+        m.save(&path).unwrap();
+        assert_eq!(MachineCode::load(&path, &prog).unwrap().features, ["fma"]);
     }
+
+    m1.with_features(&["sse9"]).save(&path).unwrap();
+    let err = MachineCode::load(&path, &prog).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "the cpu lacks sse9");
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_try_new() {
+    use crate::model::CellModel;
+
+    let text = std::fs::read_to_string("julia/lorenz.json").unwrap();
+    let prog = Program::new(&CellModel::load(&text).unwrap());
+    let arch = if cfg!(target_arch = "aarch64") {
+        "x86_64"
+    } else {
+        "aarch64"
+    };
+
+    let res = MachineCode::try_new(arch, &[0xc3], &prog, prog.frame.mem());
+    assert!(matches!(res, Err(CompileError::Arch(a)) if a == arch));
 }
@@ -1,44 +1,260 @@
 use std::env;
 use std::fs;
 use std::io::{BufWriter, Write};
+use std::ops::{ControlFlow, Range};
 use std::time::Instant;
 
+use rand::Rng;
+
 mod analyzer;
 mod code;
 mod machine;
 mod model;
+mod pool;
 mod register;
 mod runnable;
 mod solvers;
 mod utils;
 
+#[macro_use]
+mod listing;
 mod amd;
 mod arm;
+#[cfg(feature = "c")]
+mod c;
+#[cfg(feature = "cranelift")]
+mod cranelift;
 mod interpreter;
+mod llvm;
 mod rusty;
 #[cfg(feature = "wasm")]
 mod wasm;
 
-use model::{CellModel, Program};
-use runnable::{CompilerType, Runnable};
+use llvm::LlvmIrCompiler;
+use model::{CellModel, Program, Report};
+use runnable::{CompilerType, Real, Runnable};
 use solvers::*;
+use utils::Callable;
+
+// the solver settings, overridable with --solver, --dt, --tspan, --stride, and --out
+#[derive(Debug, Clone, PartialEq)]
+struct Settings {
+    solver: String,
+    dt: f64,
+    tspan: Range<f64>,
+    stride: usize,
+    out: String,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            solver: "euler".to_string(),
+            dt: 0.001,
+            tspan: 0.0..5000.0,
+            stride: 10,
+            out: "test.dat".to_string(),
+        }
+    }
+}
+
+const SOLVERS: [&str; 5] = ["euler", "rk4", "heun", "midpoint", "backward-euler"];
+
+impl Settings {
+    fn parse(flags: &[String]) -> Result<Settings, String> {
+        let mut s = Settings::default();
+        let mut it = flags.iter();
+
+        while let Some(flag) = it.next() {
+            let val = it.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            let num = |v: &str| {
+                v.parse::<f64>()
+                    .map_err(|_| format!("{} expects a number, found {}", flag, v))
+            };
+
+            match flag.as_str() {
+                "--solver" => s.solver = val.clone(),
+                "--dt" => s.dt = num(val)?,
+                "--tspan" => {
+                    let (t0, t1) = val
+                        .split_once(':')
+                        .ok_or_else(|| format!("--tspan expects t0:t1, found {}", val))?;
+                    s.tspan = num(t0)?..num(t1)?;
+                }
+                "--stride" => {
+                    s.stride = val
+                        .parse()
+                        .map_err(|_| format!("--stride expects a count, found {}", val))?
+                }
+                "--out" => s.out = val.clone(),
+                _ => return Err(format!("unknown flag {}", flag)),
+            }
+        }
+
+        if !SOLVERS.contains(&s.solver.as_str()) {
+            return Err(format!(
+                "solver should be one of {}, found {}",
+                SOLVERS.join(", "),
+                s.solver
+            ));
+        }
+        if !(s.dt > 0.0 && s.dt.is_finite()) {
+            return Err(format!("dt should be positive, found {}", s.dt));
+        }
+        if !(s.tspan.start < s.tspan.end && s.tspan.end.is_finite()) {
+            return Err(format!(
+                "tspan should be an increasing interval, found {}:{}",
+                s.tspan.start, s.tspan.end
+            ));
+        }
+        if s.stride == 0 {
+            return Err("stride should be at least 1".to_string());
+        }
 
-fn solve(r: &mut Runnable) {
+        Ok(s)
+    }
+}
+
+fn solve(r: &mut Runnable, s: &Settings) {
+    match s.solver.as_str() {
+        "backward-euler" => solve_with(r, &BackwardEuler::new(s.dt, s.stride), s),
+        _ => solve_explicit(r, s),
+    }
+}
+
+// the explicit solvers, which also step in single precision (bytecode32)
+fn solve_explicit<T: Real>(r: &mut Runnable<T>, s: &Settings) {
+    let (dt, k) = (s.dt, s.stride);
+
+    match s.solver.as_str() {
+        "rk4" => solve_with(r, &RK4::new(dt, k), s),
+        "heun" => solve_with(r, &Heun::new(dt, k), s),
+        "midpoint" => solve_with(r, &Midpoint::new(dt, k), s),
+        _ => solve_with(r, &Euler::new(dt, k), s),
+    }
+}
+
+fn solve_with<T: Real, S: Solver<Runnable<T>, T>>(r: &mut Runnable<T>, alg: &S, s: &Settings) {
     let u0 = r.initial_states();
     let p = r.params();
-    let alg = Euler::new(0.001, 10);
-    //let sol = alg.solve(r, u0.clone(), p.clone(), 0.0..1000.0);
 
+    let fd = fs::File::create(&s.out).expect("cannot open the file");
+    let mut buf = BufWriter::new(fd);
+    let frame = &r.prog.frame;
+    write_csv_header(&mut buf, &frame.state_names(), &frame.obs_names())
+        .expect("cannot write the output");
+
+    // rows are streamed to the file as they are produced, so the rows up to
+    // a failure are kept; a write error stops the integration
+    let mut failed = None;
     let now = Instant::now();
-    // let alg = Euler::new(0.001, 10);
-    let sol = alg.solve(r, u0, p, 0.0..5000.0);
+    let res = alg.solve_with(r, u0, p, s.tspan.clone(), |row| {
+        match write_csv_row(&mut buf, row) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(e) => {
+                failed = Some(e);
+                ControlFlow::Break(())
+            }
+        }
+    });
     println!("elapsed {:.1?}", now.elapsed());
 
-    let fd = fs::File::create("test.dat").expect("cannot open the file");
-    let mut buf = BufWriter::new(fd);
+    if let Err(e) = res {
+        println!("integration stopped: {}", e);
+    }
+
+    if let Some(e) = failed.or_else(|| buf.flush().err()) {
+        println!("cannot write the output: {}", e);
+    }
+}
+
+// the number of calls timed per backend by bench
+const BENCH_STEPS: usize = 100_000;
+
+/*
+    compiles the model with each available backend, times the compilation
+    and BENCH_STEPS calls, and checks that du matches the bytecode one at a
+    random state near the initial conditions (up to the sign of zero and
+    NaN payloads)
+*/
+fn bench(ml: &CellModel) {
+    let mut rng = rand::thread_rng();
+    let prog = Program::new(ml);
+
+    let tys = [
+        CompilerType::ByteCode,
+        CompilerType::Native,
+        #[cfg(target_arch = "x86_64")]
+        CompilerType::Amd { optimize: false },
+        #[cfg(feature = "wasm")]
+        CompilerType::Wasm,
+        #[cfg(feature = "rusty")]
+        CompilerType::Rusty,
+        #[cfg(feature = "cranelift")]
+        CompilerType::Cranelift,
+        #[cfg(feature = "c")]
+        CompilerType::C,
+    ];
+
+    let mut state: Option<(Vec<f64>, Vec<f64>)> = None;
+    let mut du0: Option<Vec<f64>> = None;
+
+    println!("{:<12}{:>12}{:>12}  du", "backend", "compile ms", "ns/step");
+
+    for ty in tys {
+        let now = Instant::now();
+        let mut r = match Runnable::try_new(prog.clone(), ty) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("{:<12}{:>12}", ty.to_string(), e);
+                continue;
+            }
+        };
+        let compile = now.elapsed();
 
-    for row in &sol {
-        let _ = write!(&mut buf, "{}", row);
+        let (u, p) = state.get_or_insert_with(|| {
+            let u = r.initial_states();
+            let u = u.iter().map(|x| x * rng.gen_range(0.9..1.1)).collect();
+            (u, r.params())
+        });
+        let mut du = vec![0.0; u.len()];
+
+        let now = Instant::now();
+        for i in 0..BENCH_STEPS {
+            r.call(&mut du, u, p, i as f64 * 1e-3);
+        }
+        let step = now.elapsed().as_nanos() as f64 / BENCH_STEPS as f64;
+
+        r.call(&mut du, u, p, 0.0);
+        let check = match &du0 {
+            None => {
+                du0 = Some(du.clone());
+                "reference".to_string()
+            }
+            Some(v)
+                if v.iter()
+                    .zip(du.iter())
+                    .all(|(x, y)| x == y || x.is_nan() && y.is_nan()) =>
+            {
+                "ok".to_string()
+            }
+            Some(v) => {
+                let err = v
+                    .iter()
+                    .zip(du.iter())
+                    .map(|(x, y)| (x - y).abs() / x.abs().max(f64::MIN_POSITIVE))
+                    .fold(0.0, f64::max);
+                format!("MISMATCH (max relative error {:e})", err)
+            }
+        };
+
+        println!(
+            "{:<12}{:>12.1}{:>12.1}  {}",
+            ty.to_string(),
+            compile.as_secs_f64() * 1e3,
+            step,
+            check
+        );
     }
 }
 
@@ -46,29 +262,99 @@ fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 3 {
-        println!("use: cell [bytecode|amd|arm|native|wasm|rusty] model-file.json");
+        println!("use: cell [bytecode|bytecode32|amd|amd-opt|arm|native|wasm|rusty|cranelift|c|llvm] model-file.json [flags]");
+        println!("     flags: --solver euler|rk4|heun|midpoint|backward-euler --dt 0.001");
+        println!("            --tspan 0:5000 --stride 10 --out test.dat");
+        println!("     cell check model-file.json");
+        println!("     cell bench model-file.json");
         std::process::exit(0);
     }
 
-    let text = fs::read_to_string(args[2].as_str()).unwrap();
-    let ml = CellModel::load(&text).unwrap();
+    if args[1] == "check" {
+        let text = fs::read_to_string(args[2].as_str()).unwrap();
+        let report = Report::new(&text);
+        println!("{}", report.as_json());
+        std::process::exit(if report.errors.is_empty() { 0 } else { 1 });
+    }
+
+    let ml = CellModel::from_path(&args[2]).unwrap();
+
+    if args[1] == "bench" {
+        bench(&ml);
+        return;
+    }
+
+    if args[1] == "llvm" {
+        print!("{}", LlvmIrCompiler::new().compile(&Program::new(&ml)));
+        return;
+    }
 
     let ty = match args[1].as_str() {
-        "bytecode" => CompilerType::ByteCode,
+        "bytecode" | "bytecode32" => CompilerType::ByteCode,
         "arm" => CompilerType::Arm,
-        "amd" => CompilerType::Amd,
+        "amd" => CompilerType::Amd { optimize: false },
+        "amd-opt" => CompilerType::Amd { optimize: true },
         "native" => CompilerType::Native,
         #[cfg(feature = "wasm")]
         "wasm" => CompilerType::Wasm,
         #[cfg(feature = "rusty")]
         "rusty" => CompilerType::Rusty,
+        #[cfg(feature = "cranelift")]
+        "cranelift" => CompilerType::Cranelift,
+        #[cfg(feature = "c")]
+        "c" => CompilerType::C,
         _ => {
-            println!("compiler type should be one of bytecode, amd, arm, native, wasm, or. rusty");
+            println!("compiler type should be one of bytecode, bytecode32, amd, amd-opt, arm, native, wasm, rusty, cranelift, or c");
+            std::process::exit(0);
+        }
+    };
+
+    let settings = match Settings::parse(&args[3..]) {
+        Ok(s) => s,
+        Err(msg) => {
+            println!("{}", msg);
             std::process::exit(0);
         }
     };
 
     let prog = Program::new(&ml);
-    let mut r = Runnable::new(prog, ty);
-    solve(&mut r);
+
+    if args[1] == "bytecode32" {
+        if settings.solver == "backward-euler" {
+            println!("backward-euler needs double precision, use bytecode instead");
+            std::process::exit(0);
+        }
+
+        let mut r = Runnable::new_as::<f32>(prog, ty);
+        solve_explicit(&mut r, &settings);
+    } else {
+        let mut r = Runnable::new(prog, ty);
+        solve(&mut r, &settings);
+    }
+}
+
+#[test]
+fn test_settings() {
+    let parse = |s: &str| {
+        let flags: Vec<String> = s.split_whitespace().map(String::from).collect();
+        Settings::parse(&flags)
+    };
+
+    assert_eq!(parse(""), Ok(Settings::default()));
+
+    let s = parse("--solver rk4 --dt 0.01 --tspan 0:1000 --stride 5 --out run.dat").unwrap();
+    assert_eq!(s.solver, "rk4");
+    assert_eq!(s.dt, 0.01);
+    assert_eq!(s.tspan, 0.0..1000.0);
+    assert_eq!(s.stride, 5);
+    assert_eq!(s.out, "run.dat");
+
+    assert!(parse("--solver dopri").is_err());
+    assert!(parse("--dt -1").is_err());
+    assert!(parse("--dt").is_err());
+    assert!(parse("--tspan 10:0").is_err());
+    assert!(parse("--tspan 10").is_err());
+    assert!(parse("--stride 0").is_err());
+    assert!(parse("--stride 1.5").is_err());
+    assert!(parse("--step 1").is_err());
 }
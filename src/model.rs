@@ -1,24 +1,64 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
 
+use crate::analyzer::{Analyzer, CALLERS};
 use crate::code::*;
 use crate::register::*;
 
 // lowers Expr and its constituents into a three-address_code format
 pub trait Lower {
-    fn lower(&self, prog: &mut Program) -> Word;
+    fn lower(&self, prog: &mut Program) -> Result<Word, LowerError>;
+}
+
+// compilation options
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Options {
+    // guards functions against arguments pushed slightly outside of their
+    // domain by rounding, e.g., arcsin(1.0000001) is evaluated as arcsin(1)
+    pub safe_math: bool,
+    // compiles diffs and obs into separate kernels (see Program::split)
+    pub split_kernels: bool,
+    // if set, only these observables are calculated and the equations
+    // nothing live depends on are removed (see Program::eliminate_dead_code)
+    pub live_obs: Option<Vec<String>>,
+    // contracts a*b + c into a fused multiply-add on the native backends
+    // if the cpu supports it; the result is rounded once, so it can differ
+    // from the other backends in the last bit
+    pub fma: bool,
 }
 
 // collects instructions and registers
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Program {
     pub code: Vec<Instruction>, // the list of instructions
     pub frame: Frame,           // memory (states, registers, constants, ...)
     pub ft: Vec<String>,        // function table (used to generate a virtual table)
+    pub options: Options,       // compilation options
+    // the model the program is lowered from (None if loaded from IR), see jacobian
+    pub model: Option<CellModel>,
 }
 
 impl Program {
     pub fn new(ml: &CellModel) -> Program {
+        Program::with_options(ml, Options::default())
+    }
+
+    pub fn with_options(ml: &CellModel, options: Options) -> Program {
+        Program::try_with_options(ml, options).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(ml: &CellModel) -> Result<Program, LowerError> {
+        Program::try_with_options(ml, Options::default())
+    }
+
+    pub fn try_with_options(ml: &CellModel, options: Options) -> Result<Program, LowerError> {
+        let ml = &*ml.reduce_order();
         let mut frame = Frame::new();
         
         /* 
@@ -36,10 +76,16 @@ impl Program {
             +------------------------+
             | parameters             |
             +------------------------+
+            | inputs                 |
+            +------------------------+
+            | lookup tables          |
+            +------------------------+
             | observables (output)   |
             +------------------------+
             | differentials (output) |
             +------------------------+
+            | algebraic variables    |
+            +------------------------+
             | constants and temps    |
             +------------------------+
         */
@@ -53,6 +99,14 @@ impl Program {
         for v in &ml.params {
             frame.alloc(WordType::Param(v.name.clone(), v.val));
         }
+
+        for v in &ml.inputs {
+            frame.alloc(WordType::Input(v.name.clone(), v.val));
+        }
+
+        for t in &ml.tables {
+            frame.alloc(WordType::Table(t.name.clone(), t.xs.clone(), t.ys.clone()));
+        }
         
         for eq in &ml.obs {
             if let Some(name) = eq.lhs.var() {                
@@ -66,16 +120,29 @@ impl Program {
             frame.alloc(WordType::Diff(v.name.clone()));
         }
 
+        for eq in ml.sorted_algs() {
+            frame.alloc(WordType::Local(eq.lhs.var().unwrap()));
+        }
+
         let mut prog = Program {
             code: Vec::new(),
             frame,
             ft: Vec::new(),
+            options,
+            model: Some(ml.clone()),
         };
 
-        ml.lower(&mut prog);
+        ml.lower(&mut prog)?;
+        prog.fold_constants();
+
+        if let Some(live_obs) = prog.options.live_obs.clone() {
+            prog.eliminate_dead_code(&live_obs);
+        }
+
+        prog.coalesce_movs();
         prog.code.push(Instruction::Nop);
 
-        prog
+        Ok(prog)
     }
 
     // pushes a non-op into code
@@ -135,6 +202,22 @@ impl Program {
             self.code.push(c);
         }
 
+        // strength reduction of x / c to x * (1 / c), unless x is also a constant,
+        // which is folded exactly by fold_constants
+        if op_ == "divide" && self.const_value(&x_).is_none() {
+            if let (Some(val), Some(Instruction::Num { dst, .. })) =
+                (self.const_value(&y_), self.code.last())
+            {
+                let inv = 1.0 / val;
+                if *dst == y_ && val != 0.0 && inv.is_finite() {
+                    self.code.pop();
+                    let y = self.const_word(inv);
+                    self.code.push(Instruction::Num { val: inv, dst: y });
+                    return self.push_binary("times", x_, y, dst_);
+                }
+            }
+        }
+
         let p = self.proc(op_);
 
         self.code.push(Instruction::Binary {
@@ -155,6 +238,13 @@ impl Program {
         self.frame.alloc(WordType::Const(val))
     }
 
+    // allocates a named register for an intermediate value
+    pub fn alloc_local(&mut self) -> String {
+        let name = format!("__local{}", self.frame.words.len());
+        self.frame.alloc(WordType::Local(name.clone()));
+        name
+    }
+
     // allocates a temporary register
     pub fn alloc_temp(&mut self) -> Word {
         self.frame.alloc(WordType::Temp)
@@ -174,13 +264,576 @@ impl Program {
     }
 
     pub fn reg_diff(&self, name: &str) -> Word {
-        self.frame.find_diff(name).expect("cannot find reg by name")
+        self.frame
+            .find_diff(name)
+            .unwrap_or_else(|| panic!("differential equation for undeclared state {}", name))
+    }
+
+    // reorders the states (and correspondingly the diffs), such that the
+    // new i-th state is the old permutation[i]-th one
+    pub fn reorder_states(&mut self, permutation: &[usize]) {
+        let n = self.frame.count_states();
+        assert_eq!(permutation.len(), n, "permutation size mismatch");
+
+        let mut inv = vec![usize::MAX; n];
+        for (i, &j) in permutation.iter().enumerate() {
+            assert!(j < n && inv[j] == usize::MAX, "invalid permutation");
+            inv[j] = i;
+        }
+
+        let first_state = self.frame.first_state().unwrap_or(0);
+        let first_diff = self.frame.first_diff().unwrap_or(0);
+
+        let remap = |w: Word| -> Word {
+            if w.is_temp() {
+                w
+            } else if w.0 >= first_state && w.0 < first_state + n {
+                Word(first_state + inv[w.0 - first_state], 0)
+            } else if w.0 >= first_diff && w.0 < first_diff + n {
+                Word(first_diff + inv[w.0 - first_diff], 0)
+            } else {
+                w
+            }
+        };
+
+        for c in self.code.iter_mut() {
+            c.map_words(&remap);
+        }
+
+        let words = &mut self.frame.words;
+        let states: Vec<WordType> = permutation
+            .iter()
+            .map(|j| words[first_state + j].clone())
+            .collect();
+        let diffs: Vec<WordType> = permutation
+            .iter()
+            .map(|j| words[first_diff + j].clone())
+            .collect();
+        words.splice(first_state..first_state + n, states);
+        words.splice(first_diff..first_diff + n, diffs);
+
+        for i in 0..n {
+            if let WordType::State(s, _) = &self.frame.words[first_state + i] {
+                self.frame.named.insert(s.clone(), first_state + i);
+            }
+            if let WordType::Diff(s) = &self.frame.words[first_diff + i] {
+                self.frame.named.insert(format!("δ{}", s), first_diff + i);
+            }
+        }
+    }
+
+    // the Jacobian sparsity pattern, i.e., the i-th row lists the
+    // states that the derivative of the i-th state depends on
+    pub fn sparsity(&self) -> Vec<Vec<usize>> {
+        let n = self.frame.count_states();
+        let first_state = self.frame.first_state().unwrap_or(0);
+        let first_diff = self.frame.first_diff().unwrap_or(0);
+
+        // dependencies of each assigned (obs or diff) register
+        let mut deps: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+        let mut cur: BTreeSet<usize> = BTreeSet::new();
+
+        for c in self.code.iter() {
+            match c {
+                Instruction::Eq { .. } => cur.clear(),
+                Instruction::Var { reg, .. } => {
+                    if reg.0 >= first_state && reg.0 < first_state + n {
+                        cur.insert(reg.0 - first_state);
+                    } else if let Some(d) = deps.get(&reg.0) {
+                        cur.extend(d.iter());
+                    }
+                }
+                _ => {
+                    if let Some(dst) = c.assigned() {
+                        deps.insert(dst.0, cur.clone());
+                    }
+                }
+            }
+        }
+
+        (0..n)
+            .map(|i| {
+                deps.get(&(first_diff + i))
+                    .map(|d| d.iter().cloned().collect())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    // derives a state permutation that groups coupled states together
+    // using reverse Cuthill–McKee ordering of the (symmetrized) sparsity pattern
+    pub fn locality_permutation(&self) -> Vec<usize> {
+        let pattern = self.sparsity();
+        let n = pattern.len();
+
+        let mut adj: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); n];
+        for (i, row) in pattern.iter().enumerate() {
+            for &j in row.iter().filter(|&&j| j != i) {
+                adj[i].insert(j);
+                adj[j].insert(i);
+            }
+        }
+
+        let mut order: Vec<usize> = Vec::with_capacity(n);
+        let mut visited = vec![false; n];
+
+        while order.len() < n {
+            let start = (0..n)
+                .filter(|&i| !visited[i])
+                .min_by_key(|&i| adj[i].len())
+                .unwrap();
+
+            let mut queue = VecDeque::from([start]);
+            visited[start] = true;
+
+            while let Some(i) = queue.pop_front() {
+                order.push(i);
+
+                let mut next: Vec<usize> =
+                    adj[i].iter().cloned().filter(|&j| !visited[j]).collect();
+                next.sort_by_key(|&j| adj[j].len());
+
+                for j in next {
+                    visited[j] = true;
+                    queue.push_back(j);
+                }
+            }
+        }
+
+        order.reverse();
+        order
+    }
+
+    // a program calculating the Jacobian of the odes as its observables,
+    // see CellModel::jacobian
+    // the derivatives are taken on the Expr trees of the source model, since
+    // they are gone after lowering
+    pub fn jacobian(&self) -> Program {
+        self.try_jacobian().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_jacobian(&self) -> Result<Program, LowerError> {
+        let ml = self.model.as_ref().ok_or(LowerError::NoModel)?;
+        // live_obs names the observables of the source model
+        let options = Options {
+            live_obs: None,
+            ..self.options.clone()
+        };
+        Program::try_with_options(&ml.try_jacobian()?, options)
+    }
+
+    // merges multiple models into a single program, see CellModel::merge
+    pub fn merge(models: &[CellModel], couplings: &[Coupling]) -> Program {
+        Program::new(&CellModel::merge(models, couplings))
+    }
+
+    /*
+        a peephole pass over the movs closing the equations: a self-copy
+        (Eq r, Var r, r = mov r) is dropped altogether, and t = op(...) followed
+        by dst = mov t, where t is a temp not read afterward, becomes dst = op(...)
+        the Eq markers are kept, since the stack-based backends (wasm, rusty, c)
+        take the assigned register from them
+    */
+    pub fn coalesce_movs(&mut self) {
+        let code = std::mem::take(&mut self.code);
+        let mut out: Vec<Instruction> = Vec::with_capacity(code.len());
+
+        for (i, c) in code.iter().enumerate() {
+            let (x, dst) = match c {
+                Instruction::Unary { op, x, dst, .. } if op == "mov" => (*x, *dst),
+                _ => {
+                    out.push(c.clone());
+                    continue;
+                }
+            };
+
+            let n = out.len();
+
+            if x == dst
+                && n >= 2
+                && matches!(out[n - 2], Instruction::Eq { dst: r } if r == dst)
+                && matches!(out[n - 1], Instruction::Var { reg, .. } if reg == x)
+            {
+                out.truncate(n - 2);
+                continue;
+            }
+
+            if x.is_temp() && !Self::is_read(&code[i + 1..], x) {
+                if let Some(
+                    Instruction::Unary { dst: t, .. }
+                    | Instruction::Binary { dst: t, .. }
+                    | Instruction::IfElse { dst: t, .. },
+                ) = out.last_mut()
+                {
+                    if *t == x {
+                        *t = dst;
+                        continue;
+                    }
+                }
+            }
+
+            out.push(c.clone());
+        }
+
+        self.code = out;
+    }
+
+    // whether r is read in code before it is written again
+    fn is_read(code: &[Instruction], r: Word) -> bool {
+        for c in code.iter() {
+            let (args, dst) = match c {
+                Instruction::Unary { x, dst, .. } => (vec![*x], *dst),
+                Instruction::Binary { x, y, dst, .. } => (vec![*x, *y], *dst),
+                Instruction::IfElse { x1, x2, cond, dst } => (vec![*x1, *x2, *cond], *dst),
+                Instruction::Var { reg, .. } => (vec![*reg], Frame::ZERO),
+                _ => continue,
+            };
+
+            if args.contains(&r) {
+                return true;
+            }
+
+            if dst == r {
+                return false;
+            }
+        }
+
+        false
+    }
+
+    // equations (Eq..mov segments) as (dst, start, end, registers read)
+    fn segments(&self) -> Vec<(Word, usize, usize, Vec<Word>)> {
+        let mut segments: Vec<(Word, usize, usize, Vec<Word>)> = Vec::new();
+        let mut start = 0;
+        let mut reads: Vec<Word> = Vec::new();
+
+        for (i, c) in self.code.iter().enumerate() {
+            match c {
+                Instruction::Eq { .. } => {
+                    start = i;
+                    reads.clear();
+                }
+                Instruction::Var { reg, .. } => reads.push(*reg),
+                _ => {
+                    if let Some(dst) = c.assigned() {
+                        segments.push((dst, start, i + 1, reads.clone()));
+                    }
+                }
+            }
+        }
+
+        segments
+    }
+
+    // marks the segments whose dst satisfies pred plus their dependencies
+    fn select(
+        segments: &[(Word, usize, usize, Vec<Word>)],
+        pred: &dyn Fn(&Word) -> bool,
+    ) -> Vec<bool> {
+        let defs: HashMap<Word, usize> =
+            segments.iter().enumerate().map(|(k, s)| (s.0, k)).collect();
+
+        let mut needed = vec![false; segments.len()];
+        let mut queue: VecDeque<usize> = (0..segments.len())
+            .filter(|&k| pred(&segments[k].0))
+            .collect();
+
+        while let Some(k) = queue.pop_front() {
+            if needed[k] {
+                continue;
+            }
+            needed[k] = true;
+            for r in segments[k].3.iter() {
+                if let Some(&j) = defs.get(r) {
+                    queue.push_back(j);
+                }
+            }
+        }
+
+        needed
+    }
+
+    // the code of the needed segments
+    // instructions outside of segments (e.g., the final Nop) are always kept
+    fn extract(
+        &self,
+        segments: &[(Word, usize, usize, Vec<Word>)],
+        needed: &[bool],
+    ) -> Vec<Instruction> {
+        let mut owner: Vec<Option<usize>> = vec![None; self.code.len()];
+        for (k, s) in segments.iter().enumerate() {
+            for o in owner[s.1..s.2].iter_mut() {
+                *o = Some(k);
+            }
+        }
+
+        self.code
+            .iter()
+            .zip(owner.iter())
+            .filter(|(_, o)| o.is_none_or(|k| needed[k]))
+            .map(|(c, _)| c.clone())
+            .collect()
+    }
+
+    // partitions code into two programs sharing the same frame:
+    // the first computes diffs (and the obs/locals they depend on) and
+    // the second computes obs
+    // each equation (Eq..mov segment) is assigned as a unit
+    pub fn split(&self) -> (Program, Program) {
+        let segments = self.segments();
+        let diffs = Self::select(&segments, &|r| self.frame.is_diff(r));
+        let obs = Self::select(&segments, &|r| self.frame.is_obs(r));
+
+        let program = |needed: &[bool]| -> Program {
+            Program {
+                code: self.extract(&segments, needed),
+                frame: self.frame.clone(),
+                ft: self.ft.clone(),
+                options: self.options.clone(),
+                model: self.model.clone(),
+            }
+        };
+
+        (program(&diffs), program(&obs))
+    }
+
+    // removes the equations that neither the diffs nor the observables
+    // listed in live_obs depend on; the other observables keep their
+    // registers (so the memory layout is unchanged) but are no longer calculated
+    pub fn eliminate_dead_code(&mut self, live_obs: &[String]) {
+        let is_live = |r: &Word| {
+            self.frame.is_diff(r)
+                || (self.frame.is_obs(r)
+                    && matches!(&self.frame.words[r.0], WordType::Obs(s) if live_obs.contains(s)))
+        };
+
+        let segments = self.segments();
+        let needed = Self::select(&segments, &is_live);
+        self.code = self.extract(&segments, &needed);
+    }
+
+    // the value of a constant (non-temp) register
+    fn const_value(&self, r: &Word) -> Option<f64> {
+        if r.is_temp() {
+            return None;
+        }
+
+        match self.frame.words[r.0] {
+            WordType::Const(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    // returns a constant register holding val, reusing an existing one if possible
+    fn const_word(&mut self, val: f64) -> Word {
+        let pos = self
+            .frame
+            .words
+            .iter()
+            .position(|w| matches!(w, WordType::Const(v) if v.to_bits() == val.to_bits()));
+
+        match pos {
+            Some(idx) => Word(idx, 0),
+            None => self.alloc_const(val),
+        }
+    }
+
+    /*
+        evaluates the unary and binary instructions whose operands are constants
+        at compile time and replaces each of them (and the Num instructions of
+        its operands) with a single Num
+
+        lookups are not folded, since they need the interpreter tables;
+        non-finite results, including true booleans (see Code::boolean),
+        are left for runtime
+    */
+    pub fn fold_constants(&mut self) {
+        let skip = ["mov", "lookup", "lookup_slope", "nop"];
+
+        let code = std::mem::take(&mut self.code);
+        let mut out: Vec<Instruction> = Vec::with_capacity(code.len());
+        // folded temps and their replacement constant registers
+        let mut folded: HashMap<Word, Word> = HashMap::new();
+
+        for mut c in code.into_iter() {
+            c.map_words(&|w| *folded.get(&w).unwrap_or(&w));
+
+            let (op, args, dst) = match &c {
+                Instruction::Unary { op, x, dst, .. } => (op, vec![*x], *dst),
+                Instruction::Binary { op, x, y, dst, .. } => (op, vec![*x, *y], *dst),
+                _ => {
+                    out.push(c);
+                    continue;
+                }
+            };
+
+            let vals: Vec<f64> = args.iter().filter_map(|r| self.const_value(r)).collect();
+
+            // the operands are the Num instructions immediately preceding c
+            let n = args.len();
+            let operands = out.len() >= n
+                && out[out.len() - n..]
+                    .iter()
+                    .zip(args.iter())
+                    .all(|(o, r)| matches!(o, Instruction::Num { dst, .. } if dst == r));
+
+            if skip.contains(&op.as_str()) || vals.len() != n || !operands || !dst.is_temp() {
+                out.push(c);
+                continue;
+            }
+
+            let val = Code::from_str(op)(vals[0], *vals.get(1).unwrap_or(&0.0));
+
+            if !val.is_finite() {
+                out.push(c);
+                continue;
+            }
+
+            out.truncate(out.len() - n);
+            let r = self.const_word(val);
+            out.push(Instruction::Num { val, dst: r });
+            folded.insert(dst, r);
+        }
+
+        self.code = out;
     }
 
     pub fn virtual_table(&self) -> Vec<fn(f64, f64) -> f64> {
         let vt: Vec<fn(f64, f64) -> f64> = self.ft.iter().map(|s| Code::from_str(s)).collect();
         vt
     }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            max_temps: Analyzer::new(self).max_live_temps(),
+            registers: self.frame.words.len(),
+            ..Stats::default()
+        };
+
+        for c in self.code.iter() {
+            match c {
+                Instruction::Unary { op, .. } => {
+                    stats.unary += 1;
+                    stats.calls += CALLERS.contains(&op.as_str()) as usize;
+                }
+                Instruction::Binary { op, .. } => {
+                    stats.binary += 1;
+                    stats.calls += CALLERS.contains(&op.as_str()) as usize;
+                }
+                Instruction::IfElse { .. } => stats.ifelse += 1,
+                Instruction::Num { .. } => stats.nums += 1,
+                Instruction::Var { .. } => stats.vars += 1,
+                Instruction::Eq { .. } => stats.eqs += 1,
+                Instruction::Nop => stats.nops += 1,
+            }
+        }
+
+        stats
+    }
+
+    // the lowered program as JSON, e.g., to compile it later to another
+    // backend or to attach the exact IR to a bug report
+    pub fn to_ir_json(&self) -> String {
+        let ir = Ir {
+            words: self.frame.words.clone(),
+            temps: self.frame.stack_size(),
+            code: self.code.clone(),
+            options: self.options.clone(),
+        };
+        serde_json::to_string(&ir).unwrap()
+    }
+
+    /*
+        the inverse of to_ir_json: the frame is rebuilt by Frame::from_words
+        and the function table from the ops, so ft may list the functions in
+        a different order; the ops and registers are checked, since the
+        native backends would index memory with them unchecked
+    */
+    pub fn from_ir_json(text: &str) -> Result<Program, Box<dyn Error>> {
+        let ir: Ir = serde_json::from_str(text)?;
+        let mut frame = Frame::from_words(ir.words)?;
+        frame.stack = vec![WordType::Temp; ir.temps];
+
+        let mut prog = Program {
+            code: Vec::new(),
+            frame,
+            ft: Vec::new(),
+            options: ir.options,
+            model: None,
+        };
+
+        let (n, temps) = (prog.frame.words.len(), ir.temps);
+        let bad = std::cell::Cell::new(None);
+
+        for mut c in ir.code {
+            c.map_words(&|w| {
+                if (w.is_temp() && w.0 >= temps) || (!w.is_temp() && w.0 >= n) {
+                    bad.set(Some(w));
+                }
+                w
+            });
+
+            if let Some(w) = bad.get() {
+                return Err(format!("register {:?} is out of range", w).into());
+            }
+
+            match &mut c {
+                Instruction::Unary { op, p, .. } | Instruction::Binary { op, p, .. } => {
+                    known(op)?;
+                    *p = prog.proc(op);
+                }
+                _ => {}
+            }
+
+            prog.code.push(c);
+        }
+
+        Ok(prog)
+    }
+
+    // a 64-bit FNV-1a hash of the code, frame, and function table,
+    // which identifies the compiled model, e.g., in MachineCode::load
+    pub fn checksum(&self) -> u64 {
+        let text = format!("{:?}|{:?}|{:?}", self.code, self.frame.words, self.ft);
+        text.bytes().fold(0xcbf29ce484222325, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+}
+
+// the serialized form of a Program (see Program::to_ir_json)
+#[derive(Serialize, Deserialize)]
+struct Ir {
+    words: Vec<WordType>,
+    temps: usize,
+    code: Vec<Instruction>,
+    #[serde(default)]
+    options: Options,
+}
+
+// the size of a Program, as returned by Program::stats
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub unary: usize,
+    pub binary: usize,
+    pub ifelse: usize,
+    pub nums: usize,
+    pub vars: usize,
+    pub eqs: usize,
+    pub nops: usize,
+    pub calls: usize,     // ops that are function calls (see analyzer::CALLERS)
+    pub max_temps: usize, // the high-water mark of live temps
+    pub registers: usize, // the size of the frame
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} unary, {} binary, {} ifelse ({} calls), {} registers, {} temps at most",
+            self.unary, self.binary, self.ifelse, self.calls, self.registers, self.max_temps
+        )
+    }
 }
 
 // A defined (state or param) variable
@@ -188,16 +841,27 @@ impl Program {
 pub struct Variable {
     pub name: String,
     pub val: f64,
+    // the initial value of x' if x has a second-order equation (default 0)
+    #[serde(default)]
+    pub velocity: Option<f64>,
+}
+
+// a tabulated function; the breakpoints can be in any order
+#[derive(Debug, Clone, Deserialize)]
+pub struct Table {
+    pub name: String,
+    pub xs: Vec<f64>,
+    pub ys: Vec<f64>,
 }
 
 impl Lower for Variable {
-    fn lower(&self, prog: &mut Program) -> Word {
-        prog.reg(&self.name)
+    fn lower(&self, prog: &mut Program) -> Result<Word, LowerError> {
+        Ok(prog.reg(&self.name))
     }
 }
 
 // Expr tree
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(tag = "type")]
 pub enum Expr {
     Tree { op: String, args: Vec<Expr> },
@@ -219,6 +883,14 @@ impl Expr {
         None
     }
 
+    // extracts x from the lhs of a second-order diff eq, Differential(Differential(x))
+    pub fn second_diff_var(&self) -> Option<String> {
+        match self {
+            Expr::Tree { args, op } if op == "Differential" => args[0].diff_var(),
+            _ => None,
+        }
+    }
+
     // extracts the regular variable from the lhs of an observable eq
     pub fn var(&self) -> Option<String> {
         if let Expr::Var { name } = self {
@@ -227,94 +899,680 @@ impl Expr {
         None
     }
 
-    fn lower_unary(&self, prog: &mut Program, op: &str, args: &Vec<Expr>) -> Word {
-        let x = args[0].lower(prog);
-        let dst = prog.alloc_temp();
-        prog.push_unary(op, x, dst);
-        prog.free(x);
-        dst
+    // collects the names of all the variables referenced in the expression
+    pub fn vars(&self, names: &mut Vec<String>) {
+        match self {
+            Expr::Var { name } => names.push(name.clone()),
+            Expr::Tree { args, .. } => {
+                for arg in args.iter() {
+                    arg.vars(names);
+                }
+            }
+            Expr::Const { .. } => {}
+        }
     }
 
-    fn lower_binary(&self, prog: &mut Program, op: &str, args: &Vec<Expr>) -> Word {
-        if op == "times" {
-            return self.lower_times(prog, args);
+    // returns a copy of the expression with the variables renamed by f
+    pub fn rename(&self, f: &impl Fn(&str) -> String) -> Expr {
+        match self {
+            Expr::Var { name } => Expr::Var { name: f(name) },
+            Expr::Tree { op, args } => Expr::Tree {
+                op: op.clone(),
+                args: args.iter().map(|x| x.rename(f)).collect(),
+            },
+            Expr::Const { val } => Expr::Const { val: *val },
         }
-
-        let x = args[0].lower(prog);
-        let y = args[1].lower(prog);
-        let dst = prog.alloc_temp();
-
-        prog.push_binary(op, x, y, dst);
-        prog.free(y);
-        prog.free(x);
-
-        dst
     }
 
-    fn lower_times(&self, prog: &mut Program, args: &Vec<Expr>) -> Word {
-        let x = args[0].lower(prog);
-        let dst = prog.alloc_temp();
-
-        if x == Frame::MINUS_ONE {
-            prog.pop();
-            let y = args[1].lower(prog);
-            prog.push_unary("neg", y, dst);
-            prog.free(y);
-        } else {
-            let y = args[1].lower(prog);
-            if y == Frame::MINUS_ONE {
-                prog.pop();
-                prog.push_unary("neg", x, dst);
-            } else {
-                prog.push_binary("times", x, y, dst);
-            };
-            prog.free(y);
+    pub fn contains(&self, x: &Expr) -> bool {
+        if self == x {
+            return true;
         }
+        match self {
+            Expr::Tree { args, .. } => args.iter().any(|a| a.contains(x)),
+            _ => false,
+        }
+    }
 
-        prog.free(x);
-
-        dst
+    // returns a copy of the expression with every occurrence of x replaced by y
+    pub fn replace(&self, x: &Expr, y: &Expr) -> Expr {
+        if self == x {
+            return y.clone();
+        }
+        match self {
+            Expr::Tree { op, args } => Expr::Tree {
+                op: op.clone(),
+                args: args.iter().map(|a| a.replace(x, y)).collect(),
+            },
+            _ => self.clone(),
+        }
     }
 
-    fn lower_ternary(&self, prog: &mut Program, op: &str, args: &Vec<Expr>) -> Word {
-        if op != "ifelse" {
-            return self.lower_poly(prog, op, args);
+    // finds the largest subtree of self (in pre-order) that also occurs in other
+    fn find_shared(&self, other: &Expr) -> Option<&Expr> {
+        if let Expr::Tree { args, .. } = self {
+            if other.contains(self) {
+                return Some(self);
+            }
+            for a in args.iter() {
+                if let Some(x) = a.find_shared(other) {
+                    return Some(x);
+                }
+            }
         }
+        None
+    }
 
-        let x1 = args[1].lower(prog);
-        let x2 = args[2].lower(prog);
-        let cond = args[0].lower(prog);
-        let dst = prog.alloc_temp();
+    /*
+        branch-aware CSE: the subexpressions shared by both branches of an ifelse
+        are hoisted out of the select chain and calculated once into a local register
+        the hoisted equations are lowered into prog and the rewritten expression is returned
+    */
+    pub fn hoist_branches(&self, prog: &mut Program) -> Result<Expr, LowerError> {
+        let expr = match self {
+            Expr::Tree { op, args } if op == "ifelse" && args.len() == 3 => {
+                let mut args = args.clone();
 
-        prog.push_ifelse(x1, x2, cond, dst);
+                while let Some(x) = args[1].find_shared(&args[2]) {
+                    let x = x.clone();
+                    let name = prog.alloc_local();
+
+                    Equation {
+                        lhs: Expr::Var { name: name.clone() },
+                        rhs: x.clone(),
+                    }
+                    .lower(prog)?;
+
+                    let y = Expr::Var { name };
+                    args = args.iter().map(|a| a.replace(&x, &y)).collect();
+                }
+
+                Expr::Tree {
+                    op: op.clone(),
+                    args: args
+                        .iter()
+                        .map(|a| a.hoist_branches(prog))
+                        .collect::<Result<_, _>>()?,
+                }
+            }
+            Expr::Tree { op, args } => Expr::Tree {
+                op: op.clone(),
+                args: args
+                    .iter()
+                    .map(|a| a.hoist_branches(prog))
+                    .collect::<Result<_, _>>()?,
+            },
+            _ => self.clone(),
+        };
+
+        Ok(expr)
+    }
+
+    /*
+        strength reduction of power: x^n for a small integer n is expanded into
+        repeated multiplications (and a reciprocal if n < 0) and x^0.5 into root;
+        other exponents keep the generic power. As temps are consumed once, a
+        compound base is first calculated into a local register, as in hoist_branches
+    */
+    pub fn expand_powers(&self, prog: &mut Program) -> Result<Expr, LowerError> {
+        const MAX_EXPONENT: f64 = 8.0;
+
+        let (op, args) = match self {
+            Expr::Tree { op, args } => (op, args),
+            _ => return Ok(self.clone()),
+        };
+
+        let args: Vec<Expr> = args
+            .iter()
+            .map(|a| a.expand_powers(prog))
+            .collect::<Result<_, _>>()?;
+
+        let n = match (op.as_str(), &args[..]) {
+            ("power", [_, Expr::Const { val }]) => *val,
+            _ => return Ok(Expr::tree(op, args)),
+        };
+
+        if n == 0.5 {
+            return Ok(Expr::tree("root", vec![args[0].clone()]));
+        }
+
+        if n.fract() != 0.0 || n.abs() > MAX_EXPONENT {
+            return Ok(Expr::tree(op, args));
+        }
+
+        if n == 0.0 {
+            return Ok(Expr::num(1.0));
+        }
+
+        let x = match &args[0] {
+            Expr::Tree { .. } if n.abs() > 1.0 => {
+                let name = prog.alloc_local();
+
+                Equation {
+                    lhs: Expr::Var { name: name.clone() },
+                    rhs: args[0].clone(),
+                }
+                .lower(prog)?;
+
+                Expr::Var { name }
+            }
+            x => x.clone(),
+        };
+
+        let mut y = x.clone();
+        for _ in 1..n.abs() as usize {
+            y = Expr::tree("times", vec![y, x.clone()]);
+        }
+
+        if n < 0.0 {
+            y = Expr::tree("divide", vec![Expr::num(1.0), y]);
+        }
+
+        Ok(y)
+    }
+
+    /*
+        rewrites the stimulus builtins into comparisons and ifelse, so that
+        no backend needs a new op:
+            step(x) = heaviside(x) = x >= 0 ? 1 : 0, i.e., step(0) = 1
+            pulse(t, start, duration, amplitude) is amplitude for
+            start <= t < start + duration and 0 otherwise, i.e., the pulse
+            is on at t == start and off at t == start + duration
+        a NaN argument makes the condition false, hence the result 0
+    */
+    pub fn expand_steps(&self) -> Expr {
+        let (op, args) = match self {
+            Expr::Tree { op, args } => (op.as_str(), args),
+            _ => return self.clone(),
+        };
+
+        let args: Vec<Expr> = args.iter().map(|a| a.expand_steps()).collect();
+
+        match (op, &args[..]) {
+            ("step" | "heaviside", [x]) => Expr::tree(
+                "ifelse",
+                vec![
+                    Expr::tree("geq", vec![x.clone(), Expr::num(0.0)]),
+                    Expr::num(1.0),
+                    Expr::num(0.0),
+                ],
+            ),
+            ("pulse", [t, start, duration, amplitude]) => {
+                let end = Expr::add(start.clone(), duration.clone());
+                let on = Expr::tree(
+                    "and",
+                    vec![
+                        Expr::tree("geq", vec![t.clone(), start.clone()]),
+                        Expr::tree("lt", vec![t.clone(), end]),
+                    ],
+                );
+                Expr::tree("ifelse", vec![on, amplitude.clone(), Expr::num(0.0)])
+            }
+            _ => Expr::tree(op, args),
+        }
+    }
+
+    fn is_const(&self, v: f64) -> bool {
+        matches!(self, Expr::Const { val } if *val == v)
+    }
+
+    // splits y * ln(x), the exponent the front-end writes x^y as exp(y * ln(x))
+    // with, into (x, y)
+    fn as_log_power(&self) -> Option<(Expr, Expr)> {
+        match self {
+            Expr::Tree { op, args } if op == "times" && args.len() == 2 => {
+                match (&args[0], &args[1]) {
+                    (Expr::Tree { op, args: xs }, y) | (y, Expr::Tree { op, args: xs })
+                        if op == "ln" =>
+                    {
+                        Some((xs[0].clone(), y.clone()))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn tree(op: &str, args: Vec<Expr>) -> Expr {
+        Expr::Tree {
+            op: op.to_string(),
+            args,
+        }
+    }
+
+    fn num(val: f64) -> Expr {
+        Expr::Const { val }
+    }
+
+    // constructors used by diff that drop the trivial terms (0 + x, 1 * x, ...)
+    fn add(a: Expr, b: Expr) -> Expr {
+        if a.is_const(0.0) {
+            b
+        } else if b.is_const(0.0) {
+            a
+        } else {
+            Expr::tree("plus", vec![a, b])
+        }
+    }
+
+    fn sub(a: Expr, b: Expr) -> Expr {
+        if b.is_const(0.0) {
+            a
+        } else if a.is_const(0.0) {
+            Expr::neg(b)
+        } else {
+            Expr::tree("minus", vec![a, b])
+        }
+    }
+
+    fn mul(a: Expr, b: Expr) -> Expr {
+        if a.is_const(0.0) || b.is_const(0.0) {
+            Expr::num(0.0)
+        } else if a.is_const(1.0) {
+            b
+        } else if b.is_const(1.0) {
+            a
+        } else {
+            Expr::tree("times", vec![a, b])
+        }
+    }
+
+    fn div(a: Expr, b: Expr) -> Expr {
+        if a.is_const(0.0) {
+            Expr::num(0.0)
+        } else if b.is_const(1.0) {
+            a
+        } else {
+            Expr::tree("divide", vec![a, b])
+        }
+    }
+
+    fn neg(a: Expr) -> Expr {
+        match a {
+            Expr::Const { val } => Expr::num(-val),
+            Expr::Tree { op, mut args } if op == "neg" => args.remove(0),
+            _ => Expr::tree("neg", vec![a]),
+        }
+    }
+
+    /*
+        the digamma function ψ(x) = lgamma(x)', built from the existing ops:
+        the reflection ψ(x) = ψ(1 - x) - π cot(πx) for x < 1/2, the recurrence
+        ψ(z) = ψ(z + 6) - Σ 1 / (z + k) for k in 0..6, and the asymptotic series
+        of ψ(y) for y = z + 6 >= 6.5, which is accurate to about 1e-12
+    */
+    fn digamma(x: Expr) -> Expr {
+        // B(2k) / 2k for the Bernoulli numbers B(2k), k = 1..5
+        let bernoulli = [
+            1.0 / 12.0,
+            -1.0 / 120.0,
+            1.0 / 252.0,
+            -1.0 / 240.0,
+            1.0 / 132.0,
+        ];
+
+        let small = Expr::tree("lt", vec![x.clone(), Expr::num(0.5)]);
+        let x1 = Expr::sub(Expr::num(1.0), x.clone());
+        let z = Expr::tree("ifelse", vec![small.clone(), x1, x.clone()]);
+
+        let y = Expr::add(z.clone(), Expr::num(6.0));
+        let w = Expr::div(Expr::num(1.0), Expr::mul(y.clone(), y.clone()));
+        let series = bernoulli.iter().rev().fold(Expr::num(0.0), |acc, c| {
+            Expr::mul(w.clone(), Expr::add(Expr::num(*c), acc))
+        });
+        let mut psi = Expr::sub(
+            Expr::tree("ln", vec![y.clone()]),
+            Expr::add(Expr::div(Expr::num(0.5), y), series),
+        );
+
+        for k in 0..6 {
+            let zk = Expr::add(z.clone(), Expr::num(k as f64));
+            psi = Expr::sub(psi, Expr::div(Expr::num(1.0), zk));
+        }
+
+        let pi = Expr::num(std::f64::consts::PI);
+        let cot = Expr::tree("cot", vec![Expr::mul(pi.clone(), x)]);
+        let reflected = Expr::sub(psi.clone(), Expr::mul(pi, cot));
+        Expr::tree("ifelse", vec![small, reflected, psi])
+    }
+
+    // the symbolic derivative with respect to the variable var
+    pub fn diff(&self, var: &str) -> Result<Expr, LowerError> {
+        let ds = HashMap::from([(var.to_string(), Expr::num(1.0))]);
+        self.diff_with(&ds)
+    }
+
+    // the symbolic derivative given the derivatives ds of the variables
+    // (the missing ones are constant), which applies the chain rule through
+    // named intermediates without substituting them
+    pub fn diff_with(&self, ds: &HashMap<String, Expr>) -> Result<Expr, LowerError> {
+        let (op, args) = match self {
+            Expr::Const { .. } => return Ok(Expr::num(0.0)),
+            Expr::Var { name } => return Ok(ds.get(name).cloned().unwrap_or(Expr::num(0.0))),
+            Expr::Tree { op, args } => (op.as_str(), args),
+        };
+
+        // comparisons and logical ops are piecewise constant (their bitmask
+        // results only select between branches), so their operands, like the
+        // condition of ifelse, are not differentiated
+        let d: Vec<Expr> = match op {
+            "gt" | "geq" | "lt" | "leq" | "eq" | "neq" | "and" | "or" | "xor" => {
+                return Ok(Expr::num(0.0))
+            }
+            "ifelse" => std::iter::once(Ok(Expr::num(0.0)))
+                .chain(args[1..].iter().map(|a| a.diff_with(ds)))
+                .collect::<Result<_, _>>()?,
+            _ => args
+                .iter()
+                .map(|a| a.diff_with(ds))
+                .collect::<Result<_, _>>()?,
+        };
+
+        if d.iter().all(|x| x.is_const(0.0)) {
+            return Ok(Expr::num(0.0));
+        }
+
+        let x = args[0].clone();
+        let dx = d[0].clone();
+        let f = |op: &str, x: &Expr| Expr::tree(op, vec![x.clone()]);
+        let sqr = |x: Expr| Expr::tree("times", vec![x.clone(), x]);
+
+        let df = match op {
+            "plus" => d.into_iter().fold(Expr::num(0.0), Expr::add),
+            "minus" => Expr::sub(dx, d[1].clone()),
+            "neg" => Expr::neg(dx),
+            "times" => (0..args.len()).fold(Expr::num(0.0), |acc, i| {
+                let term = args.iter().enumerate().fold(d[i].clone(), |t, (j, a)| {
+                    if j == i {
+                        t
+                    } else {
+                        Expr::mul(t, a.clone())
+                    }
+                });
+                Expr::add(acc, term)
+            }),
+            "divide" => {
+                let y = args[1].clone();
+                let num = Expr::sub(Expr::mul(dx, y.clone()), Expr::mul(x, d[1].clone()));
+                Expr::div(num, sqr(y))
+            }
+            "power" => {
+                let y = args[1].clone();
+                if d[1].is_const(0.0) {
+                    let y1 = match y {
+                        Expr::Const { val } => Expr::num(val - 1.0),
+                        _ => Expr::sub(y.clone(), Expr::num(1.0)),
+                    };
+                    let dp = Expr::mul(y, Expr::tree("power", vec![x, y1]));
+                    Expr::mul(dp, dx)
+                } else {
+                    // d(x^y) = x^y (y' ln(x) + y x' / x)
+                    let t = Expr::add(
+                        Expr::mul(d[1].clone(), f("ln", &x)),
+                        Expr::div(Expr::mul(y, dx), x),
+                    );
+                    Expr::mul(self.clone(), t)
+                }
+            }
+            "exp" => match x.as_log_power() {
+                // as the power, since exp(y ln(x)) y x' / x is 0 * inf at x = 0
+                Some((b, y)) if y.diff_with(ds)?.is_const(0.0) => {
+                    Expr::tree("power", vec![b, y]).diff_with(ds)?
+                }
+                _ => Expr::mul(self.clone(), dx),
+            },
+            "expm1" => Expr::mul(f("exp", &x), dx),
+            "ln" => Expr::div(dx, x),
+            "log" => Expr::div(dx, Expr::mul(x, Expr::num(std::f64::consts::LN_10))),
+            "log2" => Expr::div(dx, Expr::mul(x, Expr::num(std::f64::consts::LN_2))),
+            "log1p" => Expr::div(dx, Expr::add(Expr::num(1.0), x)),
+            "root" => Expr::div(dx, Expr::mul(Expr::num(2.0), self.clone())),
+            "cbrt" => Expr::div(dx, Expr::mul(Expr::num(3.0), sqr(self.clone()))),
+            // erf(x)' = 2 / sqrt(pi) exp(-x^2)
+            "erf" | "erfc" => {
+                let s = if op == "erf" { 1.0 } else { -1.0 };
+                let k = Expr::num(s * std::f64::consts::FRAC_2_SQRT_PI);
+                let e = f("exp", &Expr::neg(sqr(x)));
+                Expr::mul(Expr::mul(k, e), dx)
+            }
+            // lgamma(x)' = ψ(x) and gamma(x)' = gamma(x) ψ(x)
+            "lgamma" => Expr::mul(Expr::digamma(x), dx),
+            "gamma" => Expr::mul(Expr::mul(self.clone(), Expr::digamma(x)), dx),
+            "sin" => Expr::mul(f("cos", &x), dx),
+            "cos" => Expr::neg(Expr::mul(f("sin", &x), dx)),
+            "tan" => Expr::div(dx, sqr(f("cos", &x))),
+            "csc" => Expr::neg(Expr::mul(Expr::mul(self.clone(), f("cot", &x)), dx)),
+            "sec" => Expr::mul(Expr::mul(self.clone(), f("tan", &x)), dx),
+            "cot" => Expr::neg(Expr::mul(sqr(f("csc", &x)), dx)),
+            "arcsin" => Expr::div(dx, f("root", &Expr::sub(Expr::num(1.0), sqr(x)))),
+            "arccos" => Expr::neg(Expr::div(dx, f("root", &Expr::sub(Expr::num(1.0), sqr(x))))),
+            "arctan" => Expr::div(dx, Expr::add(Expr::num(1.0), sqr(x))),
+            "sinh" => Expr::mul(f("cosh", &x), dx),
+            "cosh" => Expr::mul(f("sinh", &x), dx),
+            "tanh" => Expr::mul(f("sech2", &x), dx),
+            // d(sech(x)^2) = -2 sech(x)^2 tanh(x)
+            "sech2" => Expr::mul(
+                Expr::mul(Expr::num(-2.0), Expr::mul(self.clone(), f("tanh", &x))),
+                dx,
+            ),
+            "abs" => {
+                let sign = Expr::tree(
+                    "ifelse",
+                    vec![
+                        Expr::tree("lt", vec![x, Expr::num(0.0)]),
+                        Expr::num(-1.0),
+                        Expr::num(1.0),
+                    ],
+                );
+                Expr::mul(sign, dx)
+            }
+            "ifelse" => Expr::tree("ifelse", vec![x, d[1].clone(), d[2].clone()]),
+            "clamp" => {
+                let x = Expr::tree("max", vec![x, args[1].clone()]);
+                Expr::tree("min", vec![x, args[2].clone()]).diff_with(ds)?
+            }
+            "min" | "max" => {
+                let cmp = if op == "min" { "lt" } else { "gt" };
+                let cond = Expr::tree(cmp, vec![x, args[1].clone()]);
+                Expr::tree("ifelse", vec![cond, dx, d[1].clone()])
+            }
+            // atan2(y, x)' = (x y' - y x') / (x^2 + y^2)
+            "atan2" => {
+                let (y, x) = (args[0].clone(), args[1].clone());
+                let num = Expr::sub(
+                    Expr::mul(x.clone(), d[0].clone()),
+                    Expr::mul(y.clone(), d[1].clone()),
+                );
+                Expr::div(num, Expr::add(sqr(x), sqr(y)))
+            }
+            // hypot(x, y)' = (x x' + y y') / hypot(x, y)
+            "hypot" => {
+                let y = args[1].clone();
+                let num = Expr::add(Expr::mul(x, dx), Expr::mul(y, d[1].clone()));
+                Expr::div(num, self.clone())
+            }
+            // logb(x, b)' = x' / (x ln(b)) - ln(x) b' / (b ln(b)^2)
+            "logb" => {
+                let b = args[1].clone();
+                let lb = f("ln", &b);
+                let t = Expr::div(dx, Expr::mul(x.clone(), lb.clone()));
+                if d[1].is_const(0.0) {
+                    t
+                } else {
+                    let s = Expr::mul(f("ln", &x), d[1].clone());
+                    Expr::sub(t, Expr::div(s, Expr::mul(b, sqr(lb))))
+                }
+            }
+            "rem" if d[1].is_const(0.0) => dx,
+            // piecewise linear, the derivative is the slope of the current segment
+            "interp1" => {
+                let slope = Expr::tree("interp1_slope", args.clone());
+                Expr::mul(slope, d[1].clone())
+            }
+            "lookup" => Expr::mul(Expr::tree("lookup_slope", args.clone()), dx),
+            // piecewise constant in everything but the amplitude
+            "step" | "heaviside" | "signum" => Expr::num(0.0),
+            "pulse" => {
+                let mut args = args.clone();
+                args[3] = d[3].clone();
+                Expr::tree("pulse", args)
+            }
+            _ => return Err(LowerError::not_differentiable(op)),
+        };
+
+        Ok(df)
+    }
+
+    // returns X if the expression is -X or (-1)*X
+    pub fn negated(&self) -> Option<&Expr> {
+        let minus_one = Expr::Const { val: -1.0 };
+
+        match self {
+            Expr::Tree { op, args } if op == "neg" && args.len() == 1 => Some(&args[0]),
+            Expr::Tree { op, args } if op == "times" && args.len() == 2 => {
+                if args[0] == minus_one {
+                    Some(&args[1])
+                } else if args[1] == minus_one {
+                    Some(&args[0])
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn lower_unary(
+        &self,
+        prog: &mut Program,
+        op: &str,
+        args: &Vec<Expr>,
+    ) -> Result<Word, LowerError> {
+        let op = match op {
+            "arcsin" if prog.options.safe_math => "safe_arcsin",
+            "arccos" if prog.options.safe_math => "safe_arccos",
+            _ => op,
+        };
+
+        known(op)?;
+        let x = args[0].lower(prog)?;
+        let dst = prog.alloc_temp();
+        prog.push_unary(op, x, dst);
+        prog.free(x);
+        Ok(dst)
+    }
+
+    fn lower_binary(
+        &self,
+        prog: &mut Program,
+        op: &str,
+        args: &Vec<Expr>,
+    ) -> Result<Word, LowerError> {
+        if op == "times" {
+            return self.lower_times(prog, args);
+        }
+
+        known(op)?;
+        let x = args[0].lower(prog)?;
+        let y = args[1].lower(prog)?;
+        let dst = prog.alloc_temp();
+
+        prog.push_binary(op, x, y, dst);
+        prog.free(y);
+        prog.free(x);
+
+        Ok(dst)
+    }
+
+    fn lower_times(&self, prog: &mut Program, args: &Vec<Expr>) -> Result<Word, LowerError> {
+        let x = args[0].lower(prog)?;
+        let dst = prog.alloc_temp();
+
+        if x == Frame::MINUS_ONE {
+            prog.pop();
+            let y = args[1].lower(prog)?;
+            prog.push_unary("neg", y, dst);
+            prog.free(y);
+        } else {
+            let y = args[1].lower(prog)?;
+            if y == Frame::MINUS_ONE {
+                prog.pop();
+                prog.push_unary("neg", x, dst);
+            } else {
+                prog.push_binary("times", x, y, dst);
+            };
+            prog.free(y);
+        }
+
+        prog.free(x);
+
+        Ok(dst)
+    }
+
+    fn lower_ternary(
+        &self,
+        prog: &mut Program,
+        op: &str,
+        args: &Vec<Expr>,
+    ) -> Result<Word, LowerError> {
+        // clamp(x, lo, hi) is min(max(x, lo), hi), so no backend needs a new op
+        if op == "clamp" {
+            let x = Expr::tree("max", vec![args[0].clone(), args[1].clone()]);
+            return Expr::tree("min", vec![x, args[2].clone()]).lower(prog);
+        }
+
+        if op != "ifelse" {
+            return self.lower_poly(prog, op, args);
+        }
+
+        let x1 = args[1].lower(prog)?;
+        let x2 = args[2].lower(prog)?;
+        let cond = args[0].lower(prog)?;
+        let dst = prog.alloc_temp();
+
+        prog.push_ifelse(x1, x2, cond, dst);
 
         prog.free(cond);
         prog.free(x2);
         prog.free(x1);
 
-        dst
+        Ok(dst)
     }
 
-    fn lower_poly(&self, prog: &mut Program, op: &str, args: &Vec<Expr>) -> Word {
+    fn lower_poly(
+        &self,
+        prog: &mut Program,
+        op: &str,
+        args: &Vec<Expr>,
+    ) -> Result<Word, LowerError> {
         if !(op == "plus" || op == "times") {
-            panic!("missing op: {}", op);
+            return Err(LowerError::unknown_op(op));
         }
 
-        let mut x = args[0].lower(prog);
+        let mut x = args[0].lower(prog)?;
         for i in 1..args.len() {
-            let y = args[i].lower(prog);
+            let y = args[i].lower(prog)?;
             let dst = prog.alloc_temp();
             prog.push_binary(op, x, y, dst);
             prog.free(x);
             x = dst;
         }
 
-        x
+        Ok(x)
+    }
+}
+
+// the unary and binary ops should have a runtime function (see Code::from_str)
+fn known(op: &str) -> Result<(), LowerError> {
+    match Code::try_from_str(op) {
+        Some(_) => Ok(()),
+        None => Err(LowerError::unknown_op(op)),
     }
 }
 
 impl Lower for Expr {
-    fn lower(&self, prog: &mut Program) -> Word {
+    fn lower(&self, prog: &mut Program) -> Result<Word, LowerError> {
         match self {
             Expr::Const { val } => {
                 let dst = if *val == 0.0 {
@@ -327,7 +1585,7 @@ impl Lower for Expr {
                     prog.alloc_const(*val)
                 };
                 prog.push(Instruction::Num { val: *val, dst });
-                dst
+                Ok(dst)
             }
             Expr::Var { name } => {
                 // Technically, this is not necessary but having Instruction::Var in the code
@@ -337,7 +1595,7 @@ impl Lower for Expr {
                     name: name.clone(),
                     reg: dst,
                 });
-                dst
+                Ok(dst)
             }
             Expr::Tree { op, args } => match args.len() {
                 1 => self.lower_unary(prog, &op, &args),
@@ -357,52 +1615,1847 @@ pub struct Equation {
 }
 
 impl Lower for Equation {
-    fn lower(&self, prog: &mut Program) -> Word {
-        let dst = if let Some(var) = self.lhs.diff_var() {
-            prog.reg_diff(&var)
+    fn lower(&self, prog: &mut Program) -> Result<Word, LowerError> {
+        let (dst, lhs) = if let Some(var) = self.lhs.diff_var() {
+            (prog.reg_diff(&var), format!("δ{}", var))
         } else if let Some(var) = self.lhs.var() {
-            prog.reg(&var)
+            (prog.reg(&var), var)
         } else {
             panic!("undefined diff variable");
         };
 
-        prog.push_eq(dst);
+        // the errors of the hoisted equations are reported in this one
+        let lower = |prog: &mut Program| -> Result<Word, LowerError> {
+            let rhs = self
+                .rhs
+                .expand_steps()
+                .hoist_branches(prog)?
+                .expand_powers(prog)?;
+
+            prog.push_eq(dst);
+            rhs.lower(prog)
+        };
 
-        let src = self.rhs.lower(prog);
+        let src = lower(prog).map_err(|e| e.within(&lhs))?;
 
         prog.push_unary("mov", src, dst);
-        Frame::ZERO
+        Ok(Frame::ZERO)
+    }
+}
+
+impl Equation {
+    pub fn rename(&self, f: &impl Fn(&str) -> String) -> Equation {
+        Equation {
+            lhs: self.lhs.rename(f),
+            rhs: self.rhs.rename(f),
+        }
     }
 }
 
+// links an observable of the source model to a parameter of the target model
+// (indices refer to the list of models passed to merge)
+#[derive(Debug, Clone)]
+pub struct Coupling {
+    pub source: usize,
+    pub obs: String,
+    pub target: usize,
+    pub param: String,
+}
+
 // loads from a JSON CellModel file
 #[derive(Debug, Clone, Deserialize)]
 pub struct CellModel {
     pub iv: Variable,
     pub params: Vec<Variable>,
     pub states: Vec<Variable>,
-    #[allow(dead_code)]
-    pub algs: Vec<Equation>,
+    pub algs: Vec<Equation>, // explicit algebraic equations, x ~ expr
     pub odes: Vec<Equation>,
     pub obs: Vec<Equation>,
+    // optional list of conserved pairs [a, b], declaring that δb = -δa
+    #[serde(default)]
+    pub conserved: Vec<(String, String)>,
+    // optional time-dependent forcing, e.g., a stimulus current; the values
+    // are the defaults and are updated by Runnable::set_input
+    #[serde(default)]
+    pub inputs: Vec<Variable>,
+    // optional tabulated functions, e.g., experimental curves, used as interp1(name, x)
+    #[serde(default)]
+    pub tables: Vec<Table>,
 }
 
 impl CellModel {
-    pub fn load(text: &str) -> Result<CellModel, Box<dyn Error>> {
-        Ok(serde_json::from_str(text)?)
+    pub fn load(text: &str) -> Result<CellModel, LoadError> {
+        CellModel::from_reader(text.as_bytes())
     }
-}
 
-impl Lower for CellModel {
-    fn lower(&self, prog: &mut Program) -> Word {
-        for eq in &self.obs {
-            eq.lower(prog);
+    // parses the model as it is read, without holding the whole text
+    pub fn from_reader<R: Read>(r: R) -> Result<CellModel, LoadError> {
+        Ok(serde_json::from_reader(r)?)
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<CellModel, LoadError> {
+        let fd = File::open(path).map_err(LoadError::Io)?;
+        CellModel::from_reader(BufReader::new(fd))
+    }
+
+    // checks the model for problems that would otherwise surface during lowering
+    pub fn validate(&self) -> Vec<ValidationError> {
+        if let Cow::Owned(ml) = self.reduce_order() {
+            return ml.validate();
         }
 
-        for eq in &self.odes {
-            eq.lower(prog);
+        let mut errs: Vec<ValidationError> = Vec::new();
+
+        let mut declared: HashSet<&str> = HashSet::new();
+        declared.insert(&self.iv.name);
+
+        for v in self
+            .states
+            .iter()
+            .chain(self.params.iter())
+            .chain(self.inputs.iter())
+        {
+            declared.insert(&v.name);
+        }
+
+        for t in self.tables.iter() {
+            declared.insert(&t.name);
+        }
+
+        let mut obs: Vec<String> = Vec::new();
+        for eq in self.obs.iter().chain(self.algs.iter()) {
+            eq.lhs.vars(&mut obs);
+        }
+        declared.extend(obs.iter().map(|s| s.as_str()));
+
+        for (i, eq) in self.algs.iter().enumerate() {
+            if eq.lhs.var().is_none() {
+                errs.push(ValidationError::ImplicitAlg { index: i });
+            }
+        }
+
+        // each state should have exactly one ode and vice versa
+        let diffs: Vec<String> = self
+            .odes
+            .iter()
+            .filter_map(|eq| eq.lhs.diff_var())
+            .collect();
+
+        for v in self.states.iter() {
+            match diffs.iter().filter(|s| **s == v.name).count() {
+                0 => errs.push(ValidationError::MissingOde {
+                    state: v.name.clone(),
+                }),
+                1 => {}
+                count => errs.push(ValidationError::DuplicateOde {
+                    state: v.name.clone(),
+                    count,
+                }),
+            }
+        }
+
+        for s in diffs.iter() {
+            if !self.states.iter().any(|v| v.name == *s) {
+                errs.push(ValidationError::UndeclaredState { name: s.clone() });
+            }
+        }
+
+        let mut used: HashSet<String> = HashSet::new();
+
+        for eq in self
+            .algs
+            .iter()
+            .chain(self.obs.iter())
+            .chain(self.odes.iter())
+        {
+            let lhs = if let Some(var) = eq.lhs.diff_var() {
+                format!("δ{}", var)
+            } else {
+                eq.lhs.var().unwrap_or_default()
+            };
+
+            let mut names: Vec<String> = Vec::new();
+            eq.rhs.vars(&mut names);
+
+            for name in names {
+                if !declared.contains(name.as_str()) {
+                    errs.push(ValidationError::UndeclaredVar {
+                        name: name.clone(),
+                        lhs: lhs.clone(),
+                    });
+                }
+                used.insert(name);
+            }
+        }
+
+        for v in self.params.iter() {
+            if !used.contains(&v.name) {
+                errs.push(ValidationError::UnusedParam {
+                    name: v.name.clone(),
+                });
+            }
+        }
+
+        errs
+    }
+
+    /*
+        rewrites each second-order equation, x'' = rhs, into two first-order
+        ones, x' = v and v' = rhs, where the velocity v is a new state named
+        x' that follows x; the model is borrowed if it is already first-order
+    */
+    pub fn reduce_order(&self) -> Cow<'_, CellModel> {
+        if !self
+            .odes
+            .iter()
+            .any(|eq| eq.lhs.second_diff_var().is_some())
+        {
+            return Cow::Borrowed(self);
+        }
+
+        let mut ml = self.clone();
+        let diff = |name: &str| {
+            Expr::tree(
+                "Differential",
+                vec![Expr::Var {
+                    name: name.to_string(),
+                }],
+            )
+        };
+
+        for i in 0..self.odes.len() {
+            if let Some(x) = self.odes[i].lhs.second_diff_var() {
+                let v = format!("{}'", x);
+
+                ml.odes[i].lhs = diff(&v);
+                ml.odes.push(Equation {
+                    lhs: diff(&x),
+                    rhs: Expr::Var { name: v.clone() },
+                });
+
+                if let Some(k) = ml.states.iter().position(|s| s.name == x) {
+                    let val = ml.states[k].velocity.unwrap_or(0.0);
+                    ml.states.insert(
+                        k + 1,
+                        Variable {
+                            name: v,
+                            val,
+                            velocity: None,
+                        },
+                    );
+                }
+            }
         }
 
-        Frame::ZERO
+        Cow::Owned(ml)
+    }
+
+    // the algebraic equations ordered such that each one is calculated
+    // before being used by another
+    pub fn sorted_algs(&self) -> Vec<&Equation> {
+        let names: Vec<String> = self.algs.iter().filter_map(|eq| eq.lhs.var()).collect();
+        assert!(
+            names.len() == self.algs.len(),
+            "implicit algebraic equations are not supported, the lhs should be a variable"
+        );
+
+        let mut algs: Vec<&Equation> = self.algs.iter().collect();
+        let mut sorted: Vec<&Equation> = Vec::new();
+
+        while !algs.is_empty() {
+            let k = algs
+                .iter()
+                .position(|eq| {
+                    let mut deps: Vec<String> = Vec::new();
+                    eq.rhs.vars(&mut deps);
+                    deps.iter().all(|d| {
+                        !names.contains(d) || sorted.iter().any(|e| e.lhs.var().as_ref() == Some(d))
+                    })
+                })
+                .expect("cyclic dependency between algebraic equations");
+            sorted.push(algs.remove(k));
+        }
+
+        sorted
+    }
+
+    /*
+        a model whose observables are the entries of the Jacobian of the odes
+        with respect to the states, i.e., ∂δxi/∂xj in row-major order
+        the entries are derived symbolically, where the derivatives of the algs
+        and obs become algs of their own (∂a/∂xj); comparisons and the conditions
+        of ifelse count as piecewise constant, while ops without a rule in
+        Expr::diff (e.g., rem of a state) are a LowerError::NotDifferentiable
+    */
+    pub fn jacobian(&self) -> CellModel {
+        self.try_jacobian().unwrap_or_else(|e| panic!("{}", e))
     }
+
+    pub fn try_jacobian(&self) -> Result<CellModel, LowerError> {
+        if let Cow::Owned(ml) = self.reduce_order() {
+            return ml.try_jacobian();
+        }
+
+        // the algs and obs are kept as algs in the order they are calculated;
+        // substituting them into the odes instead blows up the derivatives
+        let defs: Vec<Equation> = self
+            .sorted_algs()
+            .into_iter()
+            .chain(self.obs.iter())
+            .cloned()
+            .collect();
+
+        let mut algs = defs.clone();
+        let mut ds: Vec<HashMap<String, Expr>> = Vec::new();
+
+        // the nonzero derivatives of the defs with respect to each state xj
+        // become algs named ∂a/∂xj, which are then used by the chain rule
+        for xj in self.states.iter() {
+            let mut d = HashMap::from([(xj.name.clone(), Expr::num(1.0))]);
+
+            for eq in defs.iter() {
+                let a = eq.lhs.var().unwrap();
+                let lhs = format!("∂{}/∂{}", a, xj.name);
+                let rhs = eq.rhs.diff_with(&d).map_err(|e| e.within(&lhs))?;
+                if !rhs.is_const(0.0) {
+                    d.insert(a, Expr::Var { name: lhs.clone() });
+                    algs.push(Equation {
+                        lhs: Expr::Var { name: lhs },
+                        rhs,
+                    });
+                }
+            }
+
+            ds.push(d);
+        }
+
+        let mut obs: Vec<Equation> = Vec::new();
+
+        for xi in self.states.iter() {
+            let ode = self
+                .odes
+                .iter()
+                .find(|eq| eq.lhs.diff_var().as_ref() == Some(&xi.name));
+
+            for (xj, d) in self.states.iter().zip(ds.iter()) {
+                let lhs = format!("∂δ{}/∂{}", xi.name, xj.name);
+                let rhs = match ode {
+                    Some(eq) => eq.rhs.diff_with(d).map_err(|e| e.within(&lhs))?,
+                    None => Expr::num(0.0),
+                };
+                obs.push(Equation {
+                    lhs: Expr::Var { name: lhs },
+                    rhs,
+                });
+            }
+        }
+
+        Ok(CellModel {
+            iv: self.iv.clone(),
+            params: self.params.clone(),
+            states: self.states.clone(),
+            algs,
+            odes: Vec::new(),
+            obs,
+            conserved: Vec::new(),
+            inputs: self.inputs.clone(),
+            tables: self.tables.clone(),
+        })
+    }
+
+    /*
+        merges multiple models into one by renaming the variables of the i-th model
+        from name to mi.name (the independent variable is shared) and replacing
+        each coupled parameter with the corresponding source observable
+    */
+    pub fn merge(models: &[CellModel], couplings: &[Coupling]) -> CellModel {
+        assert!(!models.is_empty(), "no model to merge");
+        let iv = models[0].iv.name.clone();
+
+        let mut merged = CellModel {
+            iv: models[0].iv.clone(),
+            params: Vec::new(),
+            states: Vec::new(),
+            algs: Vec::new(),
+            odes: Vec::new(),
+            obs: Vec::new(),
+            conserved: Vec::new(),
+            inputs: Vec::new(),
+            tables: Vec::new(),
+        };
+
+        for c in couplings.iter() {
+            assert!(
+                models[c.source]
+                    .obs
+                    .iter()
+                    .any(|eq| eq.lhs.var().as_ref() == Some(&c.obs)),
+                "coupling source {} is not an observable",
+                c.obs
+            );
+            assert!(
+                models[c.target].params.iter().any(|v| v.name == c.param),
+                "coupling target {} is not a parameter",
+                c.param
+            );
+        }
+
+        let mut obs: Vec<Equation> = Vec::new();
+
+        for (i, ml) in models.iter().enumerate() {
+            let rename = |s: &str| -> String {
+                if s == ml.iv.name {
+                    return iv.clone();
+                }
+
+                if let Some(c) = couplings.iter().find(|c| c.target == i && c.param == s) {
+                    format!("m{}.{}", c.source, c.obs)
+                } else {
+                    format!("m{}.{}", i, s)
+                }
+            };
+
+            let var = |v: &Variable| Variable {
+                name: rename(&v.name),
+                ..v.clone()
+            };
+
+            merged.states.extend(ml.states.iter().map(var));
+            merged.params.extend(
+                ml.params
+                    .iter()
+                    .filter(|v| !couplings.iter().any(|c| c.target == i && c.param == v.name))
+                    .map(var),
+            );
+            merged.inputs.extend(ml.inputs.iter().map(var));
+            merged.tables.extend(ml.tables.iter().map(|t| Table {
+                name: rename(&t.name),
+                ..t.clone()
+            }));
+            merged
+                .algs
+                .extend(ml.algs.iter().map(|eq| eq.rename(&rename)));
+            merged
+                .odes
+                .extend(ml.odes.iter().map(|eq| eq.rename(&rename)));
+            obs.extend(ml.obs.iter().map(|eq| eq.rename(&rename)));
+            merged
+                .conserved
+                .extend(ml.conserved.iter().map(|(a, b)| (rename(a), rename(b))));
+        }
+
+        // observables are ordered such that each one is calculated
+        // before being used by another (possibly coupled) observable
+        let names: Vec<String> = obs.iter().filter_map(|eq| eq.lhs.var()).collect();
+
+        while !obs.is_empty() {
+            let k = obs
+                .iter()
+                .position(|eq| {
+                    let mut deps: Vec<String> = Vec::new();
+                    eq.rhs.vars(&mut deps);
+                    deps.iter().all(|d| {
+                        !names.contains(d)
+                            || merged.obs.iter().any(|e| e.lhs.var().as_ref() == Some(d))
+                    })
+                })
+                .expect("cyclic dependency between observables");
+            merged.obs.push(obs.remove(k));
+        }
+
+        merged
+    }
+
+    // finds the states whose derivative is the negative of another state's
+    // derivative, either declared in conserved or detected as δb = -(rhs of δa)
+    // returns a map from each aliased state to its partner
+    // a state is never both aliased and a partner, so the partners do not
+    // chain and are all calculated before the aliased diffs are lowered
+    pub fn aliases(&self) -> HashMap<String, String> {
+        let mut aliases: HashMap<String, String> = HashMap::new();
+        let free = |aliases: &HashMap<String, String>, s: &str| {
+            !aliases.contains_key(s) && !aliases.values().any(|v| v == s)
+        };
+
+        for (a, b) in self.conserved.iter() {
+            if a != b && free(&aliases, a) && free(&aliases, b) {
+                aliases.insert(b.clone(), a.clone());
+            }
+        }
+
+        for eq in self.odes.iter() {
+            let (Some(b), Some(x)) = (eq.lhs.diff_var(), eq.rhs.negated()) else {
+                continue;
+            };
+
+            if !free(&aliases, &b) {
+                continue;
+            }
+
+            let partner = self
+                .odes
+                .iter()
+                .filter(|e| e.rhs == *x)
+                .filter_map(|e| e.lhs.diff_var())
+                .find(|a| *a != b && free(&aliases, a));
+
+            if let Some(a) = partner {
+                aliases.insert(b, a);
+            }
+        }
+
+        aliases
+    }
+}
+
+// a problem found by CellModel::validate
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum ValidationError {
+    UndeclaredVar { name: String, lhs: String },
+    UnusedParam { name: String },
+    ImplicitAlg { index: usize },
+    MissingOde { state: String },
+    DuplicateOde { state: String, count: usize },
+    UndeclaredState { name: String }, // an ode for a variable that is not a state
+}
+
+impl ValidationError {
+    // warnings do not prevent the model from being compiled
+    pub fn is_warning(&self) -> bool {
+        matches!(self, ValidationError::UnusedParam { .. })
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValidationError::UndeclaredVar { name, lhs } => {
+                write!(f, "undeclared variable {} in the equation for {}", name, lhs)
+            }
+            ValidationError::UnusedParam { name } => write!(f, "unused parameter {}", name),
+            ValidationError::ImplicitAlg { index } => write!(
+                f,
+                "algebraic equation {} is implicit, its lhs should be a variable",
+                index
+            ),
+            ValidationError::MissingOde { state } => {
+                write!(f, "state {} has no differential equation", state)
+            }
+            ValidationError::DuplicateOde { state, count } => {
+                write!(f, "state {} has {} differential equations", state, count)
+            }
+            ValidationError::UndeclaredState { name } => {
+                write!(f, "differential equation for undeclared state {}", name)
+            }
+        }
+    }
+}
+
+// why a model could not be lowered, e.g., an op emitted by a front-end
+// that has no counterpart in Code
+#[derive(Debug, Clone, PartialEq)]
+pub enum LowerError {
+    UnknownOp { op: String, eq: String }, // eq is the lhs, e.g., δx
+    NotDifferentiable { op: String, eq: String }, // see CellModel::jacobian
+    NoModel, // the program was loaded from IR, so it cannot be differentiated
+}
+
+impl LowerError {
+    fn unknown_op(op: &str) -> LowerError {
+        LowerError::UnknownOp {
+            op: op.to_string(),
+            eq: String::new(),
+        }
+    }
+
+    fn not_differentiable(op: &str) -> LowerError {
+        LowerError::NotDifferentiable {
+            op: op.to_string(),
+            eq: String::new(),
+        }
+    }
+
+    // sets the equation in which the error occurred
+    fn within(self, lhs: &str) -> LowerError {
+        match self {
+            LowerError::UnknownOp { op, .. } => LowerError::UnknownOp {
+                op,
+                eq: lhs.to_string(),
+            },
+            LowerError::NotDifferentiable { op, .. } => LowerError::NotDifferentiable {
+                op,
+                eq: lhs.to_string(),
+            },
+            LowerError::NoModel => LowerError::NoModel,
+        }
+    }
+}
+
+impl std::fmt::Display for LowerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LowerError::UnknownOp { op, eq } => {
+                write!(f, "unknown op {} in the equation for {}", op, eq)
+            }
+            LowerError::NotDifferentiable { op, eq } => {
+                write!(f, "cannot differentiate {} in the equation for {}", op, eq)
+            }
+            LowerError::NoModel => write!(f, "the program has no model to differentiate"),
+        }
+    }
+}
+
+impl Error for LowerError {}
+
+// why a model could not be loaded; the JSON errors carry the line and column
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    Syntax(serde_json::Error),
+    Semantic(serde_json::Error), // e.g., a missing field or a value of the wrong type
+}
+
+impl LoadError {
+    // the (line, column) of a JSON error, both starting from 1
+    pub fn position(&self) -> Option<(usize, usize)> {
+        match self {
+            LoadError::Io(_) => None,
+            LoadError::Syntax(e) | LoadError::Semantic(e) => Some((e.line(), e.column())),
+        }
+    }
+}
+
+impl From<serde_json::Error> for LoadError {
+    fn from(e: serde_json::Error) -> LoadError {
+        match e.classify() {
+            serde_json::error::Category::Io => LoadError::Io(e.into()),
+            serde_json::error::Category::Data => LoadError::Semantic(e),
+            _ => LoadError::Syntax(e),
+        }
+    }
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "cannot read the model: {}", e),
+            LoadError::Syntax(e) => write!(f, "invalid JSON: {}", e),
+            LoadError::Semantic(e) => write!(f, "invalid model: {}", e),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::Syntax(e) | LoadError::Semantic(e) => Some(e),
+        }
+    }
+}
+
+// the result of parsing and validating a model without compiling it
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn new(text: &str) -> Report {
+        let mut report = Report::default();
+
+        match CellModel::load(text) {
+            Ok(ml) => {
+                for e in ml.validate() {
+                    if e.is_warning() {
+                        report.warnings.push(e.to_string());
+                    } else {
+                        report.errors.push(e.to_string());
+                    }
+                }
+            }
+            Err(e) => report.errors.push(format!("parse error: {}", e)),
+        }
+
+        report
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl Lower for CellModel {
+    fn lower(&self, prog: &mut Program) -> Result<Word, LowerError> {
+        for eq in self.sorted_algs() {
+            eq.lower(prog)?;
+        }
+
+        for eq in &self.obs {
+            eq.lower(prog)?;
+        }
+
+        let aliases = self.aliases();
+
+        for eq in &self.odes {
+            match eq.lhs.diff_var() {
+                Some(var) if aliases.contains_key(&var) => {}
+                _ => {
+                    eq.lower(prog)?;
+                }
+            }
+        }
+
+        // aliased diffs are lowered last, after their partners are calculated
+        for eq in &self.odes {
+            if let Some(var) = eq.lhs.diff_var() {
+                if let Some(partner) = aliases.get(&var) {
+                    lower_alias(prog, &var, partner);
+                }
+            }
+        }
+
+        Ok(Frame::ZERO)
+    }
+}
+
+// lowers δvar = -δpartner
+fn lower_alias(prog: &mut Program, var: &str, partner: &str) {
+    let dst = prog.reg_diff(var);
+    prog.push_eq(dst);
+
+    let src = prog.reg_diff(partner);
+    prog.push(Instruction::Var {
+        name: format!("δ{}", partner),
+        reg: src,
+    });
+
+    let t = prog.alloc_temp();
+    prog.push_unary("neg", src, t);
+    prog.push_unary("mov", t, dst);
+    prog.free(t);
+}
+
+#[test]
+fn test_conserved() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    // δa = -k*a*b and δb = k*a*b
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 0.5}],
+        "states": [{"name": "a", "val": 1.0}, {"name": "b", "val": 2.0}],
+        "algs": [],
+        "odes": [
+            {
+                "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "a"}]},
+                "rhs": {"type": "Tree", "op": "times", "args": [
+                    {"type": "Const", "val": -1.0},
+                    {"type": "Tree", "op": "times", "args": [
+                        {"type": "Var", "name": "k"}, {"type": "Var", "name": "a"}, {"type": "Var", "name": "b"}
+                    ]}
+                ]}
+            },
+            {
+                "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "b"}]},
+                "rhs": {"type": "Tree", "op": "times", "args": [
+                    {"type": "Var", "name": "k"}, {"type": "Var", "name": "a"}, {"type": "Var", "name": "b"}
+                ]}
+            }
+        ],
+        "obs": [{"lhs": {"type": "Var", "name": "s"}, "rhs": {"type": "Var", "name": "a"}}]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    assert_eq!(ml.aliases().get("a"), Some(&"b".to_string()));
+
+    let prog = Program::new(&ml);
+    let n = prog
+        .code
+        .iter()
+        .filter(|c| matches!(c, Instruction::Binary { op, .. } if op == "times"))
+        .count();
+    assert_eq!(n, 2); // k*a*b once
+
+    let mut r = Runnable::new(prog, CompilerType::ByteCode);
+    let mut du = vec![0.0; 2];
+    let p = r.params();
+    r.call(&mut du, &[1.0, 2.0], &p, 0.0);
+    assert_eq!(du[1], 1.0);
+    assert_eq!(du[0], -du[1]);
+
+    // the same pair declared by annotation
+    let text = text.replace(r#""algs": [],"#, r#""algs": [], "conserved": [["b", "a"]],"#);
+    let ml = CellModel::load(&text).unwrap();
+    assert_eq!(ml.aliases().get("a"), Some(&"b".to_string()));
+}
+
+#[test]
+fn test_conserved_chain() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    // δb = -(-k*c), δa = -(k*c), and δc = k*c, in this order, would chain b -> a -> c
+    let x = r#"{"type": "Tree", "op": "times", "args": [{"type": "Var", "name": "k"}, {"type": "Var", "name": "c"}]}"#;
+    let neg = |x: &str| format!(r#"{{"type": "Tree", "op": "neg", "args": [{}]}}"#, x);
+    let ode = |v: &str, rhs: &str| {
+        format!(
+            r#"{{"lhs": {{"type": "Tree", "op": "Differential", "args": [{{"type": "Var", "name": "{}"}}]}}, "rhs": {}}}"#,
+            v, rhs
+        )
+    };
+
+    let text = format!(
+        r#"{{
+        "iv": {{"name": "t", "val": 0.0}},
+        "params": [{{"name": "k", "val": 0.5}}],
+        "states": [{{"name": "b", "val": 1.0}}, {{"name": "a", "val": 2.0}}, {{"name": "c", "val": 3.0}}],
+        "algs": [],
+        "odes": [{}, {}, {}],
+        "obs": []
+    }}"#,
+        ode("b", &neg(&neg(x))),
+        ode("a", &neg(x)),
+        ode("c", x)
+    );
+
+    let ml = CellModel::load(&text).unwrap();
+    let aliases = ml.aliases();
+    assert_eq!(aliases.len(), 1);
+    assert_eq!(aliases.get("b"), Some(&"a".to_string()));
+
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let mut du = vec![0.0; 3];
+    let p = r.params();
+    r.call(&mut du, &[1.0, 2.0, 3.0], &p, 0.0);
+    assert_eq!(du, vec![1.5, -1.5, 1.5]);
+
+    // the same chain declared by annotation, δb = -δa and δa = -δc
+    let text = text.replace(
+        r#""algs": [],"#,
+        r#""algs": [], "conserved": [["a", "b"], ["c", "a"]],"#,
+    );
+    let ml = CellModel::load(&text).unwrap();
+    assert_eq!(ml.aliases().len(), 1);
+
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    r.call(&mut du, &[1.0, 2.0, 3.0], &p, 0.0);
+    assert_eq!(du, vec![1.5, -1.5, 1.5]);
+}
+
+#[test]
+fn test_reorder_states() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    fn trajectory(prog: Program) -> Vec<f64> {
+        let mut r = Runnable::new(prog, CompilerType::ByteCode);
+        let mut u = r.initial_states();
+        let mut du = vec![0.0; u.len()];
+        let p = r.params();
+
+        for i in 0..100 {
+            r.call(&mut du, &u, &p, i as f64 * 0.01);
+            for j in 0..u.len() {
+                u[j] += 0.01 * du[j];
+            }
+        }
+
+        u
+    }
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let u = trajectory(Program::new(&ml));
+    let n = u.len();
+
+    let prog = Program::new(&ml);
+    let perm = prog.locality_permutation();
+    let mut sorted = perm.clone();
+    sorted.sort();
+    assert_eq!(sorted, (0..n).collect::<Vec<usize>>());
+
+    for perm in [perm, (0..n).rev().collect()] {
+        let mut prog = Program::new(&ml);
+        prog.reorder_states(&perm);
+        let v = trajectory(prog);
+
+        for i in 0..n {
+            assert_eq!(v[i], u[perm[i]]);
+        }
+    }
+}
+
+#[test]
+fn test_safe_math() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "a", "val": 1.0000001}],
+        "states": [{"name": "x", "val": 0.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Const", "val": 0.0}
+        }],
+        "obs": [
+            {"lhs": {"type": "Var", "name": "y"}, "rhs": {"type": "Tree", "op": "arcsin", "args": [{"type": "Var", "name": "a"}]}},
+            {"lhs": {"type": "Var", "name": "z"}, "rhs": {"type": "Tree", "op": "arccos", "args": [
+                {"type": "Tree", "op": "neg", "args": [{"type": "Var", "name": "a"}]}
+            ]}}
+        ]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let mut obs = vec![0.0; 2];
+    let u = [0.0, 1.0000001];
+
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    r.call_py(&mut obs, &u, 0.0);
+    assert!(obs[0].is_nan() && obs[1].is_nan());
+
+    let opts = Options {
+        safe_math: true,
+        ..Options::default()
+    };
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::with_options(&ml, opts.clone()), ty);
+        r.call_py(&mut obs, &u, 0.0);
+        assert_eq!(obs[0], std::f64::consts::FRAC_PI_2);
+        assert_eq!(obs[1], std::f64::consts::PI);
+    }
+}
+
+#[test]
+fn test_merge() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    // δx = -k * x, y = x
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 0.5}],
+        "states": [{"name": "x", "val": 1.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "times", "args": [
+                {"type": "Const", "val": -1.0},
+                {"type": "Tree", "op": "times", "args": [{"type": "Var", "name": "k"}, {"type": "Var", "name": "x"}]}
+            ]}
+        }],
+        "obs": [{"lhs": {"type": "Var", "name": "y"}, "rhs": {"type": "Var", "name": "x"}}]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let models = [ml.clone(), ml];
+    let u = [2.0, 3.0];
+    let mut du = vec![0.0; 2];
+
+    let mut r = Runnable::new(Program::merge(&models, &[]), CompilerType::ByteCode);
+    assert_eq!(r.count_states, 2);
+    assert_eq!(r.count_params, 2);
+    r.call(&mut du, &u, &[0.5, 0.5], 0.0);
+    assert_eq!(du, vec![-1.0, -1.5]);
+
+    // y of the first model drives k of the second one
+    let coupling = Coupling {
+        source: 0,
+        obs: "y".to_string(),
+        target: 1,
+        param: "k".to_string(),
+    };
+
+    let prog = Program::merge(&models, &[coupling]);
+    assert!(prog.frame.find("m0.y").is_some());
+    assert!(prog.frame.find("m1.k").is_none());
+
+    let mut r = Runnable::new(prog, CompilerType::ByteCode);
+    assert_eq!(r.count_params, 1);
+    r.call(&mut du, &u, &[0.5], 0.0);
+    assert_eq!(du, vec![-1.0, -6.0]);
+}
+
+#[test]
+fn test_hoist_branches() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    // δv = v < -1 ? 2 * exp(v / k) : (v < 1 ? exp(v / k) + 1 : exp(v / k))
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 2.0}],
+        "states": [{"name": "v", "val": 0.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "v"}]},
+            "rhs": {"type": "Tree", "op": "ifelse", "args": [
+                {"type": "Tree", "op": "lt", "args": [{"type": "Var", "name": "v"}, {"type": "Const", "val": -1.0}]},
+                {"type": "Tree", "op": "times", "args": [{"type": "Const", "val": 2.0}, {"type": "Tree", "op": "exp", "args": [
+                    {"type": "Tree", "op": "divide", "args": [{"type": "Var", "name": "v"}, {"type": "Var", "name": "k"}]}
+                ]}]},
+                {"type": "Tree", "op": "ifelse", "args": [
+                    {"type": "Tree", "op": "lt", "args": [{"type": "Var", "name": "v"}, {"type": "Const", "val": 1.0}]},
+                    {"type": "Tree", "op": "plus", "args": [{"type": "Tree", "op": "exp", "args": [
+                        {"type": "Tree", "op": "divide", "args": [{"type": "Var", "name": "v"}, {"type": "Var", "name": "k"}]}
+                    ]}, {"type": "Const", "val": 1.0}]},
+                    {"type": "Tree", "op": "exp", "args": [
+                        {"type": "Tree", "op": "divide", "args": [{"type": "Var", "name": "v"}, {"type": "Var", "name": "k"}]}
+                    ]}
+                ]}
+            ]}
+        }],
+        "obs": [{"lhs": {"type": "Var", "name": "y"}, "rhs": {"type": "Var", "name": "v"}}]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let prog = Program::new(&ml);
+
+    let is_exp = |c: &Instruction| matches!(c, Instruction::Unary { op, .. } if op == "exp");
+    let is_select = |c: &Instruction| matches!(c, Instruction::IfElse { .. });
+    assert_eq!(prog.code.iter().filter(|c| is_exp(c)).count(), 1);
+    assert!(prog.code.iter().position(is_exp) < prog.code.iter().position(is_select));
+
+    let f = |v: f64| {
+        let e = (v / 2.0f64).exp();
+        if v < -1.0 {
+            2.0 * e
+        } else if v < 1.0 {
+            e + 1.0
+        } else {
+            e
+        }
+    };
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let mut du = vec![0.0];
+
+        for v in [-3.0, 0.5, 4.0] {
+            r.call(&mut du, &[v], &[2.0], 0.0);
+            assert_eq!(du[0], f(v));
+        }
+    }
+}
+
+#[test]
+fn test_algs() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    // b ~ 2a is listed before a ~ k * x, δx = -b and y = a + b
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 3.0}],
+        "states": [{"name": "x", "val": 1.0}],
+        "algs": [
+            {"lhs": {"type": "Var", "name": "b"}, "rhs": {"type": "Tree", "op": "times", "args": [
+                {"type": "Const", "val": 2.0}, {"type": "Var", "name": "a"}
+            ]}},
+            {"lhs": {"type": "Var", "name": "a"}, "rhs": {"type": "Tree", "op": "times", "args": [
+                {"type": "Var", "name": "k"}, {"type": "Var", "name": "x"}
+            ]}}
+        ],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "neg", "args": [{"type": "Var", "name": "b"}]}
+        }],
+        "obs": [{"lhs": {"type": "Var", "name": "y"}, "rhs": {"type": "Tree", "op": "plus", "args": [
+            {"type": "Var", "name": "a"}, {"type": "Var", "name": "b"}
+        ]}}]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    assert!(ml.validate().is_empty());
+
+    let order: Vec<String> = ml
+        .sorted_algs()
+        .iter()
+        .filter_map(|eq| eq.lhs.var())
+        .collect();
+    assert_eq!(order, vec!["a", "b"]);
+
+    let opts = Options {
+        split_kernels: true,
+        ..Options::default()
+    };
+
+    for (ty, opts) in [
+        (CompilerType::ByteCode, Options::default()),
+        (CompilerType::Native, Options::default()),
+        (CompilerType::ByteCode, opts),
+    ] {
+        let mut r = Runnable::new(Program::with_options(&ml, opts), ty);
+        let mut du = vec![0.0];
+        r.call(&mut du, &[0.5], &[3.0], 0.0);
+        assert_eq!(du[0], -3.0);
+        assert_eq!(r.observe(&[0.5], &[3.0], 0.0), vec![4.5]);
+    }
+
+    // implicit equations, 0 ~ a - k * x, are reported
+    let text = text.replace(
+        r#"{"lhs": {"type": "Var", "name": "a"}"#,
+        r#"{"lhs": {"type": "Const", "val": 0.0}"#,
+    );
+    let ml = CellModel::load(&text).unwrap();
+    assert!(ml
+        .validate()
+        .contains(&ValidationError::ImplicitAlg { index: 1 }));
+}
+
+#[test]
+fn test_fold_constants() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    // δx = 2 * 3 * x + exp(0) - 0.5 / 4
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [],
+        "states": [{"name": "x", "val": 1.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "minus", "args": [
+                {"type": "Tree", "op": "plus", "args": [
+                    {"type": "Tree", "op": "times", "args": [
+                        {"type": "Tree", "op": "times", "args": [{"type": "Const", "val": 2.0}, {"type": "Const", "val": 3.0}]},
+                        {"type": "Var", "name": "x"}
+                    ]},
+                    {"type": "Tree", "op": "exp", "args": [{"type": "Const", "val": 0.0}]}
+                ]},
+                {"type": "Tree", "op": "divide", "args": [{"type": "Const", "val": 0.5}, {"type": "Const", "val": 4.0}]}
+            ]}
+        }],
+        "obs": []
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let prog = Program::new(&ml);
+
+    // 6 ops (times, times, exp, plus, divide, minus) fold into 3
+    let ops = prog
+        .code
+        .iter()
+        .filter(|c| match c {
+            Instruction::Unary { op, .. } => op != "mov",
+            Instruction::Binary { .. } => true,
+            _ => false,
+        })
+        .count();
+    assert_eq!(ops, 3);
+
+    // 6 is a new constant, exp(0) reuses ONE
+    let nums: Vec<(f64, Word)> = prog
+        .code
+        .iter()
+        .filter_map(|c| match c {
+            Instruction::Num { val, dst } => Some((*val, *dst)),
+            _ => None,
+        })
+        .collect();
+    assert!(nums.contains(&(1.0, Frame::ONE)));
+    assert!(nums.iter().any(|(v, _)| *v == 6.0));
+    assert!(nums.iter().any(|(v, _)| *v == 0.125));
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let mut du = vec![0.0];
+        r.call(&mut du, &[2.0], &[], 0.0);
+        assert_eq!(du[0], 6.0 * 2.0 + 1.0 - 0.125);
+    }
+}
+
+#[test]
+fn test_lower_power() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    // δx = x^3, δy = (x + y)^-2, δz = z^0.5 + x^2.5
+    let pow = |x: &str, n: f64| {
+        format!(
+            r#"{{"type": "Tree", "op": "power", "args": [{}, {{"type": "Const", "val": {:?}}}]}}"#,
+            x, n
+        )
+    };
+    let var = |s: &str| format!(r#"{{"type": "Var", "name": "{}"}}"#, s);
+    let ode = |s: &str, rhs: String| {
+        format!(
+            r#"{{"lhs": {{"type": "Tree", "op": "Differential", "args": [{}]}}, "rhs": {}}}"#,
+            var(s),
+            rhs
+        )
+    };
+    let sum = |a: String, b: String| {
+        format!(
+            r#"{{"type": "Tree", "op": "plus", "args": [{}, {}]}}"#,
+            a, b
+        )
+    };
+
+    let text = format!(
+        r#"{{
+            "iv": {{"name": "t", "val": 0.0}},
+            "params": [],
+            "states": [{{"name": "x", "val": 1.0}}, {{"name": "y", "val": 1.0}}, {{"name": "z", "val": 1.0}}],
+            "algs": [],
+            "odes": [{}, {}, {}],
+            "obs": []
+        }}"#,
+        ode("x", pow(&var("x"), 3.0)),
+        ode("y", pow(&sum(var("x"), var("y")), -2.0)),
+        ode("z", sum(pow(&var("z"), 0.5), pow(&var("x"), 2.5)))
+    );
+
+    let ml = CellModel::load(&text).unwrap();
+    let prog = Program::new(&ml);
+
+    // only x^2.5 calls power
+    let powers = prog
+        .code
+        .iter()
+        .filter(|c| matches!(c, Instruction::Binary { op, .. } if op == "power"))
+        .count();
+    assert_eq!(powers, 1);
+    assert!(prog.ft.contains(&"root".to_string()));
+
+    let u = [1.7, -0.4, 2.3];
+    let expected = [
+        f64::powf(u[0], 3.0),
+        f64::powf(u[0] + u[1], -2.0),
+        f64::powf(u[2], 0.5) + f64::powf(u[0], 2.5),
+    ];
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let mut du = vec![0.0; 3];
+        r.call(&mut du, &u, &[], 0.0);
+
+        for (a, b) in du.iter().zip(expected.iter()) {
+            assert!((a - b).abs() <= 1e-14 * b.abs());
+        }
+    }
+}
+
+#[test]
+fn test_divide_by_const() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    // δx = x / 3 + 2 / 3 - x / 0
+    let div = |a: &str, b: &str| {
+        format!(
+            r#"{{"type": "Tree", "op": "divide", "args": [{}, {}]}}"#,
+            a, b
+        )
+    };
+    let x = r#"{"type": "Var", "name": "x"}"#;
+    let c = |v: f64| format!(r#"{{"type": "Const", "val": {:?}}}"#, v);
+
+    let text = format!(
+        r#"{{
+            "iv": {{"name": "t", "val": 0.0}},
+            "params": [],
+            "states": [{{"name": "x", "val": 1.0}}],
+            "algs": [],
+            "odes": [{{
+                "lhs": {{"type": "Tree", "op": "Differential", "args": [{}]}},
+                "rhs": {{"type": "Tree", "op": "minus", "args": [
+                    {{"type": "Tree", "op": "plus", "args": [{}, {}]}},
+                    {}
+                ]}}
+            }}],
+            "obs": []
+        }}"#,
+        x,
+        div(x, &c(3.0)),
+        div(&c(2.0), &c(3.0)),
+        div(x, &c(0.0))
+    );
+
+    let ml = CellModel::load(&text).unwrap();
+    let prog = Program::new(&ml);
+
+    // only x / 0 is left as a division, and 2 / 3 is folded exactly
+    let ops: Vec<&str> = prog
+        .code
+        .iter()
+        .filter_map(|c| match c {
+            Instruction::Binary { op, .. } => Some(op.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(ops, ["times", "plus", "divide", "minus"]);
+    assert!(prog
+        .code
+        .iter()
+        .any(|c| matches!(c, Instruction::Num { val, .. } if *val == 2.0 / 3.0)));
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let mut du = vec![0.0];
+        r.call(&mut du, &[-2.5], &[], 0.0);
+        assert_eq!(du[0], -2.5 * (1.0 / 3.0) + 2.0 / 3.0 - f64::NEG_INFINITY);
+    }
+}
+
+#[test]
+fn test_coalesce_movs() {
+    let text = std::fs::read_to_string("julia/lorenz.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let mut prog = Program::new(&ml);
+
+    // every equation of lorenz ends in an op coalesced with its mov
+    let eqs = prog
+        .code
+        .iter()
+        .filter(|c| matches!(c, Instruction::Eq { .. }))
+        .count();
+    assert_eq!(eqs, 3);
+    assert!(!prog.code.iter().any(|c| c.is_mov()));
+
+    let x = prog.reg("main₊x");
+    let d = prog.reg_diff("main₊x");
+    let name = prog.alloc_local();
+    let l = prog.reg(&name);
+    let t = prog.alloc_temp();
+
+    // δx = -x; l = l; δx = mov x
+    prog.code.clear();
+    prog.push_eq(d);
+    prog.push(Instruction::Var {
+        name: "main₊x".to_string(),
+        reg: x,
+    });
+    prog.push_unary("neg", x, t);
+    prog.push_unary("mov", t, d);
+    prog.push_eq(l);
+    prog.push(Instruction::Var { name, reg: l });
+    prog.push_unary("mov", l, l);
+    prog.push_eq(d);
+    prog.push(Instruction::Var {
+        name: "main₊x".to_string(),
+        reg: x,
+    });
+    prog.push_unary("mov", x, d);
+    assert_eq!(prog.code.len(), 10);
+
+    prog.coalesce_movs();
+
+    let text: Vec<String> = prog.code.iter().map(|c| c.to_string()).collect();
+    assert_eq!(prog.code.len(), 6);
+    assert_eq!(text[2], format!("r{:<6}← neg(r{})", d.0, x.0));
+    assert_eq!(text[5], format!("r{:<6}← mov(r{})", d.0, x.0));
+    assert_eq!(prog.code[2].assigned(), Some(d));
+}
+
+#[test]
+fn test_eliminate_dead_code() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    // δx = -z with z = k * x, and a diagnostic y = exp(x) * sin(x) nothing depends on
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 2.0}],
+        "states": [{"name": "x", "val": 0.5}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "neg", "args": [{"type": "Var", "name": "z"}]}
+        }],
+        "obs": [
+            {"lhs": {"type": "Var", "name": "z"}, "rhs": {"type": "Tree", "op": "times", "args": [
+                {"type": "Var", "name": "k"}, {"type": "Var", "name": "x"}
+            ]}},
+            {"lhs": {"type": "Var", "name": "y"}, "rhs": {"type": "Tree", "op": "times", "args": [
+                {"type": "Tree", "op": "exp", "args": [{"type": "Var", "name": "x"}]},
+                {"type": "Tree", "op": "sin", "args": [{"type": "Var", "name": "x"}]}
+            ]}}
+        ]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let full = Program::new(&ml);
+
+    let mut prog = full.clone();
+    prog.eliminate_dead_code(&[]);
+    let is_exp = |c: &Instruction| matches!(c, Instruction::Unary { op, .. } if op == "exp");
+    assert!(!prog.code.iter().any(is_exp));
+    // Eq, Var x, exp, Var x, sin, times (coalesced with the mov)
+    assert_eq!(prog.code.len(), full.code.len() - 6);
+
+    let mut kept = full.clone();
+    kept.eliminate_dead_code(&["y".to_string()]);
+    assert_eq!(kept.code.len(), full.code.len());
+
+    let opts = Options {
+        live_obs: Some(vec!["z".to_string()]),
+        ..Options::default()
+    };
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::with_options(&ml, opts.clone()), ty);
+        let mut du = vec![0.0];
+        r.call(&mut du, &[0.5], &[2.0], 0.0);
+        assert_eq!(du[0], -1.0);
+        // y is no longer calculated
+        assert_eq!(r.observe(&[0.5], &[2.0], 0.0), vec![1.0, 0.0]);
+    }
+}
+
+#[test]
+fn test_diff() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::{finite_jacobian, Callable};
+
+    let x = r#"{"type": "Tree", "op": "times", "args": [{"type": "Const", "val": 0.7}, {"type": "Var", "name": "x"}]}"#;
+    let k = r#"{"type": "Var", "name": "k"}"#;
+
+    let unary = [
+        "exp", "expm1", "ln", "log", "log2", "log1p", "root", "cbrt", "erf", "erfc", "gamma",
+        "lgamma", "sin", "cos", "tan", "csc", "sec", "cot", "arcsin", "arccos", "arctan", "sinh",
+        "cosh", "tanh", "sech2", "abs", "neg", "signum",
+    ];
+    let binary = [
+        "power", "divide", "atan2", "logb", "hypot", "min", "max", "minus",
+    ];
+
+    let rhs = unary
+        .iter()
+        .map(|op| format!(r#"{{"type": "Tree", "op": "{}", "args": [{}]}}"#, op, x))
+        .chain(binary.iter().flat_map(|op| {
+            [
+                format!(
+                    r#"{{"type": "Tree", "op": "{}", "args": [{}, {}]}}"#,
+                    op, x, k
+                ),
+                format!(
+                    r#"{{"type": "Tree", "op": "{}", "args": [{}, {}]}}"#,
+                    op, k, x
+                ),
+            ]
+        }))
+        // digamma past the reflection at 1/2 and at a negative argument
+        .chain(["gamma", "lgamma"].iter().flat_map(|op| {
+            [3.0, -1.5].map(|a| {
+                format!(
+                    r#"{{"type": "Tree", "op": "{}", "args": [{{"type": "Tree", "op": "times", "args": [{{"type": "Const", "val": {:?}}}, {{"type": "Var", "name": "x"}}]}}]}}"#,
+                    op, a
+                )
+            })
+        }));
+
+    for rhs in rhs {
+        let text = format!(
+            r#"{{
+                "iv": {{"name": "t", "val": 0.0}},
+                "params": [{{"name": "k", "val": 1.3}}],
+                "states": [{{"name": "x", "val": 0.4}}],
+                "algs": [],
+                "odes": [{{
+                    "lhs": {{"type": "Tree", "op": "Differential", "args": [{{"type": "Var", "name": "x"}}]}},
+                    "rhs": {}
+                }}],
+                "obs": []
+            }}"#,
+            rhs
+        );
+
+        let ml = CellModel::load(&text).unwrap();
+        let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode).with_jacobian();
+        let mut jac = vec![0.0];
+        let mut fd = vec![0.0];
+        r.jacobian(&mut jac, &[0.4], &[1.3], 0.0);
+        finite_jacobian(&mut r, &mut fd, &[0.4], &[1.3], 0.0);
+        assert!(
+            (jac[0] - fd[0]).abs() < 1e-6,
+            "{}: {} vs {}",
+            rhs,
+            jac[0],
+            fd[0]
+        );
+    }
+}
+
+#[test]
+fn test_diff_branches() {
+    use crate::runnable::{CompileError, CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    let model = |rhs: &str| {
+        let text = format!(
+            r#"{{
+                "iv": {{"name": "t", "val": 0.0}},
+                "params": [{{"name": "k", "val": 1.3}}],
+                "states": [{{"name": "x", "val": 0.4}}],
+                "algs": [],
+                "odes": [{{
+                    "lhs": {{"type": "Tree", "op": "Differential", "args": [{{"type": "Var", "name": "x"}}]}},
+                    "rhs": {}
+                }}],
+                "obs": []
+            }}"#,
+            rhs
+        );
+        CellModel::load(&text).unwrap()
+    };
+
+    // δx = ifelse(x > 0.5, k * x, 1), the condition is piecewise constant
+    let ml = model(
+        r#"{"type": "Tree", "op": "ifelse", "args": [
+            {"type": "Tree", "op": "gt", "args": [{"type": "Var", "name": "x"}, {"type": "Const", "val": 0.5}]},
+            {"type": "Tree", "op": "times", "args": [{"type": "Var", "name": "k"}, {"type": "Var", "name": "x"}]},
+            {"type": "Const", "val": 1.0}
+        ]}"#,
+    );
+
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode).with_jacobian();
+    let mut jac = vec![0.0];
+
+    for (x, d) in [(0.4, 0.0), (0.7, 1.3)] {
+        r.jacobian(&mut jac, &[x], &[1.3], 0.0);
+        assert_eq!(jac[0], d);
+    }
+
+    // δx = rem(k, x) has no rule
+    let ml = model(
+        r#"{"type": "Tree", "op": "rem", "args": [{"type": "Var", "name": "k"}, {"type": "Var", "name": "x"}]}"#,
+    );
+
+    assert_eq!(
+        ml.try_jacobian().unwrap_err(),
+        LowerError::NotDifferentiable {
+            op: "rem".to_string(),
+            eq: "∂δx/∂x".to_string()
+        }
+    );
+
+    let e = Runnable::new(Program::new(&ml), CompilerType::ByteCode)
+        .try_with_jacobian()
+        .err();
+    assert!(matches!(
+        e,
+        Some(CompileError::Lower(LowerError::NotDifferentiable { .. }))
+    ));
+}
+
+#[test]
+fn test_load_error() {
+    let e = CellModel::load("{\"iv\": {\"name\" \"t\"}}").unwrap_err();
+    assert!(matches!(e, LoadError::Syntax(_)));
+    assert_eq!(e.position(), Some((1, 16)));
+
+    let e = CellModel::load(r#"{"iv": {"name": "t"}}"#).unwrap_err();
+    assert!(matches!(e, LoadError::Semantic(_)));
+    assert!(e.to_string().contains("missing field `val`"));
+
+    let e = CellModel::load("").unwrap_err();
+    assert!(matches!(e, LoadError::Syntax(_)));
+}
+
+#[test]
+fn test_from_path() {
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let p1 = Program::new(&CellModel::load(&text).unwrap());
+    let p2 = Program::new(&CellModel::from_path("julia/beeler.json").unwrap());
+    let p3 = Program::new(&CellModel::from_reader(std::io::Cursor::new(text)).unwrap());
+    assert_eq!(p1.checksum(), p2.checksum());
+    assert_eq!(p1.checksum(), p3.checksum());
+
+    let e = CellModel::from_path("julia/missing.json").unwrap_err();
+    assert!(matches!(e, LoadError::Io(_)));
+    assert_eq!(e.position(), None);
+}
+
+#[test]
+fn test_step_pulse() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::solvers::{Euler, Solver};
+
+    // δx = step(t - 100), δy = pulse(t, 100, 50, 2)
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [],
+        "states": [{"name": "x", "val": 0.0}, {"name": "y", "val": 0.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "step", "args": [
+                {"type": "Tree", "op": "minus", "args": [
+                    {"type": "Var", "name": "t"}, {"type": "Const", "val": 100.0}
+                ]}
+            ]}
+        }, {
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "y"}]},
+            "rhs": {"type": "Tree", "op": "pulse", "args": [
+                {"type": "Var", "name": "t"}, {"type": "Const", "val": 100.0},
+                {"type": "Const", "val": 50.0}, {"type": "Const", "val": 2.0}
+            ]}
+        }],
+        "obs": []
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+
+    for ty in [CompilerType::ByteCode, CompilerType::Amd { optimize: true }] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let sol = Euler::new(1.0, 1)
+            .solve(&mut r, vec![0.0, 0.0], Vec::new(), 0.0..200.0)
+            .unwrap();
+
+        // the rhs is on at t == 100, so x(101) == 1
+        for row in sol.iter() {
+            assert_eq!(row.x[0], (row.t - 100.0).max(0.0));
+            assert_eq!(row.x[1], 2.0 * (row.t - 100.0).clamp(0.0, 50.0));
+        }
+    }
+
+    let jac = ml.jacobian();
+    assert!(jac.obs.iter().all(|eq| eq.rhs.is_const(0.0)));
+}
+
+#[test]
+fn test_validate_odes() {
+    let ode = |s: &str| {
+        format!(
+            r#"{{"lhs": {{"type": "Tree", "op": "Differential", "args": [{{"type": "Var", "name": "{}"}}]}},
+                "rhs": {{"type": "Const", "val": 1.0}}}}"#,
+            s
+        )
+    };
+
+    let model = |odes: &[&str]| -> CellModel {
+        let odes: Vec<String> = odes.iter().map(|s| ode(s)).collect();
+        let text = format!(
+            r#"{{"iv": {{"name": "t", "val": 0.0}}, "params": [],
+                "states": [{{"name": "x", "val": 0.0}}, {{"name": "y", "val": 0.0}}],
+                "algs": [], "odes": [{}], "obs": []}}"#,
+            odes.join(", ")
+        );
+        CellModel::load(&text).unwrap()
+    };
+
+    assert!(model(&["x", "y"]).validate().is_empty());
+
+    // y has no ode, x has two, and z is not a state
+    let errs = model(&["x", "x", "z"]).validate();
+    assert_eq!(
+        errs,
+        vec![
+            ValidationError::DuplicateOde {
+                state: "x".to_string(),
+                count: 2
+            },
+            ValidationError::MissingOde {
+                state: "y".to_string()
+            },
+            ValidationError::UndeclaredState {
+                name: "z".to_string()
+            },
+        ]
+    );
+    assert!(errs.iter().all(|e| !e.is_warning()));
+    assert_eq!(errs[1].to_string(), "state y has no differential equation");
+}
+
+#[test]
+fn test_lower_error() {
+    let model = |rhs: &str| -> CellModel {
+        let text = format!(
+            r#"{{"iv": {{"name": "t", "val": 0.0}}, "params": [],
+                "states": [{{"name": "x", "val": 0.0}}], "algs": [],
+                "odes": [{{
+                    "lhs": {{"type": "Tree", "op": "Differential", "args": [{{"type": "Var", "name": "x"}}]}},
+                    "rhs": {}
+                }}],
+                "obs": [{{"lhs": {{"type": "Var", "name": "y"}}, "rhs": {{"type": "Var", "name": "x"}}}}]}}"#,
+            rhs
+        );
+        CellModel::load(&text).unwrap()
+    };
+
+    let x = r#"{"type": "Var", "name": "x"}"#;
+    let frob = format!(r#"{{"type": "Tree", "op": "frob", "args": [{}]}}"#, x);
+
+    let cases = [
+        frob.clone(),
+        format!(
+            r#"{{"type": "Tree", "op": "frob", "args": [{}, {}]}}"#,
+            x, x
+        ),
+        format!(
+            r#"{{"type": "Tree", "op": "frob", "args": [{}, {}, {}, {}]}}"#,
+            x, x, x, x
+        ),
+        // lowered into a local before the equation itself
+        format!(
+            r#"{{"type": "Tree", "op": "power", "args": [{}, {{"type": "Const", "val": 2.0}}]}}"#,
+            frob
+        ),
+    ];
+
+    for rhs in cases {
+        let err = Program::try_new(&model(&rhs)).unwrap_err();
+        assert_eq!(
+            err,
+            LowerError::UnknownOp {
+                op: "frob".to_string(),
+                eq: "δx".to_string()
+            }
+        );
+        assert_eq!(err.to_string(), "unknown op frob in the equation for δx");
+    }
+
+    assert!(Program::try_new(&model(x)).is_ok());
+}
+
+#[test]
+fn test_second_order() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::solvers::{Solver, RK4};
+    use crate::utils::Callable;
+
+    // a damped harmonic oscillator, x'' = -k x - c x', with x(0) = 1 and x'(0) = 0
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 4.0}, {"name": "c", "val": 0.4}],
+        "states": [{"name": "x", "val": 1.0, "velocity": 0.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [
+                {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]}
+            ]},
+            "rhs": {"type": "Tree", "op": "minus", "args": [
+                {"type": "Tree", "op": "times", "args": [
+                    {"type": "Const", "val": -1.0},
+                    {"type": "Tree", "op": "times", "args": [{"type": "Var", "name": "k"}, {"type": "Var", "name": "x"}]}
+                ]},
+                {"type": "Tree", "op": "times", "args": [{"type": "Var", "name": "c"}, {"type": "Var", "name": "x'"}]}
+            ]}
+        }],
+        "obs": []
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    assert!(ml.validate().is_empty());
+    assert!(matches!(ml.reduce_order(), Cow::Owned(_)));
+
+    let prog = Program::new(&ml);
+    assert_eq!(prog.frame.state_names(), vec!["x", "x'"]);
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        assert_eq!(r.initial_states(), vec![1.0, 0.0]);
+
+        let mut du = vec![0.0; 2];
+        r.call(&mut du, &[1.0, 0.5], &[4.0, 0.4], 0.0);
+        assert_eq!(du, vec![0.5, -4.0 - 0.4 * 0.5]);
+
+        let sol = RK4::new(0.001, 100)
+            .solve(&mut r, vec![1.0, 0.0], vec![4.0, 0.4], 0.0..5.0)
+            .unwrap();
+
+        // x = exp(-γt) (cos ωt + γ/ω sin ωt), with γ = c/2 and ω = sqrt(k - γ^2)
+        let (g, w) = (0.2f64, 3.96f64.sqrt());
+        for row in sol.iter() {
+            let t = row.t;
+            let x = (-g * t).exp() * ((w * t).cos() + g / w * (w * t).sin());
+            assert!((row.x[0] - x).abs() < 1e-9);
+        }
+    }
+
+    // first-order models are not copied
+    let text = std::fs::read_to_string("julia/lorenz.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    assert!(matches!(ml.reduce_order(), Cow::Borrowed(_)));
+}
+
+#[test]
+fn test_stats() {
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 2.0}],
+        "states": [{"name": "x", "val": 1.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "plus", "args": [
+                {"type": "Tree", "op": "times", "args": [
+                    {"type": "Tree", "op": "sin", "args": [{"type": "Var", "name": "x"}]},
+                    {"type": "Var", "name": "k"}
+                ]},
+                {"type": "Tree", "op": "exp", "args": [{"type": "Var", "name": "t"}]}
+            ]}
+        }],
+        "obs": []
+    }"#;
+
+    let prog = Program::new(&CellModel::load(text).unwrap());
+    let s = prog.stats();
+
+    assert_eq!(s.calls, 2);
+    assert_eq!(s.ifelse, 0);
+    assert!(s.binary >= 2);
+    // sin(x) waits on the stack while exp(t) is calculated
+    assert!(s.max_temps >= 1);
+    assert_eq!(s.registers, prog.frame.words.len());
+    assert_eq!(
+        s.unary + s.binary + s.ifelse + s.nums + s.vars + s.eqs + s.nops,
+        prog.code.len()
+    );
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let prog = Program::new(&CellModel::load(&text).unwrap());
+    let s = prog.stats();
+    assert!(s.calls > 10 && s.ifelse > 0);
+    assert!(s
+        .to_string()
+        .contains(&format!("{} registers", s.registers)));
+}
+
+#[test]
+fn test_ir_json() {
+    use crate::runnable::{CompilerType, Runnable};
+    use crate::utils::Callable;
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let opts = Options {
+        fma: true,
+        ..Options::default()
+    };
+    let prog = Program::with_options(&ml, opts);
+
+    let json = prog.to_ir_json();
+    let back = Program::from_ir_json(&json).unwrap();
+    assert_eq!(back.to_ir_json(), json);
+    assert_eq!(format!("{:?}", back.code), format!("{:?}", prog.code));
+    assert_eq!(back.frame.named, prog.frame.named);
+    assert!(back.options.fma);
+    assert_eq!(back.try_jacobian().err(), Some(LowerError::NoModel));
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(prog.clone(), ty);
+        let mut s = Runnable::new(back.clone(), ty);
+        let u = r.initial_states();
+        let p = r.params();
+        let mut du1 = vec![0.0; u.len()];
+        let mut du2 = vec![0.0; u.len()];
+        r.call(&mut du1, &u, &p, 0.0);
+        s.call(&mut du2, &u, &p, 0.0);
+        assert_eq!(du1, du2);
+    }
+
+    assert!(Program::from_ir_json("not json").is_err());
+    assert!(json.contains(r#""op":"exp""#));
+    let unknown = json.replacen(r#""op":"exp""#, r#""op":"nope""#, 1);
+    assert!(Program::from_ir_json(&unknown).is_err());
+    let far = json.replacen(r#""dst":["#, r#""dst":[100000"#, 1);
+    assert!(Program::from_ir_json(&far).is_err());
 }
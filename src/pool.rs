@@ -0,0 +1,54 @@
+// MemoryPool hands out and recycles mem buffers of a fixed size
+// it is used by Runnable to run many replicas of the same model
+// without allocating a new buffer per replica
+#[derive(Debug)]
+pub struct MemoryPool<T = f64> {
+    size: usize,
+    free: Vec<Vec<T>>,
+    allocated: usize,
+    reused: usize,
+}
+
+impl<T: Copy> MemoryPool<T> {
+    pub fn new(size: usize) -> MemoryPool<T> {
+        MemoryPool {
+            size,
+            free: Vec::new(),
+            allocated: 0,
+            reused: 0,
+        }
+    }
+
+    // returns a buffer filled with a copy of init
+    pub fn get(&mut self, init: &[T]) -> Vec<T> {
+        assert_eq!(init.len(), self.size, "mem size mismatch");
+
+        if let Some(mut buf) = self.free.pop() {
+            self.reused += 1;
+            buf.copy_from_slice(init);
+            buf
+        } else {
+            self.allocated += 1;
+            init.to_vec()
+        }
+    }
+
+    pub fn put(&mut self, buf: Vec<T>) {
+        assert_eq!(buf.len(), self.size, "mem size mismatch");
+        self.free.push(buf);
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // number of buffers allocated by the pool
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    // number of times a recycled buffer was handed out
+    pub fn reused(&self) -> usize {
+        self.reused
+    }
+}
@@ -1,10 +1,12 @@
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
 
+use crate::code::Lookup;
+
 // Unit-like structure abstracting a single register
 // it covers the index of the register in mem
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Word(pub usize, pub usize); // index, version
 
 impl Word {
@@ -13,24 +15,34 @@ impl Word {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 // Adjacency tagging (https://serde.rs/enum-representations.html)
 #[serde(tag = "t", content = "c")]
 pub enum WordType {
-    Const(f64),
+    Const(#[serde(deserialize_with = "nullable")] f64),
     Var(String),
     State(String, f64),
     Diff(String),
     Param(String, f64),
+    Input(String, f64), // external forcing, e.g., a stimulus current I(t)
+    Table(String, Vec<f64>, Vec<f64>), // x-grid and y-values of interp1, holds the table handle
     Obs(String),
+    Local(String), // named intermediate values, e.g., hoisted common subexpressions
     Temp,
 }
 
+// serde_json writes NaN (e.g., ABS_MASK) as null
+fn nullable<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+    Ok(Option::<f64>::deserialize(d)?.unwrap_or(f64::NAN))
+}
+
 impl WordType {
     pub fn value(&self) -> Option<f64> {
         match self {
             WordType::State(_, val) => Some(*val),
             WordType::Param(_, val) => Some(*val),
+            WordType::Input(_, val) => Some(*val),
+            WordType::Table(_, xs, ys) => Some(Lookup::sorted(xs, ys).register() as f64),
             WordType::Const(val) => Some(*val),
             _ => None,
         }
@@ -38,7 +50,7 @@ impl WordType {
 }
 
 // The register file
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Frame {
     pub words: Vec<WordType>,
     pub stack: Vec<WordType>,
@@ -51,6 +63,7 @@ impl Frame {
     pub const ONE: Word = Word(1, 0);
     pub const MINUS_ONE: Word = Word(2, 0);
     pub const MINUS_ZERO: Word = Word(3, 0);
+    pub const ABS_MASK: Word = Word(4, 0);
 
     pub fn new() -> Frame {
         let mut f = Frame {
@@ -64,6 +77,7 @@ impl Frame {
         f.alloc(WordType::Const(1.0));
         f.alloc(WordType::Const(-1.0));
         f.alloc(WordType::Const(-0.0)); // MSB is 1, all other bits are 0, used for negation by xoring
+        f.alloc(WordType::Const(f64::from_bits(0x7fff_ffff_ffff_ffff))); // MSB is 0, all other bits are 1, used for abs by anding
 
         f
     }
@@ -87,7 +101,13 @@ impl Frame {
                 return self.alloc_temp();
             }
             WordType::Const(_) => {}
-            WordType::Var(s) | WordType::State(s, _) | WordType::Param(s, _) | WordType::Obs(s) => {
+            WordType::Var(s)
+            | WordType::State(s, _)
+            | WordType::Param(s, _)
+            | WordType::Input(s, _)
+            | WordType::Table(s, _, _)
+            | WordType::Obs(s)
+            | WordType::Local(s) => {
                 self.named
                     .insert(s.clone(), idx)
                     .map(|_x| panic!("key already exists"));
@@ -144,6 +164,13 @@ impl Frame {
         }
     }
 
+    pub fn is_local(&self, r: &Word) -> bool {
+        if r.is_temp() {
+            return false;
+        }
+        matches!(self.words[r.0], WordType::Local(_))
+    }
+
     pub fn find(&self, s: &str) -> Option<Word> {
         self.named.get(s).map(|idx| Word(*idx, 0))
     }
@@ -174,6 +201,13 @@ impl Frame {
             .count()
     }
 
+    pub fn count_inputs(&self) -> usize {
+        self.words
+            .iter()
+            .filter(|x| matches!(x, WordType::Input(_, _)))
+            .count()
+    }
+
     pub fn count_obs(&self) -> usize {
         self.words
             .iter()
@@ -192,6 +226,12 @@ impl Frame {
             .iter()
             .position(|x| matches!(x, WordType::Param(_, _)))
     }
+
+    pub fn first_input(&self) -> Option<usize> {
+        self.words
+            .iter()
+            .position(|x| matches!(x, WordType::Input(_, _)))
+    }
     
     pub fn first_obs(&self) -> Option<usize> {
         self.words
@@ -205,6 +245,46 @@ impl Frame {
             .position(|x| matches!(x, WordType::Diff(_)))
     }
 
+    pub fn state_names(&self) -> Vec<String> {
+        self.words
+            .iter()
+            .filter_map(|x| match x {
+                WordType::State(s, _) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn param_names(&self) -> Vec<String> {
+        self.words
+            .iter()
+            .filter_map(|x| match x {
+                WordType::Param(s, _) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn input_names(&self) -> Vec<String> {
+        self.words
+            .iter()
+            .filter_map(|x| match x {
+                WordType::Input(s, _) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn obs_names(&self) -> Vec<String> {
+        self.words
+            .iter()
+            .filter_map(|x| match x {
+                WordType::Obs(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn mem(&self) -> Vec<f64> {
         self.words
             .iter()
@@ -219,4 +299,122 @@ impl Frame {
     pub fn as_json(&self) -> Result<String, Box<dyn Error>> {
         Ok(serde_json::to_string(&self.words)?)
     }
+
+    /*
+        the inverse of as_json: the words after the reserved constants are
+        reallocated, which rebuilds the named map; the reserved constants are
+        taken from Frame::new, since NaNs do not survive JSON bit-exactly
+        the temps (stack) are not part of the layout and freed starts empty
+    */
+    pub fn from_json(text: &str) -> Result<Frame, Box<dyn Error>> {
+        Frame::from_words(serde_json::from_str(text)?)
+    }
+
+    pub fn from_words(words: Vec<WordType>) -> Result<Frame, Box<dyn Error>> {
+        let mut f = Frame::new();
+        let n = f.words.len();
+
+        if words.len() < n || !words[..n].iter().all(|w| matches!(w, WordType::Const(_))) {
+            return Err("the reserved constants are missing".into());
+        }
+
+        for w in words.into_iter().skip(n) {
+            let key = match &w {
+                WordType::Const(_) => None,
+                WordType::Diff(s) => Some(format!("δ{}", s)),
+                WordType::Var(s)
+                | WordType::State(s, _)
+                | WordType::Param(s, _)
+                | WordType::Input(s, _)
+                | WordType::Table(s, _, _)
+                | WordType::Obs(s)
+                | WordType::Local(s) => Some(s.clone()),
+                WordType::Temp => return Err("temps are not part of the layout".into()),
+            };
+
+            if let Some(k) = key.filter(|k| f.named.contains_key(k)) {
+                return Err(format!("duplicate register {}", k).into());
+            }
+
+            f.alloc(w);
+        }
+
+        Ok(f)
+    }
+}
+
+#[test]
+fn test_frame_json() {
+    use crate::model::{CellModel, Program};
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let frame = Program::new(&ml).frame;
+
+    let g = Frame::from_json(&frame.as_json().unwrap()).unwrap();
+    assert_eq!(g.as_json().unwrap(), frame.as_json().unwrap());
+    assert_eq!(g.named, frame.named);
+    assert!(g.freed.is_empty());
+
+    let bits = |f: &Frame| -> Vec<u64> { f.mem().iter().map(|x| x.to_bits()).collect() };
+    assert_eq!(bits(&g), bits(&frame));
+
+    for name in frame.state_names() {
+        assert_eq!(g.find(&name), frame.find(&name));
+        assert_eq!(g.find_diff(&name), frame.find_diff(&name));
+    }
+    assert_eq!(g.first_obs(), frame.first_obs());
+    assert_eq!(g.first_diff(), frame.first_diff());
+
+    assert!(Frame::from_json("[]").is_err());
+    assert!(Frame::from_json("not json").is_err());
+    let json = frame.as_json().unwrap();
+    let dup = json.replace(']', r#",{"t":"Obs","c":"V"}]"#);
+    assert!(Frame::from_json(&dup).is_err());
+}
+
+#[test]
+fn test_frame_layout() {
+    use crate::model::{CellModel, Program};
+
+    for name in ["lorenz", "beeler", "ohara"] {
+        let text = std::fs::read_to_string(format!("julia/{}.json", name)).unwrap();
+        let ml = CellModel::load(&text).unwrap();
+        let frame = Program::new(&ml).frame;
+
+        // each kind of word occupies a contiguous block starting at first_*
+        let contiguous = |first: Option<usize>, count: usize, f: &dyn Fn(&WordType) -> bool| {
+            let n = frame.words.iter().filter(|w| f(w)).count();
+            assert_eq!(n, count);
+            if let Some(k) = first {
+                assert!(frame.words[k..k + count].iter().all(f));
+            } else {
+                assert_eq!(count, 0);
+            }
+        };
+
+        contiguous(frame.first_state(), frame.count_states(), &|w| {
+            matches!(w, WordType::State(_, _))
+        });
+        contiguous(frame.first_param(), frame.count_params(), &|w| {
+            matches!(w, WordType::Param(_, _))
+        });
+        contiguous(frame.first_obs(), frame.count_obs(), &|w| {
+            matches!(w, WordType::Obs(_))
+        });
+        contiguous(frame.first_diff(), frame.count_diffs(), &|w| {
+            matches!(w, WordType::Diff(_))
+        });
+
+        // the i-th diff belongs to the i-th state, and the iv precedes the states
+        assert_eq!(frame.count_diffs(), frame.count_states());
+        let (s, d) = (frame.first_state().unwrap(), frame.first_diff().unwrap());
+        for i in 0..frame.count_states() {
+            match (&frame.words[s + i], &frame.words[d + i]) {
+                (WordType::State(x, _), WordType::Diff(y)) => assert_eq!(x, y),
+                _ => unreachable!(),
+            }
+        }
+        assert!(matches!(frame.words[s - 1], WordType::Var(_)));
+    }
 }
@@ -1,106 +1,681 @@
-use crate::model::Program;
+use std::any::Any;
+use std::fmt::Write;
+use std::ops::Range;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::code::{Instruction, Lookup};
+use crate::machine::has_feature;
+use crate::model::{LowerError, Program};
+use crate::pool::MemoryPool;
+use crate::register::WordType;
+use crate::solvers::{Row, SolveError, Solver};
 use crate::utils::*;
 
-use crate::amd::AmdCompiler;
+use crate::amd::{AmdCompiler, PackedCompiler, LANES};
 use crate::arm::ArmCompiler;
+#[cfg(feature = "c")]
+use crate::c::CCompiler;
+#[cfg(feature = "cranelift")]
+use crate::cranelift::CraneliftCompiler;
 use crate::interpreter::Interpreter;
 #[cfg(feature = "rusty")]
 use crate::rusty::RustyCompiler;
 #[cfg(feature = "wasm")]
 use crate::wasm::WasmCompiler;
 
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CompilerType {
     ByteCode,
     Native,
-    Amd,
+    Amd {
+        optimize: bool,
+    }, // optimize keeps hot registers in xmm4-xmm7
     Arm,
     #[cfg(feature = "wasm")]
     Wasm,
     #[cfg(feature = "rusty")]
     Rusty,
+    #[cfg(feature = "cranelift")]
+    Cranelift,
+    #[cfg(feature = "c")]
+    C,
+}
+
+impl std::fmt::Display for CompilerType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            CompilerType::ByteCode => "bytecode",
+            CompilerType::Native => "native",
+            CompilerType::Amd { optimize: false } => "amd",
+            CompilerType::Amd { optimize: true } => "amd-opt",
+            CompilerType::Arm => "arm",
+            #[cfg(feature = "wasm")]
+            CompilerType::Wasm => "wasm",
+            #[cfg(feature = "rusty")]
+            CompilerType::Rusty => "rusty",
+            #[cfg(feature = "cranelift")]
+            CompilerType::Cranelift => "cranelift",
+            #[cfg(feature = "c")]
+            CompilerType::C => "c",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// the reasons a Program cannot be turned into a Runnable
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    NoStates,
+    NoDiffs,
+    Unsupported { feature: String, ty: CompilerType },
+    Codegen(String), // the backend panicked, e.g., on an unknown op
+    Arch(String),    // native code for another architecture
+    Mmap(String),    // the code cannot be mapped executable, e.g., under a W^X policy
+    // the model cannot be lowered, e.g., the Jacobian of a non-differentiable one
+    Lower(LowerError),
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompileError::NoStates => write!(f, "the model has no states"),
+            CompileError::NoDiffs => write!(f, "the model has no differential equations"),
+            CompileError::Unsupported { feature, ty } => {
+                write!(f, "{} are not supported by the {} backend", feature, ty)
+            }
+            CompileError::Codegen(msg) => write!(f, "code generation failed: {}", msg),
+            CompileError::Arch(arch) => write!(f, "cannot run {} code on this machine", arch),
+            CompileError::Mmap(msg) => write!(
+                f,
+                "cannot map the generated code as executable ({}); the system may forbid \
+                 writable-then-executable memory, try the bytecode or wasm backend",
+                msg
+            ),
+            CompileError::Lower(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// the message of a caught panic
+pub fn panic_message(e: Box<dyn Any + Send>) -> String {
+    if let Some(s) = e.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = e.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown cause".to_string()
+    }
 }
 
-pub struct Runnable {
+pub struct Runnable<T: Real = f64> {
     pub prog: Program,
-    pub compiled: Box<dyn Compiled>,
+    pub ty: CompilerType,
+    pub compiled: Box<dyn Compiled<T> + Send>,
+    pub obs_kernel: Option<Box<dyn Compiled<T> + Send>>, // only with Options::split_kernels
+    pub jac: Option<Box<Runnable<T>>>,                   // the symbolic Jacobian, see with_jacobian
+    pub packed: Option<Box<dyn Compiled + Send>>,        // four lanes at once, see with_packed
     pub first_state: usize,    
     pub first_param: usize,
+    pub first_input: usize,
     pub first_obs: usize,    
     pub first_diff: usize,
     pub count_states: usize,
     pub count_params: usize,
+    pub count_inputs: usize,
     pub count_obs: usize,
     pub count_diffs: usize,
-    pub u0: Vec<f64>,
-    pub p: Vec<f64>,
+    pub u0: Vec<T>,
+    pub p: Vec<T>,
+    pub mem0: Vec<T>,        // the initial mem, used by reset and replicate
+    pub pool: MemoryPool<T>, // recycled buffers for replicas
+}
+
+fn compile(prog: &Program, ty: CompilerType) -> Result<Box<dyn Compiled + Send>, CompileError> {
+    let compiled: Box<dyn Compiled + Send> = match ty {
+        CompilerType::ByteCode => Box::new(Interpreter::new().try_compile(prog)?),
+        #[cfg(feature = "wasm")]
+        CompilerType::Wasm => Box::new(WasmCompiler::new().try_compile(prog)?),
+        #[cfg(feature = "rusty")]
+        CompilerType::Rusty => Box::new(RustyCompiler::new().try_compile(prog)?),
+        #[cfg(feature = "cranelift")]
+        CompilerType::Cranelift => Box::new(CraneliftCompiler::new().try_compile(prog)?),
+        #[cfg(feature = "c")]
+        CompilerType::C => Box::new(CCompiler::new().try_compile(prog)?),
+        CompilerType::Amd { optimize } => Box::new(AmdCompiler::new(optimize).try_compile(prog)?),
+        CompilerType::Arm => Box::new(ArmCompiler::new().try_compile(prog)?),
+        #[cfg(target_arch = "x86_64")]
+        CompilerType::Native => Box::new(AmdCompiler::new(true).try_compile(prog)?),
+        #[cfg(target_arch = "aarch64")]
+        CompilerType::Native => Box::new(ArmCompiler::new().try_compile(prog)?),
+    };
+
+    if keep_artifacts() {
+        match compiled.keep() {
+            Ok(Some(path)) => {
+                eprintln!("cell: kept the {} code at {}", ty, path.display());
+                if let Some(listing) = compiled.listing() {
+                    eprintln!("{}", listing);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("cell: cannot keep the {} code: {}", ty, e),
+        }
+    }
+
+    Ok(compiled)
+}
+
+// the element types of Runnable; the native backends are f64 only,
+// so f32 is limited to the bytecode
+pub trait Real: Float {
+    fn compile(
+        prog: &Program,
+        ty: CompilerType,
+    ) -> Result<Box<dyn Compiled<Self> + Send>, CompileError>;
+}
+
+impl Real for f64 {
+    fn compile(prog: &Program, ty: CompilerType) -> Result<Box<dyn Compiled + Send>, CompileError> {
+        compile(prog, ty)
+    }
+}
+
+impl Real for f32 {
+    fn compile(
+        prog: &Program,
+        ty: CompilerType,
+    ) -> Result<Box<dyn Compiled<f32> + Send>, CompileError> {
+        match ty {
+            CompilerType::ByteCode => Ok(Box::new(Interpreter::new().compile_as::<f32>(prog))),
+            _ => Err(CompileError::Unsupported {
+                feature: "single-precision kernels".to_string(),
+                ty,
+            }),
+        }
+    }
 }
 
 impl Runnable {
     pub fn new(prog: Program, ty: CompilerType) -> Runnable {
-        let compiled: Box<dyn Compiled> = match ty {
-            CompilerType::ByteCode => Box::new(Interpreter::new().compile(&prog)),
-            #[cfg(feature = "wasm")]
-            CompilerType::Wasm => Box::new(WasmCompiler::new().compile(&prog)),
-            #[cfg(feature = "rusty")]
-            CompilerType::Rusty => Box::new(RustyCompiler::new().compile(&prog)),
-            CompilerType::Amd => Box::new(AmdCompiler::new().compile(&prog)),
-            CompilerType::Arm => Box::new(ArmCompiler::new().compile(&prog)),
-            #[cfg(target_arch = "x86_64")]
-            CompilerType::Native => Box::new(AmdCompiler::new().compile(&prog)),
-            #[cfg(target_arch = "aarch64")]
-            CompilerType::Native => Box::new(ArmCompiler::new().compile(&prog)),
-        };
+        Self::new_as(prog, ty)
+    }
+
+    pub fn try_new(prog: Program, ty: CompilerType) -> Result<Runnable, CompileError> {
+        Self::try_new_as(prog, ty)
+    }
+
+    // as new, but mem and the vectors passed to call are T, e.g.,
+    // new_as::<f32> for a single-precision bytecode
+    pub fn new_as<T: Real>(prog: Program, ty: CompilerType) -> Runnable<T> {
+        Self::try_new_as(prog, ty).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new_as<T: Real>(
+        prog: Program,
+        ty: CompilerType,
+    ) -> Result<Runnable<T>, CompileError> {
+        if ty != CompilerType::ByteCode
+            && prog.ft.iter().any(|s| s == "lookup" || s == "lookup_slope")
+        {
+            return Err(CompileError::Unsupported {
+                feature: "lookup tables".to_string(),
+                ty,
+            });
+        }
 
-        let first_state = prog.frame.first_state().unwrap();
-        let first_param = prog.frame.first_param().unwrap();
-        let first_obs = prog.frame.first_obs().unwrap();
-        let first_diff = prog.frame.first_diff().unwrap();
-        
         let count_states = prog.frame.count_states();        
         let count_params = prog.frame.count_params();
+        let count_inputs = prog.frame.count_inputs();
         let count_obs = prog.frame.count_obs();        
         let count_diffs = prog.frame.count_diffs();
 
+        // empty sections (e.g., a model without obs) start where they would have been
+        let first_state = prog.frame.first_state().ok_or(CompileError::NoStates)?;
+        let first_param = prog
+            .frame
+            .first_param()
+            .unwrap_or(first_state + count_states);
+        let first_input = prog
+            .frame
+            .first_input()
+            .unwrap_or(first_param + count_params);
+        let first_obs = prog.frame.first_obs().unwrap_or(first_input + count_inputs);
+        let first_diff = prog.frame.first_diff().ok_or(CompileError::NoDiffs)?;
+
+        // the backends panic on malformed code
+        let (compiled, obs_kernel) = catch_unwind(AssertUnwindSafe(|| {
+            if prog.options.split_kernels {
+                let (diffs, obs) = prog.split();
+                Ok((T::compile(&diffs, ty)?, Some(T::compile(&obs, ty)?)))
+            } else {
+                Ok((T::compile(&prog, ty)?, None))
+            }
+        }))
+        .map_err(|e| CompileError::Codegen(panic_message(e)))??;
+
         let mem = compiled.mem();
         let u0 = mem[first_state..first_state + count_states].to_vec();
         let p = mem[first_param..first_param + count_params].to_vec();
+        let mem0 = mem.to_vec();
+        let pool = MemoryPool::new(mem0.len());
 
-        Runnable {
+        Ok(Runnable {
             prog,
+            ty,
             compiled,
+            obs_kernel,
+            jac: None,
+            packed: None,
             first_state,
             first_param,
+            first_input,
             first_obs,
             first_diff,
             count_states,            
             count_params,
+            count_inputs,
             count_obs,            
             count_diffs,
             u0,
             p,
-        }
+            mem0,
+            pool,
+        })
     }
+}
 
-    pub fn initial_states(&self) -> Vec<f64> {
+impl<T: Real> Runnable<T> {
+    // compiles the symbolic Jacobian of prog with the same backend, which is
+    // then used by Callable::jacobian instead of finite differences
+    pub fn with_jacobian(self) -> Runnable<T> {
+        self.try_with_jacobian().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_with_jacobian(mut self) -> Result<Runnable<T>, CompileError> {
+        let prog = self.prog.try_jacobian().map_err(CompileError::Lower)?;
+        self.jac = Some(Box::new(Runnable::try_new_as(prog, self.ty)?));
+        Ok(self)
+    }
+
+    pub fn initial_states(&self) -> Vec<T> {
         self.u0.clone()
     }
 
-    pub fn params(&self) -> Vec<f64> {
+    pub fn params(&self) -> Vec<T> {
         self.p.clone()
     }
+
+    // restores mem to its initial state, i.e., u0 and p (which set_param
+    // and reset_with can change) and zero obs, diffs, and locals
+    // the fresh mem comes from the pool, which gets the old one back
+    pub fn reset(&mut self) {
+        let (s, p) = (self.first_state, self.first_param);
+        let mut mem = self.pool.get(&self.mem0);
+        mem[s..s + self.count_states].copy_from_slice(&self.u0);
+        mem[p..p + self.count_params].copy_from_slice(&self.p);
+        self.compiled.swap_mem(&mut mem);
+        self.pool.put(mem);
+        self.compiled.invalidate();
+
+        if let Some(k) = &mut self.obs_kernel {
+            k.mem_mut()[p..p + self.count_params].copy_from_slice(&self.p);
+            k.invalidate();
+        }
+    }
+
+    // as reset, but with new initial states and params, e.g., for a sweep
+    pub fn reset_with(&mut self, u0: &[T], p: &[T]) {
+        assert_eq!(u0.len(), self.count_states, "initial states size mismatch");
+        assert_eq!(p.len(), self.count_params, "param set size mismatch");
+        self.u0 = u0.to_vec();
+        self.p = p.to_vec();
+        self.reset();
+    }
+
+    // sets the i-th parameter, both the default value and the one in mem
+    pub fn set_param(&mut self, i: usize, val: T) {
+        self.p[i] = val;
+        self.compiled.mem_mut()[self.first_param + i] = val;
+        self.compiled.invalidate();
+        if let Some(k) = &mut self.obs_kernel {
+            k.mem_mut()[self.first_param + i] = val;
+            k.invalidate();
+        }
+    }
+
+    // sets the i-th input in every kernel; unlike the params, the inputs
+    // are not invariant, so nothing is invalidated
+    pub fn set_input(&mut self, i: usize, val: T) {
+        assert!(i < self.count_inputs, "input index out of range");
+        let k = self.first_input + i;
+
+        self.compiled.mem_mut()[k] = val;
+        if let Some(obs) = &mut self.obs_kernel {
+            obs.mem_mut()[k] = val;
+        }
+        if let Some(jac) = &mut self.jac {
+            jac.set_input(i, val);
+        }
+        if let Some(packed) = &mut self.packed {
+            packed.mem_mut()[LANES * k..LANES * (k + 1)].fill(val.to_f64());
+        }
+    }
+
+    // integrates the model for each parameter set, starting from the
+    // initial states, without recompiling
+    // mem is reset between the runs, so nothing leaks from one run to the next
+    pub fn sweep<S: Solver<Runnable<T>, T>>(
+        &mut self,
+        param_sets: &[Vec<T>],
+        solver: &S,
+        ts: Range<f64>,
+    ) -> Result<Vec<Vec<Row<T>>>, SolveError> {
+        let mut sols = Vec::with_capacity(param_sets.len());
+
+        for p in param_sets.iter() {
+            assert_eq!(p.len(), self.count_params, "param set size mismatch");
+            self.reset();
+            let u0 = self.u0.clone();
+            sols.push(solver.solve(self, u0, p.clone(), ts.clone())?);
+        }
+
+        Ok(sols)
+    }
+
+    // integrates the parameter sets in parallel, the results are in the
+    // same order as param_sets
+    // each thread works on its own fork of the model (see Compiled::fork)
+    #[cfg(feature = "rayon")]
+    pub fn sweep_par<S: Solver<Runnable<T>, T> + Sync>(
+        &self,
+        param_sets: &[Vec<T>],
+        solver: &S,
+        ts: Range<f64>,
+    ) -> Result<Vec<Vec<Row<T>>>, SolveError> {
+        use rayon::prelude::*;
+
+        let size = param_sets
+            .len()
+            .div_ceil(rayon::current_num_threads())
+            .max(1);
+
+        let chunks: Vec<(Runnable<T>, &[Vec<T>])> = param_sets
+            .chunks(size)
+            .map(|chunk| (self.fork(), chunk))
+            .collect();
+
+        let sols: Result<Vec<Vec<Vec<Row<T>>>>, SolveError> = chunks
+            .into_par_iter()
+            .map(|(mut r, chunk)| r.sweep(chunk, solver, ts.clone()))
+            .collect();
+
+        Ok(sols?.into_iter().flatten().collect())
+    }
+
+    // returns an independent copy of the model that can be moved to another thread
+    // the copy starts from the current mem and shares the compiled code if possible
+    pub fn fork(&self) -> Runnable<T> {
+        Runnable {
+            prog: self.prog.clone(),
+            ty: self.ty,
+            compiled: self.compiled.fork(),
+            obs_kernel: self.obs_kernel.as_ref().map(|k| k.fork()),
+            jac: self.jac.as_ref().map(|j| Box::new(j.fork())),
+            packed: self.packed.as_ref().map(|k| k.fork()),
+            first_state: self.first_state,
+            first_param: self.first_param,
+            first_input: self.first_input,
+            first_obs: self.first_obs,
+            first_diff: self.first_diff,
+            count_states: self.count_states,
+            count_params: self.count_params,
+            count_inputs: self.count_inputs,
+            count_obs: self.count_obs,
+            count_diffs: self.count_diffs,
+            u0: self.u0.clone(),
+            p: self.p.clone(),
+            mem0: self.mem0.clone(),
+            pool: MemoryPool::new(self.mem0.len()),
+        }
+    }
+
+    // returns a fresh mem buffer for an independent replica of the model
+    // the buffer comes from the pool and should be returned by recycle
+    pub fn replicate(&mut self) -> Vec<T> {
+        self.pool.get(&self.mem0)
+    }
+
+    pub fn recycle(&mut self, mem: Vec<T>) {
+        self.pool.put(mem);
+    }
+
+    // similar to call but runs on the replica mem
+    pub fn call_replica(&mut self, mem: &mut Vec<T>, du: &mut [T], u: &[T], p: &[T], t: f64) {
+        self.compiled.swap_mem(mem);
+        self.call(du, u, p, t);
+        self.compiled.swap_mem(mem);
+    }
+
+    // sets the table used by lookup(x, id) expressions
+    // xs are the sorted breakpoints and ys the corresponding values
+    pub fn set_lookup(&mut self, id: usize, xs: Vec<f64>, ys: Vec<f64>) {
+        let table = Lookup::new(xs.clone(), ys.clone());
+        if let Some(j) = &mut self.jac {
+            j.set_lookup(id, xs, ys);
+        }
+        if let Some(k) = &mut self.obs_kernel {
+            k.set_lookup(id, table.clone());
+        }
+        self.compiled.set_lookup(id, table);
+    }
+
+    // computes diffs; with split kernels, obs-only instructions are skipped
+    pub fn run_diffs(&mut self, du: &mut [T], u: &[T], p: &[T], t: f64) {
+        self.call(du, u, p, t);
+    }
+
+    // returns the assembly listing of the compiled kernels (native backends only)
+    pub fn disassemble(&self) -> String {
+        let mut s = String::new();
+
+        match self.compiled.listing() {
+            Some(l) => s.push_str(l),
+            None => {
+                let _ = write!(s, "; no listing for the {} backend", self.ty);
+            }
+        }
+
+        if let Some(l) = self.obs_kernel.as_ref().and_then(|k| k.listing()) {
+            let _ = write!(s, "\n\n; observables\n{}", l);
+        }
+
+        s
+    }
+
+    // returns the generated source text, i.e., WAT for wasm and Rust or C
+    // for the rusty and c backends; None for the native and bytecode backends
+    pub fn source(&self) -> Option<String> {
+        self.compiled.source().map(String::from)
+    }
+
+    // computes observables, to be called at output times
+    pub fn run_obs(&mut self, obs: &mut [T], u: &[T], p: &[T], t: f64) {
+        let k = match &mut self.obs_kernel {
+            Some(k) => k,
+            None => &mut self.compiled,
+        };
+
+        let changed = {
+            let mem = k.mem_mut();
+            mem[self.first_state - 1] = T::from_f64(t);
+            mem[self.first_state..self.first_state + self.count_states].copy_from_slice(u);
+            let params = &mut mem[self.first_param..self.first_param + self.count_params];
+            let changed = params != p;
+            params.copy_from_slice(p);
+            changed
+        };
+
+        if changed {
+            k.invalidate();
+        }
+
+        k.run();
+        obs.copy_from_slice(&k.mem()[self.first_obs..self.first_obs + self.count_obs]);
+    }
 }
 
-impl Callable for Runnable {
+impl Runnable {
+    // compiles an AVX kernel that evaluates LANES copies of the model at once,
+    // which is then used by call_packed (x86_64 only)
+    pub fn with_packed(self) -> Runnable {
+        self.try_with_packed().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_with_packed(mut self) -> Result<Runnable, CompileError> {
+        if !cfg!(target_arch = "x86_64") {
+            return Err(CompileError::Arch("x86_64".to_string()));
+        }
+
+        if !has_feature("avx") {
+            return Err(CompileError::Arch("avx".to_string()));
+        }
+
+        let k = if self.prog.options.split_kernels {
+            PackedCompiler::new().try_compile(&self.prog.split().0)?
+        } else {
+            PackedCompiler::new().try_compile(&self.prog)?
+        };
+
+        self.packed = Some(Box::new(k));
+        Ok(self)
+    }
+
+    // like call but for LANES independent systems sharing t; u, p, and du are
+    // structure-of-arrays, i.e., lane j of state i is u[LANES * i + j]
+    pub fn call_packed(&mut self, du: &mut [f64], u: &[f64], p: &[f64], t: f64) {
+        let k = self
+            .packed
+            .as_mut()
+            .expect("packed kernel is not compiled, see with_packed");
+        let mem = k.mem_mut();
+
+        let s = LANES * self.first_state;
+        mem[s - LANES..s].fill(t);
+        mem[s..s + LANES * self.count_states].copy_from_slice(u);
+        let s = LANES * self.first_param;
+        mem[s..s + LANES * self.count_params].copy_from_slice(p);
+
+        k.run();
+
+        let s = LANES * self.first_diff;
+        du.copy_from_slice(&k.mem()[s..s + LANES * self.count_diffs]);
+    }
+
+    // the Jacobian-vector product J·v in two calls without forming J,
+    // e.g., for matrix-free (Krylov) solvers; see finite_jvp for the accuracy
+    pub fn jvp(&mut self, out: &mut [f64], u: &[f64], p: &[f64], t: f64, v: &[f64]) {
+        assert_eq!(v.len(), u.len(), "direction size mismatch");
+        finite_jvp(self, out, u, p, t, v);
+    }
+
+    // generates a C header declaring the kernel ABI, i.e.,
+    // void symbol(double *mem) plus the memory layout
+    pub fn c_header(&self, symbol: &str) -> String {
+        let mut h = String::new();
+        let guard = format!("{}_H", symbol.to_uppercase());
+
+        let _ = writeln!(h, "/* generated by cell, do not edit */");
+        let _ = writeln!(h, "#ifndef {}", guard);
+        let _ = writeln!(h, "#define {}\n", guard);
+
+        let _ = writeln!(h, "#define N_STATES {}", self.count_states);
+        let _ = writeln!(h, "#define N_PARAMS {}", self.count_params);
+        let _ = writeln!(h, "#define N_OBS {}", self.count_obs);
+        let _ = writeln!(h, "#define N_DIFFS {}\n", self.count_diffs);
+
+        let _ = writeln!(h, "#define IDX_T {}", self.first_state - 1);
+        let _ = writeln!(h, "#define FIRST_STATE {}", self.first_state);
+        let _ = writeln!(h, "#define FIRST_PARAM {}", self.first_param);
+        let _ = writeln!(h, "#define FIRST_OBS {}", self.first_obs);
+        let _ = writeln!(h, "#define FIRST_DIFF {}", self.first_diff);
+        let _ = writeln!(h, "#define MEM_SIZE {}\n", self.prog.frame.words.len());
+
+        let _ = writeln!(h, "/*");
+        let _ = writeln!(h, " * mem layout (index: name)");
+
+        for (i, w) in self.prog.frame.words.iter().enumerate() {
+            let _ = match w {
+                WordType::Var(s) => writeln!(h, " *   {}: {} (independent variable)", i, s),
+                WordType::State(s, _) => writeln!(h, " *   {}: {} (state)", i, s),
+                WordType::Param(s, _) => writeln!(h, " *   {}: {} (param)", i, s),
+                WordType::Input(s, _) => writeln!(h, " *   {}: {} (input)", i, s),
+                WordType::Obs(s) => writeln!(h, " *   {}: {} (obs)", i, s),
+                WordType::Diff(s) => writeln!(h, " *   {}: δ{} (diff)", i, s),
+                _ => Ok(()),
+            };
+        }
+
+        let _ = writeln!(h, " * the rest of mem holds constants and intermediates");
+        let _ = writeln!(h, " */\n");
+
+        let _ = writeln!(h, "void {}(double *mem);\n", symbol);
+        let _ = writeln!(h, "#endif");
+
+        h
+    }
+
+    pub fn emit_c_header(&self, path: &str, symbol: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.c_header(symbol))
+    }
+}
+
+impl<T: Real> std::fmt::Display for Runnable<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let count_instructions = self
+            .prog
+            .code
+            .iter()
+            .filter(|c| {
+                matches!(
+                    c,
+                    Instruction::Unary { .. }
+                        | Instruction::Binary { .. }
+                        | Instruction::IfElse { .. }
+                )
+            })
+            .count();
+
+        write!(
+            f,
+            "Runnable{{{}, {} states, {} params, {} obs, {} diffs, {} instructions}}",
+            self.ty,
+            self.count_states,
+            self.count_params,
+            self.count_obs,
+            self.count_diffs,
+            count_instructions
+        )
+    }
+}
+
+impl<T: Real> Callable<T> for Runnable<T> {
     // call interface to Julia ODESolver
-    fn call(&mut self, du: &mut [f64], u: &[f64], p: &[f64], t: f64) {
-        {
+    fn call(&mut self, du: &mut [T], u: &[T], p: &[T], t: f64) {
+        let changed = {
             let mem = self.compiled.mem_mut();
-            mem[self.first_state - 1] = t;
+            mem[self.first_state - 1] = T::from_f64(t);
             let _ =
                 &mut mem[self.first_state..self.first_state + self.count_states].copy_from_slice(u);
-            let _ =
-                &mut mem[self.first_param..self.first_param + self.count_params].copy_from_slice(p);
+            let params = &mut mem[self.first_param..self.first_param + self.count_params];
+            let changed = params != p;
+            params.copy_from_slice(p);
+            changed
+        };
+
+        if changed {
+            self.compiled.invalidate();
         }
 
         self.compiled.run();
@@ -110,22 +685,1470 @@ impl Callable for Runnable {
             let _ = du.copy_from_slice(&mem[self.first_diff..self.first_diff + self.count_diffs]);
         }
     }
+
+    fn set_inputs(&mut self, inputs: &[T]) {
+        assert_eq!(inputs.len(), self.count_inputs, "input size mismatch");
+        for (i, val) in inputs.iter().enumerate() {
+            self.set_input(i, *val);
+        }
+    }
+
+    fn observe(&mut self, u: &[T], p: &[T], t: f64) -> Vec<T> {
+        let mut obs = vec![T::default(); self.count_obs];
+        if self.count_obs > 0 {
+            self.run_obs(&mut obs, u, p, t);
+        }
+        obs
+    }
+
+    fn jacobian(&mut self, jac: &mut [T], u: &[T], p: &[T], t: f64) {
+        match &mut self.jac {
+            Some(j) => j.run_obs(jac, u, p, t),
+            None => finite_jacobian(self, jac, u, p, t),
+        }
+    }
+
+    fn has_jacobian(&self) -> bool {
+        self.jac.is_some()
+    }
     
     // call interface to Python scipy ode solver    
-    fn call_py(&mut self, du: &mut [f64], u: &[f64], t: f64) {
-        {
-            let mem = self.compiled.mem_mut();
-            mem[self.first_state - 1] = t;
+    fn call_py(&mut self, du: &mut [T], u: &[T], t: f64) {
+        let k = match &mut self.obs_kernel {
+            Some(k) => k,
+            None => &mut self.compiled,
+        };
+
+        // u is the states followed by the params, which are compared as in call
+        let changed = {
+            let mem = k.mem_mut();
+            mem[self.first_state - 1] = T::from_f64(t);
+            let changed = mem[self.first_param..self.first_param + self.count_params]
+                != u[self.count_states..];
             let _ =
                 &mut mem[self.first_state..self.first_state + self.count_states + self.count_params]
                     .copy_from_slice(u);
+            changed
+        };
+
+        if changed {
+            k.invalidate();
         }
 
-        self.compiled.run();
+        k.run();
 
         {
-            let mem = self.compiled.mem();
+            let mem = k.mem();
             let _ = du.copy_from_slice(&mem[self.first_obs..self.first_obs + self.count_obs]);
         }
     }
 }
+
+#[test]
+fn test_call_py_params() {
+    use crate::model::CellModel;
+
+    // exp(k) in y = exp(k) * x depends only on the param, so the bytecode caches it
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 0.5}],
+        "states": [{"name": "x", "val": 1.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Var", "name": "x"}
+        }],
+        "obs": [{"lhs": {"type": "Var", "name": "y"}, "rhs": {"type": "Tree", "op": "times", "args": [
+            {"type": "Tree", "op": "exp", "args": [{"type": "Var", "name": "k"}]}, {"type": "Var", "name": "x"}
+        ]}}]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let mut obs = vec![0.0];
+
+        r.call_py(&mut obs, &[1.0, 0.5], 0.0);
+        assert_eq!(obs[0], f64::exp(0.5), "{}", ty);
+        r.call_py(&mut obs, &[1.0, 2.0], 0.0);
+        assert_eq!(obs[0], f64::exp(2.0), "{}", ty);
+    }
+}
+
+#[test]
+fn test_lookup() {
+    use crate::model::CellModel;
+
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 1.0}],
+        "states": [{"name": "v", "val": 0.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "v"}]},
+            "rhs": {"type": "Tree", "op": "times", "args": [
+                {"type": "Var", "name": "k"},
+                {"type": "Tree", "op": "lookup", "args": [
+                    {"type": "Var", "name": "v"}, {"type": "Const", "val": 2.0}
+                ]}
+            ]}
+        }],
+        "obs": [{"lhs": {"type": "Var", "name": "w"}, "rhs": {"type": "Var", "name": "v"}}]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    r.set_lookup(2, vec![0.0, 1.0, 2.0], vec![0.0, 10.0, 40.0]);
+
+    let mut du = vec![0.0];
+    let p = r.params();
+
+    for (v, y) in [(0.5, 5.0), (1.5, 25.0), (2.0, 40.0), (-1.0, 0.0), (3.0, 40.0)] {
+        r.call(&mut du, &[v], &p, 0.0);
+        assert_eq!(du[0], y);
+    }
+
+    // the symbolic Jacobian uses the slope of the same table
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode).with_jacobian();
+    r.set_lookup(2, vec![0.0, 1.0, 2.0], vec![0.0, 10.0, 40.0]);
+    let mut jac = vec![0.0];
+
+    for (v, s) in [(0.5, 10.0), (1.5, 30.0), (-1.0, 0.0), (3.0, 0.0)] {
+        r.jacobian(&mut jac, &[v], &p, 0.0);
+        assert_eq!(jac[0], s);
+    }
+
+    let ty = CompilerType::Amd { optimize: false };
+    assert_eq!(
+        Runnable::try_new(Program::new(&ml), ty).err(),
+        Some(CompileError::Unsupported {
+            feature: "lookup tables".to_string(),
+            ty
+        })
+    );
+}
+
+#[test]
+fn test_inputs() {
+    use crate::model::CellModel;
+    use crate::solvers::{Euler, Forced};
+
+    // a leaky integrator driven by a stimulus, dv/dt = I - k v
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 2.0}],
+        "states": [{"name": "v", "val": 0.0}],
+        "inputs": [{"name": "I", "val": 0.5}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "v"}]},
+            "rhs": {"type": "Tree", "op": "minus", "args": [
+                {"type": "Var", "name": "I"},
+                {"type": "Tree", "op": "times", "args": [
+                    {"type": "Var", "name": "k"}, {"type": "Var", "name": "v"}
+                ]}
+            ]}
+        }],
+        "obs": [{"lhs": {"type": "Var", "name": "w"}, "rhs": {"type": "Var", "name": "I"}}]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    assert!(ml.validate().is_empty());
+
+    for ty in [CompilerType::ByteCode, CompilerType::Amd { optimize: true }] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        assert_eq!(r.count_inputs, 1);
+        let p = r.params();
+        let mut du = vec![0.0];
+
+        r.call(&mut du, &[1.0], &p, 0.0);
+        assert_eq!(du[0], 0.5 - 2.0);
+
+        // the input persists across calls
+        r.set_input(0, 3.0);
+        r.call(&mut du, &[1.0], &p, 0.0);
+        assert_eq!(du[0], 3.0 - 2.0);
+        r.call(&mut du, &[0.0], &p, 0.0);
+        assert_eq!(du[0], 3.0);
+        assert_eq!(r.observe(&[0.0], &p, 0.0), [3.0]);
+
+        // a unit pulse in [0, 1), integrated by hand alongside
+        let dt = 0.01;
+        let pulse = |t: f64| vec![if t < 1.0 { 1.0 } else { 0.0 }];
+        let mut f = Forced::new(&mut r, pulse);
+        let sol = Euler::new(dt, 1)
+            .solve(&mut f, vec![0.0], p.clone(), 0.0..2.0)
+            .unwrap();
+
+        let mut v = 0.0;
+        for row in sol.iter() {
+            assert_eq!(row.x[0], v);
+            assert_eq!(row.obs[0], pulse(row.t)[0]);
+            v += (pulse(row.t)[0] - 2.0 * v) * dt;
+        }
+    }
+}
+
+#[test]
+fn test_interp1() {
+    use crate::model::CellModel;
+
+    // δy = T(t), where the grid of T is not sorted and its values are not
+    // monotonic, and the observable z = S(t) has a monotonic grid
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [],
+        "states": [{"name": "y", "val": 0.0}],
+        "tables": [
+            {"name": "T", "xs": [2.0, 0.0, 3.0, 1.0], "ys": [40.0, 0.0, -20.0, 10.0]},
+            {"name": "S", "xs": [0.0, 4.0], "ys": [1.0, 3.0]}
+        ],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "y"}]},
+            "rhs": {"type": "Tree", "op": "interp1", "args": [{"type": "Var", "name": "T"}, {"type": "Var", "name": "t"}]}
+        }],
+        "obs": [{
+            "lhs": {"type": "Var", "name": "z"},
+            "rhs": {"type": "Tree", "op": "interp1", "args": [{"type": "Var", "name": "S"}, {"type": "Var", "name": "t"}]}
+        }]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    assert!(ml.validate().is_empty());
+
+    let types = [
+        CompilerType::ByteCode,
+        CompilerType::Native,
+        #[cfg(feature = "wasm")]
+        CompilerType::Wasm,
+        #[cfg(feature = "cranelift")]
+        CompilerType::Cranelift,
+        #[cfg(feature = "c")]
+        CompilerType::C,
+    ];
+
+    // out-of-range times are clamped to the endpoints
+    let expected = [
+        (-1.0, 0.0),
+        (0.5, 5.0),
+        (1.5, 25.0),
+        (2.5, 10.0),
+        (9.0, -20.0),
+    ];
+
+    for ty in types {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let mut du = vec![0.0];
+
+        for (t, y) in expected {
+            r.call(&mut du, &[0.0], &[], t);
+            assert_eq!(du[0], y, "{} at t = {}", ty, t);
+            assert_eq!(r.observe(&[0.0], &[], t), [1.0 + 0.5 * t.clamp(0.0, 4.0)]);
+        }
+    }
+
+    // the Jacobian of δy = T(y) is the slope of the segment containing y
+    let text = text.replace(
+        r#""name": "T"}, {"type": "Var", "name": "t"}"#,
+        r#""name": "T"}, {"type": "Var", "name": "y"}"#,
+    );
+    let ml = CellModel::load(&text).unwrap();
+    let slopes = [(-1.0, 0.0), (0.5, 10.0), (1.5, 30.0), (2.5, -60.0), (9.0, 0.0)];
+
+    for ty in types {
+        let mut r = Runnable::new(Program::new(&ml), ty).with_jacobian();
+        let mut jac = vec![0.0];
+
+        for (y, s) in slopes {
+            r.jacobian(&mut jac, &[y], &[], 0.0);
+            assert_eq!(jac[0], s, "{} at y = {}", ty, y);
+        }
+    }
+}
+
+#[test]
+fn test_try_new() {
+    use crate::model::CellModel;
+
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 1.0}],
+        "states": [],
+        "algs": [],
+        "odes": [],
+        "obs": [{"lhs": {"type": "Var", "name": "w"}, "rhs": {"type": "Var", "name": "k"}}]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let e = Runnable::try_new(Program::new(&ml), CompilerType::ByteCode).err();
+    assert_eq!(e, Some(CompileError::NoStates));
+    assert_eq!(e.unwrap().to_string(), "the model has no states");
+}
+
+#[test]
+fn test_c_header() {
+    use crate::model::CellModel;
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+
+    let path = std::env::temp_dir().join(format!("beeler_{}.h", std::process::id()));
+    let path = path.to_str().unwrap();
+    r.emit_c_header(path, "beeler").unwrap();
+    let h = std::fs::read_to_string(path).unwrap();
+    let _ = std::fs::remove_file(path);
+
+    assert!(h.contains(&format!("#define N_STATES {}\n", r.count_states)));
+    assert!(h.contains(&format!("#define N_PARAMS {}\n", r.count_params)));
+    assert!(h.contains("void beeler(double *mem);"));
+    assert!(h.contains("#ifndef BEELER_H"));
+}
+
+#[test]
+fn test_pool() {
+    use crate::model::CellModel;
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let p0 = Runnable::new(Program::new(&ml), CompilerType::ByteCode).params();
+
+    // Monte Carlo over initial conditions
+    let trial = |k: usize, r: &mut Runnable, pooled: bool| -> Vec<f64> {
+        let mut u = r.initial_states();
+        u[0] += k as f64;
+        let mut du = vec![0.0; u.len()];
+        let mut mem = r.replicate();
+
+        for i in 0..20 {
+            if pooled {
+                r.call_replica(&mut mem, &mut du, &u, &p0, i as f64 * 0.01);
+            } else {
+                r.call(&mut du, &u, &p0, i as f64 * 0.01);
+            }
+            for j in 0..u.len() {
+                u[j] += 0.01 * du[j];
+            }
+        }
+
+        r.recycle(mem);
+        u
+    };
+
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+
+    for k in 0..10 {
+        let mut fresh = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+        assert_eq!(trial(k, &mut r, true), trial(k, &mut fresh, false));
+    }
+
+    assert_eq!(r.pool.allocated(), 1);
+    assert_eq!(r.pool.reused(), 9);
+
+    // compared bitwise since the abs mask constant is a NaN
+    let bits = |m: &[f64]| m.iter().map(|x| x.to_bits()).collect::<Vec<u64>>();
+
+    // a recycled buffer is reset to the frame defaults, whatever it held
+    let mut mem = r.replicate();
+    mem.fill(1e300);
+    r.recycle(mem);
+    let mem = r.replicate();
+    assert_eq!(r.pool.reused(), 11);
+    assert_eq!(bits(&mem), bits(&r.mem0));
+    r.recycle(mem);
+
+    // reset swaps in a pooled buffer and recycles the old mem
+    r.compiled.mem_mut().fill(1e300);
+    r.reset();
+    assert_eq!(r.pool.reused(), 12);
+    assert_eq!(bits(r.compiled.mem()), bits(&r.mem0));
+
+    let mem = r.replicate();
+    assert_eq!(r.pool.allocated(), 1);
+    assert_eq!(bits(&mem), bits(&r.mem0));
+}
+
+#[test]
+fn test_reset() {
+    use crate::model::CellModel;
+    use crate::solvers::{Euler, Solver};
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let alg = Euler::new(0.01, 100);
+
+    let run = |r: &mut Runnable| {
+        let (u0, p) = (r.initial_states(), r.params());
+        let sol = alg.solve(r, u0, p, 0.0..10.0).unwrap();
+        let xs: Vec<_> = sol.iter().map(|row| row.x.clone()).collect();
+        (xs, r.compiled.mem().to_vec())
+    };
+
+    // back-to-back solves
+    let (sol1, _) = run(&mut r);
+    r.reset();
+    let (sol2, mem) = run(&mut r);
+    assert_eq!(sol1, sol2);
+
+    // the last integration state is discarded
+    let k = r.first_state;
+    assert_ne!(mem[k], r.u0[0]);
+    r.reset();
+    assert_eq!(r.compiled.mem()[k], r.u0[0]);
+
+    // new initial conditions and params, as a fresh model with the same values
+    let mut u0 = r.initial_states();
+    let mut p = r.params();
+    u0[0] *= 0.5;
+    p[0] *= 2.0;
+    r.reset_with(&u0, &p);
+
+    let mut fresh = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    fresh.reset_with(&u0, &p);
+    let bits = |m: &[f64]| m.iter().map(|x| x.to_bits()).collect::<Vec<u64>>();
+    assert_eq!(bits(r.compiled.mem()), bits(fresh.compiled.mem()));
+    assert_eq!(run(&mut r).0, run(&mut fresh).0);
+    assert_ne!(run(&mut r).0, sol1);
+}
+
+#[test]
+fn test_display() {
+    use crate::model::CellModel;
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let s = r.to_string();
+
+    assert!(s.starts_with("Runnable{bytecode, 8 states, "));
+    assert!(s.contains(&format!(" {} params, ", ml.params.len())));
+    assert!(s.contains(&format!(" {} obs, ", ml.obs.len())));
+    assert!(s.contains(" 8 diffs, "));
+    assert!(s.ends_with(" instructions}"));
+}
+
+#[test]
+fn test_split_kernels() {
+    use crate::interpreter::Interpreter;
+    use crate::model::{CellModel, Options};
+
+    // δx = -z with obs z = k * x (feeds the diff) and y = exp(x) * sin(x) (obs-only)
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 2.0}],
+        "states": [{"name": "x", "val": 0.5}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "neg", "args": [{"type": "Var", "name": "z"}]}
+        }],
+        "obs": [
+            {"lhs": {"type": "Var", "name": "z"}, "rhs": {"type": "Tree", "op": "times", "args": [
+                {"type": "Var", "name": "k"}, {"type": "Var", "name": "x"}
+            ]}},
+            {"lhs": {"type": "Var", "name": "y"}, "rhs": {"type": "Tree", "op": "times", "args": [
+                {"type": "Tree", "op": "exp", "args": [{"type": "Var", "name": "x"}]},
+                {"type": "Tree", "op": "sin", "args": [{"type": "Var", "name": "x"}]}
+            ]}}
+        ]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let prog = Program::new(&ml);
+    let (diffs, obs) = prog.split();
+    let first_diff = prog.frame.first_diff().unwrap();
+
+    let mut full = Interpreter::new().compile(&prog);
+    let mut kd = Interpreter::new().compile(&diffs);
+    let mut ko = Interpreter::new().compile(&obs);
+    full.run();
+    kd.run();
+    ko.run();
+
+    // the diff kernel skips exp, sin, and their product (coalesced with the mov into y) but keeps z
+    assert_eq!(kd.executed(), full.executed() - 3);
+    assert_eq!(kd.mem()[first_diff], full.mem()[first_diff]);
+    assert!(ko.executed() < full.executed());
+
+    let opts = Options {
+        split_kernels: true,
+        ..Options::default()
+    };
+    let mut r = Runnable::new(Program::with_options(&ml, opts), CompilerType::ByteCode);
+    assert!(r.obs_kernel.is_some());
+
+    let p = r.params();
+    let mut du = vec![0.0];
+    let mut o = vec![0.0; 2];
+
+    r.run_diffs(&mut du, &[0.25], &p, 0.0);
+    assert_eq!(du[0], -0.5);
+
+    r.run_obs(&mut o, &[0.25], &p, 0.0);
+    assert_eq!(o, vec![0.5, f64::exp(0.25) * f64::sin(0.25)]);
+}
+
+#[test]
+fn test_sweep() {
+    use crate::model::CellModel;
+    use crate::solvers::RK4;
+
+    let text = std::fs::read_to_string("julia/lorenz.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+
+    let p0 = r.params();
+    let mut p1 = p0.clone();
+    p1[0] *= 1.5;
+
+    let alg = RK4::new(0.01, 10);
+    let sols = r.sweep(&[p0.clone(), p1.clone()], &alg, 0.0..1.0).unwrap();
+
+    // each run matches a fresh model, regardless of the order
+    let rev = r.sweep(&[p1.clone(), p0.clone()], &alg, 0.0..1.0).unwrap();
+
+    for (p, sol) in [(&p0, &sols[0]), (&p1, &sols[1])] {
+        let mut fresh = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+        let u0 = fresh.initial_states();
+        let expected = alg.solve(&mut fresh, u0, p.clone(), 0.0..1.0).unwrap();
+        assert_eq!(sol.last().unwrap().x, expected.last().unwrap().x);
+    }
+
+    assert_eq!(sols[0].last().unwrap().x, rev[1].last().unwrap().x);
+    assert_ne!(sols[0].last().unwrap().x, sols[1].last().unwrap().x);
+}
+
+#[test]
+fn test_fork() {
+    use crate::model::CellModel;
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r1 = Runnable::new(Program::new(&ml), ty);
+        let mut r2 = r1.fork();
+
+        let u = r1.initial_states();
+        let p = r1.params();
+        let mut du1 = vec![0.0; u.len()];
+        let mut du2 = vec![0.0; u.len()];
+
+        r1.call(&mut du1, &u, &p, 0.0);
+        r2.call(&mut du2, &u, &p, 0.0);
+        assert_eq!(du1, du2);
+
+        // the forks do not share mem
+        r2.compiled.mem_mut()[r2.first_state] = 1.0;
+        assert_ne!(r1.compiled.mem()[r1.first_state], 1.0);
+        drop(r2);
+
+        // the shared code outlives the fork
+        r1.call(&mut du2, &u, &p, 0.0);
+        assert_eq!(du1, du2);
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_sweep_par() {
+    use crate::model::CellModel;
+    use crate::solvers::RK4;
+
+    let text = std::fs::read_to_string("julia/lorenz.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::Native);
+
+    let param_sets: Vec<Vec<f64>> = (0..10)
+        .map(|i| {
+            let mut p = r.params();
+            p[0] *= 1.0 + 0.1 * i as f64;
+            p
+        })
+        .collect();
+
+    let alg = RK4::new(0.01, 10);
+    let par = r.sweep_par(&param_sets, &alg, 0.0..1.0).unwrap();
+    let seq = r.sweep(&param_sets, &alg, 0.0..1.0).unwrap();
+
+    assert_eq!(par.len(), seq.len());
+    for (a, b) in par.iter().zip(seq.iter()) {
+        assert_eq!(a.last().unwrap().x, b.last().unwrap().x);
+    }
+}
+
+#[test]
+fn test_row_obs() {
+    use crate::model::CellModel;
+    use crate::solvers::Euler;
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+
+    let u0 = r.initial_states();
+    let p = r.params();
+    let sol = Euler::new(0.01, 10)
+        .solve(&mut r, u0.clone(), p.clone(), 0.0..1.0)
+        .unwrap();
+
+    let mut obs = vec![0.0; r.count_obs];
+    r.run_obs(&mut obs, &u0, &p, 0.0);
+    assert!(r.count_obs > 0);
+    assert_eq!(sol[0].obs.as_ref(), &obs[..]);
+
+    let last = sol.last().unwrap();
+    r.run_obs(&mut obs, &last.x, &p, last.t);
+    assert_eq!(last.obs.as_ref(), &obs[..]);
+
+    // t, the states, and then the observables
+    let line = format!("{}", last);
+    assert_eq!(line.split('\t').count(), 1 + r.count_states + r.count_obs);
+}
+
+#[test]
+fn test_hyperbolic() {
+    use crate::model::CellModel;
+
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [],
+        "states": [{"name": "x", "val": 0.5}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "tanh", "args": [{"type": "Var", "name": "x"}]}
+        }],
+        "obs": [
+            {"lhs": {"type": "Var", "name": "s"}, "rhs": {"type": "Tree", "op": "sinh", "args": [{"type": "Var", "name": "x"}]}},
+            {"lhs": {"type": "Var", "name": "c"}, "rhs": {"type": "Tree", "op": "cosh", "args": [{"type": "Var", "name": "x"}]}}
+        ]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+
+    // native backends reach the functions through the virtual table
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+
+        let u = r.initial_states();
+        let p = r.params();
+        let mut du = vec![0.0];
+        r.call(&mut du, &u, &p, 0.0);
+        assert_eq!(du[0], 0.5f64.tanh());
+        assert_eq!(r.observe(&u, &p, 0.0), vec![0.5f64.sinh(), 0.5f64.cosh()]);
+    }
+}
+
+#[test]
+fn test_atan2() {
+    use crate::model::CellModel;
+
+    // δx = atan2(y, x) with x = -1 and y = 0.5, in the second quadrant
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "y", "val": 0.5}],
+        "states": [{"name": "x", "val": -1.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "atan2", "args": [{"type": "Var", "name": "y"}, {"type": "Var", "name": "x"}]}
+        }],
+        "obs": []
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+
+        let u = r.initial_states();
+        let p = r.params();
+        let mut du = vec![0.0];
+        r.call(&mut du, &u, &p, 0.0);
+        assert_eq!(du[0], 0.5f64.atan2(-1.0));
+    }
+}
+
+#[test]
+fn test_log2() {
+    use crate::model::CellModel;
+
+    // δx = log2(x) and δy = logb(y, b) with x = 8, y = 64, and b = 4
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "b", "val": 4.0}],
+        "states": [{"name": "x", "val": 8.0}, {"name": "y", "val": 64.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "log2", "args": [{"type": "Var", "name": "x"}]}
+        }, {
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "y"}]},
+            "rhs": {"type": "Tree", "op": "logb", "args": [{"type": "Var", "name": "y"}, {"type": "Var", "name": "b"}]}
+        }],
+        "obs": []
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+
+    let tys = [
+        CompilerType::ByteCode,
+        CompilerType::Native,
+        #[cfg(feature = "wasm")]
+        CompilerType::Wasm,
+        #[cfg(feature = "cranelift")]
+        CompilerType::Cranelift,
+        #[cfg(feature = "c")]
+        CompilerType::C,
+    ];
+
+    for ty in tys {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+
+        let u = r.initial_states();
+        let p = r.params();
+        let mut du = vec![0.0; 2];
+        r.call(&mut du, &u, &p, 0.0);
+        assert_eq!(du[0], 3.0, "{}", ty);
+        assert_eq!(du[1], 64f64.log(4.0), "{}", ty);
+    }
+}
+
+#[test]
+fn test_hypot() {
+    use crate::model::CellModel;
+
+    // δx = hypot(x, y), δy = hypot(a, a), and δz = cbrt(z) with x = 3, y = 4,
+    // a = 1e200 (squaring it overflows), and z = -27
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "a", "val": 1e200}],
+        "states": [{"name": "x", "val": 3.0}, {"name": "y", "val": 4.0}, {"name": "z", "val": -27.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "hypot", "args": [{"type": "Var", "name": "x"}, {"type": "Var", "name": "y"}]}
+        }, {
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "y"}]},
+            "rhs": {"type": "Tree", "op": "hypot", "args": [{"type": "Var", "name": "a"}, {"type": "Var", "name": "a"}]}
+        }, {
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "z"}]},
+            "rhs": {"type": "Tree", "op": "cbrt", "args": [{"type": "Var", "name": "z"}]}
+        }],
+        "obs": []
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+
+    let tys = [
+        CompilerType::ByteCode,
+        CompilerType::Native,
+        #[cfg(feature = "wasm")]
+        CompilerType::Wasm,
+        #[cfg(feature = "cranelift")]
+        CompilerType::Cranelift,
+        #[cfg(feature = "c")]
+        CompilerType::C,
+    ];
+
+    for ty in tys {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+
+        let u = r.initial_states();
+        let p = r.params();
+        let mut du = vec![0.0; 3];
+        r.call(&mut du, &u, &p, 0.0);
+        assert_eq!(du[0], 5.0, "{}", ty);
+        assert!(du[1].is_finite(), "{}", ty);
+        assert!(
+            (du[1] / 1e200 - std::f64::consts::SQRT_2).abs() < 1e-15,
+            "{}",
+            ty
+        );
+        // the glibc cbrt (used by the C backend) is off by an ulp for -27
+        assert!((du[2] + 3.0).abs() < 1e-15, "{}", ty);
+    }
+}
+
+#[test]
+fn test_erf() {
+    use crate::model::CellModel;
+
+    let ops = ["erf", "erfc", "gamma", "lgamma"];
+    let states = [1.0, 1.0, 5.0, 10.0];
+    // erf(1), erfc(1), gamma(5) = 4!, and lgamma(10) = ln(9!)
+    let expected = [
+        0.8427007929497149,
+        0.15729920705028513,
+        24.0,
+        12.801827480081469,
+    ];
+
+    let odes: Vec<String> = ops
+        .iter()
+        .enumerate()
+        .map(|(i, op)| {
+            format!(
+                r#"{{
+                    "lhs": {{"type": "Tree", "op": "Differential", "args": [{{"type": "Var", "name": "x{}"}}]}},
+                    "rhs": {{"type": "Tree", "op": "{}", "args": [{{"type": "Var", "name": "x{}"}}]}}
+                }}"#,
+                i, op, i
+            )
+        })
+        .collect();
+    let vars: Vec<String> = states
+        .iter()
+        .enumerate()
+        .map(|(i, val)| format!(r#"{{"name": "x{}", "val": {:?}}}"#, i, val))
+        .collect();
+
+    let text = format!(
+        r#"{{
+            "iv": {{"name": "t", "val": 0.0}},
+            "params": [],
+            "states": [{}],
+            "algs": [],
+            "odes": [{}],
+            "obs": []
+        }}"#,
+        vars.join(", "),
+        odes.join(", ")
+    );
+
+    let ml = CellModel::load(&text).unwrap();
+
+    let tys = [
+        CompilerType::ByteCode,
+        CompilerType::Native,
+        #[cfg(feature = "wasm")]
+        CompilerType::Wasm,
+        #[cfg(feature = "cranelift")]
+        CompilerType::Cranelift,
+        #[cfg(feature = "c")]
+        CompilerType::C,
+    ];
+
+    for ty in tys {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+
+        let u = r.initial_states();
+        let p = r.params();
+        let mut du = vec![0.0; ops.len()];
+        r.call(&mut du, &u, &p, 0.0);
+
+        for i in 0..ops.len() {
+            let err = (du[i] - expected[i]).abs() / expected[i];
+            assert!(err < 1e-15, "{}: {} gives {}", ty, ops[i], du[i]);
+        }
+    }
+}
+
+#[test]
+fn test_clamp() {
+    use crate::model::CellModel;
+
+    // δx = clamp(x, 0, 3) and δy = clamp(y, 0, 3) with x = 5 and y = -1
+    let clamp = |v: &str| {
+        format!(
+            r#"{{"type": "Tree", "op": "clamp", "args": [
+                {{"type": "Var", "name": "{}"}}, {{"type": "Const", "val": 0.0}}, {{"type": "Const", "val": 3.0}}
+            ]}}"#,
+            v
+        )
+    };
+    let text = format!(
+        r#"{{
+        "iv": {{"name": "t", "val": 0.0}},
+        "params": [],
+        "states": [{{"name": "x", "val": 5.0}}, {{"name": "y", "val": -1.0}}],
+        "algs": [],
+        "odes": [{{
+            "lhs": {{"type": "Tree", "op": "Differential", "args": [{{"type": "Var", "name": "x"}}]}},
+            "rhs": {}
+        }}, {{
+            "lhs": {{"type": "Tree", "op": "Differential", "args": [{{"type": "Var", "name": "y"}}]}},
+            "rhs": {}
+        }}],
+        "obs": []
+    }}"#,
+        clamp("x"),
+        clamp("y")
+    );
+
+    let ml = CellModel::load(&text).unwrap();
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+
+        let u = r.initial_states();
+        let p = r.params();
+        let mut du = vec![0.0; 2];
+        r.call(&mut du, &u, &p, 0.0);
+        assert_eq!(du, vec![3.0, 0.0], "{}", ty);
+    }
+}
+
+#[test]
+fn test_abs() {
+    use crate::model::CellModel;
+
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [],
+        "states": [{"name": "x", "val": -1.5}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "abs", "args": [{"type": "Var", "name": "x"}]}
+        }],
+        "obs": []
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+
+    // native backends mask the sign bit inline instead of calling out
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let p = r.params();
+        let mut du = vec![0.0];
+
+        for x in [-1.5, 2.0, -0.0] {
+            r.call(&mut du, &[x], &p, 0.0);
+            assert_eq!(du[0].to_bits(), f64::abs(x).to_bits());
+        }
+    }
+}
+
+#[test]
+fn test_signum() {
+    use crate::model::CellModel;
+
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [],
+        "states": [{"name": "x", "val": -1.5}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "signum", "args": [{"type": "Var", "name": "x"}]}
+        }],
+        "obs": []
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+
+    let tys = [
+        CompilerType::ByteCode,
+        CompilerType::Native,
+        #[cfg(feature = "wasm")]
+        CompilerType::Wasm,
+        #[cfg(feature = "cranelift")]
+        CompilerType::Cranelift,
+        #[cfg(feature = "c")]
+        CompilerType::C,
+    ];
+
+    // both zeros give +0.0, unlike f64::signum
+    let cases = [
+        (-1.5, -1.0),
+        (2.0, 1.0),
+        (0.0, 0.0),
+        (-0.0, 0.0),
+        (-1e-300, -1.0),
+    ];
+
+    for ty in tys {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let p = r.params();
+        let mut du = vec![0.0];
+
+        for (x, s) in cases {
+            r.call(&mut du, &[x], &p, 0.0);
+            assert_eq!(du[0].to_bits(), f64::to_bits(s), "{}: signum({})", ty, x);
+        }
+
+        r.call(&mut du, &[f64::NAN], &p, 0.0);
+        assert!(du[0].is_nan(), "{}", ty);
+    }
+}
+
+#[test]
+fn test_min_max() {
+    use crate::code::Code;
+    use crate::model::CellModel;
+
+    // δx = min(x, k) and δy = max(y, k)
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 1.0}],
+        "states": [{"name": "x", "val": 0.0}, {"name": "y", "val": 0.0}],
+        "algs": [],
+        "odes": [
+            {
+                "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+                "rhs": {"type": "Tree", "op": "min", "args": [{"type": "Var", "name": "x"}, {"type": "Var", "name": "k"}]}
+            },
+            {
+                "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "y"}]},
+                "rhs": {"type": "Tree", "op": "max", "args": [{"type": "Var", "name": "y"}, {"type": "Var", "name": "k"}]}
+            }
+        ],
+        "obs": []
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let same = |a: f64, b: f64| a == b || (a.is_nan() && b.is_nan());
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let mut du = vec![0.0; 2];
+
+        for (u, k) in [(0.5, 1.0), (2.0, 1.0), (f64::NAN, 1.0), (0.5, f64::NAN)] {
+            r.call(&mut du, &[u, u], &[k], 0.0);
+            assert!(same(du[0], Code::min(u, k)), "{} min({}, {})", ty, u, k);
+            assert!(same(du[1], Code::max(u, k)), "{} max({}, {})", ty, u, k);
+        }
+    }
+
+    assert!(Code::min(f64::NAN, 1.0).is_nan());
+    assert_eq!(Code::max(-1.0, 2.0), 2.0);
+}
+
+#[test]
+fn test_symbolic_jacobian() {
+    use crate::model::CellModel;
+    use crate::solvers::BackwardEuler;
+
+    // δx = a * x - x * y with the alg a ~ sin(k) - x^2 and
+    // δy = z - exp(y) / x with the obs z = tanh(x * y)
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 0.3}],
+        "states": [{"name": "x", "val": 1.2}, {"name": "y", "val": -0.4}],
+        "algs": [{"lhs": {"type": "Var", "name": "a"}, "rhs": {"type": "Tree", "op": "minus", "args": [
+            {"type": "Tree", "op": "sin", "args": [{"type": "Var", "name": "k"}]},
+            {"type": "Tree", "op": "power", "args": [{"type": "Var", "name": "x"}, {"type": "Const", "val": 2.0}]}
+        ]}}],
+        "odes": [
+            {
+                "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+                "rhs": {"type": "Tree", "op": "minus", "args": [
+                    {"type": "Tree", "op": "times", "args": [{"type": "Var", "name": "a"}, {"type": "Var", "name": "x"}]},
+                    {"type": "Tree", "op": "times", "args": [{"type": "Var", "name": "x"}, {"type": "Var", "name": "y"}]}
+                ]}
+            },
+            {
+                "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "y"}]},
+                "rhs": {"type": "Tree", "op": "minus", "args": [
+                    {"type": "Var", "name": "z"},
+                    {"type": "Tree", "op": "divide", "args": [
+                        {"type": "Tree", "op": "exp", "args": [{"type": "Var", "name": "y"}]},
+                        {"type": "Var", "name": "x"}
+                    ]}
+                ]}
+            }
+        ],
+        "obs": [{"lhs": {"type": "Var", "name": "z"}, "rhs": {"type": "Tree", "op": "tanh", "args": [
+            {"type": "Tree", "op": "times", "args": [{"type": "Var", "name": "x"}, {"type": "Var", "name": "y"}]}
+        ]}}]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+
+    for ty in [CompilerType::ByteCode, CompilerType::Native] {
+        let mut r = Runnable::new(Program::new(&ml), ty).with_jacobian();
+        let u = r.initial_states();
+        let p = r.params();
+
+        let mut jac = vec![0.0; 4];
+        let mut fd = vec![0.0; 4];
+        r.jacobian(&mut jac, &u, &p, 0.0);
+        finite_jacobian(&mut r, &mut fd, &u, &p, 0.0);
+
+        for (a, b) in jac.iter().zip(fd.iter()) {
+            assert!((a - b).abs() < 1e-6, "{} {} vs {}", ty, a, b);
+        }
+
+        // ∂δx/∂x = sin(k) - 3x^2 - y
+        assert!((jac[0] - (0.3f64.sin() - 3.0 * 1.44 + 0.4)).abs() < 1e-12);
+
+        let alg = BackwardEuler::new(0.01, 1);
+        let s1 = alg.solve(&mut r, u.clone(), p.clone(), 0.0..1.0).unwrap();
+        let mut fd = Runnable::new(Program::new(&ml), ty);
+        let s2 = alg.solve(&mut fd, u.clone(), p.clone(), 0.0..1.0).unwrap();
+        let (x1, x2) = (&s1.last().unwrap().x, &s2.last().unwrap().x);
+        assert!((x1[0] - x2[0]).abs() < 1e-8 && (x1[1] - x2[1]).abs() < 1e-8);
+    }
+}
+
+#[test]
+fn test_model_jacobian() {
+    use crate::model::CellModel;
+
+    // both models branch on the states through ifelse and comparisons
+    for path in ["julia/beeler.json", "julia/ohara.json"] {
+        let ml = CellModel::from_path(path).unwrap();
+        let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode).with_jacobian();
+        let u = r.initial_states();
+        let p = r.params();
+        let n = u.len();
+
+        let mut jac = vec![0.0; n * n];
+        let mut fd = vec![0.0; n * n];
+        r.jacobian(&mut jac, &u, &p, 0.0);
+        finite_jacobian(&mut r, &mut fd, &u, &p, 0.0);
+
+        // the forward differences step by ~1e-8 even for states of ~1e-4,
+        // so they are only accurate to a few digits
+        for (k, (a, b)) in jac.iter().zip(fd.iter()).enumerate() {
+            assert!(
+                (a - b).abs() < 1e-3 * a.abs().max(b.abs()).max(1.0),
+                "{} [{}, {}]: {} vs {}",
+                path,
+                k / n,
+                k % n,
+                a,
+                b
+            );
+        }
+    }
+}
+
+#[test]
+fn test_jvp() {
+    use crate::model::CellModel;
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let mut r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let u = r.initial_states();
+    let p = r.params();
+    let n = u.len();
+
+    // the dense finite-difference Jacobian times v
+    let mut jac = vec![0.0; n * n];
+    finite_jacobian(&mut r, &mut jac, &u, &p, 0.0);
+    let v: Vec<f64> = (0..n).map(|i| (i as f64 + 1.0).sin()).collect();
+    let jv: Vec<f64> = (0..n)
+        .map(|i| (0..n).map(|j| jac[i * n + j] * v[j]).sum())
+        .collect();
+
+    let mut out = vec![0.0; n];
+    r.jvp(&mut out, &u, &p, 0.0, &v);
+
+    // both are finite differences, each accurate to about half of the digits
+    for i in 0..n {
+        assert!(
+            (out[i] - jv[i]).abs() < 1e-4 * jv[i].abs().max(1.0),
+            "{}: {} vs {}",
+            i,
+            out[i],
+            jv[i]
+        );
+    }
+
+    r.jvp(&mut out, &u, &p, 0.0, &vec![0.0; n]);
+    assert!(out.iter().all(|x| *x == 0.0));
+}
+
+#[test]
+fn test_booleans() {
+    use crate::code::Code;
+    use crate::model::CellModel;
+
+    let var = |s: &str| format!(r#"{{"type": "Var", "name": "{}"}}"#, s);
+    let num = |v: f64| format!(r#"{{"type": "Const", "val": {}}}"#, v);
+    let tree = |op: &str, args: &[String]| {
+        format!(
+            r#"{{"type": "Tree", "op": "{}", "args": [{}]}}"#,
+            op,
+            args.join(", ")
+        )
+    };
+    let (x, y) = (var("x"), var("y"));
+
+    // ifelse(x > y || (x < 0 ⊻ y < 0),
+    //        ifelse(x >= 0 && y != 1, x * y, -x),
+    //        ifelse(x == y, 1, y - x))
+    let cond = tree(
+        "or",
+        &[
+            tree("gt", &[x.clone(), y.clone()]),
+            tree(
+                "xor",
+                &[
+                    tree("lt", &[x.clone(), num(0.0)]),
+                    tree("lt", &[y.clone(), num(0.0)]),
+                ],
+            ),
+        ],
+    );
+    let inner1 = tree(
+        "ifelse",
+        &[
+            tree(
+                "and",
+                &[
+                    tree("geq", &[x.clone(), num(0.0)]),
+                    tree("neq", &[y.clone(), num(1.0)]),
+                ],
+            ),
+            tree("times", &[x.clone(), y.clone()]),
+            tree("neg", std::slice::from_ref(&x)),
+        ],
+    );
+    let inner2 = tree(
+        "ifelse",
+        &[
+            tree("eq", &[x.clone(), y.clone()]),
+            num(1.0),
+            tree("minus", &[y.clone(), x.clone()]),
+        ],
+    );
+    let rhs = tree("ifelse", &[cond, inner1, inner2]);
+
+    let text = format!(
+        r#"{{
+            "iv": {{"name": "t", "val": 0.0}},
+            "params": [{{"name": "y", "val": 0.0}}],
+            "states": [{{"name": "x", "val": 0.0}}],
+            "algs": [],
+            "odes": [{{
+                "lhs": {{"type": "Tree", "op": "Differential", "args": [{}]}},
+                "rhs": {}
+            }}],
+            "obs": [
+                {{"lhs": {}, "rhs": {}}},
+                {{"lhs": {}, "rhs": {}}}
+            ]
+        }}"#,
+        x,
+        rhs,
+        var("b"),
+        tree("leq", &[x.clone(), y.clone()]),
+        var("c"),
+        tree(
+            "and",
+            &[
+                tree("gt", &[x.clone(), num(0.0)]),
+                tree("lt", &[y.clone(), num(0.0)])
+            ]
+        )
+    );
+
+    let f = |x: f64, y: f64| {
+        if x > y || ((x < 0.0) ^ (y < 0.0)) {
+            if x >= 0.0 && y != 1.0 {
+                x * y
+            } else {
+                -x
+            }
+        } else if x == y {
+            1.0
+        } else {
+            y - x
+        }
+    };
+
+    let ml = CellModel::load(&text).unwrap();
+    let vals = [-2.0, -0.0, 0.0, 1.0, 3.0, f64::NAN];
+
+    let tys = [
+        CompilerType::ByteCode,
+        CompilerType::Native,
+        #[cfg(feature = "wasm")]
+        CompilerType::Wasm,
+        #[cfg(feature = "cranelift")]
+        CompilerType::Cranelift,
+        #[cfg(feature = "c")]
+        CompilerType::C,
+    ];
+
+    for ty in tys {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let mut du = vec![0.0];
+        let mut obs = vec![0.0; 2];
+
+        for x in vals {
+            for y in vals {
+                r.call(&mut du, &[x], &[y], 0.0);
+                assert_eq!(du[0].to_bits(), f(x, y).to_bits(), "{} {} {}", ty, x, y);
+
+                r.run_obs(&mut obs, &[x], &[y], 0.0);
+                assert_eq!(obs[0].to_bits(), Code::boolean(x <= y).to_bits());
+                assert_eq!(
+                    obs[1].to_bits(),
+                    Code::boolean(x > 0.0 && y < 0.0).to_bits()
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_single_precision() {
+    use crate::model::CellModel;
+    use crate::solvers::{Euler, Solver};
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+    let mut r1 = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let mut r2 = Runnable::new_as::<f32>(Program::new(&ml), CompilerType::ByteCode);
+
+    let u = r1.initial_states();
+    let p = r1.params();
+    let u2 = r2.initial_states();
+    let p2 = r2.params();
+    assert_eq!(u2, u.iter().map(|x| *x as f32).collect::<Vec<f32>>());
+
+    let mut du1 = vec![0.0; u.len()];
+    let mut du2 = vec![0.0f32; u.len()];
+    r1.call(&mut du1, &u, &p, 0.0);
+    r2.call(&mut du2, &u2, &p2, 0.0);
+
+    // the derivatives lose a few more digits to cancellation
+    for (x, y) in du1.iter().zip(du2.iter()) {
+        let y = *y as f64;
+        assert!((x - y).abs() <= 1e-4 * x.abs().max(1e-3), "{} {}", x, y);
+    }
+
+    // and the trajectories, stepped in f32 as well, drift from the f64 ones,
+    // mostly during the upstroke
+    let alg = Euler::new(0.01, 100);
+    let sol1 = alg
+        .solve(&mut r1, u.clone(), p.clone(), 0.0..100.0)
+        .unwrap();
+    let sol2 = alg.solve(&mut r2, u2, p2, 0.0..100.0).unwrap();
+    let mut err: f64 = 0.0;
+
+    assert_eq!(sol1.len(), sol2.len());
+    for (a, b) in sol1.iter().zip(sol2.iter()) {
+        for (x, y) in a.x.iter().zip(b.x.iter()) {
+            err = err.max((x - *y as f64).abs() / x.abs().max(1.0));
+        }
+    }
+    // about 4e-3 at the upstroke
+    assert!(err < 1e-2, "{}", err);
+
+    // the native backends are f64 only
+    let e = Runnable::try_new_as::<f32>(Program::new(&ml), CompilerType::Native).err();
+    assert!(matches!(e, Some(CompileError::Unsupported { .. })));
+}
+
+/*
+    differential test of the backends: each model is compiled with every
+    enabled backend and du is compared to the bytecode one at random states,
+    params, and times. A model exercising a new op belongs in the list.
+*/
+#[test]
+fn test_native_obs() {
+    use crate::model::CellModel;
+
+    // y and z are computed in registers and consumed by nothing but observe
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "k", "val": 0.5}],
+        "states": [{"name": "x", "val": 1.5}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "neg", "args": [{"type": "Var", "name": "x"}]}
+        }],
+        "obs": [
+            {"lhs": {"type": "Var", "name": "y"}, "rhs": {"type": "Tree", "op": "plus", "args": [
+                {"type": "Tree", "op": "times", "args": [{"type": "Var", "name": "x"}, {"type": "Var", "name": "x"}]},
+                {"type": "Var", "name": "k"}
+            ]}},
+            {"lhs": {"type": "Var", "name": "z"}, "rhs": {"type": "Tree", "op": "sin", "args": [{"type": "Var", "name": "x"}]}}
+        ]
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+
+    let types = [
+        CompilerType::Native,
+        #[cfg(target_arch = "x86_64")]
+        CompilerType::Amd { optimize: false },
+        #[cfg(target_arch = "x86_64")]
+        CompilerType::Amd { optimize: true },
+        #[cfg(target_arch = "aarch64")]
+        CompilerType::Arm,
+    ];
+
+    for ty in types {
+        let mut r = Runnable::new(Program::new(&ml), ty);
+        let p = r.params();
+        assert_eq!(r.observe(&[1.5], &p, 0.0), vec![2.75, f64::sin(1.5)], "{}", ty);
+        assert_eq!(r.observe(&[-2.0], &p, 0.0), vec![4.5, f64::sin(-2.0)], "{}", ty);
+    }
+}
+
+#[test]
+fn test_differential() {
+    use crate::model::CellModel;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    let types = [
+        CompilerType::Native,
+        #[cfg(target_arch = "x86_64")]
+        CompilerType::Amd { optimize: false },
+        #[cfg(target_arch = "x86_64")]
+        CompilerType::Amd { optimize: true },
+        #[cfg(target_arch = "aarch64")]
+        CompilerType::Arm,
+        #[cfg(feature = "wasm")]
+        CompilerType::Wasm,
+        #[cfg(feature = "cranelift")]
+        CompilerType::Cranelift,
+        #[cfg(feature = "c")]
+        CompilerType::C,
+    ];
+
+    let mut rng = StdRng::seed_from_u64(1575);
+
+    for name in ["beeler", "ohara", "tentusscher", "lorenz"] {
+        let text = std::fs::read_to_string(format!("julia/{}.json", name)).unwrap();
+        let ml = CellModel::load(&text).unwrap();
+        let mut r0 = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+        let mut rs: Vec<Runnable> = types
+            .iter()
+            .map(|ty| Runnable::new(Program::new(&ml), *ty))
+            .collect();
+
+        for _ in 0..20 {
+            let mut perturb = |v: Vec<f64>| -> Vec<f64> {
+                v.iter().map(|x| x * rng.gen_range(0.8..1.2)).collect()
+            };
+            let u = perturb(r0.initial_states());
+            let p = perturb(r0.params());
+            let t = rng.gen_range(0.0..1000.0);
+
+            let mut du0 = vec![0.0; u.len()];
+            r0.call(&mut du0, &u, &p, t);
+
+            for r in rs.iter_mut() {
+                let mut du = vec![0.0; u.len()];
+                r.call(&mut du, &u, &p, t);
+
+                for (i, (x, y)) in du0.iter().zip(du.iter()).enumerate() {
+                    assert!(
+                        x == y || (x - y).abs() <= 1e-12 * x.abs() || x.is_nan() && y.is_nan(),
+                        "{} on {}: du[{}] is {} instead of {} at t = {}",
+                        r.ty,
+                        name,
+                        i,
+                        y,
+                        x,
+                        t
+                    );
+                }
+            }
+        }
+    }
+}
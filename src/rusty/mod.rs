@@ -1,3 +1,4 @@
+use std::fmt::Write as _;
 use std::fs;
 use std::io::{BufWriter, Write};
 
@@ -22,6 +23,7 @@ impl RustyCompiler {
     fn unary(op: &str, x: String) -> String {
         match op {
             "neg" => format!("-({})", x),
+            "abs" => format!("f64::abs({})", x),
             "sin" => format!("f64::sin({})", x),
             "cos" => format!("f64::cos({})", x),
             "tan" => format!("f64::tan({})", x),
@@ -31,10 +33,32 @@ impl RustyCompiler {
             "arcsin" => format!("f64::asin({})", x),
             "arccos" => format!("f64::acos({})", x),
             "arctan" => format!("f64::atan({})", x),
+            "safe_arcsin" => format!("f64::asin(f64::clamp({}, -1.0, 1.0))", x),
+            "safe_arccos" => format!("f64::acos(f64::clamp({}, -1.0, 1.0))", x),
             "exp" => format!("f64::exp({})", x),
             "ln" => format!("f64::ln({})", x),
             "log" => format!("f64::log({}, 10.0)", x),
+            "log2" => format!("f64::log2({})", x),
             "root" => format!("f64::sqrt({})", x),
+            "cbrt" => format!("f64::cbrt({})", x),
+            "erf" => format!("libm::erf({})", x),
+            "erfc" => format!("libm::erfc({})", x),
+            "gamma" => format!("libm::tgamma({})", x),
+            "lgamma" => format!("libm::lgamma({})", x),
+            "sinh" => format!("f64::sinh({})", x),
+            "cosh" => format!("f64::cosh({})", x),
+            "tanh" => format!("f64::tanh({})", x),
+            "expm1" => format!("f64::exp_m1({})", x),
+            "log1p" => format!("f64::ln_1p({})", x),
+            // as Code::signum, f64::signum(0.0) is 1.0
+            "signum" => format!(
+                "{{ let x: f64 = {}; if x > 0.0 {{ 1.0 }} else if x < 0.0 {{ -1.0 }} else if x == 0.0 {{ 0.0 }} else {{ x }} }}",
+                x
+            ),
+            "sech2" => format!(
+                "{{ let e = f64::exp(-2.0 * f64::abs({})); 4.0 * e / ((1.0 + e) * (1.0 + e)) }}",
+                x
+            ),
             _ => {
                 let msg = format!("unary op_code {} not found", op);
                 panic!("{}", msg);
@@ -49,16 +73,24 @@ impl RustyCompiler {
             "times" => format!("({}) * ({})", x, y),
             "divide" => format!("({}) / ({})", x, y),
             "rem" => format!("({}) % ({})", x, y),
-            "gt" => format!("({}) > ({})", x, y),
-            "geq" => format!("({}) >= ({})", x, y),
-            "lt" => format!("({}) < ({})", x, y),
-            "leq" => format!("({}) <= ({})", x, y),
-            "eq" => format!("({}) == ({})", x, y),
-            "neq" => format!("({}) != ({})", x, y),
-            "and" => format!("({}) & ({})", x, y),
-            "or" => format!("({}) ! ({})", x, y),
-            "xor" => format!("({}) ^ ({})", x, y),
+            // booleans are bitmasks, as in Code::boolean
+            "gt" => Self::boolean(format!("({}) > ({})", x, y)),
+            "geq" => Self::boolean(format!("({}) >= ({})", x, y)),
+            "lt" => Self::boolean(format!("({}) < ({})", x, y)),
+            "leq" => Self::boolean(format!("({}) <= ({})", x, y)),
+            "eq" => Self::boolean(format!("({}) == ({})", x, y)),
+            "neq" => Self::boolean(format!("({}) != ({})", x, y)),
+            "and" => Self::bitwise("&", x, y),
+            "or" => Self::bitwise("|", x, y),
+            "xor" => Self::bitwise("^", x, y),
             "power" => format!("f64::powf({}, {})", x, y),
+            "atan2" => format!("f64::atan2({}, {})", x, y),
+            "logb" => format!("f64::log({}, {})", x, y),
+            "hypot" => format!("f64::hypot({}, {})", x, y),
+            "min" | "max" => format!(
+                "{{ let (a, b) = ({}, {}); if a.is_nan() || b.is_nan() {{ f64::NAN }} else {{ f64::{}(a, b) }} }}",
+                x, y, op
+            ),
             _ => {
                 let msg = format!("binary op_code {} not found", op);
                 panic!("{}", msg);
@@ -66,35 +98,57 @@ impl RustyCompiler {
         }
     }
 
+    fn boolean(cond: String) -> String {
+        format!("f64::from_bits(if {} {{ u64::MAX }} else {{ 0 }})", cond)
+    }
+
+    fn bitwise(op: &str, x: String, y: String) -> String {
+        format!("f64::from_bits(({}).to_bits() {} ({}).to_bits())", x, op, y)
+    }
+
+    // a mov or an op coalesced with its mov (see Program::coalesce_movs)
+    // assigns the lhs pushed by Eq
+    fn assign(&mut self, c: &Instruction, rhs: String) -> String {
+        if c.assigned().is_none() {
+            return rhs;
+        }
+
+        let lhs = self.stack.pop().unwrap();
+        if lhs.starts_with("t_") {
+            format!("let {} = {}", lhs, rhs)
+        } else {
+            format!("{} = {}", lhs, rhs)
+        }
+    }
+
     fn compose(&mut self, prog: &Program) {
         for c in prog.code.iter() {
             match c {
                 Instruction::Unary { op, .. } => {
                     let s = if op == "mov" {
                         let rhs = self.stack.pop().unwrap();
-                        let lhs = self.stack.pop().unwrap();
-                        if lhs.starts_with("t_") {
-                            format!("let {} = {}", lhs, rhs)
-                        } else {
-                            format!("{} = {}", lhs, rhs)
-                        }
+                        self.assign(c, rhs)
                     } else {
                         let x = self.stack.pop().unwrap();
-                        Self::unary(op, x)
+                        self.assign(c, Self::unary(op, x))
                     };
                     self.stack.push(s);
                 }
                 Instruction::Binary { op, .. } => {
                     let y = self.stack.pop().unwrap();
                     let x = self.stack.pop().unwrap();
-                    let s = Self::binary(op, x, y);
+                    let s = self.assign(c, Self::binary(op, x, y));
                     self.stack.push(s);
                 }
                 Instruction::IfElse { .. } => {
                     let cond = self.stack.pop().unwrap();
                     let x2 = self.stack.pop().unwrap();
                     let x1 = self.stack.pop().unwrap();
-                    let s = format!("(if {} {{{}}} else {{{}}})", cond, x1, x2);
+                    let s = format!(
+                        "{{ let c = ({}).to_bits(); f64::from_bits((({}).to_bits() & c) | (({}).to_bits() & !c)) }}",
+                        cond, x1, x2
+                    );
+                    let s = self.assign(c, s);
                     self.stack.push(s);
                 }
                 Instruction::Eq { dst } => {
@@ -124,30 +178,35 @@ impl Compiler<RustyCode> for RustyCompiler {
     fn compile(&mut self, prog: &Program) -> RustyCode {
         self.compose(prog);
 
-        let fd = fs::File::create("src/rusty/func.rs").expect("cannot create func.rs");
-        let mut buf = BufWriter::new(fd);
+        let mut src = String::new();
 
-        //let _ = writeln!(&mut buf, "use crate::code::BinaryFunc;");
-        let _ = writeln!(&mut buf, "#![allow(unused_parens)]");
-        let _ = writeln!(&mut buf, "pub fn func(mem: &mut [f64]) {{");
+        //let _ = writeln!(&mut src, "use crate::code::BinaryFunc;");
+        let _ = writeln!(&mut src, "#![allow(unused_parens)]");
+        let _ = writeln!(&mut src, "pub fn func(mem: &mut [f64]) {{");
 
         for sm in self.stack.iter() {
-            let _ = writeln!(&mut buf, "\t{};", sm);
+            let _ = writeln!(&mut src, "\t{};", sm);
         }
 
-        let _ = writeln!(&mut buf, "}}");
+        let _ = writeln!(&mut src, "}}");
 
-        RustyCode::new(prog.frame.mem())
+        let fd = fs::File::create("src/rusty/func.rs").expect("cannot create func.rs");
+        let mut buf = BufWriter::new(fd);
+        let _ = buf.write_all(src.as_bytes());
+
+        RustyCode::new(prog.frame.mem(), src)
     }
 }
 
+#[derive(Clone)]
 pub struct RustyCode {
     _mem: Vec<f64>,
+    src: String,
 }
 
 impl RustyCode {
-    fn new(_mem: Vec<f64>) -> RustyCode {
-        RustyCode { _mem }
+    fn new(_mem: Vec<f64>, src: String) -> RustyCode {
+        RustyCode { _mem, src }
     }
 }
 
@@ -165,4 +224,35 @@ impl Compiled for RustyCode {
     fn mem_mut(&mut self) -> &mut [f64] {
         &mut self._mem[..]
     }
+
+    fn source(&self) -> Option<&str> {
+        Some(&self.src)
+    }
+
+    fn fork(&self) -> Box<dyn Compiled + Send> {
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_rusty_logical() {
+    let x = || String::from("mem[0]");
+    let y = || String::from("mem[1]");
+
+    assert_eq!(
+        RustyCompiler::binary("gt", x(), y()),
+        "f64::from_bits(if (mem[0]) > (mem[1]) { u64::MAX } else { 0 })"
+    );
+    assert_eq!(
+        RustyCompiler::binary("and", x(), y()),
+        "f64::from_bits((mem[0]).to_bits() & (mem[1]).to_bits())"
+    );
+    assert_eq!(
+        RustyCompiler::binary("or", x(), y()),
+        "f64::from_bits((mem[0]).to_bits() | (mem[1]).to_bits())"
+    );
+    assert_eq!(
+        RustyCompiler::binary("xor", x(), y()),
+        "f64::from_bits((mem[0]).to_bits() ^ (mem[1]).to_bits())"
+    );
 }
@@ -0,0 +1,78 @@
+// dense LU decomposition with partial pivoting, enough for the
+// moderate-sized Newton systems of the implicit solvers
+
+// factors the row-major n x n matrix a in place and fills the pivots
+// returns false if a is (numerically) singular
+pub fn lu_factor(a: &mut [f64], piv: &mut [usize]) -> bool {
+    let n = piv.len();
+
+    for k in 0..n {
+        let mut m = k;
+        for i in k + 1..n {
+            if a[i * n + k].abs() > a[m * n + k].abs() {
+                m = i;
+            }
+        }
+
+        piv[k] = m;
+
+        if a[m * n + k] == 0.0 {
+            return false;
+        }
+
+        if m != k {
+            for j in 0..n {
+                a.swap(k * n + j, m * n + j);
+            }
+        }
+
+        for i in k + 1..n {
+            let l = a[i * n + k] / a[k * n + k];
+            a[i * n + k] = l;
+            for j in k + 1..n {
+                a[i * n + j] -= l * a[k * n + j];
+            }
+        }
+    }
+
+    true
+}
+
+// solves a x = b using the output of lu_factor, b is overwritten by x
+pub fn lu_solve(a: &[f64], piv: &[usize], b: &mut [f64]) {
+    let n = piv.len();
+
+    for (k, &m) in piv.iter().enumerate() {
+        b.swap(k, m);
+    }
+
+    for i in 0..n {
+        for j in 0..i {
+            b[i] -= a[i * n + j] * b[j];
+        }
+    }
+
+    for i in (0..n).rev() {
+        for j in i + 1..n {
+            b[i] -= a[i * n + j] * b[j];
+        }
+        b[i] /= a[i * n + i];
+    }
+}
+
+#[test]
+fn test_lu() {
+    let mut a = vec![0.0, 2.0, 1.0, 1.0, 1.0, 0.0, 3.0, 0.0, 1.0];
+    let mut piv = vec![0; 3];
+    let mut b = vec![5.0, 3.0, 4.0]; // x = [1, 2, 1]
+
+    assert!(lu_factor(&mut a, &mut piv));
+    lu_solve(&a, &piv, &mut b);
+
+    for (x, y) in b.iter().zip([1.0, 2.0, 1.0]) {
+        assert!((x - y).abs() < 1e-12);
+    }
+
+    let mut a = vec![1.0, 2.0, 2.0, 4.0];
+    assert!(!lu_factor(&mut a, &mut piv[..2]));
+}
@@ -1,34 +1,420 @@
 use std::fmt;
-use std::ops::Range;
+use std::io::{self, Write};
+use std::ops::{ControlFlow, Range};
+use std::time::{Duration, Instant};
 
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+mod linalg;
 mod vector;
 
-use super::utils::Callable;
+use super::utils::{finite_jacobian, Callable, Float};
+use linalg::{lu_factor, lu_solve};
 use vector::Vector;
 
 #[derive(Debug, Clone)]
-pub struct Row {
+pub struct Row<T = f64> {
     pub t: f64,
-    pub x: Vector,
+    pub x: Vector<T>,
+    pub obs: Vector<T>, // observables at (t, x), empty if the model has none
+}
+
+impl<T: Float> Row<T> {
+    // a row at the state u, including the observables computed by f
+    fn new<F: Callable<T>>(f: &mut F, t: f64, u: &[T], p: &[T]) -> Row<T> {
+        Row {
+            t,
+            x: Vector(u.to_vec()),
+            obs: Vector(f.observe(u, p, t)),
+        }
+    }
 }
 
-impl fmt::Display for Row {
+impl<T: Float> fmt::Display for Row<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let _ = write!(f, "{}", self.t);
         let x = &self.x;
         for j in 0..x.len() {
             let _ = write!(f, "\t{}", x[j]);
         }
+        for v in self.obs.iter() {
+            let _ = write!(f, "\t{}", v);
+        }
         let _ = writeln!(f, "");
         Ok(())
     }
 }
 
-pub trait Solver<F>
+// writes rows as comma-separated values with a header of t, state names, and observable names
+pub fn write_csv<W: Write, T: Float>(
+    rows: &[Row<T>],
+    w: &mut W,
+    state_names: &[String],
+    obs_names: &[String],
+) -> io::Result<()> {
+    write_csv_header(w, state_names, obs_names)?;
+
+    for row in rows.iter() {
+        write_csv_row(w, row)?;
+    }
+
+    Ok(())
+}
+
+// the header line of write_csv, e.g., to stream the rows from Solver::solve_with
+pub fn write_csv_header<W: Write>(
+    w: &mut W,
+    state_names: &[String],
+    obs_names: &[String],
+) -> io::Result<()> {
+    write!(w, "t")?;
+    for s in state_names.iter().chain(obs_names.iter()) {
+        write!(w, ",{}", s)?;
+    }
+    writeln!(w)
+}
+
+// a single line of write_csv
+pub fn write_csv_row<W: Write, T: Float>(w: &mut W, row: &Row<T>) -> io::Result<()> {
+    write!(w, "{}", row.t)?;
+    for v in row.x.iter().chain(row.obs.iter()) {
+        write!(w, ",{}", v)?;
+    }
+    writeln!(w)
+}
+
+// writes rows as a little-endian f64 matrix in the numpy .npy format (version 1.0),
+// one row per time point with columns t, states, observables
+pub fn write_npy<W: Write>(rows: &[Row], w: &mut W) -> io::Result<()> {
+    let ncols = rows.first().map_or(1, |r| 1 + r.x.len() + r.obs.len());
+
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}), }}",
+        rows.len(),
+        ncols
+    );
+    // magic (6) + version (2) + header length (2) + header must be a multiple of 64
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    w.write_all(b"\x93NUMPY\x01\x00")?;
+    w.write_all(&(header.len() as u16).to_le_bytes())?;
+    w.write_all(header.as_bytes())?;
+
+    for row in rows.iter() {
+        if 1 + row.x.len() + row.obs.len() != ncols {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rows have different lengths",
+            ));
+        }
+        w.write_all(&row.t.to_le_bytes())?;
+        for v in row.x.iter().chain(row.obs.iter()) {
+            w.write_all(&v.to_le_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+// the amount of work done by a solver
+#[derive(Debug, Clone, Default)]
+pub struct SolveReport {
+    pub steps: usize,    // attempted steps
+    pub accepted: usize, // accepted steps
+    pub rejected: usize, // rejected steps (adaptive solvers)
+    pub f_evals: usize,  // calls to f
+    pub t_final: f64,    // the time of the last row
+    pub wall_time: Duration,
+}
+
+// the reasons a solver stops before reaching the end of the time span
+#[derive(Debug, Clone, PartialEq)]
+pub enum SolveError {
+    NonFinite { t: f64, index: usize }, // a NaN or Inf in du[index] at time t
+    Singular { t: f64 },                // implicit solvers, singular Newton matrix
+    NotConverged { t: f64 },            // implicit solvers, Newton reached max_iter
+}
+
+impl fmt::Display for SolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolveError::NonFinite { t, index } => {
+                write!(f, "non-finite derivative of state {} at t = {}", index, t)
+            }
+            SolveError::Singular { t } => write!(f, "singular Newton matrix at t = {}", t),
+            SolveError::NotConverged { t } => {
+                write!(f, "Newton iteration did not converge at t = {}", t)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SolveError {}
+
+// checks the output of f for NaN and Inf
+fn finite<T: Float>(du: &[T], t: f64) -> Result<(), SolveError> {
+    match du.iter().position(|x| !x.is_finite()) {
+        Some(index) => Err(SolveError::NonFinite { t, index }),
+        None => Ok(()),
+    }
+}
+
+// wraps a Callable and counts the calls
+pub struct Counted<'a, F> {
+    f: &'a mut F,
+    count: usize,
+}
+
+impl<'a, F> Counted<'a, F> {
+    pub fn new(f: &'a mut F) -> Counted<'a, F> {
+        Counted { f, count: 0 }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl<'a, T: Float, F: Callable<T>> Callable<T> for Counted<'a, F> {
+    fn call(&mut self, du: &mut [T], u: &[T], p: &[T], t: f64) {
+        self.count += 1;
+        self.f.call(du, u, p, t);
+    }
+
+    fn call_py(&mut self, du: &mut [T], u: &[T], t: f64) {
+        self.f.call_py(du, u, t);
+    }
+
+    fn set_inputs(&mut self, inputs: &[T]) {
+        self.f.set_inputs(inputs);
+    }
+
+    fn observe(&mut self, u: &[T], p: &[T], t: f64) -> Vec<T> {
+        self.f.observe(u, p, t)
+    }
+
+    // finite differences go through self.call to be counted
+    fn jacobian(&mut self, jac: &mut [T], u: &[T], p: &[T], t: f64) {
+        if self.f.has_jacobian() {
+            self.f.jacobian(jac, u, p, t)
+        } else {
+            finite_jacobian(self, jac, u, p, t)
+        }
+    }
+
+    fn has_jacobian(&self) -> bool {
+        self.f.has_jacobian()
+    }
+}
+
+// wraps a Callable and sets its inputs to input(t) before each evaluation,
+// so that any solver can integrate a forced model, e.g., a stimulus protocol
+pub struct Forced<'a, F: Callable, G: Fn(f64) -> Vec<f64>> {
+    f: &'a mut F,
+    input: G,
+}
+
+impl<'a, F: Callable, G: Fn(f64) -> Vec<f64>> Forced<'a, F, G> {
+    pub fn new(f: &'a mut F, input: G) -> Forced<'a, F, G> {
+        Forced { f, input }
+    }
+}
+
+impl<'a, F: Callable, G: Fn(f64) -> Vec<f64>> Callable for Forced<'a, F, G> {
+    fn call(&mut self, du: &mut [f64], u: &[f64], p: &[f64], t: f64) {
+        self.f.set_inputs(&(self.input)(t));
+        self.f.call(du, u, p, t);
+    }
+
+    fn call_py(&mut self, du: &mut [f64], u: &[f64], t: f64) {
+        self.f.set_inputs(&(self.input)(t));
+        self.f.call_py(du, u, t);
+    }
+
+    fn observe(&mut self, u: &[f64], p: &[f64], t: f64) -> Vec<f64> {
+        self.f.set_inputs(&(self.input)(t));
+        self.f.observe(u, p, t)
+    }
+
+    fn jacobian(&mut self, jac: &mut [f64], u: &[f64], p: &[f64], t: f64) {
+        self.f.set_inputs(&(self.input)(t));
+        self.f.jacobian(jac, u, p, t)
+    }
+
+    fn has_jacobian(&self) -> bool {
+        self.f.has_jacobian()
+    }
+}
+
+// pushes row to sol after passing it to cb, returns true if cb asks to stop
+fn emit<T, C: FnMut(&Row<T>) -> ControlFlow<()>>(
+    sol: &mut Vec<Row<T>>,
+    row: Row<T>,
+    cb: &mut C,
+) -> bool {
+    let stop = cb(&row).is_break();
+    sol.push(row);
+    stop
+}
+
+// cubic Hermite interpolation between (t0, u0) and (t1, u1) with slopes f0 and f1
+fn hermite<T: Float>(
+    t0: f64,
+    u0: &[T],
+    f0: &[T],
+    t1: f64,
+    u1: &[T],
+    f1: &[T],
+    t: f64,
+) -> Vector<T> {
+    let h = t1 - t0;
+    let s = (t - t0) / h;
+    let h00 = T::from_f64((1.0 + 2.0 * s) * (1.0 - s) * (1.0 - s));
+    let h10 = T::from_f64(s * (1.0 - s) * (1.0 - s) * h);
+    let h01 = T::from_f64(s * s * (3.0 - 2.0 * s));
+    let h11 = T::from_f64(s * s * (s - 1.0) * h);
+
+    Vector(
+        (0..u0.len())
+            .map(|i| u0[i] * h00 + f0[i] * h10 + u1[i] * h01 + f1[i] * h11)
+            .collect(),
+    )
+}
+
+pub trait Solver<F, T: Float = f64>
+where
+    F: Callable<T>,
+{
+    fn solve(
+        &self,
+        f: &mut F,
+        u0: Vec<T>,
+        p: Vec<T>,
+        ts: Range<f64>,
+    ) -> Result<Vec<Row<T>>, SolveError> {
+        self.solve_with(f, u0, p, ts, |_| ControlFlow::Continue(()))
+    }
+
+    // similar to solve but calls cb for each row as it is produced
+    // returning ControlFlow::Break stops the integration and
+    // the rows collected so far are returned
+    fn solve_with<C>(
+        &self,
+        f: &mut F,
+        u0: Vec<T>,
+        p: Vec<T>,
+        ts: Range<f64>,
+        cb: C,
+    ) -> Result<Vec<Row<T>>, SolveError>
+    where
+        C: FnMut(&Row<T>) -> ControlFlow<()>;
+
+    // number of function evaluations per step for fixed-step solvers
+    fn stages(&self) -> usize {
+        1
+    }
+
+    // similar to solve but also returns a SolveReport
+    // the default implementation counts the calls to f and assumes a fixed-step solver
+    fn solve_reported(
+        &self,
+        f: &mut F,
+        u0: Vec<T>,
+        p: Vec<T>,
+        ts: Range<f64>,
+    ) -> Result<(Vec<Row<T>>, SolveReport), SolveError>
+    where
+        Self: for<'a> Solver<Counted<'a, F>, T>,
+    {
+        let now = Instant::now();
+        let t0 = ts.start;
+        let mut g = Counted::new(f);
+        let sol = self.solve(&mut g, u0, p, ts)?;
+        let steps = g.count() / Solver::<F, T>::stages(self);
+
+        let report = SolveReport {
+            steps,
+            accepted: steps,
+            rejected: 0,
+            f_evals: g.count(),
+            t_final: sol.last().map_or(t0, |r| r.t),
+            wall_time: now.elapsed(),
+        };
+
+        Ok((sol, report))
+    }
+
+    // the solution at t between two consecutive rows, given
+    // the derivatives f0 and f1 at the rows
+    // the default is cubic Hermite interpolation
+    fn interpolate(&self, r0: &Row<T>, f0: &[T], r1: &Row<T>, f1: &[T], t: f64) -> Vector<T> {
+        hermite(r0.t, &r0.x, f0, r1.t, &r1.x, f1, t)
+    }
+
+    // similar to solve but returns rows exactly at the (sorted) save_at times
+    // by interpolating between the rows produced by the solver, therefore,
+    // fixed-step solvers should use a stride of 1
+    // times after the last row are dropped
+    fn solve_at(
+        &self,
+        f: &mut F,
+        u0: Vec<T>,
+        p: Vec<T>,
+        ts: Range<f64>,
+        save_at: &[f64],
+    ) -> Result<Vec<Row<T>>, SolveError> {
+        let n = u0.len();
+        let sol = self.solve(f, u0, p.clone(), ts)?;
+        let mut rows = Vec::with_capacity(save_at.len());
+
+        let mut f0 = vec![T::default(); n];
+        let mut f1 = vec![T::default(); n];
+        let mut seg = usize::MAX; // the segment whose derivatives are in f0 and f1
+        let mut j = 0;
+
+        for &t in save_at.iter() {
+            while j + 1 < sol.len() && sol[j + 1].t < t {
+                j += 1;
+            }
+
+            if j + 1 >= sol.len() {
+                if sol.last().is_some_and(|r| r.t == t) {
+                    rows.push(sol[j].clone());
+                }
+                break;
+            }
+
+            if seg != j {
+                f.call(&mut f0, &sol[j].x, &p, sol[j].t);
+                f.call(&mut f1, &sol[j + 1].x, &p, sol[j + 1].t);
+                seg = j;
+            }
+
+            let x = self.interpolate(&sol[j], &f0, &sol[j + 1], &f1, t);
+            rows.push(Row::new(f, t, &x, &p));
+        }
+
+        Ok(rows)
+    }
+}
+
+/*
+    Stepper is a per-step interface that does not allocate, useful for
+    real-time and embedded use. The caller owns u and a scratch buffer
+    of at least scratch_size(n) elements, where n is the number of states
+*/
+pub trait Stepper<F>
 where
     F: Callable,
 {
-    fn solve(&self, f: &mut F, u0: Vec<f64>, p: Vec<f64>, ts: Range<f64>) -> Vec<Row>;
+    fn scratch_size(&self, n: usize) -> usize;
+
+    // advances u from t to t + dt in place
+    fn step(&self, f: &mut F, u: &mut [f64], scratch: &mut [f64], p: &[f64], t: f64, dt: f64);
 }
 
 pub struct Euler {
@@ -42,8 +428,18 @@ impl Euler {
     }
 }
 
-impl<F: Callable> Solver<F> for Euler {
-    fn solve(&self, f: &mut F, u0: Vec<f64>, p: Vec<f64>, ts: Range<f64>) -> Vec<Row> {
+impl<T: Float, F: Callable<T>> Solver<F, T> for Euler {
+    fn solve_with<C>(
+        &self,
+        f: &mut F,
+        u0: Vec<T>,
+        p: Vec<T>,
+        ts: Range<f64>,
+        mut cb: C,
+    ) -> Result<Vec<Row<T>>, SolveError>
+    where
+        C: FnMut(&Row<T>) -> ControlFlow<()>,
+    {
         let u0 = Vector(u0);
         let p = Vector(p);
         let mut u = u0.clone();
@@ -53,17 +449,1535 @@ impl<F: Callable> Solver<F> for Euler {
         let mut sol = Vec::new();
 
         for i in 0..n {
-            let t = i as f64 * self.dt;
+            let t = ts.start + i as f64 * self.dt;
+
+            if i % self.stride == 0 && emit(&mut sol, Row::new(f, t, &u, &p), &mut cb) {
+                return Ok(sol);
+            }
+
+            f.call(du.as_mut(), u.as_ref(), p.as_ref(), t);
+            finite(du.as_ref(), t)?;
+
+            u.axpy(T::from_f64(self.dt), &du);
+        }
+
+        Ok(sol)
+    }
+
+    // linear interpolation matches the first-order accuracy of the method
+    fn interpolate(&self, r0: &Row<T>, _f0: &[T], r1: &Row<T>, _f1: &[T], t: f64) -> Vector<T> {
+        let s = (t - r0.t) / (r1.t - r0.t);
+        &(&r0.x * T::from_f64(1.0 - s)) + &(&r1.x * T::from_f64(s))
+    }
+}
+
+// scratch holds du
+impl<F: Callable> Stepper<F> for Euler {
+    fn scratch_size(&self, n: usize) -> usize {
+        n
+    }
+
+    fn step(&self, f: &mut F, u: &mut [f64], scratch: &mut [f64], p: &[f64], t: f64, dt: f64) {
+        let n = u.len();
+        let du = &mut scratch[..n];
+
+        f.call(du, u, p, t);
+
+        for i in 0..n {
+            u[i] += du[i] * dt;
+        }
+    }
+}
+
+// the classic fourth-order Runge-Kutta method
+pub struct RK4 {
+    dt: f64,
+    stride: usize,
+}
+
+impl RK4 {
+    pub fn new(dt: f64, stride: usize) -> RK4 {
+        RK4 { dt, stride }
+    }
+}
+
+impl<T: Float, F: Callable<T>> Solver<F, T> for RK4 {
+    fn solve_with<C>(
+        &self,
+        f: &mut F,
+        u0: Vec<T>,
+        p: Vec<T>,
+        ts: Range<f64>,
+        mut cb: C,
+    ) -> Result<Vec<Row<T>>, SolveError>
+    where
+        C: FnMut(&Row<T>) -> ControlFlow<()>,
+    {
+        let mut u = Vector(u0);
+        let p = Vector(p);
+        let h = self.dt;
+
+        let mut k1 = u.clone();
+        let mut k2 = u.clone();
+        let mut k3 = u.clone();
+        let mut k4 = u.clone();
+        let mut v = u.clone();
+
+        let n = ((ts.end - ts.start) / h).floor() as usize;
+        let mut sol = Vec::new();
+
+        for i in 0..n {
+            let t = ts.start + i as f64 * h;
+
+            if i % self.stride == 0 && emit(&mut sol, Row::new(f, t, &u, &p), &mut cb) {
+                return Ok(sol);
+            }
+
+            f.call(k1.as_mut(), u.as_ref(), p.as_ref(), t);
+            finite(k1.as_ref(), t)?;
+            v.copy_from_slice(&u);
+            v.axpy(T::from_f64(0.5 * h), &k1);
+            f.call(k2.as_mut(), v.as_ref(), p.as_ref(), t + 0.5 * h);
+            finite(k2.as_ref(), t + 0.5 * h)?;
+            v.copy_from_slice(&u);
+            v.axpy(T::from_f64(0.5 * h), &k2);
+            f.call(k3.as_mut(), v.as_ref(), p.as_ref(), t + 0.5 * h);
+            finite(k3.as_ref(), t + 0.5 * h)?;
+            v.copy_from_slice(&u);
+            v.axpy(T::from_f64(h), &k3);
+            f.call(k4.as_mut(), v.as_ref(), p.as_ref(), t + h);
+            finite(k4.as_ref(), t + h)?;
+
+            // k1 accumulates k1 + 2 k2 + 2 k3 + k4
+            k1.axpy(T::from_f64(2.0), &k2);
+            k1.axpy(T::from_f64(2.0), &k3);
+            k1.axpy(T::from_f64(1.0), &k4);
+            u.axpy(T::from_f64(h / 6.0), &k1);
+        }
+
+        Ok(sol)
+    }
+
+    fn stages(&self) -> usize {
+        4
+    }
+}
+
+// scratch holds k1, k2, k3, k4 and the intermediate state (5n)
+impl<F: Callable> Stepper<F> for RK4 {
+    fn scratch_size(&self, n: usize) -> usize {
+        5 * n
+    }
+
+    fn step(&self, f: &mut F, u: &mut [f64], scratch: &mut [f64], p: &[f64], t: f64, h: f64) {
+        let n = u.len();
+        let (k1, rest) = scratch.split_at_mut(n);
+        let (k2, rest) = rest.split_at_mut(n);
+        let (k3, rest) = rest.split_at_mut(n);
+        let (k4, v) = rest.split_at_mut(n);
+        let v = &mut v[..n];
+
+        f.call(k1, u, p, t);
+
+        for i in 0..n {
+            v[i] = u[i] + k1[i] * (0.5 * h);
+        }
+        f.call(k2, v, p, t + 0.5 * h);
+
+        for i in 0..n {
+            v[i] = u[i] + k2[i] * (0.5 * h);
+        }
+        f.call(k3, v, p, t + 0.5 * h);
+
+        for i in 0..n {
+            v[i] = u[i] + k3[i] * h;
+        }
+        f.call(k4, v, p, t + h);
+
+        for i in 0..n {
+            u[i] += (k1[i] + k2[i] * 2.0 + k3[i] * 2.0 + k4[i]) * (h / 6.0);
+        }
+    }
+}
+
+// Heun's method (explicit trapezoidal rule)
+// an Euler predictor followed by a trapezoidal corrector
+pub struct Heun {
+    dt: f64,
+    stride: usize,
+}
+
+impl Heun {
+    pub fn new(dt: f64, stride: usize) -> Heun {
+        Heun { dt, stride }
+    }
+}
+
+impl<T: Float, F: Callable<T>> Solver<F, T> for Heun {
+    fn solve_with<C>(
+        &self,
+        f: &mut F,
+        u0: Vec<T>,
+        p: Vec<T>,
+        ts: Range<f64>,
+        mut cb: C,
+    ) -> Result<Vec<Row<T>>, SolveError>
+    where
+        C: FnMut(&Row<T>) -> ControlFlow<()>,
+    {
+        let mut u = Vector(u0);
+        let p = Vector(p);
+        let h = self.dt;
+
+        let mut k1 = u.clone();
+        let mut k2 = u.clone();
+        let mut v = u.clone();
+
+        let n = ((ts.end - ts.start) / h).floor() as usize;
+        let mut sol = Vec::new();
+
+        for i in 0..n {
+            let t = ts.start + i as f64 * h;
+
+            if i % self.stride == 0 && emit(&mut sol, Row::new(f, t, &u, &p), &mut cb) {
+                return Ok(sol);
+            }
+
+            f.call(k1.as_mut(), u.as_ref(), p.as_ref(), t);
+            finite(k1.as_ref(), t)?;
+            v.copy_from_slice(&u);
+            v.axpy(T::from_f64(h), &k1);
+            f.call(k2.as_mut(), v.as_ref(), p.as_ref(), t + h);
+            finite(k2.as_ref(), t + h)?;
+
+            k1.axpy(T::from_f64(1.0), &k2);
+            u.axpy(T::from_f64(0.5 * h), &k1);
+        }
+
+        Ok(sol)
+    }
+
+    fn stages(&self) -> usize {
+        2
+    }
+}
+
+// the explicit midpoint method
+pub struct Midpoint {
+    dt: f64,
+    stride: usize,
+}
+
+impl Midpoint {
+    pub fn new(dt: f64, stride: usize) -> Midpoint {
+        Midpoint { dt, stride }
+    }
+}
+
+impl<T: Float, F: Callable<T>> Solver<F, T> for Midpoint {
+    fn solve_with<C>(
+        &self,
+        f: &mut F,
+        u0: Vec<T>,
+        p: Vec<T>,
+        ts: Range<f64>,
+        mut cb: C,
+    ) -> Result<Vec<Row<T>>, SolveError>
+    where
+        C: FnMut(&Row<T>) -> ControlFlow<()>,
+    {
+        let mut u = Vector(u0);
+        let p = Vector(p);
+        let h = self.dt;
+
+        let mut k1 = u.clone();
+        let mut k2 = u.clone();
+        let mut v = u.clone();
+
+        let n = ((ts.end - ts.start) / h).floor() as usize;
+        let mut sol = Vec::new();
+
+        for i in 0..n {
+            let t = ts.start + i as f64 * h;
+
+            if i % self.stride == 0 && emit(&mut sol, Row::new(f, t, &u, &p), &mut cb) {
+                return Ok(sol);
+            }
+
+            f.call(k1.as_mut(), u.as_ref(), p.as_ref(), t);
+            finite(k1.as_ref(), t)?;
+            v.copy_from_slice(&u);
+            v.axpy(T::from_f64(0.5 * h), &k1);
+            f.call(k2.as_mut(), v.as_ref(), p.as_ref(), t + 0.5 * h);
+            finite(k2.as_ref(), t + 0.5 * h)?;
+
+            u.axpy(T::from_f64(h), &k2);
+        }
+
+        Ok(sol)
+    }
+
+    fn stages(&self) -> usize {
+        2
+    }
+}
+
+/*
+    symplectic (semi-implicit) Euler for conservative models
+    the momenta (p_idx) are updated first using the current positions,
+    then the positions (q_idx) are updated using the new momenta
+    states in neither group are advanced by the explicit Euler method
+*/
+pub struct SemiImplicitEuler {
+    dt: f64,
+    stride: usize,
+    q_idx: Vec<usize>,
+}
+
+impl SemiImplicitEuler {
+    pub fn new(dt: f64, stride: usize, q_idx: &[usize], p_idx: &[usize]) -> SemiImplicitEuler {
+        assert_eq!(q_idx.len(), p_idx.len(), "q_idx and p_idx should pair up");
+        assert!(
+            q_idx.iter().all(|k| !p_idx.contains(k)),
+            "a state cannot be both a position and a momentum"
+        );
+
+        // the momenta need no special treatment since they are updated
+        // together with the other states before the positions
+        SemiImplicitEuler {
+            dt,
+            stride,
+            q_idx: q_idx.to_vec(),
+        }
+    }
+}
+
+impl<F: Callable> Solver<F> for SemiImplicitEuler {
+    fn solve_with<C>(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+        mut cb: C,
+    ) -> Result<Vec<Row>, SolveError>
+    where
+        C: FnMut(&Row) -> ControlFlow<()>,
+    {
+        let mut u = Vector(u0);
+        let p = Vector(p);
+        let h = self.dt;
+
+        let mut du = u.clone();
+        let mut u1 = u.clone();
+
+        let n = ((ts.end - ts.start) / h).floor() as usize;
+        let mut sol = Vec::new();
+
+        for i in 0..n {
+            let t = ts.start + i as f64 * h;
 
-            if i % self.stride == 0 {
-                sol.push(Row { t, x: u.clone() });
+            if i % self.stride == 0 && emit(&mut sol, Row::new(f, t, &u, &p), &mut cb) {
+                return Ok(sol);
             }
 
             f.call(du.as_mut(), u.as_ref(), p.as_ref(), t);
+            finite(du.as_ref(), t)?;
+
+            // everything but the positions moves with the old derivatives
+            u1.copy_from_slice(u.as_ref());
+            u1.axpy(h, &du);
+
+            for &k in self.q_idx.iter() {
+                u1[k] = u[k];
+            }
+
+            // the positions move with the derivatives at the new momenta
+            f.call(du.as_mut(), u1.as_ref(), p.as_ref(), t);
+            finite(du.as_ref(), t)?;
+
+            for &k in self.q_idx.iter() {
+                u1[k] += h * du[k];
+            }
 
-            u += &du * self.dt;
+            std::mem::swap(&mut u, &mut u1);
         }
 
-        sol
+        Ok(sol)
+    }
+
+    fn stages(&self) -> usize {
+        2
+    }
+}
+
+/*
+    Euler–Maruyama for SDEs of the form du = f(u, t) dt + σ dW,
+    where the drift f is the model and the noise is additive and
+    independent per state with the diffusion coefficients σ (sigma)
+    the random numbers are generated from seed, so the same seed
+    produces the same trajectory
+*/
+pub struct EulerMaruyama {
+    dt: f64,
+    stride: usize,
+    sigma: Vec<f64>,
+    seed: u64,
+}
+
+impl EulerMaruyama {
+    pub fn new(dt: f64, stride: usize, sigma: Vec<f64>, seed: u64) -> EulerMaruyama {
+        EulerMaruyama {
+            dt,
+            stride,
+            sigma,
+            seed,
+        }
     }
 }
+
+// a standard normal sample by the Box–Muller transform
+fn gaussian(rng: &mut StdRng) -> f64 {
+    let u1: f64 = 1.0 - rng.gen::<f64>(); // in (0, 1]
+    let u2: f64 = rng.gen::<f64>();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+impl<F: Callable> Solver<F> for EulerMaruyama {
+    fn solve_with<C>(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+        mut cb: C,
+    ) -> Result<Vec<Row>, SolveError>
+    where
+        C: FnMut(&Row) -> ControlFlow<()>,
+    {
+        assert_eq!(self.sigma.len(), u0.len(), "one sigma per state is needed");
+
+        let mut u = Vector(u0);
+        let p = Vector(p);
+        let mut du = u.clone();
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let sq = self.dt.sqrt();
+
+        let n = ((ts.end - ts.start) / self.dt).floor() as usize;
+        let mut sol = Vec::new();
+
+        for i in 0..n {
+            let t = ts.start + i as f64 * self.dt;
+
+            if i % self.stride == 0 && emit(&mut sol, Row::new(f, t, &u, &p), &mut cb) {
+                return Ok(sol);
+            }
+
+            f.call(du.as_mut(), u.as_ref(), p.as_ref(), t);
+            finite(du.as_ref(), t)?;
+
+            for (k, s) in self.sigma.iter().enumerate() {
+                u[k] += du[k] * self.dt + s * sq * gaussian(&mut rng);
+            }
+        }
+
+        Ok(sol)
+    }
+}
+
+/************************ Implicit **************************/
+
+/*
+    backward Euler for stiff models, u1 = u0 + dt * f(u1, t + dt)
+    each step solves for u1 by a (simplified) Newton iteration where
+    the Jacobian is computed once at the beginning of the step
+*/
+pub struct BackwardEuler {
+    dt: f64,
+    stride: usize,
+    max_iter: usize,
+    tol: f64,
+}
+
+impl BackwardEuler {
+    pub fn new(dt: f64, stride: usize) -> BackwardEuler {
+        BackwardEuler {
+            dt,
+            stride,
+            max_iter: 10,
+            tol: 1e-10,
+        }
+    }
+
+    // sets the maximum number of Newton iterations per step and
+    // the convergence tolerance (max-norm of the Newton update)
+    pub fn with_newton(mut self, max_iter: usize, tol: f64) -> BackwardEuler {
+        self.max_iter = max_iter;
+        self.tol = tol;
+        self
+    }
+}
+
+impl BackwardEuler {
+    fn integrate<F: Callable, C: FnMut(&Row) -> ControlFlow<()>>(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+        report: &mut SolveReport,
+        cb: &mut C,
+    ) -> Result<Vec<Row>, SolveError> {
+        let n = u0.len();
+        let mut u = Vector(u0);
+        let p = Vector(p);
+        let h = self.dt;
+
+        let mut v = u.clone();
+        let mut du = u.clone();
+        let mut jac = vec![0.0; n * n];
+        let mut piv = vec![0; n];
+
+        let m = ((ts.end - ts.start) / h).floor() as usize;
+        let mut sol = Vec::new();
+
+        for i in 0..m {
+            let t = ts.start + i as f64 * h;
+
+            if i % self.stride == 0 && emit(&mut sol, Row::new(f, t, &u, &p), cb) {
+                return Ok(sol);
+            }
+
+            // the Newton matrix I - h * J
+            f.jacobian(&mut jac, u.as_ref(), p.as_ref(), t + h);
+            if !f.has_jacobian() {
+                report.f_evals += n + 1;
+            }
+            report.steps += 1;
+
+            for (k, a) in jac.iter_mut().enumerate() {
+                *a *= -h;
+                if k % (n + 1) == 0 {
+                    *a += 1.0;
+                }
+            }
+
+            if !lu_factor(&mut jac, &mut piv) {
+                return Err(SolveError::Singular { t });
+            }
+
+            v.copy_from_slice(u.as_ref());
+            let mut converged = false;
+
+            for _ in 0..self.max_iter {
+                f.call(du.as_mut(), v.as_ref(), p.as_ref(), t + h);
+                finite(du.as_ref(), t + h)?;
+                report.f_evals += 1;
+
+                // the residual u + h * f(v) - v becomes the Newton update
+                for k in 0..n {
+                    du[k] = u[k] + h * du[k] - v[k];
+                }
+
+                lu_solve(&jac, &piv, du.as_mut());
+
+                for k in 0..n {
+                    v[k] += du[k];
+                }
+
+                if du.iter().all(|x| x.abs() < self.tol) {
+                    converged = true;
+                    break;
+                }
+            }
+
+            if !converged {
+                return Err(SolveError::NotConverged { t });
+            }
+
+            report.accepted += 1;
+            std::mem::swap(&mut u, &mut v);
+        }
+
+        Ok(sol)
+    }
+}
+
+impl<F: Callable> Solver<F> for BackwardEuler {
+    fn solve_with<C>(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+        mut cb: C,
+    ) -> Result<Vec<Row>, SolveError>
+    where
+        C: FnMut(&Row) -> ControlFlow<()>,
+    {
+        self.integrate(f, u0, p, ts, &mut SolveReport::default(), &mut cb)
+    }
+
+    fn solve_reported(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+    ) -> Result<(Vec<Row>, SolveReport), SolveError> {
+        let now = Instant::now();
+        let t0 = ts.start;
+        let mut report = SolveReport::default();
+        let sol = self.integrate(
+            f,
+            u0,
+            p,
+            ts,
+            &mut report,
+            &mut |_| ControlFlow::Continue(()),
+        )?;
+        report.t_final = sol.last().map_or(t0, |r| r.t);
+        report.wall_time = now.elapsed();
+        Ok((sol, report))
+    }
+}
+
+/************************ Adaptive **************************/
+
+// the norm of the scaled local error used in the step-acceptance test
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ErrorNorm {
+    #[default]
+    Rms,
+    Inf,
+    Weighted {
+        weights: Vec<f64>,
+    },
+}
+
+impl ErrorNorm {
+    // err is the local error estimate and sc the per-component tolerance
+    // scale (atol + rtol * |u|), a norm <= 1 means the step is accepted
+    pub fn eval(&self, err: &[f64], sc: &[f64]) -> f64 {
+        let n = err.len();
+        let e = |i: usize| err[i] / sc[i];
+
+        match self {
+            ErrorNorm::Rms => ((0..n).map(|i| e(i).powi(2)).sum::<f64>() / n as f64).sqrt(),
+            ErrorNorm::Inf => (0..n).map(|i| e(i).abs()).fold(0.0, f64::max),
+            ErrorNorm::Weighted { weights } => {
+                assert_eq!(weights.len(), n, "one weight per state is needed");
+                let w: f64 = weights.iter().sum();
+                ((0..n).map(|i| weights[i] * e(i).powi(2)).sum::<f64>() / w).sqrt()
+            }
+        }
+    }
+}
+
+// Bogacki–Shampine 3(2) pair with step size control
+// rows are emitted at every accepted step
+pub struct RK23 {
+    atol: f64,
+    rtol: f64,
+    norm: ErrorNorm,
+    atols: Option<Vec<f64>>, // per-state tolerances, override atol/rtol if present
+    rtols: Option<Vec<f64>>,
+}
+
+impl RK23 {
+    pub fn new(atol: f64, rtol: f64, norm: ErrorNorm) -> RK23 {
+        RK23 {
+            atol,
+            rtol,
+            norm,
+            atols: None,
+            rtols: None,
+        }
+    }
+
+    // sets per-state absolute and/or relative tolerances
+    // the vectors should have one entry per state
+    pub fn with_tolerances(mut self, atols: Option<Vec<f64>>, rtols: Option<Vec<f64>>) -> RK23 {
+        self.atols = atols;
+        self.rtols = rtols;
+        self
+    }
+
+    fn tolerances(&self, n: usize) -> (Vec<f64>, Vec<f64>) {
+        tolerances(self.atol, self.rtol, &self.atols, &self.rtols, n)
+    }
+}
+
+// expands the scalar tolerances unless per-state ones are given
+fn tolerances(
+    atol: f64,
+    rtol: f64,
+    atols: &Option<Vec<f64>>,
+    rtols: &Option<Vec<f64>>,
+    n: usize,
+) -> (Vec<f64>, Vec<f64>) {
+    let atols = atols.clone().unwrap_or_else(|| vec![atol; n]);
+    let rtols = rtols.clone().unwrap_or_else(|| vec![rtol; n]);
+    assert_eq!(atols.len(), n, "atol size mismatch");
+    assert_eq!(rtols.len(), n, "rtol size mismatch");
+    (atols, rtols)
+}
+
+impl RK23 {
+    fn integrate<F: Callable, C: FnMut(&Row) -> ControlFlow<()>>(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+        report: &mut SolveReport,
+        cb: &mut C,
+    ) -> Result<Vec<Row>, SolveError> {
+        let n = u0.len();
+        let mut u = Vector(u0);
+        let p = Vector(p);
+
+        let mut k1 = Vector(vec![0.0; n]);
+        let mut k2 = k1.clone();
+        let mut k3 = k1.clone();
+        let mut k4 = k1.clone();
+        let mut w = k1.clone();
+        let mut sc = vec![0.0; n];
+        let (atols, rtols) = self.tolerances(n);
+
+        let mut t = ts.start;
+        let mut h = (ts.end - ts.start) * 1e-3;
+        let mut sol = Vec::new();
+
+        if emit(&mut sol, Row::new(f, t, &u, &p), cb) {
+            return Ok(sol);
+        }
+
+        f.call(k1.as_mut(), u.as_ref(), p.as_ref(), t);
+        finite(k1.as_ref(), t)?;
+        report.f_evals += 1;
+
+        while t < ts.end {
+            h = h.min(ts.end - t);
+
+            w.copy_from_slice(&u);
+            w.axpy(0.5 * h, &k1);
+            f.call(k2.as_mut(), w.as_ref(), p.as_ref(), t + 0.5 * h);
+            finite(k2.as_ref(), t + 0.5 * h)?;
+
+            w.copy_from_slice(&u);
+            w.axpy(0.75 * h, &k2);
+            f.call(k3.as_mut(), w.as_ref(), p.as_ref(), t + 0.75 * h);
+            finite(k3.as_ref(), t + 0.75 * h)?;
+
+            let v = &u + &((&k1 * (2.0 / 9.0) + &k2 * (1.0 / 3.0) + &k3 * (4.0 / 9.0)) * h);
+            f.call(k4.as_mut(), v.as_ref(), p.as_ref(), t + h);
+            finite(k4.as_ref(), t + h)?;
+            report.f_evals += 3;
+            report.steps += 1;
+
+            let err =
+                (&k1 * (-5.0 / 72.0) + &k2 * (1.0 / 12.0) + &k3 * (1.0 / 9.0) + &k4 * (-1.0 / 8.0))
+                    * h;
+
+            for i in 0..n {
+                sc[i] = atols[i] + rtols[i] * u[i].abs().max(v[i].abs());
+            }
+
+            let e = self.norm.eval(err.as_ref(), &sc);
+
+            if e <= 1.0 {
+                report.accepted += 1;
+                t += h;
+                u = v;
+                // first same as last
+                std::mem::swap(&mut k1, &mut k4);
+                if emit(&mut sol, Row::new(f, t, &u, &p), cb) {
+                    return Ok(sol);
+                }
+            } else {
+                report.rejected += 1;
+            }
+
+            h *= (0.9 * e.powf(-1.0 / 3.0)).clamp(0.2, 5.0);
+        }
+
+        Ok(sol)
+    }
+}
+
+impl<F: Callable> Solver<F> for RK23 {
+    fn solve_with<C>(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+        mut cb: C,
+    ) -> Result<Vec<Row>, SolveError>
+    where
+        C: FnMut(&Row) -> ControlFlow<()>,
+    {
+        self.integrate(f, u0, p, ts, &mut SolveReport::default(), &mut cb)
+    }
+
+    fn solve_reported(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+    ) -> Result<(Vec<Row>, SolveReport), SolveError> {
+        let now = Instant::now();
+        let t0 = ts.start;
+        let mut report = SolveReport::default();
+        let sol = self.integrate(
+            f,
+            u0,
+            p,
+            ts,
+            &mut report,
+            &mut |_| ControlFlow::Continue(()),
+        )?;
+        report.t_final = sol.last().map_or(t0, |r| r.t);
+        report.wall_time = now.elapsed();
+        Ok((sol, report))
+    }
+}
+
+// u + h * sum(a[i] * ks[i])
+fn lincomb(u: &Vector, ks: &[&Vector], a: &[f64], h: f64) -> Vector {
+    let mut v = u.clone();
+    for (k, c) in ks.iter().zip(a.iter()) {
+        if *c != 0.0 {
+            v.axpy(c * h, k);
+        }
+    }
+    v
+}
+
+// Dormand–Prince 5(4) pair with a PI step size controller
+// rows are emitted at every accepted step or, if a sampling interval
+// is set, on a uniform grid using cubic Hermite interpolation
+pub struct DoPri45 {
+    atol: f64,
+    rtol: f64,
+    norm: ErrorNorm,
+    atols: Option<Vec<f64>>, // per-state tolerances, override atol/rtol if present
+    rtols: Option<Vec<f64>>,
+    sample: Option<f64>,
+}
+
+impl DoPri45 {
+    const C: [f64; 6] = [1.0 / 5.0, 3.0 / 10.0, 4.0 / 5.0, 8.0 / 9.0, 1.0, 1.0];
+
+    const A: [&'static [f64]; 6] = [
+        &[1.0 / 5.0],
+        &[3.0 / 40.0, 9.0 / 40.0],
+        &[44.0 / 45.0, -56.0 / 15.0, 32.0 / 9.0],
+        &[
+            19372.0 / 6561.0,
+            -25360.0 / 2187.0,
+            64448.0 / 6561.0,
+            -212.0 / 729.0,
+        ],
+        &[
+            9017.0 / 3168.0,
+            -355.0 / 33.0,
+            46732.0 / 5247.0,
+            49.0 / 176.0,
+            -5103.0 / 18656.0,
+        ],
+        // the 5th order solution (first same as last)
+        &[
+            35.0 / 384.0,
+            0.0,
+            500.0 / 1113.0,
+            125.0 / 192.0,
+            -2187.0 / 6784.0,
+            11.0 / 84.0,
+        ],
+    ];
+
+    // the difference between the 5th and 4th order weights
+    const E: [f64; 7] = [
+        71.0 / 57600.0,
+        0.0,
+        -71.0 / 16695.0,
+        71.0 / 1920.0,
+        -17253.0 / 339200.0,
+        22.0 / 525.0,
+        -1.0 / 40.0,
+    ];
+
+    pub fn new(atol: f64, rtol: f64, norm: ErrorNorm) -> DoPri45 {
+        DoPri45 {
+            atol,
+            rtol,
+            norm,
+            atols: None,
+            rtols: None,
+            sample: None,
+        }
+    }
+
+    // sets per-state absolute and/or relative tolerances
+    // the vectors should have one entry per state
+    pub fn with_tolerances(mut self, atols: Option<Vec<f64>>, rtols: Option<Vec<f64>>) -> DoPri45 {
+        self.atols = atols;
+        self.rtols = rtols;
+        self
+    }
+
+    // emits rows every dt instead of at the accepted steps
+    pub fn with_sampling(mut self, dt: f64) -> DoPri45 {
+        self.sample = Some(dt);
+        self
+    }
+
+    fn integrate<F: Callable, C: FnMut(&Row) -> ControlFlow<()>>(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+        report: &mut SolveReport,
+        cb: &mut C,
+    ) -> Result<Vec<Row>, SolveError> {
+        let n = u0.len();
+        let mut u = Vector(u0);
+        let p = Vector(p);
+
+        let mut k: Vec<Vector> = vec![Vector(vec![0.0; n]); 7];
+        let mut sc = vec![0.0; n];
+        let (atols, rtols) = tolerances(self.atol, self.rtol, &self.atols, &self.rtols, n);
+
+        let mut t = ts.start;
+        let mut h = (ts.end - ts.start) * 1e-3;
+        let mut sol = Vec::new();
+
+        if emit(&mut sol, Row::new(f, t, &u, &p), cb) {
+            return Ok(sol);
+        }
+        let mut next_sample = self.sample.map(|dt| ts.start + dt);
+
+        // PI controller parameters (Hairer, Nørsett, and Wanner)
+        let beta = 0.04;
+        let alpha = 0.2 - 0.75 * beta;
+        let mut e_prev: f64 = 1e-4;
+        let mut rejected = false;
+
+        f.call(k[0].as_mut(), u.as_ref(), p.as_ref(), t);
+        finite(k[0].as_ref(), t)?;
+        report.f_evals += 1;
+
+        while t < ts.end {
+            h = h.min(ts.end - t);
+
+            let mut v = u.clone();
+            for s in 0..6 {
+                let ks: Vec<&Vector> = k[..=s].iter().collect();
+                v = lincomb(&u, &ks, DoPri45::A[s], h);
+                let tc = t + DoPri45::C[s] * h;
+                f.call(k[s + 1].as_mut(), v.as_ref(), p.as_ref(), tc);
+                finite(k[s + 1].as_ref(), tc)?;
+            }
+            report.f_evals += 6;
+            report.steps += 1;
+
+            let ks: Vec<&Vector> = k.iter().collect();
+            let err = lincomb(&Vector(vec![0.0; n]), &ks, &DoPri45::E, h);
+
+            for i in 0..n {
+                sc[i] = atols[i] + rtols[i] * u[i].abs().max(v[i].abs());
+            }
+
+            let e = self.norm.eval(err.as_ref(), &sc);
+
+            if e <= 1.0 {
+                report.accepted += 1;
+
+                if let (Some(dt), Some(ts_)) = (self.sample, next_sample.as_mut()) {
+                    while *ts_ <= t + h {
+                        let x = hermite(t, &u, &k[0], t + h, &v, &k[6], *ts_);
+                        if emit(&mut sol, Row::new(f, *ts_, &x, &p), cb) {
+                            return Ok(sol);
+                        }
+                        *ts_ += dt;
+                    }
+                }
+
+                t += h;
+                u = v;
+                // first same as last
+                k.swap(0, 6);
+
+                if self.sample.is_none() && emit(&mut sol, Row::new(f, t, &u, &p), cb) {
+                    return Ok(sol);
+                }
+
+                let mut fac = 0.9 * e.max(1e-10).powf(-alpha) * e_prev.powf(beta);
+                if rejected {
+                    fac = fac.min(1.0);
+                }
+                h *= fac.clamp(0.2, 10.0);
+                e_prev = e.max(1e-4);
+                rejected = false;
+            } else {
+                report.rejected += 1;
+                h *= (0.9 * e.powf(-alpha)).max(0.2);
+                rejected = true;
+            }
+        }
+
+        Ok(sol)
+    }
+}
+
+impl<F: Callable> Solver<F> for DoPri45 {
+    fn solve_with<C>(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+        mut cb: C,
+    ) -> Result<Vec<Row>, SolveError>
+    where
+        C: FnMut(&Row) -> ControlFlow<()>,
+    {
+        self.integrate(f, u0, p, ts, &mut SolveReport::default(), &mut cb)
+    }
+
+    fn solve_reported(
+        &self,
+        f: &mut F,
+        u0: Vec<f64>,
+        p: Vec<f64>,
+        ts: Range<f64>,
+    ) -> Result<(Vec<Row>, SolveReport), SolveError> {
+        let now = Instant::now();
+        let t0 = ts.start;
+        let mut report = SolveReport::default();
+        let sol = self.integrate(
+            f,
+            u0,
+            p,
+            ts,
+            &mut report,
+            &mut |_| ControlFlow::Continue(()),
+        )?;
+        report.t_final = sol.last().map_or(t0, |r| r.t);
+        report.wall_time = now.elapsed();
+        Ok((sol, report))
+    }
+}
+
+#[cfg(test)]
+struct Decay(Vec<f64>);
+
+#[cfg(test)]
+impl Callable for Decay {
+    fn call(&mut self, du: &mut [f64], u: &[f64], _p: &[f64], _t: f64) {
+        for i in 0..u.len() {
+            du[i] = -self.0[i] * u[i];
+        }
+    }
+
+    fn call_py(&mut self, _du: &mut [f64], _u: &[f64], _t: f64) {}
+}
+
+#[test]
+fn test_error_norm() {
+    // one fast and several slow components
+    let mut f = Decay(vec![50.0, 1.0, 1.0, 1.0, 1.0]);
+    let u0 = vec![1.0; 5];
+
+    let rms = RK23::new(1e-6, 1e-6, ErrorNorm::default())
+        .solve(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    let inf = RK23::new(1e-6, 1e-6, ErrorNorm::Inf)
+        .solve(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+
+    assert!(inf.len() > rms.len());
+
+    for sol in [&rms, &inf] {
+        let last = sol.last().unwrap();
+        assert_eq!(last.t, 1.0);
+        assert!((last.x[1] - (-1.0f64).exp()).abs() < 1e-4);
+    }
+
+    // weighting the fast component down relaxes the step acceptance further
+    let norm = ErrorNorm::Weighted {
+        weights: vec![0.1, 1.0, 1.0, 1.0, 1.0],
+    };
+    let weighted = RK23::new(1e-6, 1e-6, norm.clone())
+        .solve(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    assert!(weighted.len() < rms.len());
+
+    // the same for DoPri45
+    let rms = DoPri45::new(1e-8, 1e-8, ErrorNorm::default())
+        .solve(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    let inf = DoPri45::new(1e-8, 1e-8, ErrorNorm::Inf)
+        .solve(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    let weighted = DoPri45::new(1e-8, 1e-8, norm)
+        .solve(&mut f, u0, vec![], 0.0..1.0)
+        .unwrap();
+
+    assert!(inf.len() > rms.len());
+    assert!(weighted.len() < rms.len());
+
+    for sol in [&rms, &inf, &weighted] {
+        let last = sol.last().unwrap();
+        assert_eq!(last.t, 1.0);
+        assert!((last.x[1] - (-1.0f64).exp()).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_solve_report() {
+    let mut f = Decay(vec![1.0, 2.0]);
+
+    let (sol, report) = RK4::new(0.125, 1)
+        .solve_reported(&mut f, vec![1.0; 2], vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(sol.len(), 8);
+    assert_eq!(report.steps, 8);
+    assert_eq!(report.accepted, 8);
+    assert_eq!(report.rejected, 0);
+    assert_eq!(report.f_evals, 4 * 8);
+    assert_eq!(report.t_final, 0.875);
+
+    let (_, report) = Euler::new(0.125, 1)
+        .solve_reported(&mut f, vec![1.0; 2], vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(report.f_evals, 8);
+
+    let alg = RK23::new(1e-8, 1e-8, ErrorNorm::Rms);
+    let (sol, report) = alg
+        .solve_reported(&mut f, vec![1.0; 2], vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(report.accepted, sol.len() - 1);
+    assert_eq!(report.steps, report.accepted + report.rejected);
+    assert_eq!(report.t_final, 1.0);
+    assert_eq!(report.f_evals, 1 + 3 * report.steps);
+}
+
+#[test]
+fn test_tolerance_vectors() {
+    let mut f = Decay(vec![1.0, 1.0, 1.0]);
+    let u0 = vec![1.0; 3];
+
+    let uniform = RK23::new(1e-6, 1e-6, ErrorNorm::Inf);
+    let (_, r1) = uniform
+        .solve_reported(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+
+    // the same scalar tolerances as vectors
+    let alg = RK23::new(1.0, 1.0, ErrorNorm::Inf)
+        .with_tolerances(Some(vec![1e-6; 3]), Some(vec![1e-6; 3]));
+    let (_, r2) = alg
+        .solve_reported(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(r1.steps, r2.steps);
+
+    // tightening one state's tolerance
+    let tight = RK23::new(1e-6, 1e-6, ErrorNorm::Inf)
+        .with_tolerances(Some(vec![1e-6, 1e-10, 1e-6]), Some(vec![1e-6, 1e-10, 1e-6]));
+    let (_, r3) = tight
+        .solve_reported(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    assert!(r3.steps > r1.steps);
+
+    // the same for DoPri45
+    let uniform = DoPri45::new(1e-8, 1e-8, ErrorNorm::Inf);
+    let (_, r1) = uniform
+        .solve_reported(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+
+    let alg = DoPri45::new(1.0, 1.0, ErrorNorm::Inf)
+        .with_tolerances(Some(vec![1e-8; 3]), Some(vec![1e-8; 3]));
+    let (_, r2) = alg
+        .solve_reported(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(r1.steps, r2.steps);
+
+    let tight = DoPri45::new(1e-8, 1e-8, ErrorNorm::Inf)
+        .with_tolerances(Some(vec![1e-8, 1e-12, 1e-8]), Some(vec![1e-8, 1e-12, 1e-8]));
+    let (_, r3) = tight.solve_reported(&mut f, u0, vec![], 0.0..1.0).unwrap();
+    assert!(r3.steps > r1.steps);
+}
+
+#[test]
+fn test_stepper() {
+    fn check<S: Solver<Decay> + Stepper<Decay>>(alg: S, dt: f64) {
+        let mut f = Decay(vec![1.0, 2.0, 0.5]);
+        let sol = alg.solve(&mut f, vec![1.0; 3], vec![], 0.0..1.0).unwrap();
+
+        let mut u = [1.0; 3];
+        let mut scratch = vec![0.0; alg.scratch_size(3)];
+
+        for (i, row) in sol.iter().enumerate() {
+            assert_eq!(row.x.as_ref(), &u);
+            alg.step(&mut f, &mut u, &mut scratch, &[], i as f64 * dt, dt);
+        }
+
+        assert_eq!(sol.len(), 1000);
+    }
+
+    check(Euler::new(0.001, 1), 0.001);
+    check(RK4::new(0.001, 1), 0.001);
+}
+
+#[test]
+fn test_dopri45() {
+    let mut f = Decay(vec![1.0, 10.0]);
+    let u0 = vec![1.0; 2];
+
+    let alg = DoPri45::new(1e-8, 1e-8, ErrorNorm::Rms);
+    let (sol, report) = alg
+        .solve_reported(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    let last = sol.last().unwrap();
+    assert_eq!(last.t, 1.0);
+    assert!((last.x[0] - (-1.0f64).exp()).abs() < 1e-7);
+    assert!((last.x[1] - (-10.0f64).exp()).abs() < 1e-7);
+    assert_eq!(report.accepted, sol.len() - 1);
+    assert_eq!(report.steps, report.accepted + report.rejected);
+    assert_eq!(report.f_evals, 1 + 6 * report.steps);
+
+    // the higher order pair needs fewer steps than RK23 at the same tolerance
+    let alg = RK23::new(1e-8, 1e-8, ErrorNorm::Rms);
+    let (_, r23) = alg
+        .solve_reported(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    assert!(report.steps < r23.steps);
+
+    // dense sampling on a uniform grid
+    let sol = DoPri45::new(1e-8, 1e-8, ErrorNorm::Rms)
+        .with_sampling(0.125)
+        .solve(&mut f, u0, vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(sol.len(), 9);
+
+    for (i, row) in sol.iter().enumerate() {
+        assert!((row.t - i as f64 * 0.125).abs() < 1e-12);
+        assert!((row.x[0] - (-row.t).exp()).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_jacobian() {
+    let mut f = Decay(vec![1.0, 2.0, 3.0]);
+    let mut jac = vec![1.0; 9];
+    f.jacobian(&mut jac, &[1.0, -2.0, 0.5], &[], 0.0);
+
+    for i in 0..3 {
+        for j in 0..3 {
+            let expected = if i == j { -f.0[i] } else { 0.0 };
+            assert!((jac[i * 3 + j] - expected).abs() < 1e-6);
+        }
+    }
+}
+
+#[test]
+fn test_backward_euler() {
+    // stiff decay, explicit Euler is unstable for dt > 2 / 1000
+    let mut f = Decay(vec![1000.0, 1.0]);
+    let u0 = vec![1.0; 2];
+
+    let sol = Euler::new(0.01, 1)
+        .solve(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    assert!(sol.last().unwrap().x[0].abs() > 1e10);
+
+    let sol = BackwardEuler::new(0.01, 1)
+        .solve(&mut f, u0, vec![], 0.0..1.0)
+        .unwrap();
+    let last = sol.last().unwrap();
+    assert!(last.x[0].abs() < 1e-10);
+
+    // u1 = u0 / (1 + k dt) per step
+    let n = sol.len() as i32 - 1;
+    assert!((last.x[1] - 1.01f64.powi(-n)).abs() < 1e-9);
+
+    // a Jacobian (n + 1 calls) and at least one Newton iteration per step
+    let (sol, report) = BackwardEuler::new(0.01, 1)
+        .solve_reported(&mut f, vec![1.0; 2], vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(report.steps, sol.len());
+    assert!(report.f_evals >= 4 * report.steps);
+    assert_eq!(report.t_final, sol.last().unwrap().t);
+
+    // f_evals matches the calls that actually happen
+    let mut g = Counted::new(&mut f);
+    let (_, report) = BackwardEuler::new(0.01, 1)
+        .solve_reported(&mut g, vec![1.0; 2], vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(report.f_evals, g.count());
+
+    // a symbolic Jacobian costs no calls, the model is linear and
+    // Newton converges after one update (the second one is zero)
+    struct Symbolic(Decay);
+
+    impl Callable for Symbolic {
+        fn call(&mut self, du: &mut [f64], u: &[f64], p: &[f64], t: f64) {
+            self.0.call(du, u, p, t)
+        }
+
+        fn call_py(&mut self, _du: &mut [f64], _u: &[f64], _t: f64) {}
+
+        fn jacobian(&mut self, jac: &mut [f64], _u: &[f64], _p: &[f64], _t: f64) {
+            let n = self.0 .0.len();
+            jac.fill(0.0);
+            for i in 0..n {
+                jac[i * n + i] = -self.0 .0[i];
+            }
+        }
+
+        fn has_jacobian(&self) -> bool {
+            true
+        }
+    }
+
+    let mut f = Symbolic(Decay(vec![1000.0, 1.0]));
+    let mut g = Counted::new(&mut f);
+    let (_, report) = BackwardEuler::new(0.01, 1)
+        .solve_reported(&mut g, vec![1.0; 2], vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(report.f_evals, g.count());
+    assert_eq!(report.f_evals, 2 * report.steps);
+
+    // a step that does not converge is an error, not an accepted step
+    let mut f = Decay(vec![1000.0, 1.0]);
+    let alg = BackwardEuler::new(0.01, 1).with_newton(1, 1e-10);
+    let res = alg.solve(&mut f, vec![1.0; 2], vec![], 0.0..1.0);
+    assert_eq!(res.unwrap_err(), SolveError::NotConverged { t: 0.0 });
+}
+
+#[test]
+fn test_solve_with() {
+    let mut f = Decay(vec![1.0]);
+    let mut seen = 0;
+
+    let sol = Euler::new(0.01, 10)
+        .solve_with(&mut f, vec![1.0], vec![], 0.0..1.0, |row| {
+            seen += 1;
+            if row.t >= 0.5 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+
+    assert_eq!(sol.len(), seen);
+    assert!(sol.last().unwrap().t >= 0.5 && sol.last().unwrap().t < 0.6);
+
+    // adaptive solvers stop at the same point
+    let alg = RK23::new(1e-6, 1e-6, ErrorNorm::Rms);
+    let full = alg.solve(&mut f, vec![1.0], vec![], 0.0..1.0).unwrap();
+    let part = alg
+        .solve_with(&mut f, vec![1.0], vec![], 0.0..1.0, |row| {
+            if row.t >= 0.5 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+
+    assert!(part.len() < full.len());
+    assert_eq!(part.last().unwrap().t, full[part.len() - 1].t);
+}
+
+#[test]
+fn test_non_finite() {
+    // the second state grows until it overflows
+    let mut f = Decay(vec![1.0, -1000.0]);
+
+    match Euler::new(0.1, 1).solve(&mut f, vec![1.0; 2], vec![], 0.0..100.0) {
+        Err(SolveError::NonFinite { t, index }) => {
+            assert_eq!(index, 1);
+            assert!(t > 10.0 && t < 20.0);
+        }
+        _ => panic!("expected a non-finite error"),
+    }
+
+    let alg = RK23::new(1e-6, 1e-6, ErrorNorm::Rms);
+    let res = alg.solve(&mut f, vec![1.0; 2], vec![], 0.0..100.0);
+    assert!(matches!(res, Err(SolveError::NonFinite { index: 1, .. })));
+}
+
+#[test]
+fn test_solve_at() {
+    let mut f = Decay(vec![1.0]);
+    let save_at: Vec<f64> = (0..=10).map(|i| i as f64 * 0.1).collect();
+
+    let alg = RK23::new(1e-8, 1e-8, ErrorNorm::Rms);
+    let sol = alg
+        .solve_at(&mut f, vec![1.0], vec![], 0.0..1.0, &save_at)
+        .unwrap();
+    assert_eq!(sol.len(), save_at.len());
+
+    for (row, t) in sol.iter().zip(save_at.iter()) {
+        assert_eq!(row.t, *t);
+        assert!((row.x[0] - (-t).exp()).abs() < 1e-6);
+    }
+
+    // Euler interpolates linearly between its steps
+    let alg = Euler::new(0.25, 1);
+    let sol = alg
+        .solve_at(&mut f, vec![1.0], vec![], 0.0..1.0, &[0.125, 2.0])
+        .unwrap();
+    assert_eq!(sol.len(), 1);
+    assert_eq!(sol[0].x[0], (1.0 + 0.75) / 2.0);
+}
+
+#[test]
+fn test_second_order() {
+    // the maximum error over the rows for du = -u
+    fn error<S: Solver<Decay>>(alg: S) -> f64 {
+        let mut f = Decay(vec![1.0]);
+        let sol = alg.solve(&mut f, vec![1.0], vec![], 0.0..1.0).unwrap();
+        sol.iter()
+            .map(|r| (r.x[0] - (-r.t).exp()).abs())
+            .fold(0.0, f64::max)
+    }
+
+    let (dt1, dt2) = (0.01, 0.005);
+
+    let ratio = error(Euler::new(dt1, 1)) / error(Euler::new(dt2, 1));
+    assert!(ratio > 1.8 && ratio < 2.2);
+
+    let ratio = error(Heun::new(dt1, 1)) / error(Heun::new(dt2, 1));
+    assert!(ratio > 3.6 && ratio < 4.4);
+
+    let ratio = error(Midpoint::new(dt1, 1)) / error(Midpoint::new(dt2, 1));
+    assert!(ratio > 3.6 && ratio < 4.4);
+
+    let mut f = Decay(vec![1.0]);
+    let (_, report) = Heun::new(0.125, 1)
+        .solve_reported(&mut f, vec![1.0], vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(report.f_evals, 2 * 8);
+}
+
+#[test]
+fn test_semi_implicit_euler() {
+    // harmonic oscillator, dq = p and dp = -q, with energy (q^2 + p^2) / 2
+    struct Oscillator;
+
+    impl Callable for Oscillator {
+        fn call(&mut self, du: &mut [f64], u: &[f64], _p: &[f64], _t: f64) {
+            du[0] = u[1];
+            du[1] = -u[0];
+        }
+
+        fn call_py(&mut self, _du: &mut [f64], _u: &[f64], _t: f64) {}
+    }
+
+    let energy = |sol: &[Row]| {
+        let x = &sol.last().unwrap().x;
+        0.5 * (x[0] * x[0] + x[1] * x[1])
+    };
+
+    let mut f = Oscillator;
+    let alg = SemiImplicitEuler::new(0.05, 1, &[0], &[1]);
+    let sol = alg
+        .solve(&mut f, vec![1.0, 0.0], vec![], 0.0..100.0)
+        .unwrap();
+    assert!((energy(&sol) - 0.5).abs() < 0.05);
+
+    let sol = Euler::new(0.05, 1)
+        .solve(&mut f, vec![1.0, 0.0], vec![], 0.0..100.0)
+        .unwrap();
+    assert!(energy(&sol) > 5.0);
+}
+
+#[test]
+fn test_euler_maruyama() {
+    let mut f = Decay(vec![1.0; 3]);
+    let u0 = vec![1.0; 3];
+    let sigma = vec![0.1, 0.0, 0.5];
+
+    let solve = |f: &mut Decay, seed: u64| {
+        let alg = EulerMaruyama::new(0.01, 10, sigma.clone(), seed);
+        alg.solve(f, u0.clone(), vec![], 0.0..1.0).unwrap()
+    };
+
+    // reproducible given the seed
+    let a = solve(&mut f, 42);
+    let b = solve(&mut f, 42);
+    let c = solve(&mut f, 43);
+    assert_eq!(a.last().unwrap().x, b.last().unwrap().x);
+    assert_ne!(a.last().unwrap().x, c.last().unwrap().x);
+
+    // a state without noise follows Euler
+    let e = Euler::new(0.01, 10)
+        .solve(&mut f, u0.clone(), vec![], 0.0..1.0)
+        .unwrap();
+    assert_eq!(a.last().unwrap().x[1], e.last().unwrap().x[1]);
+
+    // pure noise, the states are N(0, σ^2 t) at time t
+    let n = 2000;
+    let mut f = Decay(vec![0.0; n]);
+    let alg = EulerMaruyama::new(0.01, 1, vec![1.0; n], 7);
+    let sol = alg.solve(&mut f, vec![0.0; n], vec![], 0.0..1.01).unwrap();
+    let x = &sol.last().unwrap().x;
+    assert_eq!(sol.last().unwrap().t, 1.0);
+
+    let var = x.iter().map(|v| v * v).sum::<f64>() / n as f64;
+    assert!((var - 1.0).abs() < 0.1);
+}
+
+#[test]
+fn test_write_csv_npy() {
+    let rows = vec![
+        Row {
+            t: 0.0,
+            x: Vector(vec![1.0, 2.0]),
+            obs: Vector(vec![3.0]),
+        },
+        Row {
+            t: 0.5,
+            x: Vector(vec![4.0, 5.0]),
+            obs: Vector(vec![6.0]),
+        },
+    ];
+
+    let mut buf = Vec::new();
+    let names = vec!["x".to_string(), "y".to_string()];
+    write_csv(&rows, &mut buf, &names, &["z".to_string()]).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "t,x,y,z\n0,1,2,3\n0.5,4,5,6\n"
+    );
+
+    // the same lines streamed one row at a time
+    let mut buf = Vec::new();
+    write_csv_header(&mut buf, &names, &["z".to_string()]).unwrap();
+    for row in rows.iter() {
+        write_csv_row(&mut buf, row).unwrap();
+    }
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "t,x,y,z\n0,1,2,3\n0.5,4,5,6\n"
+    );
+
+    let mut buf = Vec::new();
+    write_npy(&rows, &mut buf).unwrap();
+    let n = u16::from_le_bytes([buf[8], buf[9]]) as usize;
+    assert_eq!(&buf[..6], b"\x93NUMPY");
+    assert_eq!((10 + n) % 64, 0);
+    assert!(std::str::from_utf8(&buf[10..10 + n])
+        .unwrap()
+        .contains("'shape': (2, 4)"));
+    assert_eq!(buf.len(), 10 + n + 8 * 8);
+    let last = f64::from_le_bytes(buf[buf.len() - 8..].try_into().unwrap());
+    assert_eq!(last, 6.0);
+}
@@ -1,51 +1,70 @@
-use std::ops::{Add, Div, Mul, Sub};
+use std::ops::{Add, Div, Mul, Neg, Sub};
 use std::ops::{AddAssign, DivAssign, MulAssign, SubAssign};
 use std::ops::{Deref, DerefMut};
 
+use crate::utils::Float;
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct Vector(pub Vec<f64>);
+pub struct Vector<T = f64>(pub Vec<T>);
 
-impl Deref for Vector {
-    type Target = Vec<f64>;
+impl<T: Float> Deref for Vector<T> {
+    type Target = Vec<T>;
 
-    fn deref(&self) -> &Vec<f64> {
+    fn deref(&self) -> &Vec<T> {
         &self.0
     }
 }
 
-impl DerefMut for Vector {
-    fn deref_mut(&mut self) -> &mut Vec<f64> {
+impl<T: Float> DerefMut for Vector<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
         &mut self.0
     }
 }
 
-impl Vector {
-    pub fn as_ref(&self) -> &[f64] {
+impl<T: Float> Vector<T> {
+    pub fn as_ref(&self) -> &[T] {
         self.0.as_ref()
     }
 
-    pub fn as_mut(&mut self) -> &mut [f64] {
+    pub fn as_mut(&mut self) -> &mut [T] {
         self.0.as_mut()
     }
 }
 
-impl Vector {
-    /*
-        fn new(v: Vec<f64>) -> Vector {
-            Vector(v)
-        }
-    */
-    fn sum(&self) -> f64 {
+impl<T: Float> Vector<T> {
+    pub fn new(v: Vec<T>) -> Vector<T> {
+        Vector(v)
+    }
+
+    pub fn zeros(n: usize) -> Vector<T> {
+        Vector(vec![T::from_f64(0.0); n])
+    }
+
+    pub fn ones(n: usize) -> Vector<T> {
+        Vector(vec![T::from_f64(1.0); n])
+    }
+
+    pub fn sum(&self) -> T {
         self.iter().sum()
     }
 
-    fn product(&self) -> f64 {
+    pub fn product(&self) -> T {
         self.iter().product()
     }
 
-    fn mapv(&self, f: impl Fn(f64) -> f64) -> Vector {
+    pub fn mapv(&self, f: impl Fn(T) -> T) -> Vector<T> {
         Vector(self.iter().map(|x| f(*x)).collect())
     }
+
+    // the squared Euclidean norm
+    pub fn norm2(&self) -> T {
+        self.dot(self)
+    }
+
+    // the Euclidean norm
+    pub fn norm(&self) -> T {
+        self.norm2().sqrt()
+    }
 }
 
 /**************** Deref *********************/
@@ -76,8 +95,8 @@ impl DerefMut for Vector {
 
 /**************** Add **********************/
 
-impl Add<Vector> for Vector {
-    type Output = Vector;
+impl<T: Float> Add<Vector<T>> for Vector<T> {
+    type Output = Vector<T>;
 
     fn add(mut self, rhs: Self) -> Self {
         assert_eq!(self.len(), rhs.len());
@@ -90,10 +109,10 @@ impl Add<Vector> for Vector {
     }
 }
 
-impl Add<&Vector> for Vector {
-    type Output = Vector;
+impl<T: Float> Add<&Vector<T>> for Vector<T> {
+    type Output = Vector<T>;
 
-    fn add(mut self, rhs: &Vector) -> Self {
+    fn add(mut self, rhs: &Vector<T>) -> Self {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -104,10 +123,10 @@ impl Add<&Vector> for Vector {
     }
 }
 
-impl<'a> Add<&'a Vector> for &'a Vector {
-    type Output = Vector;
+impl<'a, T: Float> Add<&'a Vector<T>> for &'a Vector<T> {
+    type Output = Vector<T>;
 
-    fn add(self, rhs: &'a Vector) -> Vector {
+    fn add(self, rhs: &'a Vector<T>) -> Vector<T> {
         assert_eq!(self.len(), rhs.len());
         let mut v = self.clone();
 
@@ -120,10 +139,10 @@ impl<'a> Add<&'a Vector> for &'a Vector {
 }
 
 // note: rhs is consumed
-impl Add<Vector> for &Vector {
-    type Output = Vector;
+impl<T: Float> Add<Vector<T>> for &Vector<T> {
+    type Output = Vector<T>;
 
-    fn add(self, mut rhs: Vector) -> Vector {
+    fn add(self, mut rhs: Vector<T>) -> Vector<T> {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -134,10 +153,10 @@ impl Add<Vector> for &Vector {
     }
 }
 
-impl Add<f64> for Vector {
-    type Output = Vector;
+impl<T: Float> Add<T> for Vector<T> {
+    type Output = Vector<T>;
 
-    fn add(mut self, rhs: f64) -> Vector {
+    fn add(mut self, rhs: T) -> Vector<T> {
         for i in 0..self.len() {
             self[i] += rhs;
         }
@@ -146,10 +165,10 @@ impl Add<f64> for Vector {
     }
 }
 
-impl Add<f64> for &Vector {
-    type Output = Vector;
+impl<T: Float> Add<T> for &Vector<T> {
+    type Output = Vector<T>;
 
-    fn add(self, rhs: f64) -> Vector {
+    fn add(self, rhs: T) -> Vector<T> {
         let mut v = self.clone();
 
         for i in 0..self.len() {
@@ -160,8 +179,8 @@ impl Add<f64> for &Vector {
     }
 }
 
-impl AddAssign for Vector {
-    fn add_assign(&mut self, rhs: Vector) {
+impl<T: Float> AddAssign for Vector<T> {
+    fn add_assign(&mut self, rhs: Vector<T>) {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -172,8 +191,8 @@ impl AddAssign for Vector {
 
 /**************** Sub **********************/
 
-impl Sub<Vector> for Vector {
-    type Output = Vector;
+impl<T: Float> Sub<Vector<T>> for Vector<T> {
+    type Output = Vector<T>;
 
     fn sub(mut self, rhs: Self) -> Self {
         assert_eq!(self.len(), rhs.len());
@@ -186,10 +205,10 @@ impl Sub<Vector> for Vector {
     }
 }
 
-impl Sub<&Vector> for Vector {
-    type Output = Vector;
+impl<T: Float> Sub<&Vector<T>> for Vector<T> {
+    type Output = Vector<T>;
 
-    fn sub(mut self, rhs: &Vector) -> Self {
+    fn sub(mut self, rhs: &Vector<T>) -> Self {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -200,10 +219,10 @@ impl Sub<&Vector> for Vector {
     }
 }
 
-impl<'a> Sub<&'a Vector> for &'a Vector {
-    type Output = Vector;
+impl<'a, T: Float> Sub<&'a Vector<T>> for &'a Vector<T> {
+    type Output = Vector<T>;
 
-    fn sub(self, rhs: &'a Vector) -> Vector {
+    fn sub(self, rhs: &'a Vector<T>) -> Vector<T> {
         assert_eq!(self.len(), rhs.len());
         let mut v = self.clone();
 
@@ -216,10 +235,10 @@ impl<'a> Sub<&'a Vector> for &'a Vector {
 }
 
 // note: rhs is consumed
-impl Sub<Vector> for &Vector {
-    type Output = Vector;
+impl<T: Float> Sub<Vector<T>> for &Vector<T> {
+    type Output = Vector<T>;
 
-    fn sub(self, mut rhs: Vector) -> Vector {
+    fn sub(self, mut rhs: Vector<T>) -> Vector<T> {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -230,10 +249,10 @@ impl Sub<Vector> for &Vector {
     }
 }
 
-impl Sub<f64> for Vector {
-    type Output = Vector;
+impl<T: Float> Sub<T> for Vector<T> {
+    type Output = Vector<T>;
 
-    fn sub(mut self, rhs: f64) -> Vector {
+    fn sub(mut self, rhs: T) -> Vector<T> {
         for i in 0..self.len() {
             self[i] -= rhs;
         }
@@ -242,10 +261,10 @@ impl Sub<f64> for Vector {
     }
 }
 
-impl Sub<f64> for &Vector {
-    type Output = Vector;
+impl<T: Float> Sub<T> for &Vector<T> {
+    type Output = Vector<T>;
 
-    fn sub(self, rhs: f64) -> Vector {
+    fn sub(self, rhs: T) -> Vector<T> {
         let mut v = self.clone();
 
         for i in 0..self.len() {
@@ -256,8 +275,8 @@ impl Sub<f64> for &Vector {
     }
 }
 
-impl SubAssign for Vector {
-    fn sub_assign(&mut self, rhs: Vector) {
+impl<T: Float> SubAssign for Vector<T> {
+    fn sub_assign(&mut self, rhs: Vector<T>) {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -268,8 +287,8 @@ impl SubAssign for Vector {
 
 /********************** Mul ***************************/
 
-impl Mul<Vector> for Vector {
-    type Output = Vector;
+impl<T: Float> Mul<Vector<T>> for Vector<T> {
+    type Output = Vector<T>;
 
     fn mul(mut self, rhs: Self) -> Self {
         assert_eq!(self.len(), rhs.len());
@@ -282,10 +301,10 @@ impl Mul<Vector> for Vector {
     }
 }
 
-impl Mul<&Vector> for Vector {
-    type Output = Vector;
+impl<T: Float> Mul<&Vector<T>> for Vector<T> {
+    type Output = Vector<T>;
 
-    fn mul(mut self, rhs: &Vector) -> Self {
+    fn mul(mut self, rhs: &Vector<T>) -> Self {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -296,10 +315,10 @@ impl Mul<&Vector> for Vector {
     }
 }
 
-impl<'a> Mul<&'a Vector> for &'a Vector {
-    type Output = Vector;
+impl<'a, T: Float> Mul<&'a Vector<T>> for &'a Vector<T> {
+    type Output = Vector<T>;
 
-    fn mul(self, rhs: &'a Vector) -> Vector {
+    fn mul(self, rhs: &'a Vector<T>) -> Vector<T> {
         assert_eq!(self.len(), rhs.len());
         let mut v = self.clone();
 
@@ -312,10 +331,10 @@ impl<'a> Mul<&'a Vector> for &'a Vector {
 }
 
 // note: rhs is consumed
-impl Mul<Vector> for &Vector {
-    type Output = Vector;
+impl<T: Float> Mul<Vector<T>> for &Vector<T> {
+    type Output = Vector<T>;
 
-    fn mul(self, mut rhs: Vector) -> Vector {
+    fn mul(self, mut rhs: Vector<T>) -> Vector<T> {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -326,10 +345,10 @@ impl Mul<Vector> for &Vector {
     }
 }
 
-impl Mul<f64> for Vector {
-    type Output = Vector;
+impl<T: Float> Mul<T> for Vector<T> {
+    type Output = Vector<T>;
 
-    fn mul(mut self, rhs: f64) -> Vector {
+    fn mul(mut self, rhs: T) -> Vector<T> {
         for i in 0..self.len() {
             self[i] *= rhs;
         }
@@ -338,10 +357,10 @@ impl Mul<f64> for Vector {
     }
 }
 
-impl Mul<f64> for &Vector {
-    type Output = Vector;
+impl<T: Float> Mul<T> for &Vector<T> {
+    type Output = Vector<T>;
 
-    fn mul(self, rhs: f64) -> Vector {
+    fn mul(self, rhs: T) -> Vector<T> {
         let mut v = self.clone();
 
         for i in 0..self.len() {
@@ -352,8 +371,8 @@ impl Mul<f64> for &Vector {
     }
 }
 
-impl MulAssign for Vector {
-    fn mul_assign(&mut self, rhs: Vector) {
+impl<T: Float> MulAssign for Vector<T> {
+    fn mul_assign(&mut self, rhs: Vector<T>) {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -362,17 +381,64 @@ impl MulAssign for Vector {
     }
 }
 
-impl Vector {
-    fn dot(&self, v: &Vector) -> f64 {
+impl Mul<Vector> for f64 {
+    type Output = Vector;
+
+    fn mul(self, rhs: Vector) -> Vector {
+        rhs * self
+    }
+}
+
+impl Mul<&Vector> for f64 {
+    type Output = Vector;
+
+    fn mul(self, rhs: &Vector) -> Vector {
+        rhs * self
+    }
+}
+
+impl<T: Float> Vector<T> {
+    pub fn dot(&self, v: &Vector<T>) -> T {
         assert_eq!(self.len(), v.len());
-        self.iter().zip(v.iter()).map(|(x, y)| x * y).sum()
+        self.iter().zip(v.iter()).map(|(x, y)| *x * *y).sum()
+    }
+
+    // self += a * x in place, rounded the same as self += &x * a
+    pub fn axpy(&mut self, a: T, x: &Vector<T>) {
+        assert_eq!(self.len(), x.len());
+
+        for i in 0..self.len() {
+            self[i] += a * x[i];
+        }
+    }
+}
+
+/********************** Neg ***************************/
+
+impl<T: Float> Neg for Vector<T> {
+    type Output = Vector<T>;
+
+    fn neg(mut self) -> Vector<T> {
+        for i in 0..self.len() {
+            self[i] = -self[i];
+        }
+
+        self
+    }
+}
+
+impl<T: Float> Neg for &Vector<T> {
+    type Output = Vector<T>;
+
+    fn neg(self) -> Vector<T> {
+        -self.clone()
     }
 }
 
 /********************** Div ***************************/
 
-impl Div<Vector> for Vector {
-    type Output = Vector;
+impl<T: Float> Div<Vector<T>> for Vector<T> {
+    type Output = Vector<T>;
 
     fn div(mut self, rhs: Self) -> Self {
         assert_eq!(self.len(), rhs.len());
@@ -385,10 +451,10 @@ impl Div<Vector> for Vector {
     }
 }
 
-impl Div<&Vector> for Vector {
-    type Output = Vector;
+impl<T: Float> Div<&Vector<T>> for Vector<T> {
+    type Output = Vector<T>;
 
-    fn div(mut self, rhs: &Vector) -> Self {
+    fn div(mut self, rhs: &Vector<T>) -> Self {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -399,10 +465,10 @@ impl Div<&Vector> for Vector {
     }
 }
 
-impl<'a> Div<&'a Vector> for &'a Vector {
-    type Output = Vector;
+impl<'a, T: Float> Div<&'a Vector<T>> for &'a Vector<T> {
+    type Output = Vector<T>;
 
-    fn div(self, rhs: &'a Vector) -> Vector {
+    fn div(self, rhs: &'a Vector<T>) -> Vector<T> {
         assert_eq!(self.len(), rhs.len());
         let mut v = self.clone();
 
@@ -415,10 +481,10 @@ impl<'a> Div<&'a Vector> for &'a Vector {
 }
 
 // note: rhs is consumed
-impl Div<Vector> for &Vector {
-    type Output = Vector;
+impl<T: Float> Div<Vector<T>> for &Vector<T> {
+    type Output = Vector<T>;
 
-    fn div(self, mut rhs: Vector) -> Vector {
+    fn div(self, mut rhs: Vector<T>) -> Vector<T> {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -429,10 +495,10 @@ impl Div<Vector> for &Vector {
     }
 }
 
-impl Div<f64> for Vector {
-    type Output = Vector;
+impl<T: Float> Div<T> for Vector<T> {
+    type Output = Vector<T>;
 
-    fn div(mut self, rhs: f64) -> Vector {
+    fn div(mut self, rhs: T) -> Vector<T> {
         for i in 0..self.len() {
             self[i] /= rhs;
         }
@@ -441,10 +507,10 @@ impl Div<f64> for Vector {
     }
 }
 
-impl Div<f64> for &Vector {
-    type Output = Vector;
+impl<T: Float> Div<T> for &Vector<T> {
+    type Output = Vector<T>;
 
-    fn div(self, rhs: f64) -> Vector {
+    fn div(self, rhs: T) -> Vector<T> {
         let mut v = self.clone();
 
         for i in 0..self.len() {
@@ -455,8 +521,8 @@ impl Div<f64> for &Vector {
     }
 }
 
-impl DivAssign for Vector {
-    fn div_assign(&mut self, rhs: Vector) {
+impl<T: Float> DivAssign for Vector<T> {
+    fn div_assign(&mut self, rhs: Vector<T>) {
         assert_eq!(self.len(), rhs.len());
 
         for i in 0..self.len() {
@@ -464,3 +530,35 @@ impl DivAssign for Vector {
         }
     }
 }
+
+#[test]
+fn test_vector() {
+    let v = Vector::new(vec![3.0, -4.0]);
+
+    assert_eq!(Vector::zeros(2), Vector(vec![0.0, 0.0]));
+    assert_eq!(Vector::<f64>::ones(3).sum(), 3.0);
+    assert_eq!(v.product(), -12.0);
+    assert_eq!(v.mapv(f64::abs), Vector(vec![3.0, 4.0]));
+    assert_eq!(v.dot(&Vector::ones(2)), -1.0);
+    assert_eq!(v.norm2(), 25.0);
+    assert_eq!(v.norm(), 5.0);
+    assert_eq!(Vector::<f64>::zeros(0).norm(), 0.0);
+
+    assert_eq!(-&v, Vector(vec![-3.0, 4.0]));
+    assert_eq!(-v.clone(), &v * -1.0);
+    assert_eq!(2.0 * &v, &v * 2.0);
+    assert_eq!(2.0 * v.clone(), v.clone() * 2.0);
+}
+
+#[test]
+fn test_axpy() {
+    let x = Vector::new(vec![0.1, -2.5, 1e-3, 7.0]);
+    let mut u = Vector::new(vec![1.0, 0.3, -4.0, 1e8]);
+
+    for a in [0.0, 0.01, -1.0 / 3.0, 1e5] {
+        let mut v = u.clone();
+        v += &x * a;
+        u.axpy(a, &x);
+        assert_eq!(u, v);
+    }
+}
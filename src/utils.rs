@@ -4,21 +4,298 @@
 *   generate the binary output but is not needed for lib.
 */
 
+use std::fmt;
+use std::iter::{Product, Sum};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::ops::{AddAssign, DivAssign, MulAssign, SubAssign};
+use std::path::PathBuf;
+
+use crate::code::{BinaryFunc, BinaryFunc32, Code, Lookup};
 use crate::model::Program;
+use crate::runnable::CompileError;
+
+// the element type of mem and of the vectors passed through Callable, i.e.,
+// f64 or f32; the interpreter and the fixed-step solvers are generic over it
+// while the native backends are f64 only
+pub trait Float:
+    Copy
+    + Default
+    + PartialOrd
+    + fmt::Debug
+    + fmt::Display
+    + Send
+    + Sync
+    + 'static
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+    + MulAssign
+    + DivAssign
+    + Sum
+    + Product
+    + for<'a> Sum<&'a Self>
+    + for<'a> Product<&'a Self>
+{
+    const EPSILON: Self;
+
+    fn from_f64(x: f64) -> Self;
+    fn to_f64(self) -> f64;
+    fn abs(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn max(self, y: Self) -> Self;
+    fn is_finite(self) -> bool;
+
+    // cond ? x1 : x2, where cond is a boolean mask (see Code::boolean)
+    fn select(cond: Self, x1: Self, x2: Self) -> Self;
+
+    // the function of op in this width, see Code::from_str
+    fn op(op: &str) -> fn(Self, Self) -> Self;
+}
+
+impl Float for f64 {
+    const EPSILON: f64 = f64::EPSILON;
+
+    #[inline]
+    fn from_f64(x: f64) -> f64 {
+        x
+    }
+
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self
+    }
+
+    #[inline]
+    fn abs(self) -> f64 {
+        f64::abs(self)
+    }
+
+    #[inline]
+    fn sqrt(self) -> f64 {
+        f64::sqrt(self)
+    }
+
+    #[inline]
+    fn max(self, y: f64) -> f64 {
+        f64::max(self, y)
+    }
+
+    #[inline]
+    fn is_finite(self) -> bool {
+        f64::is_finite(self)
+    }
+
+    #[inline]
+    fn select(cond: f64, x1: f64, x2: f64) -> f64 {
+        Code::select(cond, x1, x2)
+    }
 
-pub trait Callable {
-    fn call(&mut self, du: &mut [f64], u: &[f64], p: &[f64], t: f64);
-    fn call_py(&mut self, du: &mut [f64], u: &[f64], t: f64);
+    fn op(op: &str) -> BinaryFunc {
+        Code::from_str(op)
+    }
+}
+
+// the f64 masks (all ones) are narrowed to the f32 ones and back,
+// since a NaN payload does not survive the conversion
+impl Float for f32 {
+    const EPSILON: f32 = f32::EPSILON;
+
+    #[inline]
+    fn from_f64(x: f64) -> f32 {
+        if x.to_bits() == u64::MAX {
+            f32::from_bits(u32::MAX)
+        } else {
+            x as f32
+        }
+    }
+
+    #[inline]
+    fn to_f64(self) -> f64 {
+        if self.to_bits() == u32::MAX {
+            f64::from_bits(u64::MAX)
+        } else {
+            self as f64
+        }
+    }
+
+    #[inline]
+    fn abs(self) -> f32 {
+        f32::abs(self)
+    }
+
+    #[inline]
+    fn sqrt(self) -> f32 {
+        f32::sqrt(self)
+    }
+
+    #[inline]
+    fn max(self, y: f32) -> f32 {
+        f32::max(self, y)
+    }
+
+    #[inline]
+    fn is_finite(self) -> bool {
+        f32::is_finite(self)
+    }
+
+    #[inline]
+    fn select(cond: f32, x1: f32, x2: f32) -> f32 {
+        Code::select32(cond, x1, x2)
+    }
+
+    fn op(op: &str) -> BinaryFunc32 {
+        Code::from_str32(op)
+    }
 }
 
 /********************************************/
 
-pub trait Compiled {
+// the states, params, and diffs are T, but t stays f64 in every width,
+// so that long runs do not lose the time resolution
+pub trait Callable<T: Float = f64> {
+    fn call(&mut self, du: &mut [T], u: &[T], p: &[T], t: f64);
+    fn call_py(&mut self, du: &mut [T], u: &[T], t: f64);
+
+    // updates the external inputs (see CellModel::inputs), which persist
+    // across calls; models without inputs ignore it
+    fn set_inputs(&mut self, _inputs: &[T]) {}
+
+    // the observables at (u, p, t), solvers store them in each Row
+    fn observe(&mut self, _u: &[T], _p: &[T], _t: f64) -> Vec<T> {
+        Vec::new()
+    }
+
+    // the Jacobian of the diffs with respect to the states
+    // jac is row-major n x n, i.e., jac[i * n + j] = ∂du[i] / ∂u[j]
+    fn jacobian(&mut self, jac: &mut [T], u: &[T], p: &[T], t: f64) {
+        finite_jacobian(self, jac, u, p, t);
+    }
+
+    // true if jacobian is computed without calling call, e.g., symbolically
+    fn has_jacobian(&self) -> bool {
+        false
+    }
+}
+
+// the finite-difference Jacobian, the default for Callable::jacobian
+pub fn finite_jacobian<T: Float, F: Callable<T> + ?Sized>(
+    f: &mut F,
+    jac: &mut [T],
+    u: &[T],
+    p: &[T],
+    t: f64,
+) {
+    let n = u.len();
+    let mut f0 = vec![T::default(); n];
+    let mut f1 = vec![T::default(); n];
+    let mut v = u.to_vec();
+
+    f.call(&mut f0, u, p, t);
+
+    for j in 0..n {
+        let eps = T::EPSILON.sqrt() * u[j].abs().max(T::from_f64(1.0));
+        v[j] = u[j] + eps;
+        f.call(&mut f1, &v, p, t);
+        v[j] = u[j];
+
+        for i in 0..n {
+            jac[i * n + j] = (f1[i] - f0[i]) / eps;
+        }
+    }
+}
+
+/*
+    the directional finite difference J·v ≈ (f(u + εv) - f(u)) / ε, where
+    ε = sqrt((1 + |u|) eps) / |v| balances the truncation error, O(ε |v|^2),
+    against the rounding error, O(eps |f| / ε), so about half of the digits
+    are correct, as in finite_jacobian
+*/
+pub fn finite_jvp<F: Callable + ?Sized>(
+    f: &mut F,
+    out: &mut [f64],
+    u: &[f64],
+    p: &[f64],
+    t: f64,
+    v: &[f64],
+) {
+    let n = u.len();
+    let norm = |x: &[f64]| x.iter().map(|a| a * a).sum::<f64>().sqrt();
+    let nv = norm(v);
+
+    if nv == 0.0 {
+        out.fill(0.0);
+        return;
+    }
+
+    let eps = ((1.0 + norm(u)) * f64::EPSILON).sqrt() / nv;
+    let w: Vec<f64> = (0..n).map(|i| u[i] + eps * v[i]).collect();
+    let mut f0 = vec![0.0; n];
+
+    f.call(&mut f0, u, p, t);
+    f.call(out, &w, p, t);
+
+    for i in 0..n {
+        out[i] = (out[i] - f0[i]) / eps;
+    }
+}
+
+/********************************************/
+
+pub trait Compiled<T: Float = f64> {
     fn run(&mut self);
-    fn mem(&self) -> &[f64];
-    fn mem_mut(&mut self) -> &mut [f64];
+    fn mem(&self) -> &[T];
+    fn mem_mut(&mut self) -> &mut [T];
+
+    // discards any cached results that depend on params
+    fn invalidate(&mut self) {}
+
+    // exchanges the contents of mem with buf (of the same size)
+    fn swap_mem(&mut self, buf: &mut Vec<T>) {
+        let mem = self.mem_mut();
+        assert_eq!(mem.len(), buf.len(), "mem size mismatch");
+        mem.swap_with_slice(buf);
+    }
+
+    // the assembly listing of the generated code, if the backend records one
+    fn listing(&self) -> Option<&str> {
+        None
+    }
+
+    // the generated source text, e.g., WAT, Rust, or C, if the backend has one
+    fn source(&self) -> Option<&str> {
+        None
+    }
+
+    // writes the generated machine code next to its listing for post-mortem
+    // debugging and returns the path, if the backend has any (see keep_artifacts)
+    fn keep(&self) -> std::io::Result<Option<PathBuf>> {
+        Ok(None)
+    }
+
+    fn set_lookup(&mut self, _id: usize, _table: Lookup) {
+        panic!("lookup tables are only supported by the bytecode backend");
+    }
+
+    // returns an independent instance with its own copy of mem
+    // the compiled code itself is shared when possible
+    fn fork(&self) -> Box<dyn Compiled<T> + Send>;
+}
+
+// CELL_KEEP_ARTIFACTS=1 keeps the generated code and prints where it is
+pub fn keep_artifacts() -> bool {
+    std::env::var("CELL_KEEP_ARTIFACTS").is_ok_and(|v| !v.is_empty() && v != "0")
 }
 
 pub trait Compiler<T: Compiled> {
     fn compile(&mut self, prog: &Program) -> T;
+
+    // for the backends that can fail for reasons other than a malformed
+    // program, e.g., when executable memory is not available
+    fn try_compile(&mut self, prog: &Program) -> Result<T, CompileError> {
+        Ok(self.compile(prog))
+    }
 }
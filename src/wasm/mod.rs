@@ -1,5 +1,7 @@
 use anyhow::Result;
+use std::collections::HashMap;
 use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
 use wasmtime::*;
 
 use crate::code::*;
@@ -11,7 +13,8 @@ enum OpType {
     Nop, // not implemented yet!
     Unary(&'static str),
     Binary(&'static str),
-    Ternary(&'static str),
+    Compare(&'static str), // f64 operands, i32 result
+    Logical(&'static str), // i64 operands and result
 }
 
 #[derive(Debug)]
@@ -44,6 +47,7 @@ impl WasmCompiler {
         match op {
             "mov" => OpType::Unary("f64.store"),
             "neg" => OpType::Unary("f64.neg"),
+            "abs" => OpType::Unary("f64.abs"),
             "sin" => OpType::Unary("call $sin"),
             "cos" => OpType::Unary("call $cos"),
             "tan" => OpType::Unary("call $tan"),
@@ -53,10 +57,25 @@ impl WasmCompiler {
             "arcsin" => OpType::Unary("call $asin"),
             "arccos" => OpType::Unary("call $acos"),
             "arctan" => OpType::Unary("call $atan"),
+            "safe_arcsin" => OpType::Unary("call $safe_asin"),
+            "safe_arccos" => OpType::Unary("call $safe_acos"),
             "exp" => OpType::Unary("call $exp"),
             "ln" => OpType::Unary("call $ln"),
             "log" => OpType::Unary("call $log"),
+            "log2" => OpType::Unary("call $log2"),
             "root" => OpType::Unary("f64.sqrt"),
+            "cbrt" => OpType::Unary("call $cbrt"),
+            "erf" => OpType::Unary("call $erf"),
+            "erfc" => OpType::Unary("call $erfc"),
+            "gamma" => OpType::Unary("call $gamma"),
+            "lgamma" => OpType::Unary("call $lgamma"),
+            "sech2" => OpType::Unary("call $sech2"),
+            "sinh" => OpType::Unary("call $sinh"),
+            "cosh" => OpType::Unary("call $cosh"),
+            "tanh" => OpType::Unary("call $tanh"),
+            "expm1" => OpType::Unary("call $expm1"),
+            "log1p" => OpType::Unary("call $log1p"),
+            "signum" => OpType::Unary("call $signum"),
 
             "plus" => OpType::Binary("f64.add"),
             "minus" => OpType::Binary("f64.sub"),
@@ -64,16 +83,22 @@ impl WasmCompiler {
             "divide" => OpType::Binary("f64.div"),
             "rem" => OpType::Binary("call $rem"),
             "power" => OpType::Binary("call $power"),
-            "gt" => OpType::Binary("f64.gt"),
-            "geq" => OpType::Binary("f64.ge"),
-            "lt" => OpType::Binary("f64.lt"),
-            "leq" => OpType::Binary("f64.le"),
-            "eq" => OpType::Binary("f64.eq"),
-            "neq" => OpType::Binary("f64.ne"),
-            "and" => OpType::Binary("i32.and"),
-            "or" => OpType::Binary("i32.or"),
-            "xor" => OpType::Binary("i32.xor"),
-            "select" => OpType::Ternary("select"),
+            "atan2" => OpType::Binary("call $atan2"),
+            "logb" => OpType::Binary("call $logb"),
+            "hypot" => OpType::Binary("call $hypot"),
+            "interp1" => OpType::Binary("call $interp1"),
+            "interp1_slope" => OpType::Binary("call $interp1_slope"),
+            "min" => OpType::Binary("f64.min"),
+            "max" => OpType::Binary("f64.max"),
+            "gt" => OpType::Compare("f64.gt"),
+            "geq" => OpType::Compare("f64.ge"),
+            "lt" => OpType::Compare("f64.lt"),
+            "leq" => OpType::Compare("f64.le"),
+            "eq" => OpType::Compare("f64.eq"),
+            "neq" => OpType::Compare("f64.ne"),
+            "and" => OpType::Logical("i64.and"),
+            "or" => OpType::Logical("i64.or"),
+            "xor" => OpType::Logical("i64.xor"),
             _ => {
                 let msg = format!("op_code {} not found", op);
                 panic!("{}", msg);
@@ -84,7 +109,33 @@ impl WasmCompiler {
     fn imports(&mut self) {
         // unary
         for s in [
-            "sin", "cos", "tan", "csc", "sec", "cot", "asin", "acos", "atan", "exp", "ln", "log",
+            "sin",
+            "cos",
+            "tan",
+            "csc",
+            "sec",
+            "cot",
+            "asin",
+            "acos",
+            "atan",
+            "exp",
+            "ln",
+            "log",
+            "log2",
+            "cbrt",
+            "erf",
+            "erfc",
+            "gamma",
+            "lgamma",
+            "sech2",
+            "sinh",
+            "cosh",
+            "tanh",
+            "expm1",
+            "log1p",
+            "signum",
+            "safe_asin",
+            "safe_acos",
         ] {
             let cmd = format!(
                 "(import \"code\" \"{}\" (func ${} (param f64)(result f64)))",
@@ -94,7 +145,15 @@ impl WasmCompiler {
         }
 
         // binary
-        for s in ["rem", "power"] {
+        for s in [
+            "rem",
+            "power",
+            "atan2",
+            "logb",
+            "hypot",
+            "interp1",
+            "interp1_slope",
+        ] {
             let cmd = format!(
                 "(import \"code\" \"{}\" (func ${} (param f64)(param f64)(result f64)))",
                 s, s
@@ -103,12 +162,15 @@ impl WasmCompiler {
         }
     }
 
-    fn prologue(&mut self) {
+    fn prologue(&mut self, pages: usize) {
         self.push("(module");
         self.imports();
-        self.push("(memory $memory 1)");
+        self.push(format!("(memory $memory {})", pages).as_str());
         self.push("(export \"memory\" (memory $memory))");
         self.push("(func $run");
+        // scratch registers for the bitwise boolean ops
+        self.push("(local $y i64)");
+        self.push("(local $c i64)");
     }
 
     fn epilogue(&mut self) {
@@ -118,9 +180,14 @@ impl WasmCompiler {
     }
 }
 
+// the number of 64 KiB wasm pages needed to hold n f64s
+fn pages(n: usize) -> usize {
+    usize::max((8 * n).div_ceil(65536), 1)
+}
+
 impl Compiler<WasmCode> for WasmCompiler {
     fn compile(&mut self, prog: &Program) -> WasmCode {
-        self.prologue();
+        self.prologue(pages(prog.frame.mem().len()));
 
         for c in prog.code.iter() {
             match c {
@@ -131,15 +198,44 @@ impl Compiler<WasmCode> for WasmCompiler {
                         panic!("unkown unary op");
                     }
                 }
-                Instruction::Binary { op, .. } => {
-                    if let OpType::Binary(s) = self.op_code(op) {
+                Instruction::Binary { op, .. } => match self.op_code(op) {
+                    OpType::Binary(s) => self.push(s),
+                    // booleans are f64 bitmasks (see Code::boolean), so the
+                    // i32 result (0 or 1) is widened to 0 or -1 and reinterpreted
+                    OpType::Compare(s) => {
                         self.push(s);
-                    } else {
-                        panic!("unkown binary op");
+                        self.push("i64.extend_i32_u");
+                        self.push("i64.const -1");
+                        self.push("i64.mul");
+                        self.push("f64.reinterpret_i64");
                     }
-                }
+                    OpType::Logical(s) => {
+                        self.push("i64.reinterpret_f64");
+                        self.push("local.set $y");
+                        self.push("i64.reinterpret_f64");
+                        self.push("local.get $y");
+                        self.push(s);
+                        self.push("f64.reinterpret_i64");
+                    }
+                    _ => panic!("unkown binary op"),
+                },
+                // x1, x2 and cond are on the stack, the result is the
+                // bitwise blend (x1 & cond) | (x2 & !cond), as in Code::select
                 Instruction::IfElse { .. } => {
-                    self.push("select");
+                    self.push("i64.reinterpret_f64");
+                    self.push("local.set $c");
+                    self.push("i64.reinterpret_f64");
+                    self.push("local.get $c");
+                    self.push("i64.const -1");
+                    self.push("i64.xor");
+                    self.push("i64.and");
+                    self.push("local.set $y");
+                    self.push("i64.reinterpret_f64");
+                    self.push("local.get $c");
+                    self.push("i64.and");
+                    self.push("local.get $y");
+                    self.push("i64.or");
+                    self.push("f64.reinterpret_i64");
                 }
                 Instruction::Eq { dst } => {
                     self.push(format!("i32.const {}", 8 * dst.0).as_str());
@@ -150,6 +246,12 @@ impl Compiler<WasmCode> for WasmCompiler {
                 }
                 _ => {}
             }
+
+            // an op coalesced with its mov (see Program::coalesce_movs) stores
+            // to the address pushed by Eq
+            if c.assigned().is_some() && !c.is_mov() {
+                self.push("f64.store");
+            }
         }
 
         self.epilogue();
@@ -162,6 +264,19 @@ impl Compiler<WasmCode> for WasmCompiler {
 
 type HostState = u32;
 
+/*
+    compiled modules keyed by their WAT text, so that compiling the same model
+    again skips Module::new (each module carries its own Engine); entries are
+    never evicted, long-running hosts that compile many distinct models can
+    call clear_module_cache
+*/
+static MODULES: LazyLock<Mutex<HashMap<String, Module>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn clear_module_cache() {
+    MODULES.lock().unwrap().clear();
+}
+
 pub struct WasmCode {
     _mem: Vec<f64>,
     wat: String,
@@ -176,8 +291,30 @@ pub struct WasmCode {
 
 impl WasmCode {
     fn new(wat: String, _mem: Vec<f64>) -> Result<WasmCode> {
-        let engine = Engine::default();
-        let module = Module::new(&engine, wat.as_str())?;
+        let module = Self::load_module(&wat)?;
+        let engine = module.engine().clone();
+        Self::instantiate(wat, engine, module, _mem)
+    }
+
+    // returns the cached module for wat or compiles and caches a new one
+    fn load_module(wat: &str) -> Result<Module> {
+        if let Some(module) = MODULES.lock().unwrap().get(wat) {
+            return Ok(module.clone());
+        }
+
+        // compiled outside of the lock, since Module::new is slow
+        let module = Module::new(&Engine::default(), wat)?;
+        let mut modules = MODULES.lock().unwrap();
+        Ok(modules.entry(wat.to_string()).or_insert(module).clone())
+    }
+
+    // creates a fresh store and instance of an already compiled module
+    fn instantiate(
+        wat: String,
+        engine: Engine,
+        module: Module,
+        _mem: Vec<f64>,
+    ) -> Result<WasmCode> {
         let mut linker = Linker::<HostState>::new(&engine);
 
         Self::imports(&mut linker).expect("error in importing functions to wasm");
@@ -187,8 +324,11 @@ impl WasmCode {
         let run = instance.get_typed_func::<(), ()>(&mut store, "run")?;
         let memory = instance.get_memory(&mut store, "memory").unwrap();
 
-        let p: &mut [f64] = unsafe { std::mem::transmute(memory.data_mut(&mut store)) };
-        let _ = p[.._mem.len()].copy_from_slice(&_mem[..]);
+        let data = memory.data_mut(&mut store);
+        assert!(data.len() >= 8 * _mem.len(), "wasm memory is too small");
+        let p: &mut [f64] =
+            unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut f64, _mem.len()) };
+        let _ = p.copy_from_slice(&_mem[..]);
 
         let wasm = WasmCode {
             _mem,
@@ -206,20 +346,45 @@ impl WasmCode {
     }
 
     pub fn imports(linker: &mut Linker<HostState>) -> Result<()> {
-        linker.func_wrap("code", "sin", |x: f64| -> f64 { x.sin() })?;
-        linker.func_wrap("code", "cos", |x: f64| -> f64 { x.cos() })?;
-        linker.func_wrap("code", "tan", |x: f64| -> f64 { x.tan() })?;
-        linker.func_wrap("code", "csc", |x: f64| -> f64 { 1.0 / x.sin() })?;
-        linker.func_wrap("code", "sec", |x: f64| -> f64 { 1.0 / x.cos() })?;
-        linker.func_wrap("code", "cot", |x: f64| -> f64 { 1.0 / x.tan() })?;
-        linker.func_wrap("code", "asin", |x: f64| -> f64 { x.asin() })?;
-        linker.func_wrap("code", "acos", |x: f64| -> f64 { x.acos() })?;
-        linker.func_wrap("code", "atan", |x: f64| -> f64 { x.atan() })?;
-        linker.func_wrap("code", "exp", |x: f64| -> f64 { x.exp() })?;
-        linker.func_wrap("code", "ln", |x: f64| -> f64 { x.ln() })?;
-        linker.func_wrap("code", "log", |x: f64| -> f64 { x.log(10.0) })?;
-        linker.func_wrap("code", "rem", |x: f64, y: f64| -> f64 { x % y })?;
-        linker.func_wrap("code", "power", |x: f64, y: f64| -> f64 { x.powf(y) })?;
+        // through Code, so that wasm follows the native backends (and the libm feature)
+        linker.func_wrap("code", "sin", |x: f64| -> f64 { Code::sin(x, 0.0) })?;
+        linker.func_wrap("code", "cos", |x: f64| -> f64 { Code::cos(x, 0.0) })?;
+        linker.func_wrap("code", "tan", |x: f64| -> f64 { Code::tan(x, 0.0) })?;
+        linker.func_wrap("code", "csc", |x: f64| -> f64 { Code::csc(x, 0.0) })?;
+        linker.func_wrap("code", "sec", |x: f64| -> f64 { Code::sec(x, 0.0) })?;
+        linker.func_wrap("code", "cot", |x: f64| -> f64 { Code::cot(x, 0.0) })?;
+        linker.func_wrap("code", "asin", |x: f64| -> f64 { Code::asin(x, 0.0) })?;
+        linker.func_wrap("code", "acos", |x: f64| -> f64 { Code::acos(x, 0.0) })?;
+        linker.func_wrap("code", "atan", |x: f64| -> f64 { Code::atan(x, 0.0) })?;
+        linker.func_wrap("code", "safe_asin", |x: f64| -> f64 {
+            Code::safe_asin(x, 0.0)
+        })?;
+        linker.func_wrap("code", "safe_acos", |x: f64| -> f64 {
+            Code::safe_acos(x, 0.0)
+        })?;
+        linker.func_wrap("code", "exp", |x: f64| -> f64 { Code::exp(x, 0.0) })?;
+        linker.func_wrap("code", "ln", |x: f64| -> f64 { Code::ln(x, 0.0) })?;
+        linker.func_wrap("code", "log", |x: f64| -> f64 { Code::log(x, 0.0) })?;
+        linker.func_wrap("code", "log2", |x: f64| -> f64 { Code::log2(x, 0.0) })?;
+        linker.func_wrap("code", "cbrt", |x: f64| -> f64 { Code::cbrt(x, 0.0) })?;
+        linker.func_wrap("code", "erf", |x: f64| -> f64 { Code::erf(x, 0.0) })?;
+        linker.func_wrap("code", "erfc", |x: f64| -> f64 { Code::erfc(x, 0.0) })?;
+        linker.func_wrap("code", "gamma", |x: f64| -> f64 { Code::gamma(x, 0.0) })?;
+        linker.func_wrap("code", "lgamma", |x: f64| -> f64 { Code::lgamma(x, 0.0) })?;
+        linker.func_wrap("code", "sech2", |x: f64| -> f64 { Code::sech2(x, 0.0) })?;
+        linker.func_wrap("code", "sinh", |x: f64| -> f64 { Code::sinh(x, 0.0) })?;
+        linker.func_wrap("code", "cosh", |x: f64| -> f64 { Code::cosh(x, 0.0) })?;
+        linker.func_wrap("code", "tanh", |x: f64| -> f64 { Code::tanh(x, 0.0) })?;
+        linker.func_wrap("code", "expm1", |x: f64| -> f64 { Code::expm1(x, 0.0) })?;
+        linker.func_wrap("code", "log1p", |x: f64| -> f64 { Code::log1p(x, 0.0) })?;
+        linker.func_wrap("code", "signum", |x: f64| -> f64 { Code::signum(x, 0.0) })?;
+        linker.func_wrap("code", "rem", Code::rem)?;
+        linker.func_wrap("code", "power", Code::power)?;
+        linker.func_wrap("code", "atan2", Code::atan2)?;
+        linker.func_wrap("code", "logb", Code::logb)?;
+        linker.func_wrap("code", "hypot", Code::hypot)?;
+        linker.func_wrap("code", "interp1", Code::interp1)?;
+        linker.func_wrap("code", "interp1_slope", Code::interp1_slope)?;
 
         Ok(())
     }
@@ -232,13 +397,150 @@ impl Compiled for WasmCode {
 
     #[inline]
     fn mem(&self) -> &[f64] {
-        let p: &[f64] = unsafe { std::mem::transmute(self.memory.data(&self.store)) };
-        p
+        // the linear memory is page-aligned and mem occupies its first _mem.len() f64s
+        let data = self.memory.data(&self.store);
+        unsafe { std::slice::from_raw_parts(data.as_ptr() as *const f64, self._mem.len()) }
     }
 
     #[inline]
     fn mem_mut(&mut self) -> &mut [f64] {
-        let p: &mut [f64] = unsafe { std::mem::transmute(self.memory.data_mut(&mut self.store)) };
-        p
+        let n = self._mem.len();
+        let data = self.memory.data_mut(&mut self.store);
+        unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut f64, n) }
+    }
+
+    fn source(&self) -> Option<&str> {
+        Some(&self.wat)
+    }
+
+    fn fork(&self) -> Box<dyn Compiled + Send> {
+        let mem = self.mem().to_vec();
+        let wasm = WasmCode::instantiate(
+            self.wat.clone(),
+            self.engine.clone(),
+            self.module.clone(),
+            mem,
+        );
+        Box::new(wasm.expect("cannot instantiate the wasm module"))
+    }
+}
+
+#[test]
+fn test_wasm_select() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    // δx = ifelse(x > 0, a, b)
+    let text = r#"{
+        "iv": {"name": "t", "val": 0.0},
+        "params": [{"name": "a", "val": 2.0}, {"name": "b", "val": -3.0}],
+        "states": [{"name": "x", "val": 0.0}],
+        "algs": [],
+        "odes": [{
+            "lhs": {"type": "Tree", "op": "Differential", "args": [{"type": "Var", "name": "x"}]},
+            "rhs": {"type": "Tree", "op": "ifelse", "args": [
+                {"type": "Tree", "op": "gt", "args": [{"type": "Var", "name": "x"}, {"type": "Const", "val": 0.0}]},
+                {"type": "Var", "name": "a"},
+                {"type": "Var", "name": "b"}
+            ]}
+        }],
+        "obs": []
+    }"#;
+
+    let ml = CellModel::load(text).unwrap();
+    let mut r1 = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    let mut r2 = Runnable::new(Program::new(&ml), CompilerType::Wasm);
+    let p = [2.0, -3.0];
+
+    for x in [1.0, 1e-300, 0.0, -0.0, -1.0, f64::INFINITY, f64::NAN] {
+        let mut du1 = vec![0.0];
+        let mut du2 = vec![0.0];
+        r1.call(&mut du1, &[x], &p, 0.0);
+        r2.call(&mut du2, &[x], &p, 0.0);
+        assert_eq!(du2[0], if x > 0.0 { 2.0 } else { -3.0 });
+        assert_eq!(du1[0].to_bits(), du2[0].to_bits());
+    }
+}
+
+#[test]
+fn test_wasm_pages() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    assert_eq!(pages(0), 1);
+    assert_eq!(pages(8192), 1);
+    assert_eq!(pages(8193), 2);
+
+    // δx_i = -i * x_i for 5000 states needs more than 8192 slots
+    let n = 5000;
+    let states: Vec<String> = (0..n)
+        .map(|i| format!(r#"{{"name": "x{}", "val": 1.0}}"#, i))
+        .collect();
+    let odes: Vec<String> = (0..n)
+        .map(|i| {
+            format!(
+                r#"{{
+                    "lhs": {{"type": "Tree", "op": "Differential", "args": [{{"type": "Var", "name": "x{}"}}]}},
+                    "rhs": {{"type": "Tree", "op": "times", "args": [{{"type": "Const", "val": {}}}, {{"type": "Var", "name": "x{}"}}]}}
+                }}"#,
+                i,
+                -(i as f64),
+                i
+            )
+        })
+        .collect();
+    let text = format!(
+        r#"{{"iv": {{"name": "t", "val": 0.0}}, "params": [], "states": [{}], "algs": [], "odes": [{}], "obs": []}}"#,
+        states.join(", "),
+        odes.join(", ")
+    );
+
+    let ml = CellModel::load(&text).unwrap();
+    let prog = Program::new(&ml);
+    assert!(pages(prog.frame.mem().len()) >= 2);
+
+    let mut r = Runnable::new(prog, CompilerType::Wasm);
+    let u: Vec<f64> = (0..n).map(|i| 1.0 + i as f64).collect();
+    let mut du = vec![0.0; n];
+    r.call(&mut du, &u, &[], 0.0);
+
+    for (i, (du, u)) in du.iter().zip(u.iter()).enumerate() {
+        assert_eq!(*du, -(i as f64) * u);
     }
 }
+
+#[test]
+fn test_module_cache() {
+    use crate::model::CellModel;
+
+    let text = std::fs::read_to_string("julia/beeler.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+
+    let w1 = WasmCompiler::new().compile(&Program::new(&ml));
+    let w2 = WasmCompiler::new().compile(&Program::new(&ml));
+    assert!(Engine::same(&w1.engine, &w2.engine));
+
+    clear_module_cache();
+    assert!(!MODULES.lock().unwrap().contains_key(&w1.wat));
+
+    let w3 = WasmCompiler::new().compile(&Program::new(&ml));
+    assert!(!Engine::same(&w1.engine, &w3.engine));
+    assert!(MODULES.lock().unwrap().contains_key(&w3.wat));
+}
+
+#[test]
+fn test_wasm_source() {
+    use crate::model::CellModel;
+    use crate::runnable::{CompilerType, Runnable};
+
+    let text = std::fs::read_to_string("julia/lorenz.json").unwrap();
+    let ml = CellModel::load(&text).unwrap();
+
+    let r = Runnable::new(Program::new(&ml), CompilerType::Wasm);
+    let wat = r.source().unwrap();
+    assert!(wat.starts_with("(module"));
+    assert!(wat.contains("(export \"run\" (func $run))"));
+
+    let r = Runnable::new(Program::new(&ml), CompilerType::ByteCode);
+    assert!(r.source().is_none());
+}